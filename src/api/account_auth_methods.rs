@@ -4,10 +4,12 @@ use aide::axum::routing::{get_with, post_with, put_with};
 use aide::axum::ApiRouter;
 use aide::transform::TransformOperation;
 use axum::extract::Path;
+use axum::http::StatusCode;
 use axum::Json;
 use base64::engine::general_purpose;
 use base64::Engine;
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, Utc};
+use log::error;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
@@ -15,8 +17,10 @@ use crate::database::AppState;
 use crate::error::{ServiceError, ServiceResult};
 use crate::models;
 use crate::request_state::RequestState;
+use crate::{env, wallet};
 
-use super::accounts::{AccountDto, CardTypeDto};
+use super::accounts::{AccountDto, CardTypeDto, CoinAmountDto, NfcCardStatusDto};
+use super::audit_log;
 use super::password_hash_create;
 
 pub fn router(app_state: AppState) -> ApiRouter {
@@ -63,9 +67,31 @@ pub fn router(app_state: AppState) -> ApiRouter {
                 .put_with(update_nfc_authentication, update_nfc_authentication_docs)
                 .delete_with(delete_nfc_authentication, delete_nfc_authentication_docs),
         )
+        .api_route(
+            "/account/:id/nfc-authentication/block",
+            post_with(block_nfc_authentication, block_nfc_authentication_docs),
+        )
+        .api_route(
+            "/account/:id/qr-code-authentication/rotate",
+            post_with(
+                rotate_qr_code_authentication,
+                rotate_qr_code_authentication_docs,
+            ),
+        )
         .api_route(
             "/account/:id/sessions",
-            get_with(get_account_sessions, get_account_sessions_docs)
+            get_with(get_account_sessions, get_account_sessions_docs),
+        )
+        .api_route(
+            "/account/:id/sessions/revoke-others",
+            post_with(
+                revoke_other_account_sessions,
+                revoke_other_account_sessions_docs,
+            ),
+        )
+        .api_route(
+            "/account/:id/session/:session_id",
+            put_with(update_account_session, update_account_session_docs)
                 .delete_with(delete_account_session, delete_account_session_docs),
         )
         .with_state(app_state)
@@ -77,17 +103,39 @@ pub struct SaveAuthPasswordDto {
     pub password: String,
 }
 
+/// Store the modified account and record the change of its auth methods in the audit log
+pub(super) async fn store_account_audited(
+    state: &mut RequestState,
+    action: &str,
+    before: &AccountDto,
+    account: models::Account,
+) -> ServiceResult<Json<AccountDto>> {
+    let account = state.db.store_account(account).await?;
+    let after = AccountDto::from(&account);
+    audit_log::record(
+        state,
+        action,
+        "account",
+        Some(account.id),
+        Some(before),
+        Some(&after),
+    )
+    .await?;
+    Ok(Json(after))
+}
+
 async fn set_password_authentication(
     mut state: RequestState,
     Path(id): Path<u64>,
     form: Json<SaveAuthPasswordDto>,
 ) -> ServiceResult<Json<AccountDto>> {
-    state.session_require_admin_or_self(id)?;
+    state.session_require_permission_or_self(models::Permission::AccountsWrite, id)?;
 
     let form = form.0;
     let account = state.db.get_account_by_id(id).await?;
 
     if let Some(mut account) = account {
+        let before = AccountDto::from(&account);
         account
             .auth_methods
             .retain_mut(|m| !matches!(m, &mut models::AuthMethod::PasswordBased(_)));
@@ -98,8 +146,7 @@ async fn set_password_authentication(
                 password_hash: password_hash_create(&form.password)?,
             }));
 
-        let account = state.db.store_account(account).await?;
-        return Ok(Json(AccountDto::from(&account)));
+        return store_account_audited(&mut state, "password.set", &before, account).await;
     }
 
     Err(ServiceError::NotFound)
@@ -112,7 +159,7 @@ fn set_password_authentication_docs(op: TransformOperation) -> TransformOperatio
         .response_with::<404, (), _>(|res| res.description("The requested account does not exist!"))
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin", "self"])
+        .security_requirement_scopes("SessionToken", ["accounts.write", "self"])
 }
 
 #[derive(Debug, PartialEq, Serialize, JsonSchema)]
@@ -124,7 +171,7 @@ async fn create_password_reset_token(
     mut state: RequestState,
     Path(id): Path<u64>,
 ) -> ServiceResult<Json<PasswordResetTokenDto>> {
-    state.session_require_admin_or_self(id)?;
+    state.session_require_permission_or_self(models::Permission::AccountsWrite, id)?;
 
     let account = state.db.get_account_by_id(id).await?;
     if let Some(account) = account {
@@ -134,8 +181,9 @@ async fn create_password_reset_token(
             .create_session_token(
                 account.id,
                 models::AuthMethodType::PasswordResetToken,
-                valid_until,
+                models::SessionLifetime::Fixed(valid_until),
                 false,
+                &models::SessionClient::default(),
             )
             .await?;
 
@@ -151,6 +199,16 @@ async fn create_password_reset_token(
             });
         }
 
+        audit_log::record::<()>(
+            &mut state,
+            "password_reset_token.create",
+            "account",
+            Some(id),
+            None,
+            None,
+        )
+        .await?;
+
         return Ok(Json(PasswordResetTokenDto { token }));
     }
 
@@ -166,7 +224,7 @@ fn create_password_reset_token_docs(op: TransformOperation) -> TransformOperatio
         })
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin", "self"])
+        .security_requirement_scopes("SessionToken", ["accounts.write", "self"])
 }
 
 async fn reset_token_password_authentication(
@@ -183,6 +241,7 @@ async fn reset_token_password_authentication(
     }
 
     let form = form.0;
+    let before = AccountDto::from(&account);
 
     account
         .auth_methods
@@ -194,8 +253,7 @@ async fn reset_token_password_authentication(
             password_hash: password_hash_create(&form.password)?,
         }));
 
-    let account = state.db.store_account(account).await?;
-    Ok(Json(AccountDto::from(&account)))
+    store_account_audited(&mut state, "password.reset", &before, account).await
 }
 
 fn reset_token_password_authentication_docs(op: TransformOperation) -> TransformOperation {
@@ -205,24 +263,24 @@ fn reset_token_password_authentication_docs(op: TransformOperation) -> Transform
         .response_with::<404, (), _>(|res| {
             res.description("The requested reset link does not exist!")
         })
-        .security_requirement_scopes("SessionToken", ["admin", "self"])
+        .security_requirement_scopes("SessionToken", ["self"])
 }
 
 async fn delete_password_authentication(
     mut state: RequestState,
     Path(id): Path<u64>,
 ) -> ServiceResult<Json<AccountDto>> {
-    state.session_require_admin_or_self(id)?;
+    state.session_require_permission_or_self(models::Permission::AccountsWrite, id)?;
 
     let account = state.db.get_account_by_id(id).await?;
 
     if let Some(mut account) = account {
+        let before = AccountDto::from(&account);
         account
             .auth_methods
             .retain_mut(|m| !matches!(m, &mut models::AuthMethod::PasswordBased(_)));
 
-        let account = state.db.store_account(account).await?;
-        return Ok(Json(AccountDto::from(&account)));
+        return store_account_audited(&mut state, "password.delete", &before, account).await;
     }
 
     Err(ServiceError::NotFound)
@@ -235,25 +293,25 @@ fn delete_password_authentication_docs(op: TransformOperation) -> TransformOpera
         .response_with::<404, (), _>(|res| res.description("The requested account does not exist!"))
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin", "self"])
+        .security_requirement_scopes("SessionToken", ["accounts.write", "self"])
 }
 
 async fn set_public_tab_authentication(
     mut state: RequestState,
     Path(id): Path<u64>,
 ) -> ServiceResult<Json<AccountDto>> {
-    state.session_require_admin_or_self(id)?;
+    state.session_require_permission_or_self(models::Permission::AccountsWrite, id)?;
 
     let account = state.db.get_account_by_id(id).await?;
 
     if let Some(mut account) = account {
+        let before = AccountDto::from(&account);
         account
             .auth_methods
             .retain_mut(|m| !matches!(m, &mut models::AuthMethod::PublicTab));
         account.auth_methods.push(models::AuthMethod::PublicTab);
 
-        let account = state.db.store_account(account).await?;
-        return Ok(Json(AccountDto::from(&account)));
+        return store_account_audited(&mut state, "public_tab.set", &before, account).await;
     }
 
     Err(ServiceError::NotFound)
@@ -266,24 +324,24 @@ fn set_public_tab_authentication_docs(op: TransformOperation) -> TransformOperat
         .response_with::<404, (), _>(|res| res.description("The requested account does not exist!"))
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin", "self"])
+        .security_requirement_scopes("SessionToken", ["accounts.write", "self"])
 }
 
 async fn delete_public_tab_authentication(
     mut state: RequestState,
     Path(id): Path<u64>,
 ) -> ServiceResult<Json<AccountDto>> {
-    state.session_require_admin_or_self(id)?;
+    state.session_require_permission_or_self(models::Permission::AccountsWrite, id)?;
 
     let account = state.db.get_account_by_id(id).await?;
 
     if let Some(mut account) = account {
+        let before = AccountDto::from(&account);
         account
             .auth_methods
             .retain_mut(|m| !matches!(m, &mut models::AuthMethod::PublicTab));
 
-        let account = state.db.store_account(account).await?;
-        return Ok(Json(AccountDto::from(&account)));
+        return store_account_audited(&mut state, "public_tab.delete", &before, account).await;
     }
 
     Err(ServiceError::NotFound)
@@ -296,7 +354,7 @@ fn delete_public_tab_authentication_docs(op: TransformOperation) -> TransformOpe
         .response_with::<404, (), _>(|res| res.description("The requested account does not exist!"))
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin", "self"])
+        .security_requirement_scopes("SessionToken", ["accounts.write", "self"])
 }
 
 #[derive(Debug, PartialEq, Deserialize, JsonSchema)]
@@ -312,6 +370,17 @@ pub struct CreateAuthNfcDto {
 pub struct UpdateAuthNfcDto {
     pub card_id: String,
     pub name: String,
+    /// Requires `accounts.write`, unchanged if not set
+    pub status: Option<NfcCardStatusDto>,
+    /// RFC 3339 expiry date, an empty string removes the expiry. Requires `accounts.write`, unchanged if not set
+    pub valid_until: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct BlockAuthNfcDto {
+    pub card_id: String,
+    /// Mark the card as lost instead of blocked
+    pub lost: Option<bool>,
 }
 
 #[derive(Debug, PartialEq, Deserialize, JsonSchema)]
@@ -324,7 +393,7 @@ async fn create_nfc_authentication(
     Path(id): Path<u64>,
     form: Json<CreateAuthNfcDto>,
 ) -> ServiceResult<Json<AccountDto>> {
-    state.session_require_admin_or_self(id)?;
+    state.session_require_permission_or_self(models::Permission::AccountsWrite, id)?;
 
     let form = form.0;
     let account = state.db.get_account_by_id(id).await?;
@@ -336,6 +405,7 @@ async fn create_nfc_authentication(
     };
 
     if let Some(mut account) = account {
+        let before = AccountDto::from(&account);
         let card_id = general_purpose::STANDARD
             .decode(form.card_id)
             .map_err(|_| {
@@ -357,12 +427,14 @@ async fn create_nfc_authentication(
                 name: form.name,
                 card_id,
                 card_type: form.card_type.into(),
-                data,
+                data: models::NfcSecret::seal(&data)?,
                 depends_on_session,
+                status: models::NfcCardStatus::Active,
+                last_used_at: None,
+                valid_until: None,
             }));
 
-        let account = state.db.store_account(account).await?;
-        return Ok(Json(AccountDto::from(&account)));
+        return store_account_audited(&mut state, "nfc_card.create", &before, account).await;
     }
 
     Err(ServiceError::NotFound)
@@ -375,7 +447,7 @@ fn create_nfc_authentication_docs(op: TransformOperation) -> TransformOperation
         .response_with::<404, (), _>(|res| res.description("The requested account does not exist!"))
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin", "self"])
+        .security_requirement_scopes("SessionToken", ["accounts.write", "self"])
 }
 
 async fn update_nfc_authentication(
@@ -383,12 +455,27 @@ async fn update_nfc_authentication(
     Path(id): Path<u64>,
     form: Json<UpdateAuthNfcDto>,
 ) -> ServiceResult<Json<AccountDto>> {
-    state.session_require_admin_or_self(id)?;
+    state.session_require_permission_or_self(models::Permission::AccountsWrite, id)?;
 
     let form = form.0;
+    if form.status.is_some() || form.valid_until.is_some() {
+        state.session_require_permission(models::Permission::AccountsWrite)?;
+    }
+
+    let valid_until = match form.valid_until.as_deref() {
+        None => None,
+        Some("") => Some(None),
+        Some(valid_until) => Some(Some(
+            DateTime::parse_from_rfc3339(valid_until)
+                .map_err(|_| ServiceError::BadRequest("valid_until is not a RFC 3339 timestamp"))?
+                .into(),
+        )),
+    };
+
     let account = state.db.get_account_by_id(id).await?;
 
     if let Some(mut account) = account {
+        let before = AccountDto::from(&account);
         let card_id = general_purpose::STANDARD
             .decode(form.card_id)
             .map_err(|_| {
@@ -401,12 +488,17 @@ async fn update_nfc_authentication(
             if let models::AuthMethod::NfcBased(nfc_based) = method {
                 if nfc_based.card_id == card_id {
                     nfc_based.name.clone_from(&form.name);
+                    if let Some(status) = form.status {
+                        nfc_based.status = status.into();
+                    }
+                    if let Some(valid_until) = valid_until {
+                        nfc_based.valid_until = valid_until;
+                    }
                 }
             }
         }
 
-        let account = state.db.store_account(account).await?;
-        return Ok(Json(AccountDto::from(&account)));
+        return store_account_audited(&mut state, "nfc_card.update", &before, account).await;
     }
 
     Err(ServiceError::NotFound)
@@ -416,10 +508,67 @@ fn update_nfc_authentication_docs(op: TransformOperation) -> TransformOperation
     op.description("Update an existing nfc based authentication method of the given account.")
         .tag("account_authentication")
         .response::<200, Json<AccountDto>>()
+        .response_with::<400, (), _>(|res| res.description("Invalid expiry timestamp!"))
         .response_with::<404, (), _>(|res| res.description("The requested account does not exist!"))
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin", "self"])
+        .security_requirement_scopes("SessionToken", ["accounts.write", "self"])
+}
+
+async fn block_nfc_authentication(
+    mut state: RequestState,
+    Path(id): Path<u64>,
+    form: Json<BlockAuthNfcDto>,
+) -> ServiceResult<Json<AccountDto>> {
+    state.session_require_permission_or_self(models::Permission::AccountsWrite, id)?;
+
+    let form = form.0;
+    let account = state.db.get_account_by_id(id).await?;
+
+    if let Some(mut account) = account {
+        let before = AccountDto::from(&account);
+        let card_id = general_purpose::STANDARD
+            .decode(form.card_id)
+            .map_err(|_| {
+                ServiceError::InternalServerError(
+                    "Could not decode base64 parameter 'card_id'.".to_string(),
+                )
+            })?;
+
+        let status = if form.lost.unwrap_or(false) {
+            models::NfcCardStatus::Lost
+        } else {
+            models::NfcCardStatus::Blocked
+        };
+
+        let mut found = false;
+        for method in account.auth_methods.iter_mut() {
+            if let models::AuthMethod::NfcBased(nfc_based) = method {
+                if nfc_based.card_id == card_id {
+                    nfc_based.status = status;
+                    found = true;
+                }
+            }
+        }
+
+        if !found {
+            return Err(ServiceError::NotFound);
+        }
+
+        return store_account_audited(&mut state, "nfc_card.block", &before, account).await;
+    }
+
+    Err(ServiceError::NotFound)
+}
+
+fn block_nfc_authentication_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Block a nfc card of the given account, e.g. if it was lost. Only users with `accounts.write` can unblock the card again.")
+        .tag("account_authentication")
+        .response::<200, Json<AccountDto>>()
+        .response_with::<404, (), _>(|res| res.description("The requested account or card does not exist!"))
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.write", "self"])
 }
 
 async fn delete_nfc_authentication(
@@ -427,12 +576,13 @@ async fn delete_nfc_authentication(
     Path(id): Path<u64>,
     form: Json<DeleteAuthNfcDto>,
 ) -> ServiceResult<Json<AccountDto>> {
-    state.session_require_admin_or_self(id)?;
+    state.session_require_permission_or_self(models::Permission::AccountsWrite, id)?;
 
     let form = form.0;
     let account = state.db.get_account_by_id(id).await?;
 
     if let Some(mut account) = account {
+        let before = AccountDto::from(&account);
         let card_id = general_purpose::STANDARD
             .decode(form.card_id)
             .map_err(|_| {
@@ -449,8 +599,7 @@ async fn delete_nfc_authentication(
             }
         });
 
-        let account = state.db.store_account(account).await?;
-        return Ok(Json(AccountDto::from(&account)));
+        return store_account_audited(&mut state, "nfc_card.delete", &before, account).await;
     }
 
     Err(ServiceError::NotFound)
@@ -463,7 +612,57 @@ fn delete_nfc_authentication_docs(op: TransformOperation) -> TransformOperation
         .response_with::<404, (), _>(|res| res.description("The requested account does not exist!"))
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin", "self"])
+        .security_requirement_scopes("SessionToken", ["accounts.write", "self"])
+}
+
+async fn rotate_qr_code_authentication(
+    mut state: RequestState,
+    Path(id): Path<u64>,
+) -> ServiceResult<StatusCode> {
+    state.session_require_permission_or_self(models::Permission::AccountsWrite, id)?;
+
+    let pass = state
+        .db
+        .get_apple_wallet_pass(id, &env::APPLE_WALLET_PASS_TYPE_IDENTIFIER)
+        .await?
+        .ok_or(ServiceError::NotFound)?;
+    state
+        .db
+        .rotate_apple_wallet_pass_qr_code(
+            &pass.qr_code,
+            &pass.pass_type_id,
+            &wallet::generate_random_string(64),
+            wallet::get_current_time(),
+        )
+        .await?;
+
+    audit_log::record::<()>(
+        &mut state,
+        "qr_code.rotate",
+        "account",
+        Some(id),
+        None,
+        None,
+    )
+    .await?;
+
+    tokio::spawn(async move {
+        if let Err(e) = wallet::send_update_notification(&mut state.db, id).await {
+            error!("Could not send apns update! {:?}", e)
+        }
+    });
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn rotate_qr_code_authentication_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Replace the qr code on the wallet pass of the given account, e.g. if a screenshot of the pass was shared. The old code can no longer be used to login.")
+        .tag("account_authentication")
+        .response_with::<204, (), _>(|res| res.description("The qr code was successfully rotated!"))
+        .response_with::<404, (), _>(|res| res.description("The account has no wallet pass!"))
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.write", "self"])
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
@@ -472,6 +671,8 @@ pub enum AuthMethodTypeDto {
     NfcBased,
     PublicTab,
     PasswordResetToken,
+    Impersonation,
+    QrCode,
 }
 impl From<&models::AuthMethodType> for AuthMethodTypeDto {
     fn from(value: &models::AuthMethodType) -> Self {
@@ -480,35 +681,72 @@ impl From<&models::AuthMethodType> for AuthMethodTypeDto {
             models::AuthMethodType::NfcBased => AuthMethodTypeDto::NfcBased,
             models::AuthMethodType::PublicTab => AuthMethodTypeDto::PublicTab,
             models::AuthMethodType::PasswordResetToken => AuthMethodTypeDto::PasswordResetToken,
+            models::AuthMethodType::Impersonation => AuthMethodTypeDto::Impersonation,
+            models::AuthMethodType::QrCode => AuthMethodTypeDto::QrCode,
         }
     }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
 pub struct SessionDto {
+    pub id: String,
     pub auth_method: AuthMethodTypeDto,
     pub valid_until: String,
     pub is_single_use: bool,
+    pub created_at: String,
+    pub last_used_at: String,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub device_name: Option<String>,
+    /// Only present for single use payment tokens
+    pub payment_limit: Option<CoinAmountDto>,
+    /// `true` if this is the session used for the current request
+    pub is_current: bool,
+    /// `true` if an admin acts on behalf of the account with this session
+    pub impersonated: bool,
+    pub impersonated_by_account_id: Option<u64>,
 }
 impl From<&models::Session> for SessionDto {
     fn from(value: &models::Session) -> Self {
         Self {
+            id: value.id.to_owned(),
             auth_method: (&value.auth_method).into(),
             valid_until: format!("{:?}", value.valid_until),
             is_single_use: value.is_single_use,
+            created_at: format!("{:?}", value.created_at),
+            last_used_at: format!("{:?}", value.last_used_at),
+            user_agent: value.client.user_agent.to_owned(),
+            ip_address: value.client.ip_address.to_owned(),
+            device_name: value.client.device_name.to_owned(),
+            payment_limit: value.payment_limit.as_ref().map(CoinAmountDto::from),
+            is_current: false,
+            impersonated: value.impersonated_by.is_some(),
+            impersonated_by_account_id: value.impersonated_by,
         }
     }
 }
 
+async fn list_session_dtos(state: &mut RequestState, id: u64) -> ServiceResult<Vec<SessionDto>> {
+    let current_session_id = state.session.as_ref().map(|s| s.id.clone());
+    let sessions = state.db.get_sessions_by_account(id).await?;
+
+    Ok(sessions
+        .iter()
+        .map(|s| {
+            let mut dto = SessionDto::from(s);
+            dto.is_current = current_session_id.as_ref() == Some(&s.id);
+            dto
+        })
+        .collect())
+}
+
 async fn get_account_sessions(
     mut state: RequestState,
     Path(id): Path<u64>,
 ) -> ServiceResult<Json<Vec<SessionDto>>> {
-    state.session_require_admin_or_self(id)?;
-
-    let sessions = state.db.get_sessions_by_account(id).await?;
+    state.session_require_permission_or_self(models::Permission::AccountsRead, id)?;
 
-    Ok(Json(sessions.iter().map(|s| s.into()).collect()))
+    Ok(Json(list_session_dtos(&mut state, id).await?))
 }
 
 fn get_account_sessions_docs(op: TransformOperation) -> TransformOperation {
@@ -518,39 +756,78 @@ fn get_account_sessions_docs(op: TransformOperation) -> TransformOperation {
         .response_with::<404, (), _>(|res| res.description("The requested account does not exist!"))
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin", "self"])
+        .security_requirement_scopes("SessionToken", ["accounts.read", "self"])
 }
 
-async fn delete_account_session(
+async fn revoke_other_account_sessions(
     mut state: RequestState,
     Path(id): Path<u64>,
-    form: Json<SessionDto>,
 ) -> ServiceResult<Json<Vec<SessionDto>>> {
-    state.session_require_admin_or_self(id)?;
+    state.session_require_permission_or_self(models::Permission::AccountsWrite, id)?;
+    let session = state.session_require()?;
+
+    state.db.delete_other_sessions(id, &session.token).await?;
+
+    Ok(Json(list_session_dtos(&mut state, id).await?))
+}
+
+fn revoke_other_account_sessions_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Revoke all sessions of the given account except the current session.")
+        .tag("account_authentication")
+        .response::<200, Json<Vec<SessionDto>>>()
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.write", "self"])
+}
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct UpdateSessionDto {
+    pub device_name: Option<String>,
+}
+
+async fn update_account_session(
+    mut state: RequestState,
+    Path((id, session_id)): Path<(u64, String)>,
+    form: Json<UpdateSessionDto>,
+) -> ServiceResult<Json<Vec<SessionDto>>> {
+    state.session_require_permission_or_self(models::Permission::AccountsWrite, id)?;
 
     let form = form.0;
-    let sessions = state.db.get_sessions_by_account(id).await?;
+    state
+        .db
+        .set_session_device_name(id, &session_id, form.device_name)
+        .await?;
 
-    for session in sessions {
-        let session_dto = SessionDto::from(&session);
-        if session_dto.auth_method == form.auth_method
-            && session_dto.valid_until == form.valid_until
-            && session_dto.is_single_use == form.is_single_use
-        {
-            state.db.delete_session_token(session.token).await?;
-        }
-    }
+    Ok(Json(list_session_dtos(&mut state, id).await?))
+}
 
-    let sessions = state.db.get_sessions_by_account(id).await?;
-    Ok(Json(sessions.iter().map(|s| s.into()).collect()))
+fn update_account_session_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Set the device name of an active session.")
+        .tag("account_authentication")
+        .response::<200, Json<Vec<SessionDto>>>()
+        .response_with::<404, (), _>(|res| res.description("The requested session does not exist!"))
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.write", "self"])
+}
+
+async fn delete_account_session(
+    mut state: RequestState,
+    Path((id, session_id)): Path<(u64, String)>,
+) -> ServiceResult<Json<Vec<SessionDto>>> {
+    state.session_require_permission_or_self(models::Permission::AccountsWrite, id)?;
+
+    state.db.delete_session_by_id(id, &session_id).await?;
+
+    Ok(Json(list_session_dtos(&mut state, id).await?))
 }
 
 fn delete_account_session_docs(op: TransformOperation) -> TransformOperation {
-    op.description("Delete a active session for the given account.")
+    op.description("Revoke a single session of the given account.")
         .tag("account_authentication")
         .response::<200, Json<Vec<SessionDto>>>()
-        .response_with::<404, (), _>(|res| res.description("The requested account does not exist!"))
+        .response_with::<404, (), _>(|res| res.description("The requested session does not exist!"))
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin", "self"])
+        .security_requirement_scopes("SessionToken", ["accounts.write", "self"])
 }
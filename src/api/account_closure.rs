@@ -0,0 +1,200 @@
+use aide::axum::routing::post_with;
+use aide::axum::ApiRouter;
+use aide::transform::TransformOperation;
+use axum::extract::Path;
+use axum::Json;
+use chrono::Utc;
+use log::error;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::database::AppState;
+use crate::error::{ServiceError, ServiceResult};
+use crate::models::{self, CoinAmount, CoinType};
+use crate::request_state::RequestState;
+use crate::{env, wallet};
+
+use super::accounts::AccountDto;
+use super::audit_log;
+use super::register::RegisterPayoutDto;
+use super::transactions::TransactionDto;
+
+pub fn router(app_state: AppState) -> ApiRouter {
+    ApiRouter::new()
+        .api_route(
+            "/account/:id/close",
+            post_with(close_account, close_account_docs),
+        )
+        .with_state(app_state)
+}
+
+/// Payout items that bring all balances of the account to zero.
+///
+/// The first item removes the stamps and credits their converted value, the second one pays out the cents.
+/// Returns the items and the amount of cents that leave the register.
+fn closure_payment_items(
+    balance: &CoinAmount,
+    convert_stamps: bool,
+) -> (Vec<models::PaymentItem>, i32) {
+    let get = |t: CoinType| balance.0.get(&t).copied().unwrap_or(0);
+    let coffee_stamps = get(CoinType::CoffeeStamp);
+    let bottle_stamps = get(CoinType::BottleStamp);
+
+    let converted_cents = if convert_stamps {
+        coffee_stamps.max(0) * *env::COFFEE_STAMP_PAYOUT_CENTS
+            + bottle_stamps.max(0) * *env::BOTTLE_STAMP_PAYOUT_CENTS
+    } else {
+        0
+    };
+    let payout_cents = get(CoinType::Cent) + converted_cents;
+
+    let items = vec![
+        models::PaymentItem {
+            effective_price: CoinAmount(
+                [
+                    (CoinType::Cent, -converted_cents),
+                    (CoinType::CoffeeStamp, coffee_stamps),
+                    (CoinType::BottleStamp, bottle_stamps),
+                ]
+                .into_iter()
+                .collect(),
+            ),
+            product_id: None,
+        },
+        models::PaymentItem {
+            effective_price: CoinAmount([(CoinType::Cent, payout_cents)].into_iter().collect()),
+            product_id: None,
+        },
+    ];
+    (items, payout_cents)
+}
+
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub struct AccountClosureDto {
+    pub account: AccountDto,
+    pub transaction: TransactionDto,
+    pub payout: RegisterPayoutDto,
+}
+
+async fn close_account(
+    mut state: RequestState,
+    Path(id): Path<u64>,
+) -> ServiceResult<Json<AccountClosureDto>> {
+    state.session_require_permission(models::Permission::AccountsWrite)?;
+    state.session_require_permission(models::Permission::RegisterWrite)?;
+    let session = state.session_require()?;
+
+    let account = state
+        .db
+        .get_account_by_id(id)
+        .await?
+        .ok_or(ServiceError::NotFound)?;
+    if account.archived_at.is_some() {
+        return Err(ServiceError::AccountArchived);
+    }
+
+    let convert_stamps = env::ACCOUNT_CLOSURE_STAMP_POLICY.as_str() == "convert";
+    let (items, payout_cents) = closure_payment_items(&account.balance, convert_stamps);
+    let paid_by_account_id = session.account.id;
+    let payment = models::Payment {
+        account: id,
+        items,
+        authorization: Some(session),
+    };
+
+    // The payout is booked as a regular transaction so that the account history stays complete
+    let transaction = state.db.payment(payment, Utc::now(), false).await?;
+    let payout = state
+        .db
+        .store_register_payout(models::RegisterPayout {
+            id: 0,
+            timestamp: transaction.timestamp,
+            account_id: Some(id),
+            transaction_id: transaction.id,
+            amount_cents: payout_cents,
+            paid_by_account_id: Some(paid_by_account_id),
+        })
+        .await?;
+    state.db.archive_account(id).await?;
+
+    let payout = RegisterPayoutDto::from(&payout);
+    audit_log::record(
+        &mut state,
+        "account.close",
+        "account",
+        Some(id),
+        None,
+        Some(&payout),
+    )
+    .await?;
+
+    let account = state
+        .db
+        .get_account_by_id(id)
+        .await?
+        .ok_or(ServiceError::NotFound)?;
+
+    let mut db = state.db;
+    tokio::spawn(async move {
+        if let Err(e) = wallet::send_update_notification(&mut db, id).await {
+            error!("Could not send apns update! {:?}", e)
+        }
+    });
+
+    Ok(Json(AccountClosureDto {
+        account: AccountDto::from(&account),
+        transaction: TransactionDto::from(&transaction),
+        payout,
+    }))
+}
+
+fn close_account_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Close the given account. The remaining balance is paid out from the register, stamps are forfeited or converted according to `ACCOUNT_CLOSURE_STAMP_POLICY`. The account is archived afterwards, its transactions are kept but it can no longer log in.")
+        .tag("accounts")
+        .response::<200, Json<AccountClosureDto>>()
+        .response_with::<404, (), _>(|res| res.description("The requested account does not exist!"))
+        .response_with::<409, (), _>(|res| res.description("The account is already archived!"))
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.write", "register.write"])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn balance(cents: i32, coffee_stamps: i32, bottle_stamps: i32) -> CoinAmount {
+        CoinAmount(
+            [
+                (CoinType::Cent, cents),
+                (CoinType::CoffeeStamp, coffee_stamps),
+                (CoinType::BottleStamp, bottle_stamps),
+            ]
+            .into_iter()
+            .collect(),
+        )
+    }
+
+    fn total(items: &[models::PaymentItem], t: CoinType) -> i32 {
+        items
+            .iter()
+            .map(|i| i.effective_price.0.get(&t).copied().unwrap_or(0))
+            .sum()
+    }
+
+    #[test]
+    fn test_closure_payment_items_forfeit() {
+        let (items, payout) = closure_payment_items(&balance(1250, 4, 2), false);
+        assert_eq!(payout, 1250);
+        assert_eq!(total(&items, CoinType::Cent), 1250);
+        assert_eq!(total(&items, CoinType::CoffeeStamp), 4);
+        assert_eq!(total(&items, CoinType::BottleStamp), 2);
+    }
+
+    #[test]
+    fn test_closure_payment_items_debt() {
+        let (items, payout) = closure_payment_items(&balance(-300, 0, 0), false);
+        assert_eq!(payout, -300);
+        assert_eq!(total(&items, CoinType::Cent), -300);
+    }
+}
@@ -0,0 +1,156 @@
+use aide::axum::routing::{get_with, post_with};
+use aide::axum::ApiRouter;
+use aide::transform::TransformOperation;
+use axum::extract::Path;
+use axum::Json;
+use chrono::Utc;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::database::AppState;
+use crate::error::{ServiceError, ServiceResult};
+use crate::request_state::RequestState;
+use crate::{env, models, wallet};
+
+use super::account_auth_methods::SessionDto;
+use super::accounts::AccountDto;
+use super::audit_log;
+use super::transactions::TransactionDto;
+
+pub fn router(app_state: AppState) -> ApiRouter {
+    ApiRouter::new()
+        .api_route(
+            "/account/:id/export",
+            get_with(export_account_data, export_account_data_docs),
+        )
+        .api_route(
+            "/account/:id/anonymize",
+            post_with(anonymize_account, anonymize_account_docs),
+        )
+        .with_state(app_state)
+}
+
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub struct WalletPassExportDto {
+    pub pass_type_id: String,
+    pub updated_at: u64,
+}
+
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub struct WalletRegistrationExportDto {
+    pub pass_type_id: String,
+    pub device_id: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub struct AccountExportDto {
+    pub exported_at: String,
+    /// Auth methods are listed without passwords or card keys
+    pub account: AccountDto,
+    pub sessions: Vec<SessionDto>,
+    pub transactions: Vec<TransactionDto>,
+    pub wallet_passes: Vec<WalletPassExportDto>,
+    pub wallet_registrations: Vec<WalletRegistrationExportDto>,
+}
+
+async fn export_account_data(
+    mut state: RequestState,
+    Path(id): Path<u64>,
+) -> ServiceResult<Json<AccountExportDto>> {
+    state.session_require_permission_or_self(models::Permission::AccountsRead, id)?;
+
+    let account = state
+        .db
+        .get_account_by_id(id)
+        .await?
+        .ok_or(ServiceError::NotFound)?;
+    let sessions = state.db.get_sessions_by_account(id).await?;
+    let transactions = state.db.get_transactions_by_account(id).await?;
+    let wallet_pass = state
+        .db
+        .get_apple_wallet_pass(id, &env::APPLE_WALLET_PASS_TYPE_IDENTIFIER)
+        .await?;
+    let wallet_registrations = state
+        .db
+        .get_apple_wallet_registrations_by_account(id)
+        .await?;
+
+    Ok(Json(AccountExportDto {
+        exported_at: format!("{:?}", Utc::now()),
+        account: AccountDto::from(&account),
+        sessions: sessions.iter().map(SessionDto::from).collect(),
+        transactions: transactions.iter().map(TransactionDto::from).collect(),
+        wallet_passes: wallet_pass
+            .iter()
+            .map(|p| WalletPassExportDto {
+                pass_type_id: p.pass_type_id.clone(),
+                updated_at: p.updated_at,
+            })
+            .collect(),
+        wallet_registrations: wallet_registrations
+            .iter()
+            .map(|r| WalletRegistrationExportDto {
+                pass_type_id: r.pass_type_id.clone(),
+                device_id: r.device_id.clone(),
+            })
+            .collect(),
+    }))
+}
+
+fn export_account_data_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Export all personal data stored for the given account.")
+        .tag("accounts")
+        .response::<200, Json<AccountExportDto>>()
+        .response_with::<404, (), _>(|res| res.description("The requested account does not exist!"))
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.read", "self"])
+}
+
+async fn anonymize_account(
+    mut state: RequestState,
+    Path(id): Path<u64>,
+) -> ServiceResult<Json<AccountDto>> {
+    state.session_require_permission_or_self(models::Permission::AccountsWrite, id)?;
+
+    let account = state
+        .db
+        .get_account_by_id(id)
+        .await?
+        .ok_or(ServiceError::NotFound)?;
+    if account.balance.0.values().any(|amount| *amount != 0) {
+        return Err(ServiceError::BalanceNotZero);
+    }
+
+    let pseudonym = format!("anonymous-{}", wallet::generate_random_string(12));
+    state.db.anonymize_account(id, &pseudonym).await?;
+
+    // The audit entry must not contain the scrubbed personal data
+    audit_log::record::<()>(
+        &mut state,
+        "account.anonymize",
+        "account",
+        Some(id),
+        None,
+        None,
+    )
+    .await?;
+
+    let account = state
+        .db
+        .get_account_by_id(id)
+        .await?
+        .ok_or(ServiceError::NotFound)?;
+    Ok(Json(AccountDto::from(&account)))
+}
+
+fn anonymize_account_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Remove all personal data of the given account. Name and email are replaced by a pseudonym, auth methods, sessions and wallet passes are removed. Audit log, impersonation log and pending invitations are scrubbed as well. Transactions stay attributed to the account id for bookkeeping.")
+        .tag("accounts")
+        .response::<200, Json<AccountDto>>()
+        .response_with::<404, (), _>(|res| res.description("The requested account does not exist!"))
+        .response_with::<409, (), _>(|res| res.description("The account balance is not zero!"))
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.write", "self"])
+}
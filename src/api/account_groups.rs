@@ -0,0 +1,248 @@
+use aide::axum::routing::{get_with, put_with};
+use aide::axum::ApiRouter;
+use aide::transform::TransformOperation;
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::Json;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::database::AppState;
+use crate::error::{ServiceError, ServiceResult};
+use crate::models::{self, Session};
+use crate::request_state::RequestState;
+
+use super::audit_log;
+
+pub fn router(app_state: AppState) -> ApiRouter {
+    ApiRouter::new()
+        .api_route(
+            "/account/:id/members",
+            get_with(list_group_members, list_group_members_docs),
+        )
+        .api_route(
+            "/account/:id/member/:member_id",
+            put_with(update_group_member, update_group_member_docs)
+                .delete_with(delete_group_member, delete_group_member_docs),
+        )
+        .api_route(
+            "/account/:id/groups",
+            get_with(list_account_groups, list_account_groups_docs),
+        )
+        .with_state(app_state)
+}
+
+/// Require a global `accounts.write` permission or admin rights in the given group
+async fn session_require_group_admin(
+    state: &mut RequestState,
+    group_account_id: u64,
+) -> ServiceResult<()> {
+    let account = state.session_require_login()?;
+    if state.session_has_permission(models::Permission::AccountsWrite) {
+        return Ok(());
+    }
+
+    let member = state
+        .db
+        .get_account_group_member(group_account_id, account.id)
+        .await?;
+    if member.map(|m| m.is_admin).unwrap_or(false) {
+        return Ok(());
+    }
+
+    Err(ServiceError::Forbidden)
+}
+
+/// Require a session of a member of the given group account.
+///
+/// Payment tokens of the member are accepted as well. The spending limit of the member is checked by the payment.
+pub async fn session_require_group_payment(
+    state: &mut RequestState,
+    group_account_id: u64,
+) -> ServiceResult<Session> {
+    let Some(session) = state.session.clone() else {
+        return Err(ServiceError::Unauthorized("Missing login!"));
+    };
+
+    state
+        .db
+        .get_account_group_member(group_account_id, session.account.id)
+        .await?
+        .ok_or(ServiceError::Forbidden)?;
+
+    Ok(session)
+}
+
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub struct AccountGroupMemberDto {
+    pub group_account_id: u64,
+    pub member_account_id: u64,
+    pub member_name: String,
+    pub is_admin: bool,
+    /// Maximum amount of cents the member may spend from the group account within 30 days
+    pub spending_limit_cents: Option<i32>,
+}
+
+impl From<&models::AccountGroupMember> for AccountGroupMemberDto {
+    fn from(value: &models::AccountGroupMember) -> Self {
+        Self {
+            group_account_id: value.group_account_id,
+            member_account_id: value.member_account_id,
+            member_name: value.member_name.to_owned(),
+            is_admin: value.is_admin,
+            spending_limit_cents: value.spending_limit_cents,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct SaveAccountGroupMemberDto {
+    pub is_admin: bool,
+    pub spending_limit_cents: Option<i32>,
+}
+
+async fn list_group_members(
+    mut state: RequestState,
+    Path(id): Path<u64>,
+) -> ServiceResult<Json<Vec<AccountGroupMemberDto>>> {
+    if !state.session_has_permission(models::Permission::AccountsRead) {
+        session_require_group_admin(&mut state, id).await?;
+    }
+
+    let members = state.db.get_account_group_members(id).await?;
+    Ok(Json(members.iter().map(|m| m.into()).collect()))
+}
+
+fn list_group_members_docs(op: TransformOperation) -> TransformOperation {
+    op.description("List all members of the given group account.")
+        .tag("account_groups")
+        .response::<200, Json<Vec<AccountGroupMemberDto>>>()
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.read", "group admin"])
+}
+
+async fn update_group_member(
+    mut state: RequestState,
+    Path((id, member_id)): Path<(u64, u64)>,
+    form: Json<SaveAccountGroupMemberDto>,
+) -> ServiceResult<Json<AccountGroupMemberDto>> {
+    session_require_group_admin(&mut state, id).await?;
+
+    let form = form.0;
+    if id == member_id {
+        return Err(ServiceError::Forbidden);
+    }
+    for account_id in [id, member_id] {
+        let account = state
+            .db
+            .get_account_by_id(account_id)
+            .await?
+            .ok_or(ServiceError::NotFound)?;
+        if account.archived_at.is_some() {
+            return Err(ServiceError::AccountArchived);
+        }
+    }
+
+    let before = state
+        .db
+        .get_account_group_member(id, member_id)
+        .await?
+        .map(|m| AccountGroupMemberDto::from(&m));
+    let member = state
+        .db
+        .store_account_group_member(models::AccountGroupMember {
+            group_account_id: id,
+            member_account_id: member_id,
+            member_name: String::new(),
+            is_admin: form.is_admin,
+            spending_limit_cents: form.spending_limit_cents,
+        })
+        .await?;
+
+    let member = AccountGroupMemberDto::from(&member);
+    audit_log::record(
+        &mut state,
+        if before.is_some() {
+            "account_group_member.update"
+        } else {
+            "account_group_member.create"
+        },
+        "account",
+        Some(id),
+        before.as_ref(),
+        Some(&member),
+    )
+    .await?;
+
+    Ok(Json(member))
+}
+
+fn update_group_member_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Add an account to the given group account or update its membership. Members may pay from the group account, payments record the member as `authorized_by_account_id`.")
+        .tag("account_groups")
+        .response::<200, Json<AccountGroupMemberDto>>()
+        .response_with::<404, (), _>(|res| res.description("The requested account does not exist!"))
+        .response_with::<409, (), _>(|res| res.description("One of the accounts is archived!"))
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.write", "group admin"])
+}
+
+async fn delete_group_member(
+    mut state: RequestState,
+    Path((id, member_id)): Path<(u64, u64)>,
+) -> ServiceResult<StatusCode> {
+    // Members can always leave a group
+    if !state.session_is_self(member_id) {
+        session_require_group_admin(&mut state, id).await?;
+    }
+
+    let before = state
+        .db
+        .get_account_group_member(id, member_id)
+        .await?
+        .ok_or(ServiceError::NotFound)?;
+    state.db.delete_account_group_member(id, member_id).await?;
+
+    audit_log::record(
+        &mut state,
+        "account_group_member.delete",
+        "account",
+        Some(id),
+        Some(&AccountGroupMemberDto::from(&before)),
+        None,
+    )
+    .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn delete_group_member_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Remove an account from the given group account.")
+        .tag("account_groups")
+        .response_with::<204, (), _>(|res| res.description("The member was removed!"))
+        .response_with::<404, (), _>(|res| res.description("The requested member does not exist!"))
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.write", "group admin", "self"])
+}
+
+async fn list_account_groups(
+    mut state: RequestState,
+    Path(id): Path<u64>,
+) -> ServiceResult<Json<Vec<AccountGroupMemberDto>>> {
+    state.session_require_permission_or_self(models::Permission::AccountsRead, id)?;
+
+    let groups = state.db.get_account_groups_by_member(id).await?;
+    Ok(Json(groups.iter().map(|m| m.into()).collect()))
+}
+
+fn list_account_groups_docs(op: TransformOperation) -> TransformOperation {
+    op.description("List all group accounts the given account is a member of.")
+        .tag("account_groups")
+        .response::<200, Json<Vec<AccountGroupMemberDto>>>()
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.read", "self"])
+}
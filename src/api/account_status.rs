@@ -51,7 +51,7 @@ impl From<&models::AccountStatus> for AccountStatusDto {
 async fn list_account_status(
     mut state: RequestState,
 ) -> ServiceResult<Json<Vec<AccountStatusDto>>> {
-    state.session_require_admin()?;
+    state.session_require_permission(models::Permission::AccountsRead)?;
 
     let account_status = state.db.get_all_account_status().await?;
     Ok(Json(account_status.iter().map(|a| a.into()).collect()))
@@ -63,14 +63,14 @@ fn list_account_status_docs(op: TransformOperation) -> TransformOperation {
         .response::<200, Json<Vec<AccountStatusDto>>>()
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin"])
+        .security_requirement_scopes("SessionToken", ["accounts.read"])
 }
 
 pub async fn get_account_status(
     mut state: RequestState,
     Path(id): Path<u64>,
 ) -> ServiceResult<Json<AccountStatusDto>> {
-    state.session_require_admin_or_self(id)?;
+    state.session_require_permission_or_self(models::Permission::AccountsRead, id)?;
 
     let account_status = state.db.get_account_status_by_id(id).await?;
 
@@ -90,7 +90,7 @@ fn get_account_status_docs(op: TransformOperation) -> TransformOperation {
         })
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin", "self"])
+        .security_requirement_scopes("SessionToken", ["accounts.read", "self"])
 }
 
 #[derive(Debug, PartialEq, Deserialize, JsonSchema)]
@@ -104,7 +104,7 @@ async fn create_account_status(
     mut state: RequestState,
     form: Json<SaveAccountStatusDto>,
 ) -> ServiceResult<Json<AccountStatusDto>> {
-    state.session_require_admin()?;
+    state.session_require_permission(models::Permission::AccountsWrite)?;
 
     let form = form.0;
 
@@ -125,7 +125,7 @@ fn create_account_status_docs(op: TransformOperation) -> TransformOperation {
         .response::<200, Json<AccountStatusDto>>()
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin"])
+        .security_requirement_scopes("SessionToken", ["accounts.write"])
 }
 
 async fn update_account_status(
@@ -133,7 +133,7 @@ async fn update_account_status(
     Path(id): Path<u64>,
     form: Json<SaveAccountStatusDto>,
 ) -> ServiceResult<Json<AccountStatusDto>> {
-    state.session_require_admin_or_self(id)?;
+    state.session_require_permission(models::Permission::AccountsWrite)?;
 
     let form = form.0;
     let account_status = state.db.get_account_status_by_id(id).await?;
@@ -166,14 +166,14 @@ fn update_account_status_docs(op: TransformOperation) -> TransformOperation {
         })
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin", "self"])
+        .security_requirement_scopes("SessionToken", ["accounts.write"])
 }
 
 async fn delete_account_status(
     mut state: RequestState,
     Path(id): Path<u64>,
 ) -> ServiceResult<StatusCode> {
-    state.session_require_admin_or_self(id)?;
+    state.session_require_permission(models::Permission::AccountsWrite)?;
 
     state.db.delete_account_status(id).await?;
     Ok(StatusCode::NO_CONTENT)
@@ -190,5 +190,5 @@ fn delete_account_status_docs(op: TransformOperation) -> TransformOperation {
         })
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin", "self"])
+        .security_requirement_scopes("SessionToken", ["accounts.write"])
 }
@@ -16,10 +16,12 @@ use crate::database::AppState;
 use crate::error::{ServiceError, ServiceResult};
 use crate::models::CoinType;
 use crate::request_state::RequestState;
-use crate::{models, wallet};
+use crate::{env, models, wallet};
 
 use super::account_status::AccountStatusDto;
+use super::audit_log;
 use super::password_hash_create;
+use super::roles::PermissionDto;
 
 pub fn router(app_state: AppState) -> ApiRouter {
     ApiRouter::new()
@@ -29,6 +31,10 @@ pub fn router(app_state: AppState) -> ApiRouter {
                 .put_with(update_account, update_account_docs)
                 .delete_with(delete_account, delete_account_docs),
         )
+        .api_route(
+            "/account/:id/merge",
+            post_with(merge_account, merge_account_docs),
+        )
         .api_route(
             "/accounts",
             get_with(list_accounts, list_accounts_docs)
@@ -115,38 +121,11 @@ impl From<&CoinAmountDto> for models::CoinAmount {
     }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
-pub enum RoleDto {
-    Basic,
-    Member,
-    Purchaser,
-    Admin,
-}
-impl From<&models::Role> for RoleDto {
-    fn from(value: &models::Role) -> Self {
-        match value {
-            models::Role::Basic => RoleDto::Basic,
-            models::Role::Member => RoleDto::Member,
-            models::Role::Purchaser => RoleDto::Purchaser,
-            models::Role::Admin => RoleDto::Admin,
-        }
-    }
-}
-impl From<RoleDto> for models::Role {
-    fn from(value: RoleDto) -> Self {
-        match value {
-            RoleDto::Basic => models::Role::Basic,
-            RoleDto::Member => models::Role::Member,
-            RoleDto::Purchaser => models::Role::Purchaser,
-            RoleDto::Admin => models::Role::Admin,
-        }
-    }
-}
-
 #[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub enum CardTypeDto {
     GenericNfc,
     AsciiMifare,
+    AsciiMifareAes,
     HostCardEmulation,
 }
 impl From<&models::CardType> for CardTypeDto {
@@ -154,6 +133,7 @@ impl From<&models::CardType> for CardTypeDto {
         match value {
             models::CardType::GenericNfc => CardTypeDto::GenericNfc,
             models::CardType::AsciiMifare => CardTypeDto::AsciiMifare,
+            models::CardType::AsciiMifareAes => CardTypeDto::AsciiMifareAes,
             models::CardType::HostCardEmulation => CardTypeDto::HostCardEmulation,
         }
     }
@@ -163,11 +143,40 @@ impl From<CardTypeDto> for models::CardType {
         match value {
             CardTypeDto::GenericNfc => models::CardType::GenericNfc,
             CardTypeDto::AsciiMifare => models::CardType::AsciiMifare,
+            CardTypeDto::AsciiMifareAes => models::CardType::AsciiMifareAes,
             CardTypeDto::HostCardEmulation => models::CardType::HostCardEmulation,
         }
     }
 }
 
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+pub enum NfcCardStatusDto {
+    Active,
+    Blocked,
+    Lost,
+    Expired,
+}
+impl From<&models::NfcCardStatus> for NfcCardStatusDto {
+    fn from(value: &models::NfcCardStatus) -> Self {
+        match value {
+            models::NfcCardStatus::Active => NfcCardStatusDto::Active,
+            models::NfcCardStatus::Blocked => NfcCardStatusDto::Blocked,
+            models::NfcCardStatus::Lost => NfcCardStatusDto::Lost,
+            models::NfcCardStatus::Expired => NfcCardStatusDto::Expired,
+        }
+    }
+}
+impl From<NfcCardStatusDto> for models::NfcCardStatus {
+    fn from(value: NfcCardStatusDto) -> Self {
+        match value {
+            NfcCardStatusDto::Active => models::NfcCardStatus::Active,
+            NfcCardStatusDto::Blocked => models::NfcCardStatus::Blocked,
+            NfcCardStatusDto::Lost => models::NfcCardStatus::Lost,
+            NfcCardStatusDto::Expired => models::NfcCardStatus::Expired,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Serialize, JsonSchema)]
 pub struct AuthPasswordDto {
     username: String,
@@ -179,6 +188,9 @@ pub struct AuthNfcDto {
     card_id: String,
     card_type: CardTypeDto,
     depends_on_session: Option<String>,
+    status: NfcCardStatusDto,
+    last_used_at: Option<String>,
+    valid_until: Option<String>,
 }
 
 #[derive(Debug, PartialEq, Serialize, JsonSchema)]
@@ -201,6 +213,9 @@ impl From<&models::AuthMethod> for AuthMethodDto {
                 card_id: general_purpose::STANDARD.encode(&nfc_based.card_id),
                 card_type: (&nfc_based.card_type).into(),
                 depends_on_session: nfc_based.depends_on_session.clone(),
+                status: (&nfc_based.current_status()).into(),
+                last_used_at: nfc_based.last_used_at.map(|t| format!("{t:?}")),
+                valid_until: nfc_based.valid_until.map(|t| format!("{t:?}")),
             }),
             models::AuthMethod::PublicTab => AuthMethodDto::PublicTab,
         }
@@ -213,11 +228,13 @@ pub struct AccountDto {
     pub balance: CoinAmountDto,
     pub name: String,
     pub email: String,
-    pub role: RoleDto,
+    pub role: String,
+    pub permissions: Vec<PermissionDto>,
     pub auth_methods: Vec<AuthMethodDto>,
     pub enable_monthly_mail_report: bool,
     pub enable_automatic_stamp_usage: bool,
     pub status: Option<AccountStatusDto>,
+    pub archived_at: Option<String>,
 }
 
 impl From<&models::Account> for AccountDto {
@@ -227,17 +244,24 @@ impl From<&models::Account> for AccountDto {
             balance: (&value.balance).into(),
             name: value.name.to_owned(),
             email: value.email.to_owned(),
-            role: (&value.role).into(),
+            role: value.role.name.to_owned(),
+            permissions: value
+                .role
+                .permissions
+                .iter()
+                .map(PermissionDto::from)
+                .collect(),
             auth_methods: value.auth_methods.iter().map(AuthMethodDto::from).collect(),
             enable_monthly_mail_report: value.enable_monthly_mail_report,
             enable_automatic_stamp_usage: value.enable_automatic_stamp_usage,
             status: value.status.as_ref().map(AccountStatusDto::from),
+            archived_at: value.archived_at.map(|t| format!("{:?}", t)),
         }
     }
 }
 
 async fn list_accounts(mut state: RequestState) -> ServiceResult<Json<Vec<AccountDto>>> {
-    state.session_require_admin()?;
+    state.session_require_permission(models::Permission::AccountsRead)?;
 
     let accounts = state.db.get_all_accounts().await?;
     Ok(Json(accounts.iter().map(|a| a.into()).collect()))
@@ -249,7 +273,7 @@ fn list_accounts_docs(op: TransformOperation) -> TransformOperation {
         .response::<200, Json<Vec<AccountDto>>>()
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin"])
+        .security_requirement_scopes("SessionToken", ["accounts.read"])
 }
 
 async fn list_accounts_for_public_tab_board(
@@ -278,7 +302,7 @@ pub async fn get_account(
     mut state: RequestState,
     Path(id): Path<u64>,
 ) -> ServiceResult<Json<AccountDto>> {
-    state.session_require_admin_or_self(id)?;
+    state.session_require_permission_or_self(models::Permission::AccountsRead, id)?;
 
     let account = state.db.get_account_by_id(id).await?;
 
@@ -296,14 +320,15 @@ fn get_account_docs(op: TransformOperation) -> TransformOperation {
         .response_with::<404, (), _>(|res| res.description("The requested account does not exist!"))
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin", "self"])
+        .security_requirement_scopes("SessionToken", ["accounts.read", "self"])
 }
 
 #[derive(Debug, PartialEq, Deserialize, JsonSchema)]
 pub struct SaveAccountDto {
     pub name: String,
     pub email: String,
-    pub role: RoleDto,
+    /// Name of the role, see `/roles`
+    pub role: String,
     pub enable_monthly_mail_report: bool,
     pub enable_automatic_stamp_usage: bool,
     pub status_id: Option<u64>,
@@ -313,39 +338,63 @@ async fn create_account(
     mut state: RequestState,
     form: Json<SaveAccountDto>,
 ) -> ServiceResult<Json<AccountDto>> {
-    state.session_require_admin()?;
+    state.session_require_permission(models::Permission::AccountsWrite)?;
 
     let form = form.0;
 
+    if form.role != *env::DEFAULT_ACCOUNT_ROLE {
+        // Only accounts that may manage roles are allowed to assign them
+        state.session_require_permission(models::Permission::RolesWrite)?;
+    }
+
     let status = if let Some(status_id) = form.status_id {
         state.db.get_account_status_by_id(status_id).await?
     } else {
         None
     };
 
+    let role = state
+        .db
+        .get_role_by_name(&form.role)
+        .await?
+        .ok_or(ServiceError::NotFound)?;
+
     let account = models::Account {
         id: 0,
         balance: models::CoinAmount(HashMap::new()),
         name: form.name,
         email: form.email,
-        role: form.role.into(),
+        role,
         auth_methods: Vec::new(),
         enable_monthly_mail_report: form.enable_monthly_mail_report,
         enable_automatic_stamp_usage: form.enable_automatic_stamp_usage,
+        archived_at: None,
         status,
     };
 
     let account = state.db.store_account(account).await?;
-    Ok(Json(AccountDto::from(&account)))
+    let account = AccountDto::from(&account);
+    audit_log::record(
+        &mut state,
+        "account.create",
+        "account",
+        Some(account.id),
+        None,
+        Some(&account),
+    )
+    .await?;
+
+    Ok(Json(account))
 }
 
 fn create_account_docs(op: TransformOperation) -> TransformOperation {
-    op.description("Create a new account.")
+    op.description("Create a new account. Assigning a role other than the default role requires `roles.write`.")
         .tag("accounts")
         .response::<200, Json<AccountDto>>()
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<404, (), _>(|res| res.description("The requested role does not exist!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin"])
+        .security_requirement_scopes("SessionToken", ["accounts.write"])
 }
 
 async fn update_account(
@@ -353,7 +402,7 @@ async fn update_account(
     Path(id): Path<u64>,
     form: Json<SaveAccountDto>,
 ) -> ServiceResult<Json<AccountDto>> {
-    state.session_require_admin_or_self(id)?;
+    state.session_require_permission_or_self(models::Permission::AccountsWrite, id)?;
 
     let form = form.0;
     let account = state.db.get_account_by_id(id).await?;
@@ -365,20 +414,34 @@ async fn update_account(
     };
 
     if let Some(mut account) = account {
+        let before = AccountDto::from(&account);
         account.name = form.name;
         account.email = form.email;
         account.enable_monthly_mail_report = form.enable_monthly_mail_report;
         account.enable_automatic_stamp_usage = form.enable_automatic_stamp_usage;
         account.status = status;
 
-        let new_role = form.role.into();
-        if account.role != new_role {
-            // Only admins are allowed to change account roles
-            state.session_require_admin()?;
-            account.role = new_role;
+        if account.role.name != form.role {
+            // Only accounts that may manage roles are allowed to assign them
+            state.session_require_permission(models::Permission::RolesWrite)?;
+            account.role = state
+                .db
+                .get_role_by_name(&form.role)
+                .await?
+                .ok_or(ServiceError::NotFound)?;
         }
 
         let account = state.db.store_account(account).await?;
+        let account = AccountDto::from(&account);
+        audit_log::record(
+            &mut state,
+            "account.update",
+            "account",
+            Some(id),
+            Some(&before),
+            Some(&account),
+        )
+        .await?;
 
         tokio::task::spawn(async move {
             if let Err(e) = wallet::send_update_notification(&mut state.db, id).await {
@@ -386,7 +449,7 @@ async fn update_account(
             }
         });
 
-        return Ok(Json(AccountDto::from(&account)));
+        return Ok(Json(account));
     }
 
     Err(ServiceError::NotFound)
@@ -399,14 +462,100 @@ fn update_account_docs(op: TransformOperation) -> TransformOperation {
         .response_with::<404, (), _>(|res| res.description("The requested account does not exist!"))
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin", "self"])
+        .security_requirement_scopes("SessionToken", ["accounts.write", "self"])
+}
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct MergeAccountDto {
+    /// This account is merged into the account of the path and deleted afterwards
+    pub source_account_id: u64,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct AccountMergeAuditDto {
+    source: Option<AccountDto>,
+    target: AccountDto,
+}
+
+async fn merge_account(
+    mut state: RequestState,
+    Path(id): Path<u64>,
+    form: Json<MergeAccountDto>,
+) -> ServiceResult<Json<AccountDto>> {
+    state.session_require_permission(models::Permission::AccountsWrite)?;
+    let session = state.session_require()?;
+
+    let form = form.0;
+    if form.source_account_id == id {
+        return Err(ServiceError::Forbidden);
+    }
+
+    let target = state
+        .db
+        .get_account_by_id(id)
+        .await?
+        .ok_or(ServiceError::NotFound)?;
+    let source = state
+        .db
+        .get_account_by_id(form.source_account_id)
+        .await?
+        .ok_or(ServiceError::NotFound)?;
+
+    state
+        .db
+        .merge_accounts(
+            id,
+            form.source_account_id,
+            Some(session.impersonated_by.unwrap_or(session.account.id)),
+        )
+        .await?;
+
+    let account = state
+        .db
+        .get_account_by_id(id)
+        .await?
+        .ok_or(ServiceError::NotFound)?;
+    audit_log::record(
+        &mut state,
+        "account.merge",
+        "account",
+        Some(id),
+        Some(&AccountMergeAuditDto {
+            source: Some(AccountDto::from(&source)),
+            target: AccountDto::from(&target),
+        }),
+        Some(&AccountMergeAuditDto {
+            source: None,
+            target: AccountDto::from(&account),
+        }),
+    )
+    .await?;
+
+    tokio::task::spawn(async move {
+        if let Err(e) = wallet::send_update_notification(&mut state.db, id).await {
+            error!("Could not send apns update! {:?}", e)
+        }
+    });
+
+    Ok(Json(AccountDto::from(&account)))
+}
+
+fn merge_account_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Merge another account into the given account. Balance, auth methods, transactions, wallet passes and purchases are moved, the source account is deleted. Requests for the old account id answer with `410 AccountMerged` and the new id.")
+        .tag("accounts")
+        .response::<200, Json<AccountDto>>()
+        .response_with::<404, (), _>(|res| res.description("One of the accounts does not exist!"))
+        .response_with::<410, (), _>(|res| res.description("One of the accounts was already merged!"))
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.write"])
 }
 
 async fn delete_account(mut state: RequestState, Path(id): Path<u64>) -> ServiceResult<StatusCode> {
-    state.session_require_admin_or_self(id)?;
+    state.session_require_permission_or_self(models::Permission::AccountsWrite, id)?;
 
     let account = state.db.get_account_by_id(id).await?;
-    if let Some(account) = account {
+    let before = if let Some(account) = account {
         let balance_cents = account.balance.0.get(&CoinType::Cent).copied().unwrap_or(0);
         let balance_bottle_stamps = account
             .balance
@@ -424,11 +573,21 @@ async fn delete_account(mut state: RequestState, Path(id): Path<u64>) -> Service
         if balance_cents != 0 || balance_bottle_stamps != 0 || balance_coffee_stamps != 0 {
             return Err(ServiceError::BalanceNotZero);
         }
+        AccountDto::from(&account)
     } else {
         return Ok(StatusCode::NOT_FOUND);
-    }
+    };
 
     state.db.delete_account(id).await?;
+    audit_log::record(
+        &mut state,
+        "account.delete",
+        "account",
+        Some(id),
+        Some(&before),
+        None,
+    )
+    .await?;
     Ok(StatusCode::NO_CONTENT)
 }
 
@@ -439,7 +598,7 @@ fn delete_account_docs(op: TransformOperation) -> TransformOperation {
         .response_with::<404, (), _>(|res| res.description("The requested account does not exist!"))
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin", "self"])
+        .security_requirement_scopes("SessionToken", ["accounts.write", "self"])
 }
 
 #[derive(Debug, PartialEq, Deserialize, JsonSchema)]
@@ -454,13 +613,13 @@ async fn create_admin_account(
     mut state: RequestState,
     form: Json<CreateAdminAccountDto>,
 ) -> ServiceResult<Json<AccountDto>> {
-    let accounts = state.db.get_all_accounts().await?;
-    let admin_account_found = accounts
-        .iter()
-        .any(|a| matches!(a.role, models::Role::Admin));
-    if admin_account_found {
-        return Err(ServiceError::NotFound);
-    }
+    let roles = state.db.get_all_roles().await?;
+    let admin_role = roles
+        .into_iter()
+        .find(|r| r.has_all_permissions())
+        .ok_or_else(|| {
+            ServiceError::InternalServerError("No role with all permissions exists!".to_owned())
+        })?;
 
     let form = form.0;
 
@@ -469,11 +628,12 @@ async fn create_admin_account(
         balance: models::CoinAmount(HashMap::new()),
         name: form.name,
         email: form.email,
-        role: models::Role::Admin,
+        role: admin_role,
         auth_methods: Vec::new(),
         enable_monthly_mail_report: false,
         enable_automatic_stamp_usage: true,
         status: None,
+        archived_at: None,
     };
 
     account
@@ -483,14 +643,29 @@ async fn create_admin_account(
             password_hash: password_hash_create(&form.password)?,
         }));
 
-    let account = state.db.store_account(account).await?;
-    Ok(Json(AccountDto::from(&account)))
+    // The setup is closed as soon as any account exists
+    let Some(account) = state.db.store_first_account(account).await? else {
+        return Err(ServiceError::NotFound);
+    };
+    let account = AccountDto::from(&account);
+    audit_log::record(
+        &mut state,
+        "account.create_admin",
+        "account",
+        Some(account.id),
+        None,
+        Some(&account),
+    )
+    .await?;
+
+    Ok(Json(account))
 }
 
 fn create_admin_account_docs(op: TransformOperation) -> TransformOperation {
-    op.description("Create an initial admin account.")
+    op.description("Create an initial admin account. Only possible as long as no account exists.")
         .tag("accounts")
         .response::<200, Json<AccountDto>>()
+        .response_with::<404, (), _>(|res| res.description("An account already exists!"))
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
         .security_requirement_scopes("SessionToken", ["admin"])
@@ -0,0 +1,189 @@
+use aide::axum::routing::get_with;
+use aide::axum::ApiRouter;
+use aide::transform::TransformOperation;
+use axum::extract::Query;
+use axum::Json;
+use chrono::DateTime;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::database::AppState;
+use crate::error::{ServiceError, ServiceResult};
+use crate::models;
+use crate::request_state::RequestState;
+
+const DEFAULT_LIMIT: u64 = 100;
+const MAX_LIMIT: u64 = 1000;
+
+pub fn router(app_state: AppState) -> ApiRouter {
+    ApiRouter::new()
+        .api_route("/audit-log", get_with(get_audit_log, get_audit_log_docs))
+        .with_state(app_state)
+}
+
+/// Reduce two json objects to the fields that differ between them
+fn json_diff(before: Option<Value>, after: Option<Value>) -> (Option<Value>, Option<Value>) {
+    match (before, after) {
+        (Some(Value::Object(mut before)), Some(Value::Object(mut after))) => {
+            let unchanged: Vec<String> = before
+                .iter()
+                .filter(|(key, value)| after.get(*key) == Some(*value))
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in unchanged {
+                before.remove(&key);
+                after.remove(&key);
+            }
+            (Some(Value::Object(before)), Some(Value::Object(after)))
+        }
+        (before, after) => (before, after),
+    }
+}
+
+/// Append an entry for the current session to the audit log.
+///
+/// `before` and `after` are the api representation of the target, only changed fields are stored.
+pub async fn record<T: Serialize>(
+    state: &mut RequestState,
+    action: &str,
+    target_type: &str,
+    target_id: Option<u64>,
+    before: Option<&T>,
+    after: Option<&T>,
+) -> ServiceResult<()> {
+    let to_json = |value: Option<&T>| -> ServiceResult<Option<Value>> {
+        value
+            .map(serde_json::to_value)
+            .transpose()
+            .map_err(|e| ServiceError::InternalServerError(e.to_string()))
+    };
+    let (before, after) = json_diff(to_json(before)?, to_json(after)?);
+
+    let (actor_account_id, impersonated_account_id) = match state.session {
+        Some(ref session) => match session.impersonated_by {
+            Some(admin) => (Some(admin), Some(session.account.id)),
+            None => (Some(session.account.id), None),
+        },
+        None => (None, None),
+    };
+
+    state
+        .db
+        .append_audit_log(models::AuditLogEntry {
+            id: 0,
+            timestamp: chrono::Utc::now(),
+            actor_account_id,
+            impersonated_account_id,
+            action: action.to_owned(),
+            target_type: target_type.to_owned(),
+            target_id,
+            before,
+            after,
+        })
+        .await?;
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub struct AuditLogEntryDto {
+    pub id: u64,
+    pub timestamp: String,
+    pub actor_account_id: Option<u64>,
+    pub impersonated_account_id: Option<u64>,
+    pub action: String,
+    pub target_type: String,
+    pub target_id: Option<u64>,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+impl From<&models::AuditLogEntry> for AuditLogEntryDto {
+    fn from(value: &models::AuditLogEntry) -> Self {
+        Self {
+            id: value.id,
+            timestamp: format!("{:?}", value.timestamp),
+            actor_account_id: value.actor_account_id,
+            impersonated_account_id: value.impersonated_account_id,
+            action: value.action.to_owned(),
+            target_type: value.target_type.to_owned(),
+            target_id: value.target_id,
+            before: value.before.clone(),
+            after: value.after.clone(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct AuditLogQuery {
+    pub actor_account_id: Option<u64>,
+    /// e.g. `product.update`
+    pub action: Option<String>,
+    /// e.g. `account` or `product`
+    pub target_type: Option<String>,
+    pub target_id: Option<u64>,
+    /// RFC 3339 timestamp, inclusive
+    pub from: Option<String>,
+    /// RFC 3339 timestamp, exclusive
+    pub to: Option<String>,
+    /// Defaults to 100 entries, at most 1000
+    pub limit: Option<u64>,
+}
+
+async fn get_audit_log(
+    mut state: RequestState,
+    Query(query): Query<AuditLogQuery>,
+) -> ServiceResult<Json<Vec<AuditLogEntryDto>>> {
+    state.session_require_permission(models::Permission::AuditLogRead)?;
+
+    let filter = models::AuditLogFilter {
+        actor_account_id: query.actor_account_id,
+        action: query.action,
+        target_type: query.target_type,
+        target_id: query.target_id,
+        from: query
+            .from
+            .map(|from| DateTime::parse_from_rfc3339(&from))
+            .transpose()?
+            .map(|from| from.into()),
+        to: query
+            .to
+            .map(|to| DateTime::parse_from_rfc3339(&to))
+            .transpose()?
+            .map(|to| to.into()),
+        limit: query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT),
+    };
+
+    let entries = state.db.get_audit_log(&filter).await?;
+    Ok(Json(entries.iter().map(|e| e.into()).collect()))
+}
+
+fn get_audit_log_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Query the audit log, newest entries first.")
+        .tag("audit_log")
+        .response::<200, Json<Vec<AuditLogEntryDto>>>()
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["audit_log.read"])
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_json_diff() {
+        let (before, after) = json_diff(
+            Some(json!({"id": 1, "name": "Club Mate", "price": 150})),
+            Some(json!({"id": 1, "name": "Club Mate", "price": 170})),
+        );
+        assert_eq!(before, Some(json!({"price": 150})));
+        assert_eq!(after, Some(json!({"price": 170})));
+
+        let (before, after) = json_diff(None, Some(json!({"id": 1})));
+        assert_eq!(before, None);
+        assert_eq!(after, Some(json!({"id": 1})));
+    }
+}
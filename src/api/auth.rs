@@ -9,16 +9,18 @@ use axum::Json;
 use base64::engine::general_purpose;
 use base64::Engine;
 use chrono::{Duration, Utc};
+use log::error;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use crate::database::AppState;
 use crate::error::{ServiceError, ServiceResult};
-use crate::models::{self, CardType};
+use crate::models::{self, CardType, CoinAmount, CoinType};
 use crate::request_state::RequestState;
+use crate::{env, wallet};
 
-use super::accounts::{AccountDto, CardTypeDto};
-use super::{nfc_id, nfc_mifare, password_hash_verify};
+use super::accounts::{AccountDto, CardTypeDto, CoinAmountDto, NfcCardStatusDto};
+use super::{audit_log, nfc_id, nfc_mifare, password_hash_verify};
 
 pub fn router(app_state: AppState) -> ApiRouter {
     ApiRouter::new()
@@ -26,6 +28,13 @@ pub fn router(app_state: AppState) -> ApiRouter {
             "/auth/password",
             post_with(auth_password_based, auth_password_based_docs),
         )
+        .api_route(
+            "/auth/password-reset",
+            post_with(
+                auth_request_password_reset,
+                auth_request_password_reset_docs,
+            ),
+        )
         .api_route(
             "/auth/nfc/identify",
             post_with(
@@ -42,13 +51,26 @@ pub fn router(app_state: AppState) -> ApiRouter {
             post_with(auth_nfc_based_response, auth_nfc_based_response_docs),
         )
         .api_route(
-            "/auth/nfc/simulation",
-            post_with(auth_nfc_based_simulation, auth_nfc_based_simulation_docs),
+            "/auth/impersonation",
+            post_with(auth_impersonation, auth_impersonation_docs),
+        )
+        .api_route(
+            "/auth/impersonation-log",
+            get_with(get_impersonation_log, get_impersonation_log_docs),
         )
         .api_route(
             "/auth/account",
             get_with(auth_get_account, auth_get_account_docs),
         )
+        .api_route(
+            "/auth/payment-token",
+            post_with(auth_payment_token, auth_payment_token_docs),
+        )
+        .api_route("/auth/qr", post_with(auth_qr_code, auth_qr_code_docs))
+        .api_route(
+            "/auth/refresh",
+            post_with(auth_refresh_token, auth_refresh_token_docs),
+        )
         .api_route("/auth", delete_with(auth_delete, auth_delete_docs))
         .with_state(app_state)
 }
@@ -56,12 +78,16 @@ pub fn router(app_state: AppState) -> ApiRouter {
 #[derive(Debug, PartialEq, Serialize, JsonSchema)]
 pub struct AuthTokenDto {
     pub token: String,
+    /// Only present for long lived sessions, can be exchanged for a new token at `/auth/refresh`
+    pub refresh_token: Option<String>,
 }
 
 #[derive(Debug, PartialEq, Deserialize, JsonSchema)]
 pub struct AuthPasswordBasedDto {
     pub username: String,
     pub password: String,
+    /// Optional name to recognize this session in the session list
+    pub device_name: Option<String>,
 }
 
 #[derive(Debug, PartialEq, Deserialize, JsonSchema)]
@@ -76,8 +102,6 @@ async fn auth_password_based(
 ) -> ServiceResult<Json<AuthTokenDto>> {
     let form = form.0;
 
-    state.db.cleanup_session_tokens().await?;
-
     let account = state
         .db
         .get_account_by_auth_method(models::AuthRequest::PasswordBased {
@@ -91,22 +115,42 @@ async fn auth_password_based(
                 if password_hash_verify(&password_based.password_hash, &form.password)?
                     && password_based.username == form.username
                 {
-                    let duration = if let Some(true) = query.long_lived {
-                        Duration::days(360)
+                    let long_lived = matches!(query.long_lived, Some(true));
+                    let duration = if long_lived {
+                        Duration::days(1)
                     } else {
                         Duration::minutes(60)
                     };
+                    let client = models::SessionClient {
+                        device_name: form.device_name,
+                        ..state.client
+                    };
                     let token = state
                         .db
                         .create_session_token(
                             account.id,
                             models::AuthMethodType::PasswordBased,
-                            Utc::now().add(duration),
+                            models::SessionLifetime::Sliding(duration),
                             false,
+                            &client,
                         )
                         .await?;
 
-                    return Ok(Json(AuthTokenDto { token }));
+                    let refresh_token = if long_lived {
+                        Some(
+                            state
+                                .db
+                                .create_refresh_token(&token, Utc::now().add(Duration::days(360)))
+                                .await?,
+                        )
+                    } else {
+                        None
+                    };
+
+                    return Ok(Json(AuthTokenDto {
+                        token,
+                        refresh_token,
+                    }));
                 }
             }
         }
@@ -122,6 +166,116 @@ fn auth_password_based_docs(op: TransformOperation) -> TransformOperation {
         .response_with::<401, (), _>(|res| res.description("Invalid username or password!"))
 }
 
+/// Password reset requests per client address within `PASSWORD_RESET_WINDOW_MINUTES`
+const PASSWORD_RESET_CLIENT_LIMIT: u32 = 5;
+/// Password reset mails per account within `PASSWORD_RESET_WINDOW_MINUTES`
+const PASSWORD_RESET_ACCOUNT_LIMIT: u32 = 2;
+const PASSWORD_RESET_WINDOW_MINUTES: i64 = 60;
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct AuthRequestPasswordResetDto {
+    pub username_or_email: String,
+}
+
+async fn auth_request_password_reset(
+    mut state: RequestState,
+    form: Json<AuthRequestPasswordResetDto>,
+) -> ServiceResult<StatusCode> {
+    let form = form.0;
+
+    // Clients without address must not share one bucket, they are only limited per account
+    if let Some(ref ip_address) = state.client.ip_address {
+        let client_key = format!("password_reset:client:{}", ip_address);
+        if !state
+            .db
+            .rate_limit_hit(
+                &client_key,
+                Duration::minutes(PASSWORD_RESET_WINDOW_MINUTES),
+                PASSWORD_RESET_CLIENT_LIMIT,
+            )
+            .await?
+        {
+            return Err(ServiceError::TooManyRequests);
+        }
+    }
+
+    let login = form.username_or_email.trim();
+    let mut accounts = state.db.get_accounts_by_email(login).await?;
+    if accounts.is_empty() && !login.is_empty() {
+        accounts.extend(
+            state
+                .db
+                .get_account_by_auth_method(models::AuthRequest::PasswordBased {
+                    username: login.to_owned(),
+                })
+                .await?,
+        );
+    }
+
+    // The response must not reveal whether a matching account exists
+    for account in accounts {
+        if account.email.is_empty() {
+            continue;
+        }
+
+        let account_key = format!("password_reset:account:{}", account.id);
+        if !state
+            .db
+            .rate_limit_hit(
+                &account_key,
+                Duration::minutes(PASSWORD_RESET_WINDOW_MINUTES),
+                PASSWORD_RESET_ACCOUNT_LIMIT,
+            )
+            .await?
+        {
+            continue;
+        }
+
+        let valid_until = Utc::now().add(Duration::days(1));
+        let token = state
+            .db
+            .create_session_token(
+                account.id,
+                models::AuthMethodType::PasswordResetToken,
+                models::SessionLifetime::Fixed(valid_until),
+                false,
+                &state.client,
+            )
+            .await?;
+
+        audit_log::record::<()>(
+            &mut state,
+            "password_reset_token.request",
+            "account",
+            Some(account.id),
+            None,
+            None,
+        )
+        .await?;
+
+        #[cfg(feature = "mail")]
+        tokio::spawn(async move {
+            if let Err(e) =
+                crate::mail::send_password_reset_link(&account, &token, &valid_until).await
+            {
+                log::warn!("Could not send mail: {:?}", e);
+            }
+        });
+
+        #[cfg(not(feature = "mail"))]
+        let _ = (token, valid_until);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn auth_request_password_reset_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Request a password reset link by username or email. The link is sent to the email address of the account. The response is the same whether or not a matching account exists.")
+        .tag("auth")
+        .response_with::<204, (), _>(|res| res.description("The request was accepted!"))
+        .response_with::<429, (), _>(|res| res.description("Too many requests from this client!"))
+}
+
 #[derive(Debug, PartialEq, Deserialize, JsonSchema)]
 pub struct AuthNfcBasedNfcIdentifyDto {
     pub card_id: String,
@@ -131,6 +285,8 @@ pub struct AuthNfcBasedNfcIdentifyDto {
 pub struct AuthNfcBasedNfcIdentifyResponseDto {
     pub card_id: String,
     pub card_type: Option<CardTypeDto>,
+    /// Only `Active` cards can be used for login, terminals should show the status otherwise
+    pub status: Option<NfcCardStatusDto>,
 }
 
 async fn auth_nfc_based_nfc_identify(
@@ -139,8 +295,6 @@ async fn auth_nfc_based_nfc_identify(
 ) -> ServiceResult<Json<AuthNfcBasedNfcIdentifyResponseDto>> {
     let form = form.0;
 
-    state.db.cleanup_session_tokens().await?;
-
     let card_id = general_purpose::STANDARD
         .decode(form.card_id.clone())
         .map_err(|_| {
@@ -163,6 +317,7 @@ async fn auth_nfc_based_nfc_identify(
                     return Ok(Json(AuthNfcBasedNfcIdentifyResponseDto {
                         card_id: form.card_id,
                         card_type: Some((&auth_nfc.card_type).into()),
+                        status: Some((&auth_nfc.current_status()).into()),
                     }));
                 }
             }
@@ -172,6 +327,7 @@ async fn auth_nfc_based_nfc_identify(
     Ok(Json(AuthNfcBasedNfcIdentifyResponseDto {
         card_id: form.card_id,
         card_type: None,
+        status: None,
     }))
 }
 
@@ -182,16 +338,44 @@ fn auth_nfc_based_nfc_identify_docs(op: TransformOperation) -> TransformOperatio
         .response_with::<401, (), _>(|res| res.description("Invalid card_id!"))
 }
 
+fn require_active_card(auth_nfc: &models::AuthNfc) -> ServiceResult<()> {
+    match auth_nfc.current_status() {
+        models::NfcCardStatus::Active => Ok(()),
+        models::NfcCardStatus::Expired => Err(ServiceError::Unauthorized("Card is expired!")),
+        models::NfcCardStatus::Blocked | models::NfcCardStatus::Lost => {
+            Err(ServiceError::Unauthorized("Card is blocked!"))
+        }
+    }
+}
+
+async fn get_nfc_reader(
+    state: &mut RequestState,
+    reader_id: Option<u64>,
+) -> ServiceResult<Option<models::NfcReader>> {
+    if let Some(reader_id) = reader_id {
+        let reader = state.db.get_nfc_reader_by_id(reader_id).await?;
+        return reader
+            .map(Some)
+            .ok_or(ServiceError::Unauthorized("Unknown nfc reader!"));
+    }
+
+    Ok(None)
+}
+
 #[derive(Debug, PartialEq, Deserialize, JsonSchema)]
 #[allow(non_snake_case)]
 pub struct AuthNfcBasedChallengeDto {
     pub card_id: String,
+    /// Id of the registered nfc reader, required for generic nfc cards if no global reader key is configured
+    pub reader_id: Option<u64>,
     pub request: String,
 }
 #[derive(Debug, PartialEq, Serialize, JsonSchema)]
 #[allow(non_snake_case)]
 pub struct AuthNfcBasedChallengeResponseDto {
     pub card_id: String,
+    /// Has to be sent back with the response to identify this challenge
+    pub challenge_id: String,
     pub challenge: String,
 }
 
@@ -202,8 +386,6 @@ async fn auth_nfc_based_challenge(
 ) -> ServiceResult<Json<AuthNfcBasedChallengeResponseDto>> {
     let form = form.0;
 
-    state.db.cleanup_session_tokens().await?;
-
     let card_id = general_purpose::STANDARD
         .decode(form.card_id)
         .map_err(|_| {
@@ -219,6 +401,8 @@ async fn auth_nfc_based_challenge(
         })
         .await?;
 
+    let reader = get_nfc_reader(&mut state, form.reader_id).await?;
+
     if let Some(account) = account {
         let request = general_purpose::STANDARD
             .decode(form.request)
@@ -231,11 +415,20 @@ async fn auth_nfc_based_challenge(
         for auth_method in account.auth_methods.iter() {
             if let models::AuthMethod::NfcBased(auth_nfc) = auth_method {
                 if auth_nfc.card_id == card_id {
-                    let challenge = match auth_nfc.card_type {
+                    require_active_card(auth_nfc)?;
+
+                    let (challenge_id, challenge) = match auth_nfc.card_type {
                         CardType::AsciiMifare => {
                             nfc_mifare::authenticate_phase_challenge(
                                 &state.challenge_storage,
-                                account.id,
+                                auth_nfc,
+                                &request,
+                            )
+                            .await?
+                        }
+                        CardType::AsciiMifareAes => {
+                            nfc_mifare::authenticate_ev2_phase_challenge(
+                                &state.challenge_storage,
                                 auth_nfc,
                                 &request,
                             )
@@ -244,7 +437,7 @@ async fn auth_nfc_based_challenge(
                         _ => {
                             nfc_id::authenticate_phase_challenge(
                                 &state.challenge_storage,
-                                account.id,
+                                reader.as_ref(),
                                 auth_nfc,
                                 &request,
                             )
@@ -254,6 +447,7 @@ async fn auth_nfc_based_challenge(
 
                     return Ok(Json(AuthNfcBasedChallengeResponseDto {
                         card_id: general_purpose::STANDARD.encode(card_id),
+                        challenge_id,
                         challenge: general_purpose::STANDARD.encode(challenge),
                     }));
                 }
@@ -268,13 +462,17 @@ fn auth_nfc_based_challenge_docs(op: TransformOperation) -> TransformOperation {
     op.description("Request challenge.")
         .tag("auth")
         .response::<200, Json<AuthNfcBasedChallengeResponseDto>>()
-        .response_with::<401, (), _>(|res| res.description("Invalid challenge!"))
+        .response_with::<401, (), _>(|res| {
+            res.description("Invalid challenge or the card is blocked or expired!")
+        })
 }
 
 #[derive(Debug, PartialEq, Deserialize, JsonSchema)]
 #[allow(non_snake_case)]
 pub struct AuthNfcBasedResponseDto {
     pub card_id: String,
+    pub reader_id: Option<u64>,
+    pub challenge_id: String,
     pub challenge: String,
     pub response: String,
 }
@@ -283,7 +481,10 @@ pub struct AuthNfcBasedResponseDto {
 pub struct AuthNfcBasedResponseResponseDto {
     pub card_id: String,
     pub token: String,
+    /// For `AsciiMifareAes` cards this is `KSesAuthENC || KSesAuthMAC`
     pub session_key: String,
+    /// Transaction identifier of an `AsciiMifareAes` session
+    pub transaction_identifier: Option<String>,
 }
 
 #[allow(non_snake_case)]
@@ -308,6 +509,8 @@ async fn auth_nfc_based_response(
         })
         .await?;
 
+    let reader = get_nfc_reader(&mut state, form.reader_id).await?;
+
     if let Some(account) = account {
         let challenge = general_purpose::STANDARD
             .decode(form.challenge)
@@ -327,42 +530,66 @@ async fn auth_nfc_based_response(
         for auth_method in account.auth_methods.iter() {
             if let models::AuthMethod::NfcBased(auth_nfc) = auth_method {
                 if auth_nfc.card_id == card_id {
-                    let session_key = match auth_nfc.card_type {
+                    require_active_card(auth_nfc)?;
+
+                    let (session_key, transaction_identifier) = match auth_nfc.card_type {
                         CardType::AsciiMifare => {
-                            nfc_mifare::authenticate_phase_response(
+                            let session_key = nfc_mifare::authenticate_phase_response(
                                 &state.challenge_storage,
-                                account.id,
+                                &form.challenge_id,
                                 auth_nfc,
                                 &challenge,
                                 &response,
                             )
-                            .await?
+                            .await?;
+                            (session_key, None)
+                        }
+                        CardType::AsciiMifareAes => {
+                            let session = nfc_mifare::authenticate_ev2_phase_response(
+                                &state.challenge_storage,
+                                &form.challenge_id,
+                                auth_nfc,
+                                &challenge,
+                                &response,
+                            )
+                            .await?;
+
+                            let mut session_key = session.enc_key;
+                            session_key.extend(session.mac_key);
+                            (session_key, Some(session.transaction_identifier))
                         }
                         _ => {
-                            nfc_id::authenticate_phase_response(
+                            let session_key = nfc_id::authenticate_phase_response(
                                 &state.challenge_storage,
-                                account.id,
+                                &form.challenge_id,
+                                reader.as_ref(),
                                 auth_nfc,
                                 &challenge,
                                 &response,
                             )
-                            .await?
+                            .await?;
+                            (session_key, None)
                         }
                     };
 
+                    state.db.set_nfc_card_last_used(&card_id).await?;
+
                     let token = state
                         .db
                         .create_session_token(
                             account.id,
                             models::AuthMethodType::NfcBased,
-                            Utc::now().add(Duration::minutes(30)),
+                            models::SessionLifetime::Sliding(Duration::minutes(30)),
                             false,
+                            &state.client,
                         )
                         .await?;
 
                     return Ok(Json(AuthNfcBasedResponseResponseDto {
                         card_id: general_purpose::STANDARD.encode(card_id),
                         session_key: general_purpose::STANDARD.encode(session_key),
+                        transaction_identifier: transaction_identifier
+                            .map(|ti| general_purpose::STANDARD.encode(ti)),
                         token,
                     }));
                 }
@@ -377,48 +604,349 @@ fn auth_nfc_based_response_docs(op: TransformOperation) -> TransformOperation {
     op.description("Respond to challenge.")
         .tag("auth")
         .response::<200, Json<AuthNfcBasedResponseResponseDto>>()
-        .response_with::<401, (), _>(|res| res.description("Invalid response!"))
+        .response_with::<401, (), _>(|res| {
+            res.description("Invalid response or the card is blocked or expired!")
+        })
 }
 
+const IMPERSONATION_LIFETIME_MINUTES: i64 = 30;
+
 #[derive(Debug, PartialEq, Deserialize, JsonSchema)]
-pub struct AuthNfcBasedSimulationDto {
+pub struct AuthImpersonationDto {
     pub account_id: u64,
+    /// Is recorded in the impersonation log
+    pub reason: Option<String>,
 }
 
-async fn auth_nfc_based_simulation(
+async fn auth_impersonation(
     mut state: RequestState,
-    form: Json<AuthNfcBasedSimulationDto>,
+    form: Json<AuthImpersonationDto>,
 ) -> ServiceResult<Json<AuthTokenDto>> {
-    state.db.cleanup_session_tokens().await?;
+    let admin = state.session_require()?;
+    state.session_require_permission(models::Permission::AccountsImpersonate)?;
 
-    state.session_require_admin()?;
+    // Impersonation sessions cannot be chained
+    if admin.impersonated_by.is_some() {
+        return Err(ServiceError::Forbidden);
+    }
 
     let form = form.0;
 
     let account = state.db.get_account_by_id(form.account_id).await?;
 
     if let Some(account) = account {
+        // The admin must not gain permissions by acting as the account
+        if !account.role.is_subset_of(&admin.account.role) {
+            return Err(ServiceError::Forbidden);
+        }
+
         let token = state
             .db
-            .create_session_token(
+            .create_impersonation_session(
                 account.id,
-                models::AuthMethodType::NfcBased,
-                Utc::now().add(Duration::minutes(30)),
-                false,
+                admin.account.id,
+                Utc::now().add(Duration::minutes(IMPERSONATION_LIFETIME_MINUTES)),
+                &state.client,
             )
             .await?;
 
-        return Ok(Json(AuthTokenDto { token }));
+        let session = state.db.get_session_by_session_token(token.clone()).await?;
+        if let Some(session) = session {
+            let action = match form.reason {
+                Some(reason) => format!("start: {reason}"),
+                None => "start".to_string(),
+            };
+            state
+                .db
+                .log_impersonation(&session, &action, &state.client)
+                .await?;
+        }
+
+        return Ok(Json(AuthTokenDto {
+            token,
+            refresh_token: None,
+        }));
     }
 
     Err(ServiceError::NotFound)
 }
 
-fn auth_nfc_based_simulation_docs(op: TransformOperation) -> TransformOperation {
-    op.description("Simulate login with nfc card.")
+fn auth_impersonation_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Act on behalf of the given account. The role of the account must not have permissions that the current account lacks. Transactions are authorized by the current account and every request of the session is recorded in the impersonation log.")
         .tag("auth")
         .response::<200, Json<AuthTokenDto>>()
-        .response_with::<401, (), _>(|res| res.description("Invalid card_id!"))
+        .response_with::<404, (), _>(|res| res.description("The requested account does not exist!"))
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions or the account has permissions that the current account lacks!"))
+        .security_requirement_scopes("SessionToken", ["accounts.impersonate"])
+}
+
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub struct ImpersonationLogEntryDto {
+    pub id: u64,
+    pub admin_account_id: u64,
+    pub account_id: u64,
+    pub session_id: String,
+    pub action: String,
+    pub ip_address: Option<String>,
+    pub timestamp: String,
+}
+
+impl From<&models::ImpersonationLogEntry> for ImpersonationLogEntryDto {
+    fn from(value: &models::ImpersonationLogEntry) -> Self {
+        Self {
+            id: value.id,
+            admin_account_id: value.admin_account_id,
+            account_id: value.account_id,
+            session_id: value.session_id.to_owned(),
+            action: value.action.to_owned(),
+            ip_address: value.ip_address.to_owned(),
+            timestamp: format!("{:?}", value.timestamp),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct ImpersonationLogQuery {
+    pub account_id: Option<u64>,
+}
+
+async fn get_impersonation_log(
+    mut state: RequestState,
+    Query(query): Query<ImpersonationLogQuery>,
+) -> ServiceResult<Json<Vec<ImpersonationLogEntryDto>>> {
+    state.session_require_permission(models::Permission::AccountsWrite)?;
+
+    let entries = state.db.get_impersonation_log(query.account_id).await?;
+    Ok(Json(entries.iter().map(|e| e.into()).collect()))
+}
+
+fn get_impersonation_log_docs(op: TransformOperation) -> TransformOperation {
+    op.description("List the impersonation log, newest entries first.")
+        .tag("auth")
+        .response::<200, Json<Vec<ImpersonationLogEntryDto>>>()
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.write"])
+}
+
+const PAYMENT_TOKEN_DEFAULT_LIFETIME_SECONDS: u64 = 60;
+const PAYMENT_TOKEN_MAX_LIFETIME_SECONDS: u64 = 300;
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct AuthPaymentTokenDto {
+    pub account_id: u64,
+    /// Maximum amount per coin type the token may be used for
+    pub limit: CoinAmountDto,
+    /// Defaults to 60 seconds, at most 300 seconds
+    pub valid_seconds: Option<u64>,
+}
+
+async fn auth_payment_token(
+    mut state: RequestState,
+    form: Json<AuthPaymentTokenDto>,
+) -> ServiceResult<Json<AuthTokenDto>> {
+    let form = form.0;
+
+    state.session_require_permission_or_self(models::Permission::PaymentsWrite, form.account_id)?;
+    let session = state.session_require()?;
+
+    let valid_seconds = form
+        .valid_seconds
+        .unwrap_or(PAYMENT_TOKEN_DEFAULT_LIFETIME_SECONDS)
+        .min(PAYMENT_TOKEN_MAX_LIFETIME_SECONDS);
+    let valid_until = Utc::now().add(Duration::seconds(
+        i64::try_from(valid_seconds).expect("lifetime is less than 2**63"),
+    ));
+
+    if state.db.get_account_by_id(form.account_id).await?.is_none() {
+        return Err(ServiceError::NotFound);
+    }
+
+    let token = state
+        .db
+        .create_payment_token(
+            form.account_id,
+            session.auth_method,
+            valid_until,
+            &form.limit.into(),
+            session.impersonated_by,
+            &state.client,
+        )
+        .await?;
+
+    Ok(Json(AuthTokenDto {
+        token,
+        refresh_token: None,
+    }))
+}
+
+fn auth_payment_token_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Create a single use payment token for the given account. The token expires after its first request and can only be used for one payment up to the given limit.")
+        .tag("auth")
+        .response::<200, Json<AuthTokenDto>>()
+        .response_with::<404, (), _>(|res| res.description("The requested account does not exist!"))
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["payments.write", "self"])
+}
+
+/// Qr code logins per client address within `QR_CODE_WINDOW_MINUTES`
+const QR_CODE_CLIENT_LIMIT: u32 = 30;
+/// Qr code logins per account within `QR_CODE_WINDOW_MINUTES`
+const QR_CODE_ACCOUNT_LIMIT: u32 = 10;
+const QR_CODE_WINDOW_MINUTES: i64 = 10;
+const QR_CODE_SESSION_LIFETIME_SECONDS: i64 = 60;
+/// Enough for one product that is paid with stamps
+const QR_CODE_PAYMENT_LIMIT_STAMPS: i32 = 10;
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct AuthQrCodeDto {
+    /// Content of the qr code on the wallet pass
+    pub qr_code: String,
+}
+
+async fn auth_qr_code(
+    mut state: RequestState,
+    form: Json<AuthQrCodeDto>,
+) -> ServiceResult<Json<AuthTokenDto>> {
+    let form = form.0;
+
+    // Clients without address must not share one bucket, they are only limited per account
+    if let Some(ref ip_address) = state.client.ip_address {
+        let client_key = format!("qr_code:client:{}", ip_address);
+        if !state
+            .db
+            .rate_limit_hit(
+                &client_key,
+                Duration::minutes(QR_CODE_WINDOW_MINUTES),
+                QR_CODE_CLIENT_LIMIT,
+            )
+            .await?
+        {
+            return Err(ServiceError::TooManyRequests);
+        }
+    }
+
+    let qr_code = form.qr_code.trim();
+    let pass = state
+        .db
+        .get_apple_wallet_pass_by_qr_code(qr_code, &env::APPLE_WALLET_PASS_TYPE_IDENTIFIER)
+        .await?
+        .ok_or(ServiceError::Unauthorized("Invalid qr code"))?;
+
+    // Rate limited scans must not invalidate the code that is shown on the pass
+    let account_key = format!("qr_code:account:{}", pass.account_id);
+    if !state
+        .db
+        .rate_limit_hit(
+            &account_key,
+            Duration::minutes(QR_CODE_WINDOW_MINUTES),
+            QR_CODE_ACCOUNT_LIMIT,
+        )
+        .await?
+    {
+        return Err(ServiceError::TooManyRequests);
+    }
+
+    // Every scan of the current code replaces it, the pass is updated with the next one. Until a wallet received
+    // the new code the scanned code stays valid, otherwise a failed update would break the qr code login.
+    let pass = if pass.qr_code == qr_code {
+        state
+            .db
+            .rotate_apple_wallet_pass_qr_code(
+                qr_code,
+                &env::APPLE_WALLET_PASS_TYPE_IDENTIFIER,
+                &wallet::generate_random_string(64),
+                wallet::get_current_time(),
+            )
+            .await?
+            .ok_or(ServiceError::Unauthorized("Invalid qr code"))?
+    } else {
+        pass
+    };
+    let account_id = pass.account_id;
+
+    let account = state
+        .db
+        .get_account_by_id(account_id)
+        .await?
+        .ok_or(ServiceError::Unauthorized("Invalid qr code"))?;
+    if account.archived_at.is_some() {
+        return Err(ServiceError::Unauthorized("Invalid qr code"));
+    }
+
+    let limit = CoinAmount(
+        [
+            (CoinType::Cent, *env::QR_CODE_PAYMENT_LIMIT_CENTS),
+            (CoinType::CoffeeStamp, QR_CODE_PAYMENT_LIMIT_STAMPS),
+            (CoinType::BottleStamp, QR_CODE_PAYMENT_LIMIT_STAMPS),
+        ]
+        .into(),
+    );
+    let token = state
+        .db
+        .create_payment_token(
+            account.id,
+            models::AuthMethodType::QrCode,
+            Utc::now().add(Duration::seconds(QR_CODE_SESSION_LIFETIME_SECONDS)),
+            &limit,
+            None,
+            &state.client,
+        )
+        .await?;
+
+    tokio::spawn(async move {
+        if let Err(e) = wallet::send_update_notification(&mut state.db, account_id).await {
+            error!("Could not send apns update! {:?}", e)
+        }
+    });
+
+    Ok(Json(AuthTokenDto {
+        token,
+        refresh_token: None,
+    }))
+}
+
+fn auth_qr_code_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Login by scanning the qr code of a wallet pass. The returned token is a single use payment token that expires after 60 seconds. Every scan replaces the code, the wallet pass is updated with the new code afterwards. The scanned code stays valid until a wallet received the new code.")
+        .tag("auth")
+        .response::<200, Json<AuthTokenDto>>()
+        .response_with::<401, (), _>(|res| res.description("Invalid qr code!"))
+        .response_with::<429, (), _>(|res| res.description("Too many attempts!"))
+}
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct AuthRefreshTokenDto {
+    pub refresh_token: String,
+}
+
+async fn auth_refresh_token(
+    mut state: RequestState,
+    form: Json<AuthRefreshTokenDto>,
+) -> ServiceResult<Json<AuthTokenDto>> {
+    let form = form.0;
+
+    let tokens = state
+        .db
+        .refresh_session_token(&form.refresh_token, Utc::now().add(Duration::days(360)))
+        .await?;
+
+    if let Some((token, refresh_token)) = tokens {
+        return Ok(Json(AuthTokenDto {
+            token,
+            refresh_token: Some(refresh_token),
+        }));
+    }
+
+    Err(ServiceError::Unauthorized("Invalid refresh token"))
+}
+
+fn auth_refresh_token_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Exchange a refresh token for a new session token. Both the session token and the refresh token are replaced, the previous tokens become invalid.")
+        .tag("auth")
+        .response::<200, Json<AuthTokenDto>>()
+        .response_with::<401, (), _>(|res| res.description("Invalid refresh token!"))
 }
 
 async fn auth_delete(mut state: RequestState) -> ServiceResult<StatusCode> {
@@ -426,8 +954,6 @@ async fn auth_delete(mut state: RequestState) -> ServiceResult<StatusCode> {
         state.db.delete_session_token(session.token).await?;
     }
 
-    state.db.cleanup_session_tokens().await?;
-
     Ok(StatusCode::NO_CONTENT)
 }
 
@@ -449,5 +975,5 @@ fn auth_get_account_docs(op: TransformOperation) -> TransformOperation {
         .response_with::<404, (), _>(|res| res.description("The requested account does not exist!"))
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin", "self"])
+        .security_requirement_scopes("SessionToken", ["self"])
 }
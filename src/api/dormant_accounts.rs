@@ -0,0 +1,238 @@
+use aide::axum::routing::{get_with, post_with};
+use aide::axum::ApiRouter;
+use aide::transform::TransformOperation;
+use axum::extract::Query;
+use axum::Json;
+use chrono::{DateTime, Months, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::database::AppState;
+use crate::error::{ServiceError, ServiceResult};
+use crate::models;
+use crate::request_state::RequestState;
+
+use super::account_auth_methods::store_account_audited;
+use super::accounts::AccountDto;
+use super::audit_log;
+
+const DEFAULT_INACTIVE_MONTHS: u32 = 12;
+
+pub fn router(app_state: AppState) -> ApiRouter {
+    ApiRouter::new()
+        .api_route(
+            "/report/dormant-accounts",
+            get_with(list_dormant_accounts, list_dormant_accounts_docs),
+        )
+        .api_route(
+            "/report/dormant-accounts/mail",
+            post_with(mail_dormant_accounts, mail_dormant_accounts_docs),
+        )
+        .api_route(
+            "/report/dormant-accounts/archive",
+            post_with(archive_dormant_accounts, archive_dormant_accounts_docs),
+        )
+        .api_route(
+            "/report/dormant-accounts/remove-public-tab",
+            post_with(
+                remove_public_tab_of_dormant_accounts,
+                remove_public_tab_of_dormant_accounts_docs,
+            ),
+        )
+        .with_state(app_state)
+}
+
+fn inactive_since(months: Option<u32>) -> ServiceResult<DateTime<Utc>> {
+    Utc::now()
+        .checked_sub_months(Months::new(months.unwrap_or(DEFAULT_INACTIVE_MONTHS)))
+        .ok_or_else(|| ServiceError::InternalServerError(String::from("Invalid month count")))
+}
+
+/// Dormant accounts for the given period, optionally restricted to the selected account ids
+async fn get_selected_dormant_accounts(
+    state: &mut RequestState,
+    form: &DormantAccountsActionDto,
+) -> ServiceResult<Vec<models::DormantAccount>> {
+    let dormant_accounts = state
+        .db
+        .get_dormant_accounts(inactive_since(form.months)?)
+        .await?;
+
+    Ok(match form.account_ids {
+        Some(ref account_ids) => dormant_accounts
+            .into_iter()
+            .filter(|d| account_ids.contains(&d.account.id))
+            .collect(),
+        None => dormant_accounts,
+    })
+}
+
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub struct DormantAccountDto {
+    pub account: AccountDto,
+    /// Latest transaction or session use, missing if the account was never used
+    pub last_activity: Option<String>,
+}
+
+impl From<&models::DormantAccount> for DormantAccountDto {
+    fn from(value: &models::DormantAccount) -> Self {
+        Self {
+            account: AccountDto::from(&value.account),
+            last_activity: value.last_activity.map(|t| format!("{:?}", t)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct DormantAccountsQuery {
+    /// Minimum number of months without activity, defaults to 12
+    pub months: Option<u32>,
+}
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct DormantAccountsActionDto {
+    /// Minimum number of months without activity, defaults to 12
+    pub months: Option<u32>,
+    /// Restrict the action to these accounts. Accounts that are no longer dormant are skipped.
+    pub account_ids: Option<Vec<u64>>,
+}
+
+async fn list_dormant_accounts(
+    mut state: RequestState,
+    Query(query): Query<DormantAccountsQuery>,
+) -> ServiceResult<Json<Vec<DormantAccountDto>>> {
+    state.session_require_permission(models::Permission::AccountsRead)?;
+
+    let dormant_accounts = state
+        .db
+        .get_dormant_accounts(inactive_since(query.months)?)
+        .await?;
+    Ok(Json(dormant_accounts.iter().map(|d| d.into()).collect()))
+}
+
+fn list_dormant_accounts_docs(op: TransformOperation) -> TransformOperation {
+    op.description("List all accounts without transactions or sessions in the given number of months, least recently used first. Archived accounts are not included.")
+        .tag("reports")
+        .response::<200, Json<Vec<DormantAccountDto>>>()
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.read"])
+}
+
+async fn mail_dormant_accounts(
+    mut state: RequestState,
+    form: Json<DormantAccountsActionDto>,
+) -> ServiceResult<Json<Vec<u64>>> {
+    state.session_require_permission(models::Permission::ReportsSend)?;
+
+    let dormant_accounts: Vec<models::DormantAccount> =
+        get_selected_dormant_accounts(&mut state, &form.0)
+            .await?
+            .into_iter()
+            .filter(|d| !d.account.email.is_empty())
+            .collect();
+    let account_ids = dormant_accounts.iter().map(|d| d.account.id).collect();
+
+    #[cfg(feature = "mail")]
+    tokio::spawn(async move {
+        for dormant_account in dormant_accounts {
+            if let Err(e) = crate::mail::send_dormant_account_notice(
+                &dormant_account.account,
+                dormant_account.last_activity,
+            )
+            .await
+            {
+                log::warn!("Could not send mail: {:?}", e);
+            }
+        }
+    });
+    #[cfg(not(feature = "mail"))]
+    let _ = dormant_accounts;
+
+    Ok(Json(account_ids))
+}
+
+fn mail_dormant_accounts_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Send a notice to all selected dormant accounts that have an email address. Returns the ids of the notified accounts.")
+        .tag("reports")
+        .response::<200, Json<Vec<u64>>>()
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["reports.send"])
+}
+
+async fn archive_dormant_accounts(
+    mut state: RequestState,
+    form: Json<DormantAccountsActionDto>,
+) -> ServiceResult<Json<Vec<u64>>> {
+    state.session_require_permission(models::Permission::AccountsWrite)?;
+
+    let dormant_accounts = get_selected_dormant_accounts(&mut state, &form.0).await?;
+
+    let mut account_ids = Vec::new();
+    for dormant_account in dormant_accounts {
+        let id = dormant_account.account.id;
+        state.db.archive_account(id).await?;
+        audit_log::record::<()>(
+            &mut state,
+            "account.archive",
+            "account",
+            Some(id),
+            None,
+            None,
+        )
+        .await?;
+        account_ids.push(id);
+    }
+
+    Ok(Json(account_ids))
+}
+
+fn archive_dormant_accounts_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Archive all selected dormant accounts. Their balance is kept, auth methods, sessions and wallet passes are removed. Returns the ids of the archived accounts.")
+        .tag("reports")
+        .response::<200, Json<Vec<u64>>>()
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.write"])
+}
+
+async fn remove_public_tab_of_dormant_accounts(
+    mut state: RequestState,
+    form: Json<DormantAccountsActionDto>,
+) -> ServiceResult<Json<Vec<u64>>> {
+    state.session_require_permission(models::Permission::AccountsWrite)?;
+
+    let dormant_accounts = get_selected_dormant_accounts(&mut state, &form.0).await?;
+
+    let mut account_ids = Vec::new();
+    for dormant_account in dormant_accounts {
+        let mut account = dormant_account.account;
+        if !account
+            .auth_methods
+            .iter()
+            .any(|m| matches!(m, models::AuthMethod::PublicTab))
+        {
+            continue;
+        }
+
+        let before = AccountDto::from(&account);
+        account
+            .auth_methods
+            .retain_mut(|m| !matches!(m, &mut models::AuthMethod::PublicTab));
+        let account =
+            store_account_audited(&mut state, "public_tab.delete", &before, account).await?;
+        account_ids.push(account.id);
+    }
+
+    Ok(Json(account_ids))
+}
+
+fn remove_public_tab_of_dormant_accounts_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Remove the selected dormant accounts from the public tab board. Returns the ids of the changed accounts.")
+        .tag("reports")
+        .response::<200, Json<Vec<u64>>>()
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.write"])
+}
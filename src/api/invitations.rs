@@ -0,0 +1,439 @@
+use std::ops::Add;
+
+use aide::axum::routing::{delete_with, get_with, post_with};
+use aide::axum::ApiRouter;
+use aide::transform::TransformOperation;
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::Json;
+use base64::engine::general_purpose;
+use base64::Engine;
+use chrono::{Duration, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::database::AppState;
+use crate::error::{ServiceError, ServiceResult};
+use crate::request_state::RequestState;
+use crate::{env, models};
+
+use super::account_status::AccountStatusDto;
+use super::accounts::{AccountDto, CardTypeDto};
+use super::audit_log;
+use super::auth::AuthTokenDto;
+use super::password_hash_create;
+
+const INVITATION_VALIDITY_DAYS: i64 = 7;
+
+pub fn router(app_state: AppState) -> ApiRouter {
+    ApiRouter::new()
+        .api_route(
+            "/invitations",
+            get_with(list_invitations, list_invitations_docs)
+                .post_with(create_invitation, create_invitation_docs),
+        )
+        .api_route(
+            "/invitation/:id",
+            delete_with(revoke_invitation, revoke_invitation_docs),
+        )
+        .api_route(
+            "/invitation/:id/resend",
+            post_with(resend_invitation, resend_invitation_docs),
+        )
+        .api_route(
+            "/invitation-token/:token",
+            get_with(get_invitation_by_token, get_invitation_by_token_docs)
+                .post_with(accept_invitation, accept_invitation_docs),
+        )
+        .with_state(app_state)
+}
+
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub struct InvitationDto {
+    pub id: u64,
+    pub name: String,
+    pub email: String,
+    pub role: String,
+    pub status: Option<AccountStatusDto>,
+    pub invited_by_account_id: Option<u64>,
+    pub created_at: String,
+    pub valid_until: String,
+}
+
+impl From<&models::Invitation> for InvitationDto {
+    fn from(value: &models::Invitation) -> Self {
+        Self {
+            id: value.id,
+            name: value.name.clone(),
+            email: value.email.clone(),
+            role: value.role.name.clone(),
+            status: value.status.as_ref().map(|s| s.into()),
+            invited_by_account_id: value.invited_by_account_id,
+            created_at: format!("{:?}", value.created_at),
+            valid_until: format!("{:?}", value.valid_until),
+        }
+    }
+}
+
+/// Only returned when the invitation is created or resent
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub struct InvitationTokenDto {
+    pub id: u64,
+    pub token: String,
+    pub valid_until: String,
+}
+
+impl From<&models::Invitation> for InvitationTokenDto {
+    fn from(value: &models::Invitation) -> Self {
+        Self {
+            id: value.id,
+            token: value.token.clone(),
+            valid_until: format!("{:?}", value.valid_until),
+        }
+    }
+}
+
+fn send_invitation_mail(invitation: &models::Invitation) {
+    #[cfg(feature = "mail")]
+    {
+        let invitation = invitation.clone();
+        tokio::spawn(async move {
+            if let Err(e) = crate::mail::send_account_invitation(&invitation).await {
+                log::warn!("Could not send mail: {:?}", e);
+            }
+        });
+    }
+
+    #[cfg(not(feature = "mail"))]
+    let _ = invitation;
+}
+
+async fn list_invitations(mut state: RequestState) -> ServiceResult<Json<Vec<InvitationDto>>> {
+    state.session_require_permission(models::Permission::AccountsWrite)?;
+
+    let invitations = state.db.get_all_invitations().await?;
+    Ok(Json(invitations.iter().map(|i| i.into()).collect()))
+}
+
+fn list_invitations_docs(op: TransformOperation) -> TransformOperation {
+    op.description("List all pending invitations, including expired ones.")
+        .tag("invitations")
+        .response::<200, Json<Vec<InvitationDto>>>()
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.write"])
+}
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct SaveInvitationDto {
+    pub name: String,
+    pub email: String,
+    /// Name of the role, see `/roles`
+    pub role: String,
+    pub status_id: Option<u64>,
+}
+
+async fn create_invitation(
+    mut state: RequestState,
+    form: Json<SaveInvitationDto>,
+) -> ServiceResult<Json<InvitationTokenDto>> {
+    state.session_require_permission(models::Permission::AccountsWrite)?;
+    let session = state.session_require()?;
+
+    let form = form.0;
+
+    if form.role != *env::DEFAULT_ACCOUNT_ROLE {
+        // Only accounts that may manage roles are allowed to assign them
+        state.session_require_permission(models::Permission::RolesWrite)?;
+    }
+
+    let status = if let Some(status_id) = form.status_id {
+        state.db.get_account_status_by_id(status_id).await?
+    } else {
+        None
+    };
+
+    let role = state
+        .db
+        .get_role_by_name(&form.role)
+        .await?
+        .ok_or(ServiceError::NotFound)?;
+
+    let invitation = models::Invitation {
+        id: 0,
+        token: String::new(),
+        name: form.name,
+        email: form.email,
+        role,
+        status,
+        invited_by_account_id: Some(session.impersonated_by.unwrap_or(session.account.id)),
+        created_at: Utc::now(),
+        valid_until: Utc::now().add(Duration::days(INVITATION_VALIDITY_DAYS)),
+    };
+
+    let invitation = state.db.store_invitation(invitation, false).await?;
+    audit_log::record(
+        &mut state,
+        "invitation.create",
+        "invitation",
+        Some(invitation.id),
+        None,
+        Some(&InvitationDto::from(&invitation)),
+    )
+    .await?;
+
+    send_invitation_mail(&invitation);
+    Ok(Json(InvitationTokenDto::from(&invitation)))
+}
+
+fn create_invitation_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Invite a new user by email. The account is created once the invitation is accepted. Assigning a role other than the default role requires `roles.write`.",
+    )
+    .tag("invitations")
+    .response::<200, Json<InvitationTokenDto>>()
+    .response_with::<404, (), _>(|res| res.description("The requested role does not exist!"))
+    .response_with::<401, (), _>(|res| res.description("Missing login!"))
+    .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+    .security_requirement_scopes("SessionToken", ["accounts.write"])
+}
+
+async fn resend_invitation(
+    mut state: RequestState,
+    Path(id): Path<u64>,
+) -> ServiceResult<Json<InvitationTokenDto>> {
+    state.session_require_permission(models::Permission::AccountsWrite)?;
+
+    let invitation = state.db.get_invitation_by_id(id).await?;
+
+    if let Some(mut invitation) = invitation {
+        let before = InvitationDto::from(&invitation);
+        invitation.valid_until = Utc::now().add(Duration::days(INVITATION_VALIDITY_DAYS));
+
+        let invitation = state.db.store_invitation(invitation, true).await?;
+        audit_log::record(
+            &mut state,
+            "invitation.resend",
+            "invitation",
+            Some(id),
+            Some(&before),
+            Some(&InvitationDto::from(&invitation)),
+        )
+        .await?;
+
+        send_invitation_mail(&invitation);
+        return Ok(Json(InvitationTokenDto::from(&invitation)));
+    }
+
+    Err(ServiceError::NotFound)
+}
+
+fn resend_invitation_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Send the invitation again with a new link and extended expiry. The previous link stops working.")
+        .tag("invitations")
+        .response::<200, Json<InvitationTokenDto>>()
+        .response_with::<404, (), _>(|res| res.description("The requested invitation does not exist!"))
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.write"])
+}
+
+async fn revoke_invitation(
+    mut state: RequestState,
+    Path(id): Path<u64>,
+) -> ServiceResult<StatusCode> {
+    state.session_require_permission(models::Permission::AccountsWrite)?;
+
+    let before = state.db.get_invitation_by_id(id).await?;
+    state.db.delete_invitation(id).await?;
+    audit_log::record(
+        &mut state,
+        "invitation.revoke",
+        "invitation",
+        Some(id),
+        before.as_ref().map(InvitationDto::from).as_ref(),
+        None,
+    )
+    .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn revoke_invitation_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Revoke a pending invitation.")
+        .tag("invitations")
+        .response_with::<204, (), _>(|res| {
+            res.description("The invitation was successfully revoked!")
+        })
+        .response_with::<404, (), _>(|res| {
+            res.description("The requested invitation does not exist!")
+        })
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.write"])
+}
+
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub struct InvitationPreviewDto {
+    pub name: String,
+    pub email: String,
+    pub valid_until: String,
+}
+
+async fn get_invitation_by_token(
+    mut state: RequestState,
+    Path(token): Path<String>,
+) -> ServiceResult<Json<InvitationPreviewDto>> {
+    let invitation = state
+        .db
+        .get_invitation_by_token(&token)
+        .await?
+        .ok_or(ServiceError::NotFound)?;
+
+    Ok(Json(InvitationPreviewDto {
+        name: invitation.name,
+        email: invitation.email,
+        valid_until: format!("{:?}", invitation.valid_until),
+    }))
+}
+
+fn get_invitation_by_token_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Show the invitation for the given invitation link.")
+        .tag("invitations")
+        .response::<200, Json<InvitationPreviewDto>>()
+        .response_with::<404, (), _>(|res| {
+            res.description("The invitation does not exist or is expired!")
+        })
+}
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct AcceptInvitationNfcDto {
+    pub name: String,
+    pub card_id: String,
+    pub card_type: CardTypeDto,
+    pub data: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct AcceptInvitationDto {
+    pub username: String,
+    pub password: String,
+    /// Optionally enroll a nfc card for the new account
+    pub nfc: Option<AcceptInvitationNfcDto>,
+}
+
+async fn accept_invitation(
+    mut state: RequestState,
+    Path(token): Path<String>,
+    form: Json<AcceptInvitationDto>,
+) -> ServiceResult<Json<AuthTokenDto>> {
+    let form = form.0;
+
+    let invitation = state
+        .db
+        .get_invitation_by_token(&token)
+        .await?
+        .ok_or(ServiceError::NotFound)?;
+
+    let username_owner = state
+        .db
+        .get_account_by_auth_method(models::AuthRequest::PasswordBased {
+            username: form.username.clone(),
+        })
+        .await?;
+    if username_owner.is_some() {
+        return Err(ServiceError::UsernameInUse);
+    }
+
+    let mut auth_methods = vec![models::AuthMethod::PasswordBased(models::AuthPassword {
+        username: form.username,
+        password_hash: password_hash_create(&form.password)?,
+    })];
+
+    if let Some(nfc) = form.nfc {
+        let card_id = general_purpose::STANDARD.decode(nfc.card_id).map_err(|_| {
+            ServiceError::InternalServerError(
+                "Could not decode base64 parameter 'card_id'.".to_string(),
+            )
+        })?;
+        let data = general_purpose::STANDARD
+            .decode(nfc.data.unwrap_or_default())
+            .map_err(|_| {
+                ServiceError::InternalServerError(
+                    "Could not decode base64 parameter 'data'.".to_string(),
+                )
+            })?;
+
+        let card_owner = state
+            .db
+            .get_account_by_auth_method(models::AuthRequest::NfcBased {
+                card_id: card_id.clone(),
+            })
+            .await?;
+        if card_owner.is_some() {
+            return Err(ServiceError::CardInUse);
+        }
+
+        auth_methods.push(models::AuthMethod::NfcBased(models::AuthNfc {
+            name: nfc.name,
+            card_id,
+            card_type: nfc.card_type.into(),
+            data: models::NfcSecret::seal(&data)?,
+            depends_on_session: None,
+            status: models::NfcCardStatus::Active,
+            last_used_at: None,
+            valid_until: None,
+        }));
+    }
+
+    let account = models::Account {
+        id: 0,
+        balance: models::CoinAmount(Default::default()),
+        name: invitation.name,
+        email: invitation.email,
+        role: invitation.role,
+        auth_methods,
+        enable_monthly_mail_report: false,
+        enable_automatic_stamp_usage: true,
+        status: invitation.status,
+        archived_at: None,
+    };
+    let account = state.db.accept_invitation(invitation.id, account).await?;
+
+    audit_log::record(
+        &mut state,
+        "invitation.accept",
+        "account",
+        Some(account.id),
+        None,
+        Some(&AccountDto::from(&account)),
+    )
+    .await?;
+
+    let token = state
+        .db
+        .create_session_token(
+            account.id,
+            models::AuthMethodType::PasswordBased,
+            models::SessionLifetime::Sliding(Duration::minutes(60)),
+            false,
+            &state.client,
+        )
+        .await?;
+
+    Ok(Json(AuthTokenDto {
+        token,
+        refresh_token: None,
+    }))
+}
+
+fn accept_invitation_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Accept the invitation by choosing username and password. Creates the account and returns a session for it.")
+        .tag("invitations")
+        .response::<200, Json<AuthTokenDto>>()
+        .response_with::<404, (), _>(|res| {
+            res.description("The invitation does not exist or is expired!")
+        })
+        .response_with::<409, (), _>(|res| {
+            res.description("The username or nfc card is already in use!")
+        })
+}
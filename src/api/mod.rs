@@ -5,29 +5,53 @@ use rand::RngCore;
 use crate::{database::AppState, error::ServiceResult};
 
 mod account_auth_methods;
+mod account_closure;
+mod account_data;
+mod account_groups;
 mod account_status;
 mod accounts;
+mod audit_log;
 mod auth;
-mod nfc_id;
+mod dormant_accounts;
+mod invitations;
 mod nfc_mifare;
+mod nfc_provisioning;
+mod nfc_readers;
 mod products;
 mod purchases;
+mod push_jobs;
 mod register;
 mod report;
+mod roles;
+mod spending_limits;
 mod transactions;
+mod vouchers;
 
+pub mod nfc_id;
 pub mod wallet_routes;
 
 pub fn init(app_state: AppState) -> ApiRouter {
     ApiRouter::new()
         .merge(account_auth_methods::router(app_state.clone()))
+        .merge(account_closure::router(app_state.clone()))
+        .merge(account_data::router(app_state.clone()))
+        .merge(account_groups::router(app_state.clone()))
+        .merge(spending_limits::router(app_state.clone()))
+        .merge(dormant_accounts::router(app_state.clone()))
         .merge(account_status::router(app_state.clone()))
         .merge(accounts::router(app_state.clone()))
+        .merge(audit_log::router(app_state.clone()))
         .merge(auth::router(app_state.clone()))
+        .merge(invitations::router(app_state.clone()))
         .merge(products::router(app_state.clone()))
         .merge(register::router(app_state.clone()))
         .merge(transactions::router(app_state.clone()))
         .merge(purchases::router(app_state.clone()))
+        .merge(push_jobs::router(app_state.clone()))
+        .merge(roles::router(app_state.clone()))
+        .merge(nfc_readers::router(app_state.clone()))
+        .merge(nfc_provisioning::router(app_state.clone()))
+        .merge(vouchers::router(app_state.clone()))
         .merge(report::router(app_state))
 }
 
@@ -1,6 +1,4 @@
-use std::collections::HashMap;
 use std::ops::Add;
-use std::sync::Arc;
 
 use aes::Aes256;
 use block_modes::block_padding::ZeroPadding;
@@ -8,12 +6,13 @@ use block_modes::cipher::{BlockCipher, BlockDecrypt, BlockEncrypt, NewBlockCiphe
 use block_modes::{BlockMode, Cbc};
 use chrono::{Duration, Utc};
 use generic_array::GenericArray;
+use log::warn;
 use rand::RngCore;
-use tokio::sync::Mutex;
 
-use crate::database::AppStateNfcChallenge;
+use crate::database::{AppStateNfcChallenge, NfcChallengeStorage};
+use crate::env;
 use crate::error::{ServiceError, ServiceResult};
-use crate::models::{AuthNfc, CardType};
+use crate::models::{AuthNfc, CardType, NfcReader};
 
 /// Communication to the mifare desfire always requires the tdes decribt
 struct NfcAes {
@@ -86,29 +85,59 @@ fn str_to_bytes(s: &str) -> Vec<u8> {
         .collect()
 }
 
-fn get_reader_key() -> Vec<u8> {
-    let key = std::env::var("READER_KEY").unwrap_or_else(|_| {
-        "c50ab42b5d32b6ccf26b4c5d2e9862c7694cfba9a8eac568a36e1f400a0f480d".to_owned()
-    });
-    str_to_bytes(&key)
+/// Publicly known reader key, only accepted in dev mode
+const DEFAULT_READER_KEY: &str = "c50ab42b5d32b6ccf26b4c5d2e9862c7694cfba9a8eac568a36e1f400a0f480d";
+
+/// Refuse to start with the built-in reader key outside of dev mode
+pub fn check_reader_key() {
+    if *env::DEV_MODE {
+        return;
+    }
+
+    match env::READER_KEY.as_deref() {
+        Some(DEFAULT_READER_KEY) => {
+            panic!("READER_KEY must not be the built-in default key outside of dev mode!")
+        }
+        Some(_) => {}
+        None => warn!("READER_KEY is not set, generic nfc cards can only be used with registered nfc readers!"),
+    }
+}
+
+fn get_reader_key(reader: Option<&NfcReader>) -> ServiceResult<Vec<u8>> {
+    if let Some(reader) = reader {
+        return reader.key.open();
+    }
+
+    match env::READER_KEY.as_deref() {
+        Some(key) => Ok(str_to_bytes(key)),
+        None if *env::DEV_MODE => Ok(str_to_bytes(DEFAULT_READER_KEY)),
+        None => Err(ServiceError::Unauthorized("Unknown nfc reader!")),
+    }
+}
+
+/// Generate a new random key for a nfc reader
+pub fn generate_reader_key() -> [u8; 32] {
+    generate_key()
 }
 
 #[allow(non_snake_case)]
 pub async fn authenticate_phase_challenge(
-    challenge_storage: &Arc<Mutex<HashMap<u64, AppStateNfcChallenge>>>,
-    account_id: u64,
+    challenge_storage: &NfcChallengeStorage,
+    reader: Option<&NfcReader>,
     auth_nfc: &AuthNfc,
     request: &[u8],
-) -> ServiceResult<Vec<u8>> {
+) -> ServiceResult<(String, Vec<u8>)> {
     let ek_rndB = request;
     let key = match auth_nfc.card_type {
-        CardType::GenericNfc => get_reader_key(),
-        _ => auth_nfc.data.clone(),
+        CardType::GenericNfc => get_reader_key(reader)?,
+        _ => auth_nfc.data.open()?,
     };
 
     let rndA = generate_key();
     let rndB = vec_to_array::<u8, 32>(aes_decrypt(&key, ek_rndB)?)?;
     let state = AppStateNfcChallenge {
+        card_id: auth_nfc.card_id.clone(),
+        reader_id: reader.map(|r| r.id),
         valid_until: Utc::now().add(Duration::seconds(10)),
         rnd_a: rndA.to_vec(),
         rnd_b: rndB.to_vec(),
@@ -124,16 +153,16 @@ pub async fn authenticate_phase_challenge(
 
     let dk_rndA_rndBshifted = aes_encrypt(&key, &rndA_rndBshifted)?;
 
-    let mut map = challenge_storage.lock().await;
-    map.insert(account_id, state);
+    let challenge_id = challenge_storage.insert(state).await?;
 
-    Ok(dk_rndA_rndBshifted)
+    Ok((challenge_id, dk_rndA_rndBshifted))
 }
 
 #[allow(non_snake_case)]
 pub async fn authenticate_phase_response(
-    challenge_storage: &Arc<Mutex<HashMap<u64, AppStateNfcChallenge>>>,
-    account_id: u64,
+    challenge_storage: &NfcChallengeStorage,
+    challenge_id: &str,
+    reader: Option<&NfcReader>,
     auth_nfc: &AuthNfc,
     challenge: &[u8],
     response: &[u8],
@@ -141,14 +170,19 @@ pub async fn authenticate_phase_response(
     let dk_rndA_rndBshifted = challenge;
     let ek_rndAshifted_card = response;
     let key = match auth_nfc.card_type {
-        CardType::GenericNfc => get_reader_key(),
-        _ => auth_nfc.data.clone(),
+        CardType::GenericNfc => get_reader_key(reader)?,
+        _ => auth_nfc.data.open()?,
     };
 
-    let mut map = challenge_storage.lock().await;
-    let state = map.remove(&account_id).ok_or(ServiceError::Unauthorized(
-        "response does not match challenge!",
-    ))?;
+    let state = challenge_storage
+        .take(challenge_id)
+        .await?
+        .filter(|state| {
+            state.card_id == auth_nfc.card_id && state.reader_id == reader.map(|r| r.id)
+        })
+        .ok_or(ServiceError::Unauthorized(
+            "response does not match challenge!",
+        ))?;
 
     if state.valid_until < Utc::now() {
         return Err(ServiceError::Unauthorized("challenge response timeout!"));
@@ -1,19 +1,20 @@
-use std::collections::HashMap;
 use std::ops::Add;
-use std::sync::Arc;
 
-use block_modes::block_padding::ZeroPadding;
+use aes::Aes128;
+use block_modes::block_padding::{NoPadding, ZeroPadding};
 use block_modes::cipher::{BlockCipher, BlockDecrypt, BlockEncrypt, NewBlockCipher};
 use block_modes::{BlockMode, Cbc};
 use chrono::{Duration, Utc};
 use des::TdesEde2;
 use generic_array::GenericArray;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use openssl::symm::Cipher;
 use rand::RngCore;
-use tokio::sync::Mutex;
 
-use crate::database::AppStateNfcChallenge;
+use crate::database::{AppStateNfcChallenge, NfcChallengeStorage};
 use crate::error::{ServiceError, ServiceResult};
-use crate::models::AuthNfc;
+use crate::models::{AuthNfc, NfcSecret};
 
 /// Communication to the mifare desfire always requires the tdes decribt
 struct MiFareTdes {
@@ -107,17 +108,18 @@ fn vec_to_array<T, const N: usize>(v: Vec<T>) -> ServiceResult<[T; N]> {
 
 #[allow(non_snake_case)]
 pub async fn authenticate_phase_challenge(
-    challenge_storage: &Arc<Mutex<HashMap<u64, AppStateNfcChallenge>>>,
-    account_id: u64,
+    challenge_storage: &NfcChallengeStorage,
     auth_nfc: &AuthNfc,
     request: &[u8],
-) -> ServiceResult<Vec<u8>> {
+) -> ServiceResult<(String, Vec<u8>)> {
     let ek_rndB = request;
-    let key = auth_nfc.data.clone();
+    let key = auth_nfc.data.open()?;
 
     let rndA = generate_key();
     let rndB = vec_to_array::<u8, 8>(tdes_decrypt(&key, ek_rndB)?)?;
     let state = AppStateNfcChallenge {
+        card_id: auth_nfc.card_id.clone(),
+        reader_id: None,
         valid_until: Utc::now().add(Duration::seconds(10)),
         rnd_a: rndA.to_vec(),
         rnd_b: rndB.to_vec(),
@@ -133,28 +135,30 @@ pub async fn authenticate_phase_challenge(
 
     let dk_rndA_rndBshifted = tdes_encrypt(&key, &rndA_rndBshifted)?;
 
-    let mut map = challenge_storage.lock().await;
-    map.insert(account_id, state);
+    let challenge_id = challenge_storage.insert(state).await?;
 
-    Ok(dk_rndA_rndBshifted)
+    Ok((challenge_id, dk_rndA_rndBshifted))
 }
 
 #[allow(non_snake_case)]
 pub async fn authenticate_phase_response(
-    challenge_storage: &Arc<Mutex<HashMap<u64, AppStateNfcChallenge>>>,
-    account_id: u64,
+    challenge_storage: &NfcChallengeStorage,
+    challenge_id: &str,
     auth_nfc: &AuthNfc,
     challenge: &[u8],
     response: &[u8],
 ) -> ServiceResult<Vec<u8>> {
     let dk_rndA_rndBshifted = challenge;
     let ek_rndAshifted_card = response;
-    let key = auth_nfc.data.clone();
+    let key = auth_nfc.data.open()?;
 
-    let mut map = challenge_storage.lock().await;
-    let state = map.remove(&account_id).ok_or(ServiceError::Unauthorized(
-        "response does not match challenge!",
-    ))?;
+    let state = challenge_storage
+        .take(challenge_id)
+        .await?
+        .filter(|state| state.card_id == auth_nfc.card_id)
+        .ok_or(ServiceError::Unauthorized(
+            "response does not match challenge!",
+        ))?;
 
     if state.valid_until < Utc::now() {
         return Err(ServiceError::Unauthorized("challenge response timeout!"));
@@ -198,3 +202,351 @@ pub async fn authenticate_phase_response(
 
     Ok(session_key)
 }
+
+fn aes_encrypt(key: &[u8], value: &[u8]) -> ServiceResult<Vec<u8>> {
+    aes_encrypt_with_iv(key, &[0u8; 16], value)
+}
+
+fn aes_encrypt_with_iv(key: &[u8], iv: &[u8], value: &[u8]) -> ServiceResult<Vec<u8>> {
+    let cipher: Cbc<Aes128, NoPadding> = Cbc::new_from_slices(key, iv)?;
+    Ok(cipher.encrypt_vec(value))
+}
+
+fn aes_decrypt(key: &[u8], value: &[u8]) -> ServiceResult<Vec<u8>> {
+    let cipher: Cbc<Aes128, NoPadding> = Cbc::new_from_slices(key, &[0u8; 16])?;
+    Ok(cipher.decrypt_vec(value)?)
+}
+
+fn aes_cmac(key: &[u8], value: &[u8]) -> ServiceResult<Vec<u8>> {
+    let key = PKey::cmac(&Cipher::aes_128_cbc(), key)?;
+    let mut signer = Signer::new_without_digest(&key)?;
+    signer.update(value)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+fn rotate_left(value: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(value.len());
+    out.extend(&value[1..]);
+    out.push(value[0]);
+    out
+}
+
+pub fn generate_key_aes() -> [u8; 16] {
+    let mut data = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut data);
+    data
+}
+
+/// Application keys of a freshly created DESFire application
+pub const DEFAULT_KEY_AES: [u8; 16] = [0u8; 16];
+
+/// Application id of the ascii application, in the byte order of the card commands
+pub const ASCII_APPLICATION_ID: [u8; 3] = [0x50, 0x41, 0x41];
+
+/// Build the commands that create the ascii application on a blank card and select it.
+///
+/// The factory settings of the PICC master key allow `CreateApplication` without authentication.
+/// The application gets a single AES key that is initialized with `DEFAULT_KEY_AES`, only the
+/// application master key can change it. Cards that were already set up answer the
+/// `CreateApplication` with `DUPLICATE_ERROR` (0x91DE), this can be ignored.
+pub fn ev2_card_setup_commands() -> Vec<Vec<u8>> {
+    const CMD_SELECT_APPLICATION: u8 = 0x5A;
+    const CMD_CREATE_APPLICATION: u8 = 0xCA;
+    // Master key changeable, configuration changeable, free directory listing
+    const KEY_SETTINGS: u8 = 0x0B;
+    // AES crypto with one key
+    const KEY_SETTINGS_2: u8 = 0x80 | 0x01;
+
+    let select_picc = vec![
+        0x90,
+        CMD_SELECT_APPLICATION,
+        0x00,
+        0x00,
+        0x03,
+        0x00,
+        0x00,
+        0x00,
+        0x00,
+    ];
+
+    let mut create_application = vec![0x90, CMD_CREATE_APPLICATION, 0x00, 0x00, 0x05];
+    create_application.extend(ASCII_APPLICATION_ID);
+    create_application.extend([KEY_SETTINGS, KEY_SETTINGS_2]);
+    create_application.push(0x00);
+
+    let mut select_application = vec![0x90, CMD_SELECT_APPLICATION, 0x00, 0x00, 0x03];
+    select_application.extend(ASCII_APPLICATION_ID);
+    select_application.push(0x00);
+
+    vec![select_picc, create_application, select_application]
+}
+
+/// Session established by `AuthenticateEV2First`
+#[derive(Debug, PartialEq)]
+pub struct Ev2Session {
+    pub transaction_identifier: Vec<u8>,
+    pub enc_key: Vec<u8>,
+    pub mac_key: Vec<u8>,
+}
+
+#[allow(non_snake_case)]
+fn ev2_encrypt_rndA_rndBshifted(key: &[u8], rndA: &[u8], rndB: &[u8]) -> ServiceResult<Vec<u8>> {
+    let mut rndA_rndBshifted: Vec<u8> = Vec::with_capacity(32);
+    rndA_rndBshifted.extend(rndA);
+    rndA_rndBshifted.extend(rotate_left(rndB));
+
+    aes_encrypt(key, &rndA_rndBshifted)
+}
+
+/// Second step of `AuthenticateEV2First`, returns rndB and the encrypted `rndA || rndB'` for the card
+#[allow(non_snake_case)]
+fn ev2_challenge(
+    key: &[u8],
+    ek_rndB: &[u8],
+    rndA: &[u8; 16],
+) -> ServiceResult<([u8; 16], Vec<u8>)> {
+    let rndB = vec_to_array::<u8, 16>(aes_decrypt(key, ek_rndB)?)?;
+    let challenge = ev2_encrypt_rndA_rndBshifted(key, rndA, &rndB)?;
+
+    Ok((rndB, challenge))
+}
+
+/// Verify the card answer `E(TI || rndA' || PDcap2 || PCDcap2)` and derive the session keys
+#[allow(non_snake_case)]
+fn ev2_response(
+    key: &[u8],
+    rndA: &[u8],
+    rndB: &[u8],
+    challenge: &[u8],
+    response: &[u8],
+) -> ServiceResult<Ev2Session> {
+    if rndA.len() != 16 || rndB.len() != 16 {
+        return Err(ServiceError::Unauthorized(
+            "response does not match challenge!",
+        ));
+    }
+
+    if challenge != ev2_encrypt_rndA_rndBshifted(key, rndA, rndB)? {
+        return Err(ServiceError::Unauthorized(
+            "response does not match challenge!",
+        ));
+    }
+
+    if response.len() != 32 {
+        return Err(ServiceError::Unauthorized("challenge response failed!"));
+    }
+    let card_answer = aes_decrypt(key, response)?;
+    let transaction_identifier = card_answer[0..4].to_vec();
+    if card_answer[4..20] != rotate_left(rndA) {
+        return Err(ServiceError::Unauthorized("challenge response failed!"));
+    }
+
+    // Session vectors as defined for AuthenticateEV2First (NXP AN12343)
+    let mut sv: Vec<u8> = Vec::with_capacity(26);
+    sv.extend(&rndA[0..2]);
+    sv.extend(rndA[2..8].iter().zip(&rndB[0..6]).map(|(a, b)| a ^ b));
+    sv.extend(&rndB[6..16]);
+    sv.extend(&rndA[8..16]);
+
+    let mut sv1 = vec![0xA5, 0x5A, 0x00, 0x01, 0x00, 0x80];
+    sv1.extend(&sv);
+    let mut sv2 = vec![0x5A, 0xA5, 0x00, 0x01, 0x00, 0x80];
+    sv2.extend(&sv);
+
+    Ok(Ev2Session {
+        transaction_identifier,
+        enc_key: aes_cmac(key, &sv1)?,
+        mac_key: aes_cmac(key, &sv2)?,
+    })
+}
+
+/// Build the `ChangeKey` command for the key that was used for authentication.
+///
+/// This has to be the first command of the session, it is sent in `CommMode.Full`
+/// and returned as wrapped native APDU. The new key is only opened for the encryption.
+pub fn ev2_change_key_command(
+    session: &Ev2Session,
+    key_no: u8,
+    new_key: &NfcSecret,
+    key_version: u8,
+) -> ServiceResult<Vec<u8>> {
+    const CMD_CHANGE_KEY: u8 = 0xC4;
+    let new_key = new_key.open()?;
+    let cmd_ctr = 0u16.to_le_bytes();
+
+    let mut iv_input = vec![0xA5, 0x5A];
+    iv_input.extend(&session.transaction_identifier);
+    iv_input.extend(cmd_ctr);
+    iv_input.extend([0u8; 8]);
+    let iv = aes_encrypt(&session.enc_key, &iv_input)?;
+
+    let mut key_data = Vec::with_capacity(32);
+    key_data.extend(&new_key);
+    key_data.push(key_version);
+    key_data.push(0x80);
+    key_data.resize(32, 0x00);
+    let encrypted_key_data = aes_encrypt_with_iv(&session.enc_key, &iv, &key_data)?;
+
+    let mut mac_input = vec![CMD_CHANGE_KEY];
+    mac_input.extend(cmd_ctr);
+    mac_input.extend(&session.transaction_identifier);
+    mac_input.push(key_no);
+    mac_input.extend(&encrypted_key_data);
+    let mac = aes_cmac(&session.mac_key, &mac_input)?;
+
+    let mut data = vec![key_no];
+    data.extend(encrypted_key_data);
+    // The truncated mac only consists of the odd bytes
+    data.extend(mac.iter().skip(1).step_by(2));
+
+    let mut apdu = vec![0x90, CMD_CHANGE_KEY, 0x00, 0x00, data.len() as u8];
+    apdu.extend(data);
+    apdu.push(0x00);
+
+    Ok(apdu)
+}
+
+#[allow(non_snake_case)]
+pub async fn authenticate_ev2_phase_challenge(
+    challenge_storage: &NfcChallengeStorage,
+    auth_nfc: &AuthNfc,
+    request: &[u8],
+) -> ServiceResult<(String, Vec<u8>)> {
+    let key = auth_nfc.data.open()?;
+
+    let rndA = generate_key_aes();
+    let (rndB, challenge) = ev2_challenge(&key, request, &rndA)?;
+    let state = AppStateNfcChallenge {
+        card_id: auth_nfc.card_id.clone(),
+        reader_id: None,
+        valid_until: Utc::now().add(Duration::seconds(10)),
+        rnd_a: rndA.to_vec(),
+        rnd_b: rndB.to_vec(),
+    };
+
+    let challenge_id = challenge_storage.insert(state).await?;
+
+    Ok((challenge_id, challenge))
+}
+
+pub async fn authenticate_ev2_phase_response(
+    challenge_storage: &NfcChallengeStorage,
+    challenge_id: &str,
+    auth_nfc: &AuthNfc,
+    challenge: &[u8],
+    response: &[u8],
+) -> ServiceResult<Ev2Session> {
+    let key = auth_nfc.data.open()?;
+
+    let state = challenge_storage
+        .take(challenge_id)
+        .await?
+        .filter(|state| state.card_id == auth_nfc.card_id)
+        .ok_or(ServiceError::Unauthorized(
+            "response does not match challenge!",
+        ))?;
+
+    if state.valid_until < Utc::now() {
+        return Err(ServiceError::Unauthorized("challenge response timeout!"));
+    }
+
+    ev2_response(&key, &state.rnd_a, &state.rnd_b, challenge, response)
+}
+
+#[cfg(test)]
+mod tests {
+    use hex_literal::hex;
+
+    use super::*;
+
+    // AuthenticateEV2First example from NXP AN12343, key number 0x00 with an all zero key
+    const KEY: [u8; 16] = [0u8; 16];
+    const RND_A: [u8; 16] = hex!("13C5DB8A5930439FC3DEF9A4C675360F");
+    const RND_B: [u8; 16] = hex!("B9E2FC789B64BF237CCCAA20EC7E6E48");
+    const EK_RND_B: [u8; 16] = hex!("A04C124213C186F22399D33AC2A30215");
+    const EK_RND_A_RND_B_SHIFTED: [u8; 32] =
+        hex!("35C3E05A752E0144BAC0DE51C1F22C56B34408A23D8AEA266CAB947EA8E0118D");
+    const EK_CARD_ANSWER: [u8; 32] =
+        hex!("3FA64DB5446D1F34CD6EA311167F5E4985B89690C04A05F17FA7AB2F08120663");
+
+    #[test]
+    fn test_ev2_challenge() {
+        let (rnd_b, challenge) = ev2_challenge(&KEY, &EK_RND_B, &RND_A).unwrap();
+        assert_eq!(rnd_b, RND_B);
+        assert_eq!(challenge, EK_RND_A_RND_B_SHIFTED);
+    }
+
+    #[test]
+    fn test_ev2_response() {
+        let session = ev2_response(
+            &KEY,
+            &RND_A,
+            &RND_B,
+            &EK_RND_A_RND_B_SHIFTED,
+            &EK_CARD_ANSWER,
+        )
+        .unwrap();
+
+        assert_eq!(session.transaction_identifier, hex!("9D00C4DF"));
+        assert_eq!(session.enc_key, hex!("1309C877509E5A215007FF0ED19CA564"));
+        assert_eq!(session.mac_key, hex!("4C6626F5E72EA694202139295C7A7FC7"));
+    }
+
+    #[test]
+    fn test_ev2_response_rejects_wrong_card_answer() {
+        let mut card_answer = EK_CARD_ANSWER;
+        card_answer[0] ^= 1;
+
+        let r = ev2_response(&KEY, &RND_A, &RND_B, &EK_RND_A_RND_B_SHIFTED, &card_answer);
+        assert_eq!(
+            r,
+            Err(ServiceError::Unauthorized("challenge response failed!"))
+        );
+    }
+
+    #[test]
+    fn test_ev2_change_key_command() {
+        let session = ev2_response(
+            &KEY,
+            &RND_A,
+            &RND_B,
+            &EK_RND_A_RND_B_SHIFTED,
+            &EK_CARD_ANSWER,
+        )
+        .unwrap();
+        let new_key = hex!("00112233445566778899AABBCCDDEEFF");
+
+        let apdu =
+            ev2_change_key_command(&session, 0x00, &NfcSecret::Plain(new_key.to_vec()), 0x01)
+                .unwrap();
+        assert_eq!(apdu.len(), 5 + 41 + 1);
+        assert_eq!(apdu[0..6], [0x90, 0xC4, 0x00, 0x00, 41, 0x00]);
+        assert_eq!(apdu[46], 0x00);
+
+        let iv = aes_encrypt(&session.enc_key, &hex!("A55A9D00C4DF00000000000000000000")).unwrap();
+        let cipher: Cbc<Aes128, NoPadding> = Cbc::new_from_slices(&session.enc_key, &iv).unwrap();
+        let key_data = cipher.decrypt_vec(&apdu[6..38]).unwrap();
+        assert_eq!(key_data[0..16], new_key);
+        assert_eq!(key_data[16..18], [0x01, 0x80]);
+        assert!(key_data[18..].iter().all(|b| *b == 0));
+
+        let mut mac_input = hex!("C400009D00C4DF00").to_vec();
+        mac_input.extend(&apdu[6..38]);
+        let mac = aes_cmac(&session.mac_key, &mac_input).unwrap();
+        let truncated: Vec<u8> = mac.into_iter().skip(1).step_by(2).collect();
+        assert_eq!(apdu[38..46], truncated);
+    }
+
+    #[test]
+    fn test_ev2_card_setup_commands() {
+        let commands = ev2_card_setup_commands();
+        assert_eq!(
+            commands,
+            vec![
+                hex!("905A00000300000000").to_vec(),
+                hex!("90CA0000055041410B8100").to_vec(),
+                hex!("905A00000350414100").to_vec(),
+            ]
+        );
+    }
+}
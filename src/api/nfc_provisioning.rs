@@ -0,0 +1,439 @@
+use std::ops::Add;
+
+use aide::axum::routing::{delete_with, get_with, post_with};
+use aide::axum::ApiRouter;
+use aide::transform::TransformOperation;
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::Json;
+use base64::engine::general_purpose;
+use base64::Engine;
+use chrono::{Duration, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::database::AppState;
+use crate::error::{ServiceError, ServiceResult};
+use crate::models::{self, CardType};
+use crate::request_state::RequestState;
+
+use super::accounts::AccountDto;
+use super::audit_log;
+use super::nfc_mifare;
+
+/// Key number of the ascii application key that is used for authentication
+const KEY_NO: u8 = 0x00;
+const KEY_VERSION: u8 = 0x01;
+
+pub fn router(app_state: AppState) -> ApiRouter {
+    ApiRouter::new()
+        .api_route(
+            "/account/:id/nfc-provisioning",
+            post_with(start_nfc_provisioning, start_nfc_provisioning_docs),
+        )
+        .api_route(
+            "/account/:id/nfc-provisioning/card-setup",
+            get_with(get_nfc_card_setup, get_nfc_card_setup_docs),
+        )
+        .api_route(
+            "/account/:id/nfc-provisioning/:provisioning_id",
+            delete_with(cancel_nfc_provisioning, cancel_nfc_provisioning_docs),
+        )
+        .api_route(
+            "/account/:id/nfc-provisioning/:provisioning_id/key-change",
+            post_with(
+                nfc_provisioning_key_change,
+                nfc_provisioning_key_change_docs,
+            ),
+        )
+        .api_route(
+            "/account/:id/nfc-provisioning/:provisioning_id/confirm",
+            post_with(confirm_nfc_provisioning, confirm_nfc_provisioning_docs),
+        )
+        .with_state(app_state)
+}
+
+fn decode_base64(value: &str, name: &str) -> ServiceResult<Vec<u8>> {
+    general_purpose::STANDARD.decode(value).map_err(|_| {
+        ServiceError::InternalServerError(format!("Could not decode base64 parameter '{name}'."))
+    })
+}
+
+/// The key that is currently stored on the card. Blank cards and replacement cards still use
+/// the default key, only re-keying an existing aes card authenticates with its current key.
+/// Re-keying other card types is rejected by `require_rekeyable_card`.
+fn current_card_key(
+    account: &models::Account,
+    provisioning: &models::NfcProvisioning,
+) -> models::AuthNfc {
+    let current = account.auth_methods.iter().find_map(|m| match m {
+        models::AuthMethod::NfcBased(auth_nfc)
+            if Some(&auth_nfc.card_id) == provisioning.replaces_card_id.as_ref()
+                && auth_nfc.card_id == provisioning.card_id
+                && auth_nfc.card_type == CardType::AsciiMifareAes =>
+        {
+            Some(auth_nfc.clone())
+        }
+        _ => None,
+    });
+
+    current.unwrap_or_else(|| models::AuthNfc {
+        name: provisioning.name.clone(),
+        card_id: provisioning.card_id.clone(),
+        card_type: CardType::AsciiMifareAes,
+        data: models::NfcSecret::Plain(nfc_mifare::DEFAULT_KEY_AES.to_vec()),
+        depends_on_session: None,
+        status: models::NfcCardStatus::Active,
+        last_used_at: None,
+        valid_until: None,
+    })
+}
+
+/// Legacy DES cards and generic cards do not hold the ascii AES application key, they have to be
+/// replaced by a new card instead
+fn require_rekeyable_card(
+    account: &models::Account,
+    card_id: &[u8],
+    replaces_card_id: Option<&Vec<u8>>,
+) -> ServiceResult<()> {
+    if replaces_card_id.map(|id| id.as_slice()) != Some(card_id) {
+        return Ok(());
+    }
+
+    let is_aes_card = account.auth_methods.iter().any(|m| {
+        matches!(m, models::AuthMethod::NfcBased(auth_nfc) if auth_nfc.card_id == card_id
+            && auth_nfc.card_type == CardType::AsciiMifareAes)
+    });
+    if !is_aes_card {
+        return Err(ServiceError::BadRequest(
+            "Only ascii mifare aes cards can be re-keyed, replace the card with a new one",
+        ));
+    }
+    Ok(())
+}
+
+/// Re-keying a blocked or lost card makes it active again, this is reserved for admins
+fn require_permission_to_reactivate(
+    state: &RequestState,
+    account: &models::Account,
+    card_id: &[u8],
+    replaces_card_id: Option<&Vec<u8>>,
+) -> ServiceResult<()> {
+    if replaces_card_id.map(|id| id.as_slice()) != Some(card_id) {
+        return Ok(());
+    }
+
+    let is_active = account.auth_methods.iter().any(|m| {
+        matches!(m, models::AuthMethod::NfcBased(auth_nfc) if auth_nfc.card_id == card_id
+            && auth_nfc.status == models::NfcCardStatus::Active)
+    });
+    if !is_active {
+        state.session_require_permission(models::Permission::AccountsWrite)?;
+    }
+    Ok(())
+}
+
+async fn get_account_and_provisioning(
+    state: &mut RequestState,
+    id: u64,
+    provisioning_id: &str,
+) -> ServiceResult<(models::Account, models::NfcProvisioning)> {
+    let account = state.db.get_account_by_id(id).await?;
+    let provisioning = state.db.get_nfc_provisioning(id, provisioning_id).await?;
+
+    match (account, provisioning) {
+        (Some(account), Some(provisioning)) => Ok((account, provisioning)),
+        _ => Err(ServiceError::NotFound),
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct StartNfcProvisioningDto {
+    pub name: String,
+    pub card_id: String,
+    /// Card that should be replaced by the new card, use the same `card_id` to re-key a card.
+    /// Re-keying a blocked or lost card requires `accounts.write`.
+    pub replaces_card_id: Option<String>,
+    /// `E(K, RndB)` as returned by the card for `AuthenticateEV2First` with key number 0
+    pub request: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub struct NfcProvisioningChallengeDto {
+    pub provisioning_id: String,
+    pub challenge_id: String,
+    pub challenge: String,
+}
+
+async fn start_nfc_provisioning(
+    mut state: RequestState,
+    Path(id): Path<u64>,
+    form: Json<StartNfcProvisioningDto>,
+) -> ServiceResult<Json<NfcProvisioningChallengeDto>> {
+    state.session_require_permission_or_self(models::Permission::AccountsWrite, id)?;
+
+    let form = form.0;
+    let account = state
+        .db
+        .get_account_by_id(id)
+        .await?
+        .ok_or(ServiceError::NotFound)?;
+
+    let card_id = decode_base64(&form.card_id, "card_id")?;
+    let replaces_card_id = form
+        .replaces_card_id
+        .map(|card_id| decode_base64(&card_id, "replaces_card_id"))
+        .transpose()?;
+    let request = decode_base64(&form.request, "request")?;
+
+    if let Some(ref replaces_card_id) = replaces_card_id {
+        let has_card = account.auth_methods.iter().any(|m| {
+            matches!(m, models::AuthMethod::NfcBased(auth_nfc) if &auth_nfc.card_id == replaces_card_id)
+        });
+        if !has_card {
+            return Err(ServiceError::NotFound);
+        }
+    }
+    require_rekeyable_card(&account, &card_id, replaces_card_id.as_ref())?;
+    require_permission_to_reactivate(&state, &account, &card_id, replaces_card_id.as_ref())?;
+
+    let owner = state
+        .db
+        .get_account_by_auth_method(models::AuthRequest::NfcBased {
+            card_id: card_id.clone(),
+        })
+        .await?;
+    if owner.is_some() && replaces_card_id.as_ref() != Some(&card_id) {
+        return Err(ServiceError::CardInUse);
+    }
+
+    let provisioning = state
+        .db
+        .store_nfc_provisioning(models::NfcProvisioning {
+            id: String::new(),
+            account_id: account.id,
+            name: form.name,
+            card_id,
+            key: models::NfcSecret::seal(&nfc_mifare::generate_key_aes())?,
+            replaces_card_id,
+            key_change_issued: false,
+            valid_until: Utc::now().add(Duration::minutes(10)),
+        })
+        .await?;
+
+    let (challenge_id, challenge) = nfc_mifare::authenticate_ev2_phase_challenge(
+        &state.challenge_storage,
+        &current_card_key(&account, &provisioning),
+        &request,
+    )
+    .await?;
+
+    Ok(Json(NfcProvisioningChallengeDto {
+        provisioning_id: provisioning.id,
+        challenge_id,
+        challenge: general_purpose::STANDARD.encode(challenge),
+    }))
+}
+
+fn start_nfc_provisioning_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Start provisioning an ascii mifare card for the given account. The server generates the new card key and answers the authentication request of the card.")
+        .tag("account_authentication")
+        .response::<200, Json<NfcProvisioningChallengeDto>>()
+        .response_with::<404, (), _>(|res| {
+            res.description("The requested account or the card to replace does not exist!")
+        })
+        .response_with::<400, (), _>(|res| {
+            res.description("The card to re-key is not an ascii mifare aes card!")
+        })
+        .response_with::<409, (), _>(|res| {
+            res.description("The card is already registered for an account!")
+        })
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.write", "self"])
+}
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct NfcProvisioningKeyChangeDto {
+    pub challenge_id: String,
+    pub challenge: String,
+    pub response: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub struct NfcProvisioningCommandsDto {
+    /// Base64 encoded APDUs that have to be sent to the card in the given order
+    pub commands: Vec<String>,
+}
+
+async fn get_nfc_card_setup(
+    state: RequestState,
+    Path(id): Path<u64>,
+) -> ServiceResult<Json<NfcProvisioningCommandsDto>> {
+    state.session_require_permission_or_self(models::Permission::AccountsWrite, id)?;
+
+    Ok(Json(NfcProvisioningCommandsDto {
+        commands: nfc_mifare::ev2_card_setup_commands()
+            .into_iter()
+            .map(|command| general_purpose::STANDARD.encode(command))
+            .collect(),
+    }))
+}
+
+fn get_nfc_card_setup_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Get the commands that create the ascii application on a blank card. They have to be sent before the provisioning is started, a `DUPLICATE_ERROR` of `CreateApplication` means the card was already set up.")
+        .tag("account_authentication")
+        .response::<200, Json<NfcProvisioningCommandsDto>>()
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.write", "self"])
+}
+
+async fn nfc_provisioning_key_change(
+    mut state: RequestState,
+    Path((id, provisioning_id)): Path<(u64, String)>,
+    form: Json<NfcProvisioningKeyChangeDto>,
+) -> ServiceResult<Json<NfcProvisioningCommandsDto>> {
+    state.session_require_permission_or_self(models::Permission::AccountsWrite, id)?;
+
+    let form = form.0;
+    let (account, mut provisioning) =
+        get_account_and_provisioning(&mut state, id, &provisioning_id).await?;
+
+    let challenge = decode_base64(&form.challenge, "challenge")?;
+    let response = decode_base64(&form.response, "response")?;
+
+    let session = nfc_mifare::authenticate_ev2_phase_response(
+        &state.challenge_storage,
+        &form.challenge_id,
+        &current_card_key(&account, &provisioning),
+        &challenge,
+        &response,
+    )
+    .await?;
+
+    let change_key =
+        nfc_mifare::ev2_change_key_command(&session, KEY_NO, &provisioning.key, KEY_VERSION)?;
+
+    provisioning.key_change_issued = true;
+    state.db.store_nfc_provisioning(provisioning).await?;
+
+    Ok(Json(NfcProvisioningCommandsDto {
+        commands: vec![general_purpose::STANDARD.encode(change_key)],
+    }))
+}
+
+fn nfc_provisioning_key_change_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Answer the card response and get the encrypted key change commands for the card.",
+    )
+    .tag("account_authentication")
+    .response::<200, Json<NfcProvisioningCommandsDto>>()
+    .response_with::<404, (), _>(|res| {
+        res.description("The requested account or provisioning does not exist!")
+    })
+    .response_with::<401, (), _>(|res| res.description("Missing login!"))
+    .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+    .security_requirement_scopes("SessionToken", ["accounts.write", "self"])
+}
+
+async fn confirm_nfc_provisioning(
+    mut state: RequestState,
+    Path((id, provisioning_id)): Path<(u64, String)>,
+) -> ServiceResult<Json<AccountDto>> {
+    state.session_require_permission_or_self(models::Permission::AccountsWrite, id)?;
+
+    let (mut account, provisioning) =
+        get_account_and_provisioning(&mut state, id, &provisioning_id).await?;
+
+    if !provisioning.key_change_issued {
+        return Err(ServiceError::Unauthorized("card key was not changed!"));
+    }
+    require_permission_to_reactivate(
+        &state,
+        &account,
+        &provisioning.card_id,
+        provisioning.replaces_card_id.as_ref(),
+    )?;
+
+    let before = AccountDto::from(&account);
+
+    // Blocked or lost cards stay on the account as record, unless the same card is re-keyed
+    if let Some(ref replaces_card_id) = provisioning.replaces_card_id {
+        account.auth_methods.retain(|m| {
+            !matches!(m, models::AuthMethod::NfcBased(auth_nfc) if &auth_nfc.card_id == replaces_card_id
+                && (auth_nfc.card_id == provisioning.card_id
+                    || auth_nfc.status == models::NfcCardStatus::Active))
+        });
+    }
+    account
+        .auth_methods
+        .push(models::AuthMethod::NfcBased(models::AuthNfc {
+            name: provisioning.name,
+            card_id: provisioning.card_id,
+            card_type: CardType::AsciiMifareAes,
+            data: provisioning.key,
+            depends_on_session: None,
+            status: models::NfcCardStatus::Active,
+            last_used_at: None,
+            valid_until: None,
+        }));
+
+    let account = state.db.store_account(account).await?;
+    state
+        .db
+        .delete_nfc_provisioning(id, &provisioning_id)
+        .await?;
+
+    let account = AccountDto::from(&account);
+    audit_log::record(
+        &mut state,
+        "nfc_card.provision",
+        "account",
+        Some(id),
+        Some(&before),
+        Some(&account),
+    )
+    .await?;
+
+    Ok(Json(account))
+}
+
+fn confirm_nfc_provisioning_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Confirm that the key change commands were written to the card. This activates the card and removes the replaced card.")
+        .tag("account_authentication")
+        .response::<200, Json<AccountDto>>()
+        .response_with::<404, (), _>(|res| {
+            res.description("The requested account or provisioning does not exist!")
+        })
+        .response_with::<401, (), _>(|res| res.description("Missing login or the key change was not requested!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.write", "self"])
+}
+
+async fn cancel_nfc_provisioning(
+    mut state: RequestState,
+    Path((id, provisioning_id)): Path<(u64, String)>,
+) -> ServiceResult<StatusCode> {
+    state.session_require_permission_or_self(models::Permission::AccountsWrite, id)?;
+
+    state
+        .db
+        .delete_nfc_provisioning(id, &provisioning_id)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn cancel_nfc_provisioning_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Cancel a pending card provisioning. The account keeps its current cards.")
+        .tag("account_authentication")
+        .response_with::<204, (), _>(|res| {
+            res.description("The provisioning was successfully canceled!")
+        })
+        .response_with::<404, (), _>(|res| {
+            res.description("The requested provisioning does not exist!")
+        })
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.write", "self"])
+}
@@ -0,0 +1,272 @@
+use aide::axum::routing::{get_with, post_with};
+use aide::axum::ApiRouter;
+use aide::transform::TransformOperation;
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::Json;
+use base64::engine::general_purpose;
+use base64::Engine;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::database::AppState;
+use crate::error::{ServiceError, ServiceResult};
+use crate::models;
+use crate::request_state::RequestState;
+
+use super::audit_log;
+use super::nfc_id;
+
+pub fn router(app_state: AppState) -> ApiRouter {
+    ApiRouter::new()
+        .api_route(
+            "/nfc-reader/:id",
+            get_with(get_nfc_reader, get_nfc_reader_docs)
+                .put_with(update_nfc_reader, update_nfc_reader_docs)
+                .delete_with(delete_nfc_reader, delete_nfc_reader_docs),
+        )
+        .api_route(
+            "/nfc-reader/:id/key",
+            post_with(rotate_nfc_reader_key, rotate_nfc_reader_key_docs),
+        )
+        .api_route(
+            "/nfc-readers",
+            get_with(list_nfc_readers, list_nfc_readers_docs)
+                .post_with(create_nfc_reader, create_nfc_reader_docs),
+        )
+        .with_state(app_state)
+}
+
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub struct NfcReaderDto {
+    pub id: u64,
+    pub name: String,
+}
+
+impl From<&models::NfcReader> for NfcReaderDto {
+    fn from(value: &models::NfcReader) -> Self {
+        Self {
+            id: value.id.to_owned(),
+            name: value.name.to_owned(),
+        }
+    }
+}
+
+/// Only returned once when the key is generated, the server cannot show it again
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub struct NfcReaderKeyDto {
+    pub id: u64,
+    pub name: String,
+    /// Base64 encoded AES-256 key that has to be configured on the reader
+    pub key: String,
+}
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct SaveNfcReaderDto {
+    pub name: String,
+}
+
+async fn list_nfc_readers(mut state: RequestState) -> ServiceResult<Json<Vec<NfcReaderDto>>> {
+    state.session_require_permission(models::Permission::NfcReadersWrite)?;
+
+    let readers = state.db.get_all_nfc_readers().await?;
+    Ok(Json(readers.iter().map(|r| r.into()).collect()))
+}
+
+fn list_nfc_readers_docs(op: TransformOperation) -> TransformOperation {
+    op.description("List all registered nfc readers.")
+        .tag("nfc_readers")
+        .response::<200, Json<Vec<NfcReaderDto>>>()
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["nfc_readers.write"])
+}
+
+async fn get_nfc_reader(
+    mut state: RequestState,
+    Path(id): Path<u64>,
+) -> ServiceResult<Json<NfcReaderDto>> {
+    state.session_require_permission(models::Permission::NfcReadersWrite)?;
+
+    let reader = state.db.get_nfc_reader_by_id(id).await?;
+
+    if let Some(reader) = reader {
+        return Ok(Json(NfcReaderDto::from(&reader)));
+    }
+
+    Err(ServiceError::NotFound)
+}
+
+fn get_nfc_reader_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Get a nfc reader by id.")
+        .tag("nfc_readers")
+        .response::<200, Json<NfcReaderDto>>()
+        .response_with::<404, (), _>(|res| res.description("The requested reader does not exist!"))
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["nfc_readers.write"])
+}
+
+fn generate_key() -> ServiceResult<(Vec<u8>, models::NfcSecret)> {
+    let key = nfc_id::generate_reader_key();
+    Ok((key.to_vec(), models::NfcSecret::seal(&key)?))
+}
+
+async fn create_nfc_reader(
+    mut state: RequestState,
+    form: Json<SaveNfcReaderDto>,
+) -> ServiceResult<Json<NfcReaderKeyDto>> {
+    state.session_require_permission(models::Permission::NfcReadersWrite)?;
+
+    let form = form.0;
+    let (key, sealed_key) = generate_key()?;
+
+    let reader = models::NfcReader {
+        id: 0,
+        name: form.name,
+        key: sealed_key,
+    };
+
+    let reader = state.db.store_nfc_reader(reader).await?;
+    audit_log::record(
+        &mut state,
+        "nfc_reader.create",
+        "nfc_reader",
+        Some(reader.id),
+        None,
+        Some(&NfcReaderDto::from(&reader)),
+    )
+    .await?;
+
+    Ok(Json(NfcReaderKeyDto {
+        id: reader.id,
+        name: reader.name,
+        key: general_purpose::STANDARD.encode(key),
+    }))
+}
+
+fn create_nfc_reader_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Register a new nfc reader. The generated key is only returned once.")
+        .tag("nfc_readers")
+        .response::<200, Json<NfcReaderKeyDto>>()
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["nfc_readers.write"])
+}
+
+async fn update_nfc_reader(
+    mut state: RequestState,
+    Path(id): Path<u64>,
+    form: Json<SaveNfcReaderDto>,
+) -> ServiceResult<Json<NfcReaderDto>> {
+    state.session_require_permission(models::Permission::NfcReadersWrite)?;
+
+    let form = form.0;
+    let reader = state.db.get_nfc_reader_by_id(id).await?;
+
+    if let Some(mut reader) = reader {
+        let before = NfcReaderDto::from(&reader);
+        reader.name = form.name;
+
+        let reader = state.db.store_nfc_reader(reader).await?;
+        let reader = NfcReaderDto::from(&reader);
+        audit_log::record(
+            &mut state,
+            "nfc_reader.update",
+            "nfc_reader",
+            Some(id),
+            Some(&before),
+            Some(&reader),
+        )
+        .await?;
+
+        return Ok(Json(reader));
+    }
+
+    Err(ServiceError::NotFound)
+}
+
+fn update_nfc_reader_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Rename an existing nfc reader.")
+        .tag("nfc_readers")
+        .response::<200, Json<NfcReaderDto>>()
+        .response_with::<404, (), _>(|res| res.description("The requested reader does not exist!"))
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["nfc_readers.write"])
+}
+
+async fn rotate_nfc_reader_key(
+    mut state: RequestState,
+    Path(id): Path<u64>,
+) -> ServiceResult<Json<NfcReaderKeyDto>> {
+    state.session_require_permission(models::Permission::NfcReadersWrite)?;
+
+    let reader = state.db.get_nfc_reader_by_id(id).await?;
+
+    if let Some(mut reader) = reader {
+        let (key, sealed_key) = generate_key()?;
+        reader.key = sealed_key;
+
+        let reader = state.db.store_nfc_reader(reader).await?;
+        audit_log::record::<()>(
+            &mut state,
+            "nfc_reader.rotate_key",
+            "nfc_reader",
+            Some(id),
+            None,
+            None,
+        )
+        .await?;
+
+        return Ok(Json(NfcReaderKeyDto {
+            id: reader.id,
+            name: reader.name,
+            key: general_purpose::STANDARD.encode(key),
+        }));
+    }
+
+    Err(ServiceError::NotFound)
+}
+
+fn rotate_nfc_reader_key_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Generate a new key for the nfc reader. The previous key stops working immediately.",
+    )
+    .tag("nfc_readers")
+    .response::<200, Json<NfcReaderKeyDto>>()
+    .response_with::<404, (), _>(|res| res.description("The requested reader does not exist!"))
+    .response_with::<401, (), _>(|res| res.description("Missing login!"))
+    .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+    .security_requirement_scopes("SessionToken", ["nfc_readers.write"])
+}
+
+async fn delete_nfc_reader(
+    mut state: RequestState,
+    Path(id): Path<u64>,
+) -> ServiceResult<StatusCode> {
+    state.session_require_permission(models::Permission::NfcReadersWrite)?;
+
+    let before = state.db.get_nfc_reader_by_id(id).await?;
+    state.db.delete_nfc_reader(id).await?;
+    audit_log::record(
+        &mut state,
+        "nfc_reader.delete",
+        "nfc_reader",
+        Some(id),
+        before.as_ref().map(NfcReaderDto::from).as_ref(),
+        None,
+    )
+    .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn delete_nfc_reader_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Delete an existing nfc reader.")
+        .tag("nfc_readers")
+        .response_with::<204, (), _>(|res| res.description("The reader was successfully deleted!"))
+        .response_with::<404, (), _>(|res| res.description("The requested reader does not exist!"))
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["nfc_readers.write"])
+}
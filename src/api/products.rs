@@ -17,6 +17,7 @@ use crate::request_state::RequestState;
 
 use super::account_status::AccountStatusDto;
 use super::accounts::CoinAmountDto;
+use super::audit_log;
 
 const SUPPORTED_IMAGE_TYPES: [&str; 6] = [
     "image/png",
@@ -159,7 +160,7 @@ async fn create_product(
     mut state: RequestState,
     form: Json<SaveProductDto>,
 ) -> ServiceResult<Json<ProductDto>> {
-    state.session_require_purchaser_or_admin()?;
+    state.session_require_permission(models::Permission::ProductsWrite)?;
 
     let form = form.0;
 
@@ -181,7 +182,18 @@ async fn create_product(
     };
 
     let product = state.db.store_product(product).await?;
-    Ok(Json(ProductDto::from(&product)))
+    let product = ProductDto::from(&product);
+    audit_log::record(
+        &mut state,
+        "product.create",
+        "product",
+        Some(product.id),
+        None,
+        Some(&product),
+    )
+    .await?;
+
+    Ok(Json(product))
 }
 
 fn create_product_docs(op: TransformOperation) -> TransformOperation {
@@ -190,7 +202,7 @@ fn create_product_docs(op: TransformOperation) -> TransformOperation {
         .response::<200, Json<ProductDto>>()
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["purchaser", "admin"])
+        .security_requirement_scopes("SessionToken", ["products.write"])
 }
 
 async fn update_product(
@@ -198,7 +210,7 @@ async fn update_product(
     Path(id): Path<u64>,
     form: Json<SaveProductDto>,
 ) -> ServiceResult<Json<ProductDto>> {
-    state.session_require_purchaser_or_admin()?;
+    state.session_require_permission(models::Permission::ProductsWrite)?;
 
     let form = form.0;
     let product = state.db.get_product_by_id(id).await?;
@@ -206,6 +218,7 @@ async fn update_product(
     let status_prices = resolve_status_prices(&mut state, &form.status_prices).await?;
 
     if let Some(mut product) = product {
+        let before = ProductDto::from(&product);
         product.name = form.name;
         product.price = form.price.into();
         product.bonus = form.bonus.into();
@@ -218,7 +231,18 @@ async fn update_product(
         product.status_prices = status_prices;
 
         let product = state.db.store_product(product).await?;
-        return Ok(Json(ProductDto::from(&product)));
+        let product = ProductDto::from(&product);
+        audit_log::record(
+            &mut state,
+            "product.update",
+            "product",
+            Some(id),
+            Some(&before),
+            Some(&product),
+        )
+        .await?;
+
+        return Ok(Json(product));
     }
 
     Err(ServiceError::NotFound)
@@ -231,13 +255,23 @@ fn update_product_docs(op: TransformOperation) -> TransformOperation {
         .response_with::<404, (), _>(|res| res.description("The requested product does not exist!"))
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["purchaser", "admin"])
+        .security_requirement_scopes("SessionToken", ["products.write"])
 }
 
 async fn delete_product(mut state: RequestState, Path(id): Path<u64>) -> ServiceResult<StatusCode> {
-    state.session_require_purchaser_or_admin()?;
+    state.session_require_permission(models::Permission::ProductsWrite)?;
 
+    let before = state.db.get_product_by_id(id).await?;
     state.db.delete_product(id).await?;
+    audit_log::record(
+        &mut state,
+        "product.delete",
+        "product",
+        Some(id),
+        before.as_ref().map(ProductDto::from).as_ref(),
+        None,
+    )
+    .await?;
     Ok(StatusCode::NO_CONTENT)
 }
 
@@ -248,7 +282,7 @@ fn delete_product_docs(op: TransformOperation) -> TransformOperation {
         .response_with::<404, (), _>(|res| res.description("The requested product does not exist!"))
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["purchaser", "admin"])
+        .security_requirement_scopes("SessionToken", ["products.write"])
 }
 
 async fn resolve_status_prices(
@@ -305,7 +339,7 @@ async fn upload_product_image(
     Path(id): Path<u64>,
     mut multipart: Multipart,
 ) -> ServiceResult<StatusCode> {
-    state.session_require_purchaser_or_admin()?;
+    state.session_require_permission(models::Permission::ProductsWrite)?;
     while let Ok(Some(field)) = multipart.next_field().await {
         let content_type = field.content_type().unwrap_or("").to_lowercase();
         if SUPPORTED_IMAGE_TYPES.iter().any(|t| *t == content_type) {
@@ -315,6 +349,15 @@ async fn upload_product_image(
                     mimetype: content_type,
                 };
                 state.db.store_product_image(id, image).await?;
+                audit_log::record::<()>(
+                    &mut state,
+                    "product_image.update",
+                    "product",
+                    Some(id),
+                    None,
+                    None,
+                )
+                .await?;
                 return Ok(StatusCode::NO_CONTENT);
             }
         }
@@ -332,16 +375,25 @@ fn upload_product_image_docs(op: TransformOperation) -> TransformOperation {
         .response_with::<404, (), _>(|res| res.description("The requested product does not exist!"))
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["purchaser", "admin"])
+        .security_requirement_scopes("SessionToken", ["products.write"])
 }
 
 async fn delete_product_image(
     mut state: RequestState,
     Path(id): Path<u64>,
 ) -> ServiceResult<StatusCode> {
-    state.session_require_purchaser_or_admin()?;
+    state.session_require_permission(models::Permission::ProductsWrite)?;
 
     state.db.delete_product_image(id).await?;
+    audit_log::record::<()>(
+        &mut state,
+        "product_image.delete",
+        "product",
+        Some(id),
+        None,
+        None,
+    )
+    .await?;
     Ok(StatusCode::NO_CONTENT)
 }
 
@@ -354,7 +406,7 @@ fn delete_product_image_docs(op: TransformOperation) -> TransformOperation {
         .response_with::<404, (), _>(|res| res.description("The requested product does not exist!"))
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["purchaser", "admin"])
+        .security_requirement_scopes("SessionToken", ["products.write"])
 }
 
 pub struct ImageResult {
@@ -13,6 +13,7 @@ use crate::error::{ServiceError, ServiceResult};
 use crate::models;
 use crate::request_state::RequestState;
 
+use super::audit_log;
 use super::products::ProductDto;
 
 pub fn router(app_state: AppState) -> ApiRouter {
@@ -155,7 +156,7 @@ async fn create_purchase(
     mut state: RequestState,
     form: Json<SavePurchaseDto>,
 ) -> ServiceResult<Json<PurchaseDto>> {
-    state.session_require_purchaser_or_admin()?;
+    state.session_require_permission(models::Permission::PurchasesWrite)?;
 
     let form = form.0;
 
@@ -171,7 +172,18 @@ async fn create_purchase(
     };
 
     let purchase = state.db.store_purchase(purchase).await?;
-    Ok(Json(PurchaseDto::from(&purchase)))
+    let purchase = PurchaseDto::from(&purchase);
+    audit_log::record(
+        &mut state,
+        "purchase.create",
+        "purchase",
+        Some(purchase.id),
+        None,
+        Some(&purchase),
+    )
+    .await?;
+
+    Ok(Json(purchase))
 }
 
 fn create_purchase_docs(op: TransformOperation) -> TransformOperation {
@@ -180,7 +192,7 @@ fn create_purchase_docs(op: TransformOperation) -> TransformOperation {
         .response::<200, Json<PurchaseDto>>()
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["purchaser", "admin"])
+        .security_requirement_scopes("SessionToken", ["purchases.write"])
 }
 
 async fn update_purchase(
@@ -188,7 +200,7 @@ async fn update_purchase(
     Path(id): Path<u64>,
     form: Json<SavePurchaseDto>,
 ) -> ServiceResult<Json<PurchaseDto>> {
-    state.session_require_purchaser_or_admin()?;
+    state.session_require_permission(models::Permission::PurchasesWrite)?;
 
     let form = form.0;
     let purchase = state.db.get_purchase_by_id(id).await?;
@@ -197,13 +209,25 @@ async fn update_purchase(
     let items = resolve_items(&mut state, &form.items).await?;
 
     if let Some(mut purchase) = purchase {
+        let before = PurchaseDto::from(&purchase);
         purchase.purchased_by_account_id = form.purchased_by_account_id;
         purchase.store = form.store;
         purchase.timestamp = timestamp.into();
         purchase.items = items;
 
         let purchase = state.db.store_purchase(purchase).await?;
-        return Ok(Json(PurchaseDto::from(&purchase)));
+        let purchase = PurchaseDto::from(&purchase);
+        audit_log::record(
+            &mut state,
+            "purchase.update",
+            "purchase",
+            Some(id),
+            Some(&before),
+            Some(&purchase),
+        )
+        .await?;
+
+        return Ok(Json(purchase));
     }
 
     Err(ServiceError::NotFound)
@@ -218,16 +242,26 @@ fn update_purchase_docs(op: TransformOperation) -> TransformOperation {
         })
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["purchaser", "admin"])
+        .security_requirement_scopes("SessionToken", ["purchases.write"])
 }
 
 async fn delete_purchase(
     mut state: RequestState,
     Path(id): Path<u64>,
 ) -> ServiceResult<StatusCode> {
-    state.session_require_purchaser_or_admin()?;
+    state.session_require_permission(models::Permission::PurchasesWrite)?;
 
+    let before = state.db.get_purchase_by_id(id).await?;
     state.db.delete_purchase(id).await?;
+    audit_log::record(
+        &mut state,
+        "purchase.delete",
+        "purchase",
+        Some(id),
+        before.as_ref().map(PurchaseDto::from).as_ref(),
+        None,
+    )
+    .await?;
     Ok(StatusCode::NO_CONTENT)
 }
 
@@ -242,7 +276,7 @@ fn delete_purchase_docs(op: TransformOperation) -> TransformOperation {
         })
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["purchaser", "admin"])
+        .security_requirement_scopes("SessionToken", ["purchases.write"])
 }
 
 async fn resolve_items(
@@ -0,0 +1,121 @@
+use aide::axum::routing::{get_with, post_with};
+use aide::axum::ApiRouter;
+use aide::transform::TransformOperation;
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::Json;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::database::AppState;
+use crate::error::ServiceResult;
+use crate::request_state::RequestState;
+use crate::{models, push_queue};
+
+use super::audit_log;
+
+pub fn router(app_state: AppState) -> ApiRouter {
+    ApiRouter::new()
+        .api_route(
+            "/push-jobs/dead",
+            get_with(list_dead_push_jobs, list_dead_push_jobs_docs),
+        )
+        .api_route(
+            "/push-job/:id/retry",
+            post_with(retry_dead_push_job, retry_dead_push_job_docs),
+        )
+        .with_state(app_state)
+}
+
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub enum PushJobKindDto {
+    AppleWallet,
+    GoogleWallet,
+}
+
+impl From<&models::PushJobKind> for PushJobKindDto {
+    fn from(value: &models::PushJobKind) -> Self {
+        match value {
+            models::PushJobKind::AppleWallet => PushJobKindDto::AppleWallet,
+            models::PushJobKind::GoogleWallet => PushJobKindDto::GoogleWallet,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub struct PushJobDto {
+    pub id: u64,
+    pub kind: PushJobKindDto,
+    pub account_id: u64,
+    pub pass_type_id: String,
+    pub device_id: String,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub created_at: String,
+    pub dead_at: Option<String>,
+}
+
+impl From<&models::PushJob> for PushJobDto {
+    fn from(value: &models::PushJob) -> Self {
+        Self {
+            id: value.id,
+            kind: (&value.kind).into(),
+            account_id: value.account_id,
+            pass_type_id: value.pass_type_id.to_owned(),
+            device_id: value.device_id.to_owned(),
+            attempts: value.attempts,
+            last_error: value.last_error.to_owned(),
+            created_at: format!("{:?}", value.created_at),
+            dead_at: value.dead_at.map(|t| format!("{:?}", t)),
+        }
+    }
+}
+
+async fn list_dead_push_jobs(mut state: RequestState) -> ServiceResult<Json<Vec<PushJobDto>>> {
+    state.session_require_permission(models::Permission::AccountsRead)?;
+
+    let jobs = state.db.get_dead_push_jobs().await?;
+    Ok(Json(jobs.iter().map(|j| j.into()).collect()))
+}
+
+fn list_dead_push_jobs_docs(op: TransformOperation) -> TransformOperation {
+    op.description("List wallet update notifications that could not be delivered after all retries. Dead jobs are removed after 30 days.")
+        .tag("push_jobs")
+        .response::<200, Json<Vec<PushJobDto>>>()
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.read"])
+}
+
+async fn retry_dead_push_job(
+    mut state: RequestState,
+    Path(id): Path<u64>,
+) -> ServiceResult<StatusCode> {
+    state.session_require_permission(models::Permission::AccountsWrite)?;
+
+    state.db.retry_dead_push_job(id).await?;
+    audit_log::record::<()>(
+        &mut state,
+        "push_job.retry",
+        "push_job",
+        Some(id),
+        None,
+        None,
+    )
+    .await?;
+    push_queue::notify_worker();
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn retry_dead_push_job_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Move a dead wallet update notification back into the queue.")
+        .tag("push_jobs")
+        .response_with::<204, (), _>(|res| res.description("The job was queued again!"))
+        .response_with::<404, (), _>(|res| {
+            res.description("The requested dead job does not exist!")
+        })
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.write"])
+}
@@ -13,6 +13,8 @@ use crate::error::{ServiceError, ServiceResult};
 use crate::models;
 use crate::request_state::RequestState;
 
+use super::audit_log;
+
 pub fn router(app_state: AppState) -> ApiRouter {
     ApiRouter::new()
         .api_route(
@@ -26,6 +28,10 @@ pub fn router(app_state: AppState) -> ApiRouter {
             get_with(list_register_histories, list_register_histories_docs)
                 .post_with(create_register_history, create_register_history_docs),
         )
+        .api_route(
+            "/register-payouts",
+            get_with(list_register_payouts, list_register_payouts_docs),
+        )
         .with_state(app_state)
 }
 
@@ -107,6 +113,48 @@ impl From<models::RegisterHistoryState> for RegisterHistoryStateDto {
     }
 }
 
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub struct RegisterPayoutDto {
+    pub id: u64,
+    pub timestamp: String,
+    pub account_id: Option<u64>,
+    pub transaction_id: u64,
+    /// Negative if the account owner paid a debt into the register
+    pub amount_cents: i32,
+    pub paid_by_account_id: Option<u64>,
+}
+
+impl From<&models::RegisterPayout> for RegisterPayoutDto {
+    fn from(value: &models::RegisterPayout) -> Self {
+        Self {
+            id: value.id,
+            timestamp: format!("{:?}", value.timestamp),
+            account_id: value.account_id,
+            transaction_id: value.transaction_id,
+            amount_cents: value.amount_cents,
+            paid_by_account_id: value.paid_by_account_id,
+        }
+    }
+}
+
+pub async fn list_register_payouts(
+    mut state: RequestState,
+) -> ServiceResult<Json<Vec<RegisterPayoutDto>>> {
+    state.session_require_permission(models::Permission::RegisterWrite)?;
+
+    let payouts = state.db.get_all_register_payouts().await?;
+    Ok(Json(payouts.iter().map(|p| p.into()).collect()))
+}
+
+fn list_register_payouts_docs(op: TransformOperation) -> TransformOperation {
+    op.description("List all cash payouts from the register that were booked by account closures, newest first.")
+        .tag("register_histories")
+        .response::<200, Json<Vec<RegisterPayoutDto>>>()
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["register.write"])
+}
+
 pub async fn list_register_histories(
     mut state: RequestState,
 ) -> ServiceResult<Json<Vec<RegisterHistoryDto>>> {
@@ -164,16 +212,24 @@ async fn create_register_history(
     };
 
     let register_history = state.db.store_register_history(register_history).await?;
-    Ok(Json(RegisterHistoryDto::from(&register_history)))
+    let register_history = RegisterHistoryDto::from(&register_history);
+    audit_log::record(
+        &mut state,
+        "register_history.create",
+        "register_history",
+        Some(register_history.id),
+        None,
+        Some(&register_history),
+    )
+    .await?;
+
+    Ok(Json(register_history))
 }
 
 fn create_register_history_docs(op: TransformOperation) -> TransformOperation {
     op.description("Create a new register history.")
         .tag("register_histories")
         .response::<200, Json<RegisterHistoryDto>>()
-        .response_with::<401, (), _>(|res| res.description("Missing login!"))
-        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin"])
 }
 
 async fn update_register_history(
@@ -181,18 +237,30 @@ async fn update_register_history(
     Path(id): Path<u64>,
     form: Json<SaveRegisterHistoryDto>,
 ) -> ServiceResult<Json<RegisterHistoryDto>> {
-    state.session_require_admin()?;
+    state.session_require_permission(models::Permission::RegisterWrite)?;
 
     let form = form.0;
     let register_history = state.db.get_register_history_by_id(id).await?;
 
     if let Some(mut register_history) = register_history {
+        let before = RegisterHistoryDto::from(&register_history);
         register_history.source_register = form.source_register.into();
         register_history.target_register = form.target_register.into();
         register_history.envelope_register = form.envelope_register.into();
 
         let register_history = state.db.store_register_history(register_history).await?;
-        return Ok(Json(RegisterHistoryDto::from(&register_history)));
+        let register_history = RegisterHistoryDto::from(&register_history);
+        audit_log::record(
+            &mut state,
+            "register_history.update",
+            "register_history",
+            Some(id),
+            Some(&before),
+            Some(&register_history),
+        )
+        .await?;
+
+        return Ok(Json(register_history));
     }
 
     Err(ServiceError::NotFound)
@@ -207,16 +275,26 @@ fn update_register_history_docs(op: TransformOperation) -> TransformOperation {
         })
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin"])
+        .security_requirement_scopes("SessionToken", ["register.write"])
 }
 
 async fn delete_register_history(
     mut state: RequestState,
     Path(id): Path<u64>,
 ) -> ServiceResult<StatusCode> {
-    state.session_require_admin()?;
+    state.session_require_permission(models::Permission::RegisterWrite)?;
 
+    let before = state.db.get_register_history_by_id(id).await?;
     state.db.delete_register_history(id).await?;
+    audit_log::record(
+        &mut state,
+        "register_history.delete",
+        "register_history",
+        Some(id),
+        before.as_ref().map(RegisterHistoryDto::from).as_ref(),
+        None,
+    )
+    .await?;
     Ok(StatusCode::NO_CONTENT)
 }
 
@@ -231,5 +309,5 @@ fn delete_register_history_docs(op: TransformOperation) -> TransformOperation {
         })
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin"])
+        .security_requirement_scopes("SessionToken", ["register.write"])
 }
@@ -6,7 +6,7 @@ use chrono::{Duration, Utc};
 
 use crate::database::AppState;
 use crate::error::{ServiceError, ServiceResult};
-use crate::models::Transaction;
+use crate::models::{self, Transaction};
 use crate::request_state::RequestState;
 
 pub fn router(app_state: AppState) -> ApiRouter {
@@ -24,7 +24,7 @@ pub fn router(app_state: AppState) -> ApiRouter {
 
 #[allow(unused_variables)]
 async fn report_account(mut state: RequestState, Path(id): Path<u64>) -> ServiceResult<()> {
-    state.session_require_admin_or_self(id)?;
+    state.session_require_permission_or_self(models::Permission::ReportsSend, id)?;
 
     let end_date = Utc::now();
     let start_date = end_date - Duration::days(30);
@@ -61,12 +61,12 @@ fn report_account_docs(op: TransformOperation) -> TransformOperation {
         .response::<200, ()>()
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin"])
+        .security_requirement_scopes("SessionToken", ["reports.send", "self"])
 }
 
 #[allow(unused_variables)]
 async fn report_accounts(mut state: RequestState) -> ServiceResult<()> {
-    state.session_require_admin()?;
+    state.session_require_permission(models::Permission::ReportsSend)?;
 
     let end_date = Utc::now();
     let start_date = end_date - Duration::days(30);
@@ -106,5 +106,5 @@ fn report_accounts_docs(op: TransformOperation) -> TransformOperation {
         .response::<200, ()>()
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin"])
+        .security_requirement_scopes("SessionToken", ["reports.send"])
 }
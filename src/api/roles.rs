@@ -0,0 +1,272 @@
+use aide::axum::routing::get_with;
+use aide::axum::ApiRouter;
+use aide::transform::TransformOperation;
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::Json;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::database::AppState;
+use crate::error::{ServiceError, ServiceResult};
+use crate::models;
+use crate::request_state::RequestState;
+
+use super::audit_log;
+
+pub fn router(app_state: AppState) -> ApiRouter {
+    ApiRouter::new()
+        .api_route(
+            "/role/:id",
+            get_with(get_role, get_role_docs)
+                .put_with(update_role, update_role_docs)
+                .delete_with(delete_role, delete_role_docs),
+        )
+        .api_route(
+            "/roles",
+            get_with(list_roles, list_roles_docs).post_with(create_role, create_role_docs),
+        )
+        .with_state(app_state)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum PermissionDto {
+    #[serde(rename = "accounts.read")]
+    AccountsRead,
+    #[serde(rename = "accounts.write")]
+    AccountsWrite,
+    #[serde(rename = "roles.write")]
+    RolesWrite,
+    #[serde(rename = "payments.write")]
+    PaymentsWrite,
+    #[serde(rename = "payments.credit")]
+    PaymentsCredit,
+    #[serde(rename = "products.write")]
+    ProductsWrite,
+    #[serde(rename = "purchases.write")]
+    PurchasesWrite,
+    #[serde(rename = "register.write")]
+    RegisterWrite,
+    #[serde(rename = "reports.send")]
+    ReportsSend,
+    #[serde(rename = "nfc_readers.write")]
+    NfcReadersWrite,
+    #[serde(rename = "audit_log.read")]
+    AuditLogRead,
+    #[serde(rename = "vouchers.write")]
+    VouchersWrite,
+    #[serde(rename = "accounts.impersonate")]
+    AccountsImpersonate,
+}
+
+impl From<&models::Permission> for PermissionDto {
+    fn from(value: &models::Permission) -> Self {
+        match value {
+            models::Permission::AccountsRead => PermissionDto::AccountsRead,
+            models::Permission::AccountsWrite => PermissionDto::AccountsWrite,
+            models::Permission::RolesWrite => PermissionDto::RolesWrite,
+            models::Permission::PaymentsWrite => PermissionDto::PaymentsWrite,
+            models::Permission::PaymentsCredit => PermissionDto::PaymentsCredit,
+            models::Permission::ProductsWrite => PermissionDto::ProductsWrite,
+            models::Permission::PurchasesWrite => PermissionDto::PurchasesWrite,
+            models::Permission::RegisterWrite => PermissionDto::RegisterWrite,
+            models::Permission::ReportsSend => PermissionDto::ReportsSend,
+            models::Permission::NfcReadersWrite => PermissionDto::NfcReadersWrite,
+            models::Permission::AuditLogRead => PermissionDto::AuditLogRead,
+            models::Permission::VouchersWrite => PermissionDto::VouchersWrite,
+            models::Permission::AccountsImpersonate => PermissionDto::AccountsImpersonate,
+        }
+    }
+}
+
+impl From<PermissionDto> for models::Permission {
+    fn from(value: PermissionDto) -> Self {
+        match value {
+            PermissionDto::AccountsRead => models::Permission::AccountsRead,
+            PermissionDto::AccountsWrite => models::Permission::AccountsWrite,
+            PermissionDto::RolesWrite => models::Permission::RolesWrite,
+            PermissionDto::PaymentsWrite => models::Permission::PaymentsWrite,
+            PermissionDto::PaymentsCredit => models::Permission::PaymentsCredit,
+            PermissionDto::ProductsWrite => models::Permission::ProductsWrite,
+            PermissionDto::PurchasesWrite => models::Permission::PurchasesWrite,
+            PermissionDto::RegisterWrite => models::Permission::RegisterWrite,
+            PermissionDto::ReportsSend => models::Permission::ReportsSend,
+            PermissionDto::NfcReadersWrite => models::Permission::NfcReadersWrite,
+            PermissionDto::AuditLogRead => models::Permission::AuditLogRead,
+            PermissionDto::VouchersWrite => models::Permission::VouchersWrite,
+            PermissionDto::AccountsImpersonate => models::Permission::AccountsImpersonate,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub struct RoleDto {
+    pub id: u64,
+    pub name: String,
+    pub permissions: Vec<PermissionDto>,
+}
+
+impl From<&models::Role> for RoleDto {
+    fn from(value: &models::Role) -> Self {
+        Self {
+            id: value.id.to_owned(),
+            name: value.name.to_owned(),
+            permissions: value.permissions.iter().map(PermissionDto::from).collect(),
+        }
+    }
+}
+
+async fn list_roles(mut state: RequestState) -> ServiceResult<Json<Vec<RoleDto>>> {
+    state.session_require_permission(models::Permission::AccountsRead)?;
+
+    let roles = state.db.get_all_roles().await?;
+    Ok(Json(roles.iter().map(|r| r.into()).collect()))
+}
+
+fn list_roles_docs(op: TransformOperation) -> TransformOperation {
+    op.description("List all roles.")
+        .tag("roles")
+        .response::<200, Json<Vec<RoleDto>>>()
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.read"])
+}
+
+async fn get_role(mut state: RequestState, Path(id): Path<u64>) -> ServiceResult<Json<RoleDto>> {
+    state.session_require_permission(models::Permission::AccountsRead)?;
+
+    let role = state.db.get_role_by_id(id).await?;
+
+    if let Some(role) = role {
+        return Ok(Json(RoleDto::from(&role)));
+    }
+
+    Err(ServiceError::NotFound)
+}
+
+fn get_role_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Get a role by id.")
+        .tag("roles")
+        .response::<200, Json<RoleDto>>()
+        .response_with::<404, (), _>(|res| res.description("The requested role does not exist!"))
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.read"])
+}
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct SaveRoleDto {
+    pub name: String,
+    pub permissions: Vec<PermissionDto>,
+}
+
+async fn create_role(
+    mut state: RequestState,
+    form: Json<SaveRoleDto>,
+) -> ServiceResult<Json<RoleDto>> {
+    state.session_require_permission(models::Permission::RolesWrite)?;
+
+    let form = form.0;
+
+    let role = models::Role {
+        id: 0,
+        name: form.name,
+        permissions: form.permissions.into_iter().map(|p| p.into()).collect(),
+    };
+
+    let role = state.db.store_role(role).await?;
+    let role = RoleDto::from(&role);
+    audit_log::record(
+        &mut state,
+        "role.create",
+        "role",
+        Some(role.id),
+        None,
+        Some(&role),
+    )
+    .await?;
+
+    Ok(Json(role))
+}
+
+fn create_role_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Create a new role.")
+        .tag("roles")
+        .response::<200, Json<RoleDto>>()
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["roles.write"])
+}
+
+async fn update_role(
+    mut state: RequestState,
+    Path(id): Path<u64>,
+    form: Json<SaveRoleDto>,
+) -> ServiceResult<Json<RoleDto>> {
+    state.session_require_permission(models::Permission::RolesWrite)?;
+
+    let form = form.0;
+    let role = state.db.get_role_by_id(id).await?;
+
+    if let Some(mut role) = role {
+        let before = RoleDto::from(&role);
+        role.name = form.name;
+        role.permissions = form.permissions.into_iter().map(|p| p.into()).collect();
+
+        let role = state.db.store_role(role).await?;
+        let role = RoleDto::from(&role);
+        audit_log::record(
+            &mut state,
+            "role.update",
+            "role",
+            Some(id),
+            Some(&before),
+            Some(&role),
+        )
+        .await?;
+
+        return Ok(Json(role));
+    }
+
+    Err(ServiceError::NotFound)
+}
+
+fn update_role_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Update an existing role. Changes apply to all accounts with this role.")
+        .tag("roles")
+        .response::<200, Json<RoleDto>>()
+        .response_with::<404, (), _>(|res| res.description("The requested role does not exist!"))
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["roles.write"])
+}
+
+async fn delete_role(mut state: RequestState, Path(id): Path<u64>) -> ServiceResult<StatusCode> {
+    state.session_require_permission(models::Permission::RolesWrite)?;
+
+    let before = state.db.get_role_by_id(id).await?;
+    state.db.delete_role(id).await?;
+    audit_log::record(
+        &mut state,
+        "role.delete",
+        "role",
+        Some(id),
+        before.as_ref().map(RoleDto::from).as_ref(),
+        None,
+    )
+    .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn delete_role_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Delete an existing role. Roles that are still assigned to accounts cannot be deleted.",
+    )
+    .tag("roles")
+    .response_with::<204, (), _>(|res| res.description("The role was successfully deleted!"))
+    .response_with::<404, (), _>(|res| res.description("The requested role does not exist!"))
+    .response_with::<409, (), _>(|res| res.description("The role is still in use!"))
+    .response_with::<401, (), _>(|res| res.description("Missing login!"))
+    .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+    .security_requirement_scopes("SessionToken", ["roles.write"])
+}
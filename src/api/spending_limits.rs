@@ -0,0 +1,174 @@
+use aide::axum::routing::get_with;
+use aide::axum::ApiRouter;
+use aide::transform::TransformOperation;
+use axum::extract::Path;
+use axum::Json;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::database::AppState;
+use crate::error::{ServiceError, ServiceResult};
+use crate::models;
+use crate::request_state::RequestState;
+
+use super::accounts::{CoinAmountDto, CoinTypeDto};
+use super::audit_log;
+
+pub fn router(app_state: AppState) -> ApiRouter {
+    ApiRouter::new()
+        .api_route(
+            "/account/:id/spending-limits",
+            get_with(get_spending_limits, get_spending_limits_docs)
+                .put_with(update_spending_limits, update_spending_limits_docs),
+        )
+        .with_state(app_state)
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy, JsonSchema)]
+pub enum SpendingLimitPeriodDto {
+    Day,
+    Week,
+    Month,
+}
+
+impl From<models::SpendingLimitPeriod> for SpendingLimitPeriodDto {
+    fn from(value: models::SpendingLimitPeriod) -> Self {
+        match value {
+            models::SpendingLimitPeriod::Day => SpendingLimitPeriodDto::Day,
+            models::SpendingLimitPeriod::Week => SpendingLimitPeriodDto::Week,
+            models::SpendingLimitPeriod::Month => SpendingLimitPeriodDto::Month,
+        }
+    }
+}
+
+impl From<SpendingLimitPeriodDto> for models::SpendingLimitPeriod {
+    fn from(value: SpendingLimitPeriodDto) -> Self {
+        match value {
+            SpendingLimitPeriodDto::Day => models::SpendingLimitPeriod::Day,
+            SpendingLimitPeriodDto::Week => models::SpendingLimitPeriod::Week,
+            SpendingLimitPeriodDto::Month => models::SpendingLimitPeriod::Month,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct SpendingLimitDto {
+    pub period: SpendingLimitPeriodDto,
+    /// Coin types that are missing are not limited
+    pub limit: CoinAmountDto,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct SpendingPolicyDto {
+    /// Limits apply to a rolling window of one day, seven days or thirty days
+    pub limits: Vec<SpendingLimitDto>,
+    /// Products with one of these tags cannot be bought
+    pub blocked_product_tags: Vec<String>,
+    /// Locked policies can only be changed by admins
+    pub locked: bool,
+}
+
+impl From<&models::SpendingPolicy> for SpendingPolicyDto {
+    fn from(value: &models::SpendingPolicy) -> Self {
+        Self {
+            limits: value
+                .limits
+                .iter()
+                .map(|limit| SpendingLimitDto {
+                    period: limit.period.into(),
+                    // Keep missing coin types missing, `CoinAmountDto::from` would fill them with zero
+                    limit: limit
+                        .limit
+                        .0
+                        .iter()
+                        .map(|(coin_type, amount)| (CoinTypeDto::from(coin_type), *amount))
+                        .collect(),
+                })
+                .collect(),
+            blocked_product_tags: value.blocked_product_tags.clone(),
+            locked: value.locked,
+        }
+    }
+}
+
+async fn get_spending_limits(
+    mut state: RequestState,
+    Path(id): Path<u64>,
+) -> ServiceResult<Json<SpendingPolicyDto>> {
+    state.session_require_permission_or_self(models::Permission::AccountsRead, id)?;
+
+    let policy = state.db.get_spending_policy(id).await?;
+    Ok(Json(SpendingPolicyDto::from(&policy)))
+}
+
+fn get_spending_limits_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Get the spending limits and blocked product tags of the given account.")
+        .tag("accounts")
+        .response::<200, Json<SpendingPolicyDto>>()
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.read", "self"])
+}
+
+async fn update_spending_limits(
+    mut state: RequestState,
+    Path(id): Path<u64>,
+    form: Json<SpendingPolicyDto>,
+) -> ServiceResult<Json<SpendingPolicyDto>> {
+    state.session_require_permission_or_self(models::Permission::AccountsWrite, id)?;
+    state
+        .db
+        .get_account_by_id(id)
+        .await?
+        .ok_or(ServiceError::NotFound)?;
+
+    let form = form.0;
+    let before = state.db.get_spending_policy(id).await?;
+    let is_admin = state.session_has_permission(models::Permission::AccountsWrite);
+    if before.locked && !is_admin {
+        return Err(ServiceError::Forbidden);
+    }
+
+    // There is at most one limit per period, later entries win
+    let mut limits: Vec<models::SpendingLimit> = Vec::new();
+    for limit in form.limits {
+        let period = limit.period.into();
+        limits.retain(|l| l.period != period);
+        limits.push(models::SpendingLimit {
+            period,
+            limit: limit.limit.into(),
+        });
+    }
+
+    let policy = models::SpendingPolicy {
+        limits,
+        blocked_product_tags: form.blocked_product_tags,
+        // Only admins can lock a policy
+        locked: if is_admin { form.locked } else { before.locked },
+    };
+    state.db.store_spending_policy(id, &policy).await?;
+
+    let before = SpendingPolicyDto::from(&before);
+    let policy = SpendingPolicyDto::from(&state.db.get_spending_policy(id).await?);
+    audit_log::record(
+        &mut state,
+        "spending_policy.update",
+        "account",
+        Some(id),
+        Some(&before),
+        Some(&policy),
+    )
+    .await?;
+
+    Ok(Json(policy))
+}
+
+fn update_spending_limits_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Replace the spending limits and blocked product tags of the given account. Payments that exceed a limit fail with `PaymentError` and a reason like `SpendingLimit:day:Cent` or `BlockedProductTag:alcohol`. Accounts can change their own policy unless an admin locked it.")
+        .tag("accounts")
+        .response::<200, Json<SpendingPolicyDto>>()
+        .response_with::<404, (), _>(|res| res.description("The requested account does not exist!"))
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions or the policy is locked!"))
+        .security_requirement_scopes("SessionToken", ["accounts.write", "self"])
+}
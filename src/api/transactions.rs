@@ -14,6 +14,7 @@ use crate::request_state::RequestState;
 use crate::{models, wallet};
 
 use super::account_auth_methods::AuthMethodTypeDto;
+use super::account_groups::session_require_group_payment;
 use super::accounts::{AccountDto, CoinAmountDto};
 use super::products::ProductDto;
 
@@ -23,6 +24,10 @@ pub fn router(app_state: AppState) -> ApiRouter {
             "/account/:id/payment",
             post_with(post_payment, post_payment_docs),
         )
+        .api_route(
+            "/account/:id/transfer",
+            post_with(post_transfer, post_transfer_docs),
+        )
         .api_route(
             "/account/:id/transaction/:transaction",
             get_with(get_transaction, get_transaction_docs),
@@ -62,6 +67,8 @@ pub struct TransactionDto {
     pub account_id: u64,
     pub authorized_by_account_id: Option<u64>,
     pub authorized_with_method: Option<AuthMethodTypeDto>,
+    /// Counterpart of a transfer between two accounts
+    pub linked_transaction_id: Option<u64>,
     pub items: Vec<TransactionItemDto>,
 }
 
@@ -73,6 +80,7 @@ impl From<&models::Transaction> for TransactionDto {
             account_id: value.account.to_owned(),
             authorized_by_account_id: value.authorized_by_account_id.to_owned(),
             authorized_with_method: value.authorized_with_method.map(|ref m| m.into()),
+            linked_transaction_id: value.linked_transaction_id,
             items: value.items.iter().map(|i| i.into()).collect(),
         }
     }
@@ -82,7 +90,7 @@ pub async fn list_transactions(
     mut state: RequestState,
     Path(id): Path<u64>,
 ) -> ServiceResult<Json<Vec<TransactionDto>>> {
-    state.session_require_admin_or_self(id)?;
+    state.session_require_permission_or_self(models::Permission::AccountsRead, id)?;
 
     let transactions = state.db.get_transactions_by_account(id).await?;
     Ok(Json(transactions.iter().map(|t| t.into()).collect()))
@@ -95,13 +103,13 @@ fn list_transactions_docs(op: TransformOperation) -> TransformOperation {
         .response_with::<404, (), _>(|res| res.description("The requested account does not exist!"))
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin", "self"])
+        .security_requirement_scopes("SessionToken", ["accounts.read", "self"])
 }
 
 pub async fn list_global_transactions(
     mut state: RequestState,
 ) -> ServiceResult<Json<Vec<TransactionDto>>> {
-    state.session_require_admin()?;
+    state.session_require_permission(models::Permission::AccountsRead)?;
 
     let transactions = state.db.get_transactions().await?;
     Ok(Json(transactions.iter().map(|t| t.into()).collect()))
@@ -114,14 +122,14 @@ fn list_global_transactions_docs(op: TransformOperation) -> TransformOperation {
         .response_with::<404, (), _>(|res| res.description("The requested account does not exist!"))
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin", "self"])
+        .security_requirement_scopes("SessionToken", ["accounts.read"])
 }
 
 pub async fn get_transaction(
     mut state: RequestState,
     Path((account_id, transaction_id)): Path<(u64, u64)>,
 ) -> ServiceResult<Json<TransactionDto>> {
-    state.session_require_admin_or_self(account_id)?;
+    state.session_require_permission_or_self(models::Permission::AccountsRead, account_id)?;
 
     let transaction = state.db.get_transaction_by_id(transaction_id).await?;
 
@@ -143,7 +151,7 @@ fn get_transaction_docs(op: TransformOperation) -> TransformOperation {
         })
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin", "self"])
+        .security_requirement_scopes("SessionToken", ["accounts.read", "self"])
 }
 
 #[derive(Debug, PartialEq, Deserialize, JsonSchema)]
@@ -168,21 +176,26 @@ async fn post_payment(
     Path(id): Path<u64>,
     form: Json<PaymentDto>,
 ) -> ServiceResult<Json<PaymentResponseDto>> {
-    state.session_require_admin_or_self(id)?;
-    let session = state.session_require()?;
-
     let form = form.0;
+    let items: Vec<models::PaymentItem> = form
+        .items
+        .into_iter()
+        .map(|item| models::PaymentItem {
+            effective_price: item.effective_price.into(),
+            product_id: item.product_id,
+        })
+        .collect();
+
+    let session = match state.session_require_payment(id) {
+        Ok(session) => session,
+        // Members of a group account may pay from it as well
+        Err(ServiceError::Forbidden) => session_require_group_payment(&mut state, id).await?,
+        Err(e) => return Err(e),
+    };
 
     let payment = models::Payment {
         account: id,
-        items: form
-            .items
-            .into_iter()
-            .map(|item| models::PaymentItem {
-                effective_price: item.effective_price.into(),
-                product_id: item.product_id,
-            })
-            .collect(),
+        items,
         authorization: Some(session),
     };
 
@@ -209,7 +222,87 @@ fn post_payment_docs(op: TransformOperation) -> TransformOperation {
         .tag("transactions")
         .response::<200, Json<PaymentResponseDto>>()
         .response_with::<404, (), _>(|res| res.description("The requested account does not exist!"))
+        .response_with::<409, (), _>(|res| {
+            res.description("The balance is insufficient, the payment token limit is exceeded or the account is archived!")
+        })
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["payments.write", "self", "group member"])
+}
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct TransferDto {
+    pub target_account_id: u64,
+    /// Amount that is moved to the target account, all values must be positive or zero
+    pub amount: CoinAmountDto,
+}
+
+async fn post_transfer(
+    mut state: RequestState,
+    Path(id): Path<u64>,
+    form: Json<TransferDto>,
+) -> ServiceResult<Json<PaymentResponseDto>> {
+    let session = state.session_require()?;
+    if session.account.id != id {
+        return Err(ServiceError::Forbidden);
+    }
+
+    let form = form.0;
+    let amount: models::CoinAmount = form.amount.into();
+    if form.target_account_id == id {
+        return Err(ServiceError::BadRequest(
+            "Transfers to the own account are not possible",
+        ));
+    }
+    if amount.0.values().any(|value| *value < 0) || amount.0.values().all(|value| *value == 0) {
+        return Err(ServiceError::BadRequest(
+            "The amount must not be negative or zero",
+        ));
+    }
+
+    let (transaction, _) = state
+        .db
+        .transfer(
+            id,
+            form.target_account_id,
+            &amount,
+            Some(&session),
+            Utc::now(),
+        )
+        .await?;
+    let account = state
+        .db
+        .get_account_by_id(id)
+        .await?
+        .ok_or(ServiceError::NotFound)?;
+
+    let target_account_id = form.target_account_id;
+    tokio::spawn(async move {
+        for account_id in [id, target_account_id] {
+            if let Err(e) = wallet::send_update_notification(&mut state.db, account_id).await {
+                error!("Could not send apns update! {:?}", e)
+            }
+        }
+    });
+
+    Ok(Json(PaymentResponseDto {
+        account: AccountDto::from(&account),
+        transaction: TransactionDto::from(&transaction),
+    }))
+}
+
+fn post_transfer_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Transfer cents or stamps from the logged in account to another account. Both accounts get a transaction that references the other one by `linked_transaction_id`.")
+        .tag("transactions")
+        .response::<200, Json<PaymentResponseDto>>()
+        .response_with::<400, (), _>(|res| {
+            res.description("The target is the own account or the amount is negative or zero!")
+        })
+        .response_with::<404, (), _>(|res| res.description("The target account does not exist!"))
+        .response_with::<409, (), _>(|res| {
+            res.description("The balance is insufficient, the spending policy is violated or one of the accounts is archived!")
+        })
         .response_with::<401, (), _>(|res| res.description("Missing login!"))
         .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
-        .security_requirement_scopes("SessionToken", ["admin", "self"])
+        .security_requirement_scopes("SessionToken", ["self"])
 }
@@ -0,0 +1,482 @@
+use std::collections::HashMap;
+use std::ops::Add;
+
+use aide::axum::routing::{get_with, post_with};
+use aide::axum::ApiRouter;
+use aide::transform::TransformOperation;
+use axum::extract::Path;
+use axum::Json;
+use chrono::{DateTime, Duration, Utc};
+use log::error;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::database::AppState;
+use crate::error::{ServiceError, ServiceResult};
+use crate::models::{self, CoinAmount};
+use crate::request_state::RequestState;
+use crate::{env, wallet};
+
+use super::accounts::{AccountDto, CoinAmountDto, CoinTypeDto};
+use super::audit_log;
+use super::auth::AuthTokenDto;
+use super::transactions::{PaymentResponseDto, TransactionDto};
+
+const MAX_VOUCHERS_PER_BATCH: u32 = 1000;
+const VOUCHER_CODE_LENGTH: usize = 16;
+/// Guest sessions are meant for a single visit at the counter
+const GUEST_SESSION_MINUTES: i64 = 15;
+/// Voucher logins and redemptions per client address or account within `VOUCHER_WINDOW_MINUTES`
+const VOUCHER_ATTEMPT_LIMIT: u32 = 10;
+const VOUCHER_WINDOW_MINUTES: i64 = 60;
+
+pub fn router(app_state: AppState) -> ApiRouter {
+    ApiRouter::new()
+        .api_route(
+            "/voucher-batches",
+            get_with(list_voucher_batches, list_voucher_batches_docs)
+                .post_with(create_voucher_batch, create_voucher_batch_docs),
+        )
+        .api_route(
+            "/voucher-batch/:id/vouchers",
+            get_with(list_vouchers, list_vouchers_docs),
+        )
+        .api_route(
+            "/account/:id/redeem-voucher",
+            post_with(redeem_voucher, redeem_voucher_docs),
+        )
+        .api_route("/auth/voucher", post_with(auth_voucher, auth_voucher_docs))
+        .api_route(
+            "/report/voucher-liability",
+            get_with(get_voucher_liability, get_voucher_liability_docs),
+        )
+        .with_state(app_state)
+}
+
+async fn check_voucher_rate_limit(state: &mut RequestState, key: &str) -> ServiceResult<()> {
+    if !state
+        .db
+        .rate_limit_hit(
+            key,
+            Duration::minutes(VOUCHER_WINDOW_MINUTES),
+            VOUCHER_ATTEMPT_LIMIT,
+        )
+        .await?
+    {
+        return Err(ServiceError::TooManyRequests);
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub struct VoucherBatchDto {
+    pub id: u64,
+    pub name: String,
+    pub created_at: String,
+    pub created_by_account_id: Option<u64>,
+    pub valid_until: String,
+    /// Value of every single voucher in this batch
+    pub value: CoinAmountDto,
+    pub voucher_count: u64,
+    pub used_count: u64,
+}
+
+impl From<&models::VoucherBatch> for VoucherBatchDto {
+    fn from(value: &models::VoucherBatch) -> Self {
+        Self {
+            id: value.id,
+            name: value.name.to_owned(),
+            created_at: format!("{:?}", value.created_at),
+            created_by_account_id: value.created_by_account_id,
+            valid_until: format!("{:?}", value.valid_until),
+            value: (&value.value).into(),
+            voucher_count: value.voucher_count,
+            used_count: value.used_count,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub struct VoucherDto {
+    pub id: u64,
+    pub code: String,
+    pub used_at: Option<String>,
+    pub account_id: Option<u64>,
+    pub is_guest: bool,
+}
+
+impl From<&models::Voucher> for VoucherDto {
+    fn from(value: &models::Voucher) -> Self {
+        Self {
+            id: value.id,
+            code: value.code.to_owned(),
+            used_at: value.used_at.map(|t| format!("{:?}", t)),
+            account_id: value.account_id,
+            is_guest: value.is_guest,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct CreateVoucherBatchDto {
+    pub name: String,
+    /// Number of vouchers, between 1 and 1000
+    pub count: u32,
+    pub value: CoinAmountDto,
+    /// RFC 3339 timestamp
+    pub valid_until: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub struct CreatedVoucherBatchDto {
+    pub batch: VoucherBatchDto,
+    pub codes: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct VoucherCodeDto {
+    pub code: String,
+}
+
+async fn list_voucher_batches(
+    mut state: RequestState,
+) -> ServiceResult<Json<Vec<VoucherBatchDto>>> {
+    state.session_require_permission(models::Permission::VouchersWrite)?;
+
+    let batches = state.db.get_all_voucher_batches().await?;
+    Ok(Json(batches.iter().map(|b| b.into()).collect()))
+}
+
+fn list_voucher_batches_docs(op: TransformOperation) -> TransformOperation {
+    op.description("List all voucher batches, newest first.")
+        .tag("vouchers")
+        .response::<200, Json<Vec<VoucherBatchDto>>>()
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["vouchers.write"])
+}
+
+async fn create_voucher_batch(
+    mut state: RequestState,
+    form: Json<CreateVoucherBatchDto>,
+) -> ServiceResult<Json<CreatedVoucherBatchDto>> {
+    let account = state.session_require_permission(models::Permission::VouchersWrite)?;
+
+    let form = form.0;
+    let value: CoinAmount = form.value.into();
+    if value.0.values().any(|amount| *amount < 0) {
+        return Err(ServiceError::Forbidden);
+    }
+    if !(1..=MAX_VOUCHERS_PER_BATCH).contains(&form.count) {
+        return Err(ServiceError::BadRequest(
+            "The number of vouchers must be between 1 and 1000",
+        ));
+    }
+
+    let valid_until = DateTime::parse_from_rfc3339(&form.valid_until)
+        .map_err(|_| ServiceError::BadRequest("valid_until is not a RFC 3339 timestamp"))?;
+
+    let codes: Vec<String> = (0..form.count)
+        .map(|_| wallet::generate_random_string(VOUCHER_CODE_LENGTH))
+        .collect();
+    let batch = state
+        .db
+        .create_voucher_batch(
+            models::VoucherBatch {
+                id: 0,
+                name: form.name,
+                created_at: Utc::now(),
+                created_by_account_id: Some(account.id),
+                valid_until: valid_until.into(),
+                value,
+                voucher_count: 0,
+                used_count: 0,
+            },
+            &codes,
+        )
+        .await?;
+
+    let batch = VoucherBatchDto::from(&batch);
+    audit_log::record(
+        &mut state,
+        "voucher_batch.create",
+        "voucher_batch",
+        Some(batch.id),
+        None,
+        Some(&batch),
+    )
+    .await?;
+
+    Ok(Json(CreatedVoucherBatchDto { batch, codes }))
+}
+
+fn create_voucher_batch_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Create a batch of voucher codes with the given value and expiry. The codes can be printed as qr codes.")
+        .tag("vouchers")
+        .response::<200, Json<CreatedVoucherBatchDto>>()
+        .response_with::<400, (), _>(|res| {
+            res.description("Invalid number of vouchers or expiry timestamp!")
+        })
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["vouchers.write"])
+}
+
+async fn list_vouchers(
+    mut state: RequestState,
+    Path(id): Path<u64>,
+) -> ServiceResult<Json<Vec<VoucherDto>>> {
+    state.session_require_permission(models::Permission::VouchersWrite)?;
+
+    if state.db.get_voucher_batch_by_id(id).await?.is_none() {
+        return Err(ServiceError::NotFound);
+    }
+    let vouchers = state.db.get_vouchers_by_batch(id).await?;
+    Ok(Json(vouchers.iter().map(|v| v.into()).collect()))
+}
+
+fn list_vouchers_docs(op: TransformOperation) -> TransformOperation {
+    op.description("List all vouchers of the given batch.")
+        .tag("vouchers")
+        .response::<200, Json<Vec<VoucherDto>>>()
+        .response_with::<404, (), _>(|res| {
+            res.description("The requested voucher batch does not exist!")
+        })
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["vouchers.write"])
+}
+
+async fn redeem_voucher(
+    mut state: RequestState,
+    Path(id): Path<u64>,
+    form: Json<VoucherCodeDto>,
+) -> ServiceResult<Json<PaymentResponseDto>> {
+    state.session_require_permission_or_self(models::Permission::AccountsWrite, id)?;
+    let session = state.session_require()?;
+
+    check_voucher_rate_limit(&mut state, &format!("voucher:account:{}", id)).await?;
+
+    let account = state
+        .db
+        .get_account_by_id(id)
+        .await?
+        .ok_or(ServiceError::NotFound)?;
+    if account.archived_at.is_some() {
+        return Err(ServiceError::AccountArchived);
+    }
+
+    let (_, transaction) = state
+        .db
+        .redeem_voucher(form.code.trim(), account, Some(session))
+        .await?;
+    let account = state
+        .db
+        .get_account_by_id(id)
+        .await?
+        .ok_or(ServiceError::NotFound)?;
+
+    tokio::spawn(async move {
+        if let Err(e) = wallet::send_update_notification(&mut state.db, id).await {
+            error!("Could not send apns update! {:?}", e)
+        }
+    });
+
+    Ok(Json(PaymentResponseDto {
+        account: AccountDto::from(&account),
+        transaction: TransactionDto::from(&transaction),
+    }))
+}
+
+fn redeem_voucher_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Redeem a voucher code, its value is credited to the given account.")
+        .tag("vouchers")
+        .response::<200, Json<PaymentResponseDto>>()
+        .response_with::<404, (), _>(|res| {
+            res.description(
+                "The account does not exist or the voucher is invalid, used or expired!",
+            )
+        })
+        .response_with::<409, (), _>(|res| res.description("The account is archived!"))
+        .response_with::<429, (), _>(|res| res.description("Too many attempts!"))
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["accounts.write", "self"])
+}
+
+async fn auth_voucher(
+    mut state: RequestState,
+    form: Json<VoucherCodeDto>,
+) -> ServiceResult<Json<AuthTokenDto>> {
+    let client_key = format!(
+        "voucher:client:{}",
+        state.client.ip_address.as_deref().unwrap_or_default()
+    );
+    check_voucher_rate_limit(&mut state, &client_key).await?;
+
+    let code = form.code.trim();
+    let voucher = state
+        .db
+        .get_voucher_by_code(code)
+        .await?
+        .ok_or(ServiceError::Unauthorized("Invalid voucher code"))?;
+
+    let account_id = match (voucher.used_at, voucher.account_id) {
+        // The code stays the login of the guest account
+        (Some(_), Some(account_id)) if voucher.is_guest => account_id,
+        (Some(_), _) => return Err(ServiceError::Unauthorized("Invalid voucher code")),
+        (None, _) => {
+            let role = state
+                .db
+                .get_role_by_name(&env::GUEST_ACCOUNT_ROLE)
+                .await?
+                .ok_or_else(|| {
+                    ServiceError::InternalServerError(String::from("Guest role does not exist"))
+                })?;
+            let batch = state
+                .db
+                .get_voucher_batch_by_id(voucher.batch_id)
+                .await?
+                .ok_or(ServiceError::NotFound)?;
+            let guest = models::Account {
+                id: 0,
+                balance: CoinAmount::zero(),
+                name: format!("Guest ({})", batch.name),
+                email: String::new(),
+                role,
+                auth_methods: Vec::new(),
+                enable_monthly_mail_report: false,
+                enable_automatic_stamp_usage: true,
+                status: None,
+                archived_at: None,
+            };
+
+            // The guest account is only created if the voucher did not expire or was used concurrently
+            let (voucher, _) =
+                state
+                    .db
+                    .redeem_voucher(code, guest, None)
+                    .await
+                    .map_err(|e| match e {
+                        ServiceError::NotFound => {
+                            ServiceError::Unauthorized("Invalid voucher code")
+                        }
+                        e => e,
+                    })?;
+            let account_id = voucher
+                .account_id
+                .expect("redeemed vouchers belong to an account");
+
+            audit_log::record::<()>(
+                &mut state,
+                "voucher.guest_account",
+                "account",
+                Some(account_id),
+                None,
+                None,
+            )
+            .await?;
+            account_id
+        }
+    };
+
+    let account = state
+        .db
+        .get_account_by_id(account_id)
+        .await?
+        .ok_or(ServiceError::Unauthorized("Invalid voucher code"))?;
+    if account.archived_at.is_some() {
+        return Err(ServiceError::Unauthorized("Invalid voucher code"));
+    }
+
+    let token = state
+        .db
+        .create_session_token(
+            account.id,
+            models::AuthMethodType::QrCode,
+            models::SessionLifetime::Fixed(
+                Utc::now().add(Duration::minutes(GUEST_SESSION_MINUTES)),
+            ),
+            false,
+            &state.client,
+        )
+        .await?;
+
+    Ok(Json(AuthTokenDto {
+        token,
+        refresh_token: None,
+    }))
+}
+
+fn auth_voucher_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Login with a voucher code. The first login creates an anonymous guest account that is credited with the voucher value, later logins with the same code open a new session for this guest account.")
+        .tag("auth")
+        .response::<200, Json<AuthTokenDto>>()
+        .response_with::<401, (), _>(|res| res.description("Invalid voucher code!"))
+        .response_with::<429, (), _>(|res| res.description("Too many attempts!"))
+}
+
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub struct VoucherLiabilityDto {
+    /// Total value of all unused vouchers that have not expired yet
+    pub outstanding: HashMap<CoinTypeDto, i64>,
+    pub outstanding_vouchers: u64,
+    /// Unused vouchers of expired batches, they are no longer a liability
+    pub expired_vouchers: u64,
+    pub batches: Vec<VoucherBatchDto>,
+}
+
+async fn get_voucher_liability(
+    mut state: RequestState,
+) -> ServiceResult<Json<VoucherLiabilityDto>> {
+    state.session_require_permission(models::Permission::VouchersWrite)?;
+
+    let batches = state.db.get_all_voucher_batches().await?;
+
+    let now = Utc::now();
+    let mut outstanding: HashMap<CoinTypeDto, i64> = [
+        (CoinTypeDto::Cent, 0),
+        (CoinTypeDto::BottleStamp, 0),
+        (CoinTypeDto::CoffeeStamp, 0),
+    ]
+    .into();
+    let mut outstanding_vouchers = 0;
+    let mut expired_vouchers = 0;
+    for batch in batches.iter() {
+        let unused = batch.voucher_count - batch.used_count;
+        if batch.valid_until <= now {
+            expired_vouchers += unused;
+            continue;
+        }
+
+        outstanding_vouchers += unused;
+        for (coin_type, amount) in batch.value.0.iter() {
+            let total = outstanding.entry(coin_type.into()).or_insert(0);
+            *total = i64::try_from(unused)
+                .ok()
+                .and_then(|unused| i64::from(*amount).checked_mul(unused))
+                .and_then(|value| total.checked_add(value))
+                .ok_or_else(|| {
+                    ServiceError::InternalServerError(String::from(
+                        "Voucher liability is out of range",
+                    ))
+                })?;
+        }
+    }
+
+    Ok(Json(VoucherLiabilityDto {
+        outstanding,
+        outstanding_vouchers,
+        expired_vouchers,
+        batches: batches.iter().map(|b| b.into()).collect(),
+    }))
+}
+
+fn get_voucher_liability_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Report the value of all vouchers that were issued but not used yet. Value that was redeemed or moved to guest accounts is part of the account balances.")
+        .tag("reports")
+        .response::<200, Json<VoucherLiabilityDto>>()
+        .response_with::<401, (), _>(|res| res.description("Missing login!"))
+        .response_with::<403, (), _>(|res| res.description("Missing permissions!"))
+        .security_requirement_scopes("SessionToken", ["vouchers.write"])
+}
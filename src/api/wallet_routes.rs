@@ -12,10 +12,10 @@ use log::info;
 use serde::{Deserialize, Serialize};
 
 use crate::database::AppState;
-use crate::error::ServiceResult;
-use crate::models::{AppleWalletPass, AppleWalletRegistration};
+use crate::error::{ServiceError, ServiceResult};
+use crate::models::AppleWalletRegistration;
 use crate::request_state::RequestState;
-use crate::{env, wallet};
+use crate::{env, google_wallet, wallet};
 
 fn get_authentication_token(request: Option<TypedHeader<Authorization<Bearer>>>) -> Option<String> {
     if let Some(TypedHeader(Authorization(bearer))) = request {
@@ -46,6 +46,10 @@ pub fn router(app_state: AppState) -> Router {
         .route("/passes/:pass_type_id/:serial_number", get(pass_delivery))
         .route("/log", get(log))
         .route("/asciipay.pkpass", get(create_pass))
+        .route(
+            "/google-wallet-save-link",
+            get(create_google_wallet_save_link),
+        )
         .with_state(app_state)
 }
 
@@ -313,6 +317,10 @@ pub async fn pass_delivery(
 
         if let Some(account) = account {
             let pass_binary = wallet::create_pass_binary(&account, &pass)?;
+            state
+                .db
+                .confirm_apple_wallet_pass_qr_code(account_id, &pass.pass_type_id, &pass.qr_code)
+                .await?;
             Ok(PassResult {
                 body: pass_binary,
                 last_modified: Some(last_modified),
@@ -330,33 +338,50 @@ pub async fn pass_delivery(
 pub async fn create_pass(mut state: RequestState) -> ServiceResult<PassResult> {
     let account = state.session_require_login()?;
 
-    let pass = state
-        .db
-        .get_apple_wallet_pass(account.id, &env::APPLE_WALLET_PASS_TYPE_IDENTIFIER)
-        .await?;
-
-    let pass = if let Some(pass) = pass {
-        pass
-    } else {
-        state
-            .db
-            .store_apple_wallet_pass(AppleWalletPass {
-                account_id: account.id,
-                pass_type_id: env::APPLE_WALLET_PASS_TYPE_IDENTIFIER.to_string(),
-                authentication_token: String::new(),
-                qr_code: wallet::generate_random_string(64),
-                updated_at: wallet::get_current_time(),
-            })
-            .await?
-    };
+    let pass = wallet::get_or_create_pass(&mut state.db, account.id).await?;
 
     let vec = wallet::create_pass_binary(&account, &pass)?;
+    state
+        .db
+        .confirm_apple_wallet_pass_qr_code(account.id, &pass.pass_type_id, &pass.qr_code)
+        .await?;
     Ok(PassResult {
         body: vec,
         last_modified: None,
     })
 }
 
+#[derive(Debug, Serialize)]
+pub struct GoogleWalletSaveLink {
+    pub url: String,
+}
+
+/// GET route for `/v1/google-wallet-save-link` if user is logged in
+pub async fn create_google_wallet_save_link(
+    mut state: RequestState,
+) -> ServiceResult<Json<GoogleWalletSaveLink>> {
+    let account = state.session_require_login()?;
+
+    if !google_wallet::is_enabled() {
+        return Err(ServiceError::NotFound);
+    }
+
+    let pass = wallet::get_or_create_pass(&mut state.db, account.id).await?;
+    let key = google_wallet::ServiceAccountKey::load()?;
+    let url = google_wallet::create_save_link(
+        &key,
+        google_wallet::create_loyalty_object(&account, &pass),
+    )?;
+
+    // Later balance changes are pushed to this object
+    state
+        .db
+        .store_google_wallet_object(account.id, &google_wallet::get_object_id(account.id))
+        .await?;
+
+    Ok(Json(GoogleWalletSaveLink { url }))
+}
+
 #[derive(Debug, Deserialize)]
 #[allow(dead_code)]
 pub struct PassDeliveryPath {
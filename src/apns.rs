@@ -5,12 +5,14 @@ use std::{
 };
 
 use log::error;
-use reqwest::{header, Certificate, Client, Identity, Version};
+use reqwest::{header, Certificate, Client, Identity};
 
+use crate::push_queue::PushService;
 use crate::{env, error::ServiceResult};
 
 pub struct ApplePushNotificationService {
     client: Client,
+    endpoint: String,
 }
 
 impl ApplePushNotificationService {
@@ -36,16 +38,28 @@ impl ApplePushNotificationService {
             .http2_prior_knowledge()
             .build()?;
 
-        Ok(Self { client })
+        Ok(Self::with_client(
+            client,
+            env::APPLE_WALLET_APNS_URL.as_str(),
+        ))
     }
 
+    /// Create APNS client that sends to the given endpoint, e.g. a local mock server
+    pub fn with_client(client: Client, endpoint: &str) -> Self {
+        Self {
+            client,
+            endpoint: endpoint.trim_end_matches('/').to_owned(),
+        }
+    }
+}
+
+impl PushService for ApplePushNotificationService {
     /// Send message over APNS client
-    pub async fn send(&self, push_token: &str) -> ServiceResult<u16> {
-        let path = format!("https://api.push.apple.com:443/3/device/{}", push_token);
+    async fn send(&self, push_token: &str) -> ServiceResult<u16> {
+        let path = format!("{}/3/device/{}", self.endpoint, push_token);
         let builder = self
             .client
             .post(&path)
-            .version(Version::HTTP_2)
             .header(header::CONTENT_TYPE, "application/json");
 
         let payload_json = "{}";
@@ -74,3 +88,34 @@ fn load_x509(path: &str) -> ServiceResult<Certificate> {
 
     Ok(Certificate::from_pem(&buffer)?)
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::Path;
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use axum::Router;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_send_to_mock_endpoint() {
+        let app = Router::new().route(
+            "/3/device/:push_token",
+            post(|Path(push_token): Path<String>| async move {
+                if push_token == "expired" {
+                    StatusCode::GONE
+                } else {
+                    StatusCode::OK
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let apns = ApplePushNotificationService::with_client(Client::new(), &endpoint);
+        assert_eq!(apns.send("token").await.unwrap(), 200);
+        assert_eq!(apns.send("expired").await.unwrap(), 410);
+    }
+}
@@ -1,75 +1,74 @@
 #![allow(unused_variables)]
 
-use std::collections::HashMap;
-use std::sync::Arc;
-
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use futures::StreamExt;
 use log::error;
 use serde::{Deserialize, Serialize};
 use sqlx::migrate::Migrator;
 use sqlx::postgres::{PgPoolOptions, PgRow};
 use sqlx::types::Json;
-use sqlx::{Connection, FromRow, PgPool, Row};
+use sqlx::{Connection, FromRow, PgConnection, PgPool, Row};
 use sqlx::{Pool, Postgres};
-use tokio::sync::Mutex;
 
+use crate::env;
 use crate::error::{ServiceError, ServiceResult};
 use crate::models::{
-    self, Account, AccountStatus, AppleWalletPass, AppleWalletRegistration, AuthMethod,
-    AuthMethodType, AuthNfc, AuthPassword, AuthRequest, CardType, CoinAmount, CoinType, Image,
-    PaymentItem, Product, ProductStatusPrice, Role, Session, Transaction, TransactionItem,
+    self, Account, AccountStatus, AppleWalletPass, AppleWalletRegistration, AuditLogEntry,
+    AuthMethod, AuthMethodType, AuthNfc, AuthPassword, AuthRequest, CardType, CoinAmount, CoinType,
+    Image, ImpersonationLogEntry, Invitation, NfcCardStatus, NfcProvisioning, NfcReader, NfcSecret,
+    PaymentItem, Permission, Product, ProductStatusPrice, Role, Session, Transaction,
+    TransactionItem,
 };
 
 const MINIMUM_PAYMENT_CENTS: i32 = 0;
 const MINIMUM_PAYMENT_BOTTLE_STAMPS: i32 = 0;
 const MINIMUM_PAYMENT_COFFEE_STAMPS: i32 = 0;
+/// Period in which the spending limit of a group member applies
+const MEMBER_SPENDING_LIMIT_DAYS: i64 = 30;
 
 mod migration;
+mod nfc_challenge;
 #[cfg(test)]
 mod tests;
 
-pub struct AppStateNfcChallenge {
-    pub valid_until: DateTime<Utc>,
-    pub rnd_a: Vec<u8>,
-    pub rnd_b: Vec<u8>,
-}
+pub use nfc_challenge::{AppStateNfcChallenge, NfcChallengeStorage};
 
 #[derive(Clone)]
 pub struct AppState {
     pub pool: Pool<Postgres>,
-    pub ascii_mifare_challenge: Arc<Mutex<HashMap<u64, AppStateNfcChallenge>>>,
+    pub nfc_challenge_storage: NfcChallengeStorage,
 }
 
-#[derive(sqlx::Type)]
-#[sqlx(type_name = "tp_account_role", rename_all = "lowercase")]
-enum AccountRoleDto {
-    Basic,
-    Member,
-    Purchaser,
-    Admin,
+fn permission_to_key(permission: Permission) -> &'static str {
+    match permission {
+        Permission::AccountsRead => "accounts.read",
+        Permission::AccountsWrite => "accounts.write",
+        Permission::RolesWrite => "roles.write",
+        Permission::PaymentsWrite => "payments.write",
+        Permission::PaymentsCredit => "payments.credit",
+        Permission::ProductsWrite => "products.write",
+        Permission::PurchasesWrite => "purchases.write",
+        Permission::RegisterWrite => "register.write",
+        Permission::ReportsSend => "reports.send",
+        Permission::NfcReadersWrite => "nfc_readers.write",
+        Permission::AuditLogRead => "audit_log.read",
+        Permission::VouchersWrite => "vouchers.write",
+        Permission::AccountsImpersonate => "accounts.impersonate",
+    }
 }
 
-impl From<AccountRoleDto> for Role {
-    fn from(value: AccountRoleDto) -> Self {
-        match value {
-            AccountRoleDto::Basic => Role::Basic,
-            AccountRoleDto::Member => Role::Member,
-            AccountRoleDto::Purchaser => Role::Purchaser,
-            AccountRoleDto::Admin => Role::Admin,
-        }
-    }
+fn permissions_from_keys(keys: &[String]) -> Vec<Permission> {
+    Permission::ALL
+        .into_iter()
+        .filter(|p| keys.iter().any(|k| k == permission_to_key(*p)))
+        .collect()
 }
 
-impl From<Role> for AccountRoleDto {
-    fn from(value: Role) -> Self {
-        match value {
-            Role::Basic => AccountRoleDto::Basic,
-            Role::Member => AccountRoleDto::Member,
-            Role::Purchaser => AccountRoleDto::Purchaser,
-            Role::Admin => AccountRoleDto::Admin,
-        }
-    }
+fn permissions_to_keys(permissions: &[Permission]) -> Vec<String> {
+    permissions
+        .iter()
+        .map(|p| permission_to_key(*p).to_owned())
+        .collect()
 }
 
 #[derive(sqlx::FromRow)]
@@ -80,7 +79,9 @@ struct AccountRow {
     balance_bottle_stamps: i32,
     name: String,
     email: String,
-    role: AccountRoleDto,
+    role_id: i64,
+    role_name: String,
+    role_permissions: Vec<String>,
     auth_methods: Vec<Json<AccountAuthMethodData>>,
     enable_monthly_mail_report: bool,
     enable_automatic_stamp_usage: bool,
@@ -88,6 +89,7 @@ struct AccountRow {
     status_name: Option<String>,
     status_color: Option<String>,
     status_priority: Option<i32>,
+    archived_at: Option<DateTime<Utc>>,
 }
 
 impl AccountRow {
@@ -123,11 +125,159 @@ impl From<AccountRow> for Account {
             ]),
             name: row.name,
             email: row.email,
-            role: row.role.into(),
+            role: Role {
+                id: row
+                    .role_id
+                    .try_into()
+                    .expect("id in database is always positive"),
+                name: row.role_name,
+                permissions: permissions_from_keys(&row.role_permissions),
+            },
             auth_methods: row.auth_methods.into_iter().map(|j| j.0.into()).collect(),
             enable_monthly_mail_report: row.enable_monthly_mail_report,
             enable_automatic_stamp_usage: row.enable_automatic_stamp_usage,
             status,
+            archived_at: row.archived_at,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct RoleRow {
+    id: i64,
+    name: String,
+    permissions: Vec<String>,
+}
+
+impl From<RoleRow> for Role {
+    fn from(row: RoleRow) -> Self {
+        Role {
+            id: row
+                .id
+                .try_into()
+                .expect("id in database is always positive"),
+            name: row.name,
+            permissions: permissions_from_keys(&row.permissions),
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct NfcReaderRow {
+    id: i64,
+    name: String,
+    secret: Vec<u8>,
+    secret_key_id: String,
+}
+
+impl From<NfcReaderRow> for NfcReader {
+    fn from(row: NfcReaderRow) -> Self {
+        NfcReader {
+            id: row
+                .id
+                .try_into()
+                .expect("id in database is always positive"),
+            name: row.name,
+            key: NfcSecret::Sealed {
+                key_id: row.secret_key_id,
+                data: row.secret,
+            },
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct NfcProvisioningRow {
+    id: String,
+    account_id: i64,
+    name: String,
+    card_id: Vec<u8>,
+    secret: Vec<u8>,
+    secret_key_id: String,
+    replaces_card_id: Option<Vec<u8>>,
+    key_change_issued: bool,
+    valid_until: DateTime<Utc>,
+}
+
+impl From<NfcProvisioningRow> for NfcProvisioning {
+    fn from(row: NfcProvisioningRow) -> Self {
+        NfcProvisioning {
+            id: row.id,
+            account_id: row
+                .account_id
+                .try_into()
+                .expect("id in database is always positive"),
+            name: row.name,
+            card_id: row.card_id,
+            key: NfcSecret::Sealed {
+                key_id: row.secret_key_id,
+                data: row.secret,
+            },
+            replaces_card_id: row.replaces_card_id,
+            key_change_issued: row.key_change_issued,
+            valid_until: row.valid_until,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct InvitationRow {
+    id: i64,
+    token: String,
+    name: String,
+    email: String,
+    role_id: i64,
+    role_name: String,
+    role_permissions: Vec<String>,
+    status_id: Option<i64>,
+    status_name: Option<String>,
+    status_color: Option<String>,
+    status_priority: Option<i32>,
+    invited_by_account_id: Option<i64>,
+    created_at: DateTime<Utc>,
+    valid_until: DateTime<Utc>,
+}
+
+impl From<InvitationRow> for Invitation {
+    fn from(row: InvitationRow) -> Self {
+        let status = match (
+            row.status_id,
+            row.status_name,
+            row.status_color,
+            row.status_priority,
+        ) {
+            (Some(id), Some(name), Some(color), Some(priority)) => Some(AccountStatus {
+                id: id.try_into().expect("id in database is always positive"),
+                name,
+                color,
+                priority: priority
+                    .try_into()
+                    .expect("priority in database is always positive"),
+            }),
+            _ => None,
+        };
+        Invitation {
+            id: row
+                .id
+                .try_into()
+                .expect("id in database is always positive"),
+            token: row.token,
+            name: row.name,
+            email: row.email,
+            role: Role {
+                id: row
+                    .role_id
+                    .try_into()
+                    .expect("id in database is always positive"),
+                name: row.role_name,
+                permissions: permissions_from_keys(&row.role_permissions),
+            },
+            status,
+            invited_by_account_id: row
+                .invited_by_account_id
+                .map(|id| id.try_into().expect("id in database is always positive")),
+            created_at: row.created_at,
+            valid_until: row.valid_until,
         }
     }
 }
@@ -157,6 +307,84 @@ impl From<AccountStatusRow> for AccountStatus {
     }
 }
 
+#[derive(sqlx::Type, Clone, Copy)]
+#[sqlx(type_name = "tp_push_job_kind", rename_all = "snake_case")]
+enum PushJobKindDto {
+    AppleWallet,
+    GoogleWallet,
+}
+
+impl From<PushJobKindDto> for models::PushJobKind {
+    fn from(value: PushJobKindDto) -> Self {
+        match value {
+            PushJobKindDto::AppleWallet => models::PushJobKind::AppleWallet,
+            PushJobKindDto::GoogleWallet => models::PushJobKind::GoogleWallet,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+pub struct PushJobRow {
+    pub id: i64,
+    kind: PushJobKindDto,
+    pub account_id: i64,
+    pub pass_type_id: String,
+    pub device_id: String,
+    pub push_token: String,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub dead_at: Option<DateTime<Utc>>,
+}
+
+impl From<PushJobRow> for models::PushJob {
+    fn from(row: PushJobRow) -> Self {
+        models::PushJob {
+            id: row
+                .id
+                .try_into()
+                .expect("id in database is always positive"),
+            kind: row.kind.into(),
+            account_id: row
+                .account_id
+                .try_into()
+                .expect("id in database is always positive"),
+            pass_type_id: row.pass_type_id,
+            device_id: row.device_id,
+            push_token: row.push_token,
+            attempts: row
+                .attempts
+                .try_into()
+                .expect("attempts in database are always positive"),
+            next_attempt_at: row.next_attempt_at,
+            last_error: row.last_error,
+            created_at: row.created_at,
+            dead_at: row.dead_at,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+pub struct GoogleWalletObjectRow {
+    pub account_id: i64,
+    pub object_id: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<GoogleWalletObjectRow> for models::GoogleWalletObject {
+    fn from(row: GoogleWalletObjectRow) -> Self {
+        models::GoogleWalletObject {
+            account_id: row
+                .account_id
+                .try_into()
+                .expect("id in database is always positive"),
+            object_id: row.object_id,
+            created_at: row.created_at,
+        }
+    }
+}
+
 #[derive(sqlx::FromRow)]
 pub struct AppleWalletPassRow {
     pub account_id: i64,
@@ -218,6 +446,7 @@ struct PaymentAccountRow {
 enum CardTypeDto {
     NfcId,
     AsciiMifare,
+    AsciiMifareAes,
     HostCardEmulation,
 }
 
@@ -226,6 +455,7 @@ impl From<CardTypeDto> for CardType {
         match value {
             CardTypeDto::NfcId => CardType::GenericNfc,
             CardTypeDto::AsciiMifare => CardType::AsciiMifare,
+            CardTypeDto::AsciiMifareAes => CardType::AsciiMifareAes,
             CardTypeDto::HostCardEmulation => CardType::HostCardEmulation,
         }
     }
@@ -236,11 +466,50 @@ impl From<CardType> for CardTypeDto {
         match value {
             CardType::GenericNfc => CardTypeDto::NfcId,
             CardType::AsciiMifare => CardTypeDto::AsciiMifare,
+            CardType::AsciiMifareAes => CardTypeDto::AsciiMifareAes,
             CardType::HostCardEmulation => CardTypeDto::HostCardEmulation,
         }
     }
 }
 
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+enum NfcCardStatusDto {
+    #[default]
+    Active,
+    Blocked,
+    Lost,
+    Expired,
+}
+
+impl From<NfcCardStatusDto> for NfcCardStatus {
+    fn from(value: NfcCardStatusDto) -> Self {
+        match value {
+            NfcCardStatusDto::Active => NfcCardStatus::Active,
+            NfcCardStatusDto::Blocked => NfcCardStatus::Blocked,
+            NfcCardStatusDto::Lost => NfcCardStatus::Lost,
+            NfcCardStatusDto::Expired => NfcCardStatus::Expired,
+        }
+    }
+}
+
+impl From<NfcCardStatus> for NfcCardStatusDto {
+    fn from(value: NfcCardStatus) -> Self {
+        match value {
+            NfcCardStatus::Active => NfcCardStatusDto::Active,
+            NfcCardStatus::Blocked => NfcCardStatusDto::Blocked,
+            NfcCardStatus::Lost => NfcCardStatusDto::Lost,
+            NfcCardStatus::Expired => NfcCardStatusDto::Expired,
+        }
+    }
+}
+
+fn parse_rfc3339(value: Option<String>) -> Option<DateTime<Utc>> {
+    value
+        .and_then(|value| DateTime::parse_from_rfc3339(&value).ok())
+        .map(|value| value.into())
+}
+
 #[derive(Serialize, Deserialize)]
 enum AccountAuthMethodData {
     Password {
@@ -252,7 +521,17 @@ enum AccountAuthMethodData {
         card_id: Vec<u8>,
         card_type: CardTypeDto,
         data: Vec<u8>,
+        /// Id of the master key `data` is encrypted with, `None` for unencrypted legacy data
+        data_key_id: Option<String>,
         depends_on_session: Option<String>,
+        #[serde(default)]
+        status: NfcCardStatusDto,
+        /// RFC 3339 timestamp
+        #[serde(default)]
+        last_used_at: Option<String>,
+        /// RFC 3339 timestamp
+        #[serde(default)]
+        valid_until: Option<String>,
     },
     PublicTab,
 }
@@ -272,13 +551,23 @@ impl From<AccountAuthMethodData> for AuthMethod {
                 card_id,
                 card_type,
                 data,
+                data_key_id,
                 depends_on_session,
+                status,
+                last_used_at,
+                valid_until,
             } => AuthMethod::NfcBased(AuthNfc {
                 name,
                 card_id,
                 card_type: card_type.into(),
-                data,
+                data: match data_key_id {
+                    Some(key_id) => NfcSecret::Sealed { key_id, data },
+                    None => NfcSecret::Plain(data),
+                },
                 depends_on_session,
+                status: status.into(),
+                last_used_at: parse_rfc3339(last_used_at),
+                valid_until: parse_rfc3339(valid_until),
             }),
             AccountAuthMethodData::PublicTab => AuthMethod::PublicTab,
         }
@@ -292,13 +581,23 @@ impl From<AuthMethod> for AccountAuthMethodData {
                 username: auth.username,
                 password_hash: auth.password_hash,
             },
-            AuthMethod::NfcBased(auth) => AccountAuthMethodData::Nfc {
-                name: auth.name,
-                card_id: auth.card_id,
-                card_type: auth.card_type.into(),
-                data: auth.data,
-                depends_on_session: auth.depends_on_session,
-            },
+            AuthMethod::NfcBased(auth) => {
+                let (data, data_key_id) = match auth.data {
+                    NfcSecret::Plain(data) => (data, None),
+                    NfcSecret::Sealed { key_id, data } => (data, Some(key_id)),
+                };
+                AccountAuthMethodData::Nfc {
+                    name: auth.name,
+                    card_id: auth.card_id,
+                    card_type: auth.card_type.into(),
+                    data,
+                    data_key_id,
+                    depends_on_session: auth.depends_on_session,
+                    status: auth.status.into(),
+                    last_used_at: auth.last_used_at.map(|t| t.to_rfc3339()),
+                    valid_until: auth.valid_until.map(|t| t.to_rfc3339()),
+                }
+            }
             AuthMethod::PublicTab => AccountAuthMethodData::PublicTab,
         }
     }
@@ -311,6 +610,8 @@ enum AuthMethodTypeDto {
     Nfc,
     PublicTab,
     PasswordResetToken,
+    Impersonation,
+    QrCode,
 }
 
 impl From<AuthMethodTypeDto> for AuthMethodType {
@@ -320,6 +621,8 @@ impl From<AuthMethodTypeDto> for AuthMethodType {
             AuthMethodTypeDto::Nfc => AuthMethodType::NfcBased,
             AuthMethodTypeDto::PublicTab => AuthMethodType::PublicTab,
             AuthMethodTypeDto::PasswordResetToken => AuthMethodType::PasswordResetToken,
+            AuthMethodTypeDto::Impersonation => AuthMethodType::Impersonation,
+            AuthMethodTypeDto::QrCode => AuthMethodType::QrCode,
         }
     }
 }
@@ -331,6 +634,8 @@ impl From<AuthMethodType> for AuthMethodTypeDto {
             AuthMethodType::NfcBased => AuthMethodTypeDto::Nfc,
             AuthMethodType::PublicTab => AuthMethodTypeDto::PublicTab,
             AuthMethodType::PasswordResetToken => AuthMethodTypeDto::PasswordResetToken,
+            AuthMethodType::Impersonation => AuthMethodTypeDto::Impersonation,
+            AuthMethodType::QrCode => AuthMethodTypeDto::QrCode,
         }
     }
 }
@@ -338,21 +643,287 @@ impl From<AuthMethodType> for AuthMethodTypeDto {
 #[derive(sqlx::FromRow)]
 struct SessionRow {
     uuid: String,
+    public_id: String,
     #[sqlx(flatten)]
     account: AccountRow,
     auth_method: AuthMethodTypeDto,
     valid_until: DateTime<Utc>,
     is_single_use: bool,
+    created_at: DateTime<Utc>,
+    last_used_at: DateTime<Utc>,
+    user_agent: Option<String>,
+    ip_address: Option<String>,
+    device_name: Option<String>,
+    payment_limit_cents: Option<i32>,
+    payment_limit_bottle_stamps: Option<i32>,
+    payment_limit_coffee_stamps: Option<i32>,
+    impersonated_by_account_id: Option<i64>,
+}
+
+#[derive(sqlx::Type, Clone, Copy)]
+#[sqlx(type_name = "tp_spending_limit_period", rename_all = "snake_case")]
+enum SpendingLimitPeriodDto {
+    Day,
+    Week,
+    Month,
+}
+
+impl From<SpendingLimitPeriodDto> for models::SpendingLimitPeriod {
+    fn from(value: SpendingLimitPeriodDto) -> Self {
+        match value {
+            SpendingLimitPeriodDto::Day => models::SpendingLimitPeriod::Day,
+            SpendingLimitPeriodDto::Week => models::SpendingLimitPeriod::Week,
+            SpendingLimitPeriodDto::Month => models::SpendingLimitPeriod::Month,
+        }
+    }
+}
+
+impl From<models::SpendingLimitPeriod> for SpendingLimitPeriodDto {
+    fn from(value: models::SpendingLimitPeriod) -> Self {
+        match value {
+            models::SpendingLimitPeriod::Day => SpendingLimitPeriodDto::Day,
+            models::SpendingLimitPeriod::Week => SpendingLimitPeriodDto::Week,
+            models::SpendingLimitPeriod::Month => SpendingLimitPeriodDto::Month,
+        }
+    }
+}
+
+impl SpendingLimitPeriodDto {
+    fn name(self) -> &'static str {
+        match self {
+            SpendingLimitPeriodDto::Day => "day",
+            SpendingLimitPeriodDto::Week => "week",
+            SpendingLimitPeriodDto::Month => "month",
+        }
+    }
+
+    /// Limits apply to a rolling window that ends with the payment
+    fn duration(self) -> Duration {
+        match self {
+            SpendingLimitPeriodDto::Day => Duration::days(1),
+            SpendingLimitPeriodDto::Week => Duration::days(7),
+            SpendingLimitPeriodDto::Month => Duration::days(30),
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct SpendingLimitRow {
+    period: SpendingLimitPeriodDto,
+    limit_cents: Option<i32>,
+    limit_coffee_stamps: Option<i32>,
+    limit_bottle_stamps: Option<i32>,
+}
+
+impl SpendingLimitRow {
+    /// Unlike balances a limit of zero is kept, a missing value means no limit
+    fn limits(&self) -> Vec<(CoinType, i32)> {
+        [
+            (CoinType::Cent, self.limit_cents),
+            (CoinType::CoffeeStamp, self.limit_coffee_stamps),
+            (CoinType::BottleStamp, self.limit_bottle_stamps),
+        ]
+        .into_iter()
+        .filter_map(|(coin_type, limit)| Some((coin_type, limit?)))
+        .collect()
+    }
+}
+
+impl From<SpendingLimitRow> for models::SpendingLimit {
+    fn from(row: SpendingLimitRow) -> Self {
+        models::SpendingLimit {
+            period: row.period.into(),
+            limit: CoinAmount(row.limits().into_iter().collect()),
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct VoucherBatchRow {
+    id: i64,
+    name: String,
+    created_at: DateTime<Utc>,
+    created_by_account_id: Option<i64>,
+    valid_until: DateTime<Utc>,
+    value_cents: i32,
+    value_coffee_stamps: i32,
+    value_bottle_stamps: i32,
+    voucher_count: i64,
+    used_count: i64,
+}
+
+impl From<VoucherBatchRow> for models::VoucherBatch {
+    fn from(row: VoucherBatchRow) -> Self {
+        let to_id = |id: i64| -> u64 { id.try_into().expect("id in database is always positive") };
+        models::VoucherBatch {
+            id: to_id(row.id),
+            name: row.name,
+            created_at: row.created_at,
+            created_by_account_id: row.created_by_account_id.map(to_id),
+            valid_until: row.valid_until,
+            value: to_coin_amount(&[
+                (CoinType::Cent, Some(row.value_cents)),
+                (CoinType::CoffeeStamp, Some(row.value_coffee_stamps)),
+                (CoinType::BottleStamp, Some(row.value_bottle_stamps)),
+            ]),
+            voucher_count: to_id(row.voucher_count),
+            used_count: to_id(row.used_count),
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct VoucherRow {
+    id: i64,
+    batch_id: i64,
+    code: String,
+    used_at: Option<DateTime<Utc>>,
+    account_id: Option<i64>,
+    is_guest: bool,
+}
+
+impl From<VoucherRow> for models::Voucher {
+    fn from(row: VoucherRow) -> Self {
+        let to_id = |id: i64| -> u64 { id.try_into().expect("id in database is always positive") };
+        models::Voucher {
+            id: to_id(row.id),
+            batch_id: to_id(row.batch_id),
+            code: row.code,
+            used_at: row.used_at,
+            account_id: row.account_id.map(to_id),
+            is_guest: row.is_guest,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct AccountGroupMemberRow {
+    group_account_id: i64,
+    member_account_id: i64,
+    member_name: String,
+    is_admin: bool,
+    spending_limit_cents: Option<i32>,
+}
+
+impl From<AccountGroupMemberRow> for models::AccountGroupMember {
+    fn from(row: AccountGroupMemberRow) -> Self {
+        let to_id = |id: i64| -> u64 { id.try_into().expect("id in database is always positive") };
+        models::AccountGroupMember {
+            group_account_id: to_id(row.group_account_id),
+            member_account_id: to_id(row.member_account_id),
+            member_name: row.member_name,
+            is_admin: row.is_admin,
+            spending_limit_cents: row.spending_limit_cents,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct DormantAccountRow {
+    #[sqlx(flatten)]
+    account: AccountRow,
+    last_activity: Option<DateTime<Utc>>,
+}
+
+impl From<DormantAccountRow> for models::DormantAccount {
+    fn from(value: DormantAccountRow) -> Self {
+        models::DormantAccount {
+            account: value.account.into(),
+            last_activity: value.last_activity,
+        }
+    }
 }
 
 impl From<SessionRow> for Session {
     fn from(value: SessionRow) -> Self {
         Session {
+            id: value.public_id,
             account: value.account.into(),
             token: value.uuid,
             auth_method: value.auth_method.into(),
             valid_until: value.valid_until,
             is_single_use: value.is_single_use,
+            created_at: value.created_at,
+            last_used_at: value.last_used_at,
+            client: models::SessionClient {
+                user_agent: value.user_agent,
+                ip_address: value.ip_address,
+                device_name: value.device_name,
+            },
+            payment_limit: value.payment_limit_cents.map(|cents| {
+                to_coin_amount(&[
+                    (CoinType::Cent, Some(cents)),
+                    (CoinType::BottleStamp, value.payment_limit_bottle_stamps),
+                    (CoinType::CoffeeStamp, value.payment_limit_coffee_stamps),
+                ])
+            }),
+            impersonated_by: value
+                .impersonated_by_account_id
+                .map(|id| id.try_into().expect("id in database is always positive")),
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct AuditLogRow {
+    id: i64,
+    timestamp: DateTime<Utc>,
+    actor_account_id: Option<i64>,
+    impersonated_account_id: Option<i64>,
+    action: String,
+    target_type: String,
+    target_id: Option<i64>,
+    before: Option<serde_json::Value>,
+    after: Option<serde_json::Value>,
+}
+
+impl From<AuditLogRow> for AuditLogEntry {
+    fn from(row: AuditLogRow) -> Self {
+        let to_id = |id: i64| -> u64 { id.try_into().expect("id in database is always positive") };
+        AuditLogEntry {
+            id: to_id(row.id),
+            timestamp: row.timestamp,
+            actor_account_id: row.actor_account_id.map(to_id),
+            impersonated_account_id: row.impersonated_account_id.map(to_id),
+            action: row.action,
+            target_type: row.target_type,
+            target_id: row.target_id.map(to_id),
+            before: row.before,
+            after: row.after,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct ImpersonationLogRow {
+    id: i64,
+    admin_account_id: i64,
+    account_id: i64,
+    session_id: String,
+    action: String,
+    ip_address: Option<String>,
+    timestamp: DateTime<Utc>,
+}
+
+impl From<ImpersonationLogRow> for ImpersonationLogEntry {
+    fn from(row: ImpersonationLogRow) -> Self {
+        ImpersonationLogEntry {
+            id: row
+                .id
+                .try_into()
+                .expect("id in database is always positive"),
+            admin_account_id: row
+                .admin_account_id
+                .try_into()
+                .expect("id in database is always positive"),
+            account_id: row
+                .account_id
+                .try_into()
+                .expect("id in database is always positive"),
+            session_id: row.session_id,
+            action: row.action,
+            ip_address: row.ip_address,
+            timestamp: row.timestamp,
         }
     }
 }
@@ -374,9 +945,14 @@ impl AppState {
             .expect("load migrations");
         migrator.run(&pool).await.expect("run migrations");
 
+        let nfc_challenge_storage = match env::NFC_CHALLENGE_STORAGE.as_str() {
+            "postgres" => NfcChallengeStorage::postgres(pool.clone()),
+            _ => NfcChallengeStorage::memory(),
+        };
+
         Self {
             pool,
-            ascii_mifare_challenge: Arc::new(Mutex::new(HashMap::new())),
+            nfc_challenge_storage,
         }
     }
 }
@@ -537,6 +1113,7 @@ struct TransactionRow {
     account_id: Option<i64>,
     authorized_by_account_id: Option<i64>,
     authorized_with_method: Option<AuthMethodTypeDto>,
+    linked_transaction_id: Option<i64>,
     #[sqlx(flatten)]
     item: TransactionItemRow,
 }
@@ -624,6 +1201,30 @@ impl From<models::RegisterHistoryState> for RegisterHistoryRowDataState {
     }
 }
 
+#[derive(sqlx::FromRow)]
+struct RegisterPayoutRow {
+    id: i64,
+    timestamp: DateTime<Utc>,
+    account_id: Option<i64>,
+    transaction_id: i64,
+    amount_cents: i32,
+    paid_by_account_id: Option<i64>,
+}
+
+impl From<RegisterPayoutRow> for models::RegisterPayout {
+    fn from(row: RegisterPayoutRow) -> Self {
+        let to_id = |id: i64| -> u64 { id.try_into().expect("id in database is always positive") };
+        models::RegisterPayout {
+            id: to_id(row.id),
+            timestamp: row.timestamp,
+            account_id: row.account_id.map(to_id),
+            transaction_id: to_id(row.transaction_id),
+            amount_cents: row.amount_cents,
+            paid_by_account_id: row.paid_by_account_id.map(to_id),
+        }
+    }
+}
+
 #[derive(sqlx::FromRow)]
 struct PurchaseRow {
     #[sqlx(try_from = "i64")]
@@ -665,9 +1266,9 @@ impl DatabaseConnection {
             r#"
             SELECT
                 a.id, a.balance_cents, a.balance_coffee_stamps, a.balance_bottle_stamps,
-                a.name, a.email, a.role,
+                a.name, a.email, a.role_id, account_role.name AS role_name, account_role.permissions AS role_permissions,
                 coalesce(array_agg(account_auth_method.data ORDER BY account_auth_method.id ASC) FILTER (where account_auth_method.id IS NOT NULL), '{}') AS auth_methods,
-                a.enable_monthly_mail_report, a.enable_automatic_stamp_usage,
+                a.enable_monthly_mail_report, a.enable_automatic_stamp_usage, a.archived_at,
                 (array_agg(account_status.id))[1] as status_id,
                 (array_agg(account_status.name))[1] as status_name,
                 (array_agg(account_status.color))[1] as status_color,
@@ -675,7 +1276,8 @@ impl DatabaseConnection {
             FROM account AS a
                 LEFT OUTER JOIN account_auth_method ON a.id = account_auth_method.account_id
                 LEFT OUTER JOIN account_status on a.status_id = account_status.id
-            GROUP BY a.id
+                INNER JOIN account_role ON a.role_id = account_role.id
+            GROUP BY a.id, account_role.id
         "#,
         )
         .fetch(self.connection.as_mut());
@@ -689,380 +1291,2158 @@ impl DatabaseConnection {
         Ok(out)
     }
 
-    pub async fn get_account_by_id(&mut self, id: u64) -> ServiceResult<Option<models::Account>> {
-        let r = sqlx::query_as::<_, AccountRow>(
+    /// Accounts that are not archived and had no transaction or session since `inactive_since`
+    pub async fn get_dormant_accounts(
+        &mut self,
+        inactive_since: DateTime<Utc>,
+    ) -> ServiceResult<Vec<models::DormantAccount>> {
+        let mut r = sqlx::query_as::<_, DormantAccountRow>(
             r#"
+            WITH
+                activity AS (
+                    SELECT account_id, max(timestamp) AS last_activity FROM transaction_item GROUP BY account_id
+                    UNION ALL
+                    SELECT account_id, max(last_used_at) AS last_activity FROM session GROUP BY account_id
+                ),
+                last_activity AS (
+                    SELECT account_id, max(last_activity) AS last_activity FROM activity GROUP BY account_id
+                )
             SELECT
                 a.id, a.balance_cents, a.balance_coffee_stamps, a.balance_bottle_stamps,
-                a.name, a.email, a.role,
+                a.name, a.email, a.role_id, account_role.name AS role_name, account_role.permissions AS role_permissions,
                 coalesce(array_agg(account_auth_method.data ORDER BY account_auth_method.id ASC) FILTER (where account_auth_method.id IS NOT NULL), '{}') AS auth_methods,
-                a.enable_monthly_mail_report, a.enable_automatic_stamp_usage,
+                a.enable_monthly_mail_report, a.enable_automatic_stamp_usage, a.archived_at,
                 (array_agg(account_status.id))[1] as status_id,
                 (array_agg(account_status.name))[1] as status_name,
                 (array_agg(account_status.color))[1] as status_color,
-                (array_agg(account_status.priority))[1] as status_priority
+                (array_agg(account_status.priority))[1] as status_priority,
+                last_activity.last_activity
             FROM account AS a
                 LEFT OUTER JOIN account_auth_method ON a.id = account_auth_method.account_id
                 LEFT OUTER JOIN account_status on a.status_id = account_status.id
-            WHERE a.id = $1
-            GROUP BY a.id
-        "#)
-        .bind(i64::try_from(id).expect("account id is less than 2**63"))
-        .fetch_optional(self.connection.as_mut())
-        .await;
-        let r = to_service_result(r)?;
+                LEFT OUTER JOIN last_activity ON a.id = last_activity.account_id
+                INNER JOIN account_role ON a.role_id = account_role.id
+            WHERE a.archived_at IS NULL
+                AND (last_activity.last_activity IS NULL OR last_activity.last_activity < $1)
+            GROUP BY a.id, account_role.id, last_activity.last_activity
+            ORDER BY last_activity.last_activity ASC NULLS FIRST, a.id ASC
+        "#,
+        )
+        .bind(inactive_since)
+        .fetch(self.connection.as_mut());
 
-        Ok(r.map(Account::from))
+        let mut out = Vec::new();
+        while let Some(row) = r.next().await {
+            let row = to_service_result(row)?;
+            out.push(row.into());
+        }
+        Ok(out)
     }
 
-    pub async fn get_account_by_auth_method(
-        &mut self,
-        auth_method: AuthRequest,
-    ) -> ServiceResult<Option<models::Account>> {
+    pub async fn get_account_by_id(&mut self, id: u64) -> ServiceResult<Option<models::Account>> {
         let r = sqlx::query_as::<_, AccountRow>(
             r#"
-            WITH
-                matching AS (SELECT account_id FROM account_auth_method WHERE login_key = $1)
             SELECT
                 a.id, a.balance_cents, a.balance_coffee_stamps, a.balance_bottle_stamps,
-                a.name, a.email, a.role,
+                a.name, a.email, a.role_id, account_role.name AS role_name, account_role.permissions AS role_permissions,
                 coalesce(array_agg(account_auth_method.data ORDER BY account_auth_method.id ASC) FILTER (where account_auth_method.id IS NOT NULL), '{}') AS auth_methods,
-                a.enable_monthly_mail_report, a.enable_automatic_stamp_usage,
+                a.enable_monthly_mail_report, a.enable_automatic_stamp_usage, a.archived_at,
                 (array_agg(account_status.id))[1] as status_id,
                 (array_agg(account_status.name))[1] as status_name,
                 (array_agg(account_status.color))[1] as status_color,
                 (array_agg(account_status.priority))[1] as status_priority
-            FROM account AS a INNER JOIN matching ON matching.account_id = a.id
+            FROM account AS a
                 LEFT OUTER JOIN account_auth_method ON a.id = account_auth_method.account_id
                 LEFT OUTER JOIN account_status on a.status_id = account_status.id
-            GROUP BY a.id
+                INNER JOIN account_role ON a.role_id = account_role.id
+            WHERE a.id = $1
+            GROUP BY a.id, account_role.id
         "#)
-        .bind(auth_method.login_key())
+        .bind(i64::try_from(id).expect("account id is less than 2**63"))
         .fetch_optional(self.connection.as_mut())
         .await;
         let r = to_service_result(r)?;
 
+        if r.is_none() {
+            if let Some(target) = self.get_account_merged_into(id).await? {
+                return Err(ServiceError::AccountMerged(target));
+            }
+        }
+
         Ok(r.map(Account::from))
     }
 
-    pub async fn create_session_token(
-        &mut self,
-        account: u64,
-        auth_method: models::AuthMethodType,
-        valid_until: DateTime<Utc>,
-        is_single_use: bool,
-    ) -> ServiceResult<String> {
+    /// Replace all personal data of the account, transactions stay attributed to the account id.
+    ///
+    /// Auth methods, sessions, wallet passes and pending nfc provisionings are removed. Name, email and
+    /// usernames in the audit log, ip addresses in the impersonation log and pending invitations are scrubbed.
+    pub async fn anonymize_account(&mut self, id: u64, pseudonym: &str) -> ServiceResult<()> {
+        let id = i64::try_from(id).expect("account id is less than 2**63");
+        let mut transaction = self.connection.begin().await?;
+
         let r = sqlx::query(
             r#"
-            INSERT INTO session (account_id, auth_method, valid_until, is_single_use) VALUES
-                ($1, $2, $3, $4)
-            RETURNING CAST(uuid AS TEXT)
+            WITH previous AS (SELECT email FROM account WHERE id = $1 FOR UPDATE)
+            UPDATE account
+            SET name = $2, email = '', status_id = NULL, enable_monthly_mail_report = FALSE
+            FROM previous
+            WHERE id = $1
+            RETURNING previous.email
         "#,
         )
-        .bind(i64::try_from(account).expect("account id is less than 2**63"))
-        .bind(AuthMethodTypeDto::from(auth_method))
-        .bind(valid_until)
-        .bind(is_single_use)
-        .fetch_one(self.connection.as_mut())
+        .bind(id)
+        .bind(pseudonym)
+        .fetch_optional(transaction.as_mut())
         .await;
-        let r = to_service_result(r)?;
+        let Some(row) = to_service_result(r)? else {
+            return Err(ServiceError::NotFound);
+        };
+        let email: String = row.get(0);
 
-        Ok(r.get(0))
-    }
+        let r = sqlx::query(
+            r#"
+            WITH
+                auth_methods AS (DELETE FROM account_auth_method WHERE account_id = $1),
+                passes AS (DELETE FROM apple_wallet_pass WHERE account_id = $1),
+                google_objects AS (DELETE FROM google_wallet_object WHERE account_id = $1),
+                provisionings AS (DELETE FROM nfc_provisioning WHERE account_id = $1)
+            DELETE FROM session WHERE account_id = $1
+        "#,
+        )
+        .bind(id)
+        .execute(transaction.as_mut())
+        .await;
+        to_service_result(r)?;
+
+        // The audit log is append-only, personal data may only be rewritten by this redaction
+        let r = sqlx::query(r#"SELECT set_config('ascii_pay.audit_log_redaction', 'on', true)"#)
+            .execute(transaction.as_mut())
+            .await;
+        to_service_result(r)?;
 
-    pub async fn delete_session_token(&mut self, session_token: String) -> ServiceResult<()> {
         let r = sqlx::query(
             r#"
-            DELETE FROM session WHERE uuid = CAST($1 as UUID)
+            WITH
+                audit_log_entries AS (
+                    UPDATE audit_log
+                    SET before = fn_audit_log_redact(before, $2), after = fn_audit_log_redact(after, $2)
+                    WHERE (target_type = 'account' AND target_id = $1)
+                        OR ($3 <> '' AND (before->>'email' = $3 OR after->>'email' = $3))
+                ),
+                impersonations AS (
+                    UPDATE impersonation_log SET ip_address = NULL
+                    WHERE account_id = $1 OR admin_account_id = $1
+                )
+            DELETE FROM invitation WHERE $3 <> '' AND email = $3
         "#,
         )
-        .bind(session_token)
-        .execute(self.connection.as_mut())
+        .bind(id)
+        .bind(pseudonym)
+        .bind(&email)
+        .execute(transaction.as_mut())
         .await;
         to_service_result(r)?;
+
+        transaction.commit().await?;
         Ok(())
     }
 
-    pub async fn cleanup_session_tokens(&mut self) -> ServiceResult<()> {
+    /// Mark the account as archived and remove everything that allows to log in.
+    ///
+    /// Name, email and transactions are kept for bookkeeping.
+    pub async fn archive_account(&mut self, id: u64) -> ServiceResult<()> {
+        let id = i64::try_from(id).expect("account id is less than 2**63");
+        let mut transaction = self.connection.begin().await?;
+
         let r = sqlx::query(
             r#"
-            DELETE FROM session WHERE valid_until < now()
+            UPDATE account
+            SET archived_at = now(), enable_monthly_mail_report = FALSE
+            WHERE id = $1 AND archived_at IS NULL
         "#,
         )
-        .execute(self.connection.as_mut())
+        .bind(id)
+        .execute(transaction.as_mut())
+        .await;
+        if to_service_result(r)?.rows_affected() != 1 {
+            return Err(ServiceError::NotFound);
+        }
+
+        let r = sqlx::query(
+            r#"
+            WITH
+                auth_methods AS (DELETE FROM account_auth_method WHERE account_id = $1),
+                passes AS (DELETE FROM apple_wallet_pass WHERE account_id = $1),
+                google_objects AS (DELETE FROM google_wallet_object WHERE account_id = $1),
+                provisionings AS (DELETE FROM nfc_provisioning WHERE account_id = $1)
+            DELETE FROM session WHERE account_id = $1
+        "#,
+        )
+        .bind(id)
+        .execute(transaction.as_mut())
         .await;
         to_service_result(r)?;
+
+        transaction.commit().await?;
         Ok(())
     }
 
-    pub async fn get_session_by_session_token(
-        &mut self,
-        session_token: String,
-    ) -> ServiceResult<Option<models::Session>> {
-        let r = sqlx::query_as::<_, SessionRow>(r#"
-            WITH
-                fulL_account AS (
-                    SELECT
-                        a.id, a.balance_cents, a.balance_coffee_stamps, a.balance_bottle_stamps,
-                        a.name, a.email, a.role,
-                        coalesce(array_agg(account_auth_method.data ORDER BY account_auth_method.id ASC) FILTER (where account_auth_method.id IS NOT NULL), '{}') AS auth_methods,
-                        a.enable_monthly_mail_report, a.enable_automatic_stamp_usage,
-                        (array_agg(account_status.id))[1] as status_id,
-                        (array_agg(account_status.name))[1] as status_name,
-                        (array_agg(account_status.color))[1] as status_color,
-                        (array_agg(account_status.priority))[1] as status_priority
-                    FROM account AS a
-                        LEFT OUTER JOIN account_auth_method ON a.id = account_auth_method.account_id
-                        LEFT OUTER JOIN account_status on a.status_id = account_status.id
-                    GROUP BY a.id
-                )
-            SELECT CAST(session.uuid as TEXT) as uuid, session.auth_method, session.valid_until, session.is_single_use, full_account.*
-            FROM full_account INNER JOIN session on full_account.id = session.account_id
-            WHERE session.valid_until > now() AND session.uuid = CAST($1 as UUID)
-        "#)
-        .bind(session_token)
+    /// Id of the account the given account was merged into
+    pub async fn get_account_merged_into(&mut self, id: u64) -> ServiceResult<Option<u64>> {
+        let r = sqlx::query(
+            r#"SELECT target_account_id FROM account_merge WHERE source_account_id = $1"#,
+        )
+        .bind(i64::try_from(id).expect("account id is less than 2**63"))
         .fetch_optional(self.connection.as_mut())
         .await;
+        let r = to_service_result(r)?;
 
-        if let Ok(r) = r {
-            Ok(r.map(Session::from))
-        } else {
-            Ok(None)
-        }
+        Ok(r.map(|row| {
+            row.get::<i64, _>(0)
+                .try_into()
+                .expect("id in database is always positive")
+        }))
     }
 
-    pub async fn get_sessions_by_account(
+    /// Move balance, auth methods, transactions, wallet passes and purchases of `source` to `target` and delete `source`.
+    ///
+    /// If both accounts have a password, public tab or wallet pass of the same type, the one of `target` is kept.
+    pub async fn merge_accounts(
         &mut self,
-        account_id: u64,
-    ) -> ServiceResult<Vec<models::Session>> {
-        let account_id = i64::try_from(account_id).expect("ids must fit into i64");
-        let mut r = sqlx::query_as::<_, SessionRow>(r#"
-            WITH
-                fulL_account AS (
-                    SELECT
-                        a.id, a.balance_cents, a.balance_coffee_stamps, a.balance_bottle_stamps,
-                        a.name, a.email, a.role,
-                        coalesce(array_agg(account_auth_method.data ORDER BY account_auth_method.id ASC) FILTER (where account_auth_method.id IS NOT NULL), '{}') AS auth_methods,
-                        a.enable_monthly_mail_report, a.enable_automatic_stamp_usage,
-                        (array_agg(account_status.id))[1] as status_id,
-                        (array_agg(account_status.name))[1] as status_name,
-                        (array_agg(account_status.color))[1] as status_color,
-                        (array_agg(account_status.priority))[1] as status_priority
-                    FROM account AS a
-                        LEFT OUTER JOIN account_auth_method ON a.id = account_auth_method.account_id
-                        LEFT OUTER JOIN account_status on a.status_id = account_status.id
-                    GROUP BY a.id
-                )
-            SELECT CAST(session.uuid as TEXT) as uuid, session.auth_method, session.valid_until, session.is_single_use, full_account.*
-            FROM full_account INNER JOIN session on full_account.id = session.account_id
-            WHERE session.valid_until > now() AND session.account_id = $1
-        "#)
-        .bind(account_id)
-        .fetch(self.connection.as_mut());
+        target: u64,
+        source: u64,
+        merged_by: Option<u64>,
+    ) -> ServiceResult<()> {
+        let target_id = i64::try_from(target).expect("account id is less than 2**63");
+        let source_id = i64::try_from(source).expect("account id is less than 2**63");
 
-        let mut out = Vec::new();
-        while let Some(row) = r.next().await {
-            let row = to_service_result(row)?;
+        let mut transaction = self.connection.begin().await?;
 
-            out.push(row.into());
+        let r = sqlx::query(r#"SELECT id FROM account WHERE id = $1 OR id = $2 FOR UPDATE"#)
+            .bind(target_id)
+            .bind(source_id)
+            .fetch_all(transaction.as_mut())
+            .await;
+        if to_service_result(r)?.len() != 2 {
+            return Err(ServiceError::NotFound);
         }
-        Ok(out)
-    }
 
-    pub async fn store_account(
-        &mut self,
-        mut account: models::Account,
-    ) -> ServiceResult<models::Account> {
-        let q = if account.id == 0 {
-            sqlx::query(
-                r#"
-                INSERT INTO account (balance_cents, balance_coffee_stamps, balance_bottle_stamps, name, email, role, enable_monthly_mail_report, enable_automatic_stamp_usage, status_id)
-                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
-                RETURNING id
-            "#,
-            )
-        } else {
-            sqlx::query(r#"
-                WITH
-                    delete AS (DELETE FROM account_auth_method WHERE account_id = $1)
-                UPDATE account
-                SET balance_cents = $2, balance_coffee_stamps = $3, balance_bottle_stamps = $4, name = $5, email = $6, role = $7, enable_monthly_mail_report = $8, enable_automatic_stamp_usage = $9, status_id = $10
-                WHERE id = $1
-                RETURNING id
+        let r = sqlx::query(
+            r#"
+            UPDATE account AS a
+            SET
+                balance_cents = a.balance_cents + s.balance_cents,
+                balance_coffee_stamps = a.balance_coffee_stamps + s.balance_coffee_stamps,
+                balance_bottle_stamps = a.balance_bottle_stamps + s.balance_bottle_stamps
+            FROM account AS s
+            WHERE a.id = $1 AND s.id = $2
+        "#,
+        )
+        .bind(target_id)
+        .bind(source_id)
+        .execute(transaction.as_mut())
+        .await;
+        to_service_result(r)?;
 
-            "#).bind(i64::try_from(account.id).expect("account id is less than 2**63"))
-        };
-        let r = q
-            .bind(account.balance.0.get(&CoinType::Cent).unwrap_or(&0))
-            .bind(account.balance.0.get(&CoinType::CoffeeStamp).unwrap_or(&0))
-            .bind(account.balance.0.get(&CoinType::BottleStamp).unwrap_or(&0))
-            .bind(&account.name)
-            .bind(&account.email)
-            .bind(AccountRoleDto::from(account.role))
-            .bind(account.enable_monthly_mail_report)
-            .bind(account.enable_automatic_stamp_usage)
-            .bind(
-                account
-                    .status
-                    .as_ref()
-                    .map(|s| i64::try_from(s.id).expect("status id is less than 2**63")),
-            )
-            .fetch_one(self.connection.as_mut())
-            .await;
-        let r = to_service_result(r)?;
-        let account_id = r.get::<i64, _>(0);
-        account.id = account_id.try_into().expect("id is always positive");
+        let r = sqlx::query(
+            r#"
+            DELETE FROM account_auth_method AS s
+            WHERE s.account_id = $2 AND (s.data ? 'Password' OR s.data = '"PublicTab"')
+                AND EXISTS (
+                    SELECT 1 FROM account_auth_method AS t
+                    WHERE t.account_id = $1 AND (t.data ? 'Password') = (s.data ? 'Password')
+                        AND (t.data = '"PublicTab"') = (s.data = '"PublicTab"')
+                )
+        "#,
+        )
+        .bind(target_id)
+        .bind(source_id)
+        .execute(transaction.as_mut())
+        .await;
+        to_service_result(r)?;
 
         let r = sqlx::query(
             r#"
-            INSERT INTO account_auth_method (account_id, login_key, data, depends_on_session)
-            SELECT $1, login_key, data, CAST(depends_on_session AS UUID) as depends_on_session
-            FROM UNNEST($2, $3, $4) AS input (login_key, data, depends_on_session)
+            UPDATE account_auth_method
+            SET
+                account_id = $1,
+                login_key = CASE WHEN data = '"PublicTab"' THEN $3 ELSE login_key END
+            WHERE account_id = $2
         "#,
         )
-        .bind(account_id)
-        .bind(
-            account
-                .auth_methods
-                .iter()
-                .map(|m| m.to_request(account.id).login_key())
-                .collect::<Vec<_>>(),
+        .bind(target_id)
+        .bind(source_id)
+        .bind(AuthRequest::PublicTab { account_id: target }.login_key())
+        .execute(transaction.as_mut())
+        .await;
+        to_service_result(r)?;
+
+        let r = sqlx::query(
+            r#"
+            WITH
+                moved AS (
+                    UPDATE transaction_item SET account_id = $1 WHERE account_id = $2
+                ),
+                authorized AS (
+                    UPDATE transaction_item SET authorized_by_account_id = $1 WHERE authorized_by_account_id = $2
+                ),
+                purchases AS (
+                    UPDATE purchase SET purchased_by_account_id = $1 WHERE purchased_by_account_id = $2
+                ),
+                invitations AS (
+                    UPDATE invitation SET invited_by_account_id = $1 WHERE invited_by_account_id = $2
+                ),
+                vouchers AS (
+                    UPDATE voucher SET account_id = $1 WHERE account_id = $2
+                )
+            UPDATE account_merge SET target_account_id = $1 WHERE target_account_id = $2
+        "#,
         )
-        .bind(
-            account
-                .auth_methods
-                .iter()
-                .map(|m| {
-                    serde_json::to_value(AccountAuthMethodData::from(m.clone()))
-                        .expect("to json cannot fail")
-                })
-                .collect::<Vec<_>>(),
+        .bind(target_id)
+        .bind(source_id)
+        .execute(transaction.as_mut())
+        .await;
+        to_service_result(r)?;
+
+        // Registrations reference the pass, so the pass is copied before the source account is deleted.
+        // The google wallet object of the source is kept if the target has none.
+        let r = sqlx::query(
+            r#"
+            WITH
+                google AS (
+                    INSERT INTO google_wallet_object (account_id, object_id, created_at)
+                    SELECT $1, object_id, created_at FROM google_wallet_object WHERE account_id = $2
+                    ON CONFLICT DO NOTHING
+                ),
+                moved AS (
+                    INSERT INTO apple_wallet_pass (account_id, pass_type_id, authentication_token, qr_code, updated_at)
+                    SELECT $1, pass_type_id, authentication_token, qr_code, $3
+                    FROM apple_wallet_pass WHERE account_id = $2
+                    ON CONFLICT DO NOTHING
+                    RETURNING pass_type_id
+                )
+            INSERT INTO apple_wallet_registration (account_id, pass_type_id, device_id, push_token)
+            SELECT $1, r.pass_type_id, r.device_id, r.push_token
+            FROM apple_wallet_registration AS r INNER JOIN moved ON r.pass_type_id = moved.pass_type_id
+            WHERE r.account_id = $2
+            ON CONFLICT DO NOTHING
+        "#,
         )
+        .bind(target_id)
+        .bind(source_id)
         .bind(
-            account
-                .auth_methods
-                .iter()
-                .map(|m| {
-                    if let AuthMethod::NfcBased(ref nfc_based) = m {
-                        nfc_based.depends_on_session.clone()
-                    } else {
-                        None
-                    }
-                })
-                .collect::<Vec<_>>(),
+            i64::try_from(crate::wallet::get_current_time()).expect("timestamp is less than 2**63"),
         )
-        .execute(self.connection.as_mut())
+        .execute(transaction.as_mut())
         .await;
         to_service_result(r)?;
 
-        Ok(account)
-    }
-
-    pub async fn delete_account(&mut self, id: u64) -> ServiceResult<()> {
+        // Memberships of the target win, memberships between both accounts are dropped
         let r = sqlx::query(
             r#"
-            DELETE FROM account WHERE id = $1
+            INSERT INTO account_group_member (group_account_id, member_account_id, is_admin, spending_limit_cents)
+            SELECT
+                CASE WHEN group_account_id = $2 THEN $1 ELSE group_account_id END,
+                CASE WHEN member_account_id = $2 THEN $1 ELSE member_account_id END,
+                is_admin, spending_limit_cents
+            FROM account_group_member
+            WHERE (group_account_id = $2 AND member_account_id <> $1)
+                OR (member_account_id = $2 AND group_account_id <> $1)
+            ON CONFLICT DO NOTHING
         "#,
         )
-        .bind(i64::try_from(id).expect("account id is less than 2**63"))
-        .execute(self.connection.as_mut())
+        .bind(target_id)
+        .bind(source_id)
+        .execute(transaction.as_mut())
         .await;
         to_service_result(r)?;
-        Ok(())
-    }
 
-    pub async fn get_all_account_status(&mut self) -> ServiceResult<Vec<models::AccountStatus>> {
-        let mut r = sqlx::query_as::<_, AccountStatusRow>(
+        // The stricter restriction of both accounts applies to the merged account
+        let r = sqlx::query(
             r#"
-            SELECT
-                id, name, priority, color
-            FROM account_status
-            "#,
+            WITH
+                policy AS (
+                    INSERT INTO account_spending_policy (account_id, blocked_product_tags, locked)
+                    SELECT $1, blocked_product_tags, locked FROM account_spending_policy WHERE account_id = $2
+                    ON CONFLICT (account_id) DO UPDATE
+                    SET
+                        blocked_product_tags = ARRAY(
+                            SELECT DISTINCT unnest(account_spending_policy.blocked_product_tags || EXCLUDED.blocked_product_tags)
+                        ),
+                        locked = account_spending_policy.locked OR EXCLUDED.locked
+                )
+            INSERT INTO account_spending_limit (account_id, period, limit_cents, limit_coffee_stamps, limit_bottle_stamps)
+            SELECT $1, period, limit_cents, limit_coffee_stamps, limit_bottle_stamps
+            FROM account_spending_limit WHERE account_id = $2
+            ON CONFLICT (account_id, period) DO UPDATE
+            SET
+                limit_cents = LEAST(account_spending_limit.limit_cents, EXCLUDED.limit_cents),
+                limit_coffee_stamps = LEAST(account_spending_limit.limit_coffee_stamps, EXCLUDED.limit_coffee_stamps),
+                limit_bottle_stamps = LEAST(account_spending_limit.limit_bottle_stamps, EXCLUDED.limit_bottle_stamps)
+        "#,
         )
-        .fetch(self.connection.as_mut());
+        .bind(target_id)
+        .bind(source_id)
+        .execute(transaction.as_mut())
+        .await;
+        to_service_result(r)?;
 
-        let mut out = Vec::new();
-        while let Some(row) = r.next().await {
-            let row = to_service_result(row)?;
-            out.push(row.into());
+        let r = sqlx::query(
+            r#"
+            WITH
+                merged AS (
+                    INSERT INTO account_merge (source_account_id, target_account_id, merged_by_account_id)
+                    VALUES ($2, $1, $3)
+                )
+            DELETE FROM account WHERE id = $2
+        "#,
+        )
+        .bind(target_id)
+        .bind(source_id)
+        .bind(merged_by.map(|id| i64::try_from(id).expect("account id is less than 2**63")))
+        .execute(transaction.as_mut())
+        .await;
+        to_service_result(r)?;
+
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    /// Accounts with the given email address, compared case insensitive
+    pub async fn get_accounts_by_email(
+        &mut self,
+        email: &str,
+    ) -> ServiceResult<Vec<models::Account>> {
+        let r = sqlx::query_as::<_, AccountRow>(
+            r#"
+            SELECT
+                a.id, a.balance_cents, a.balance_coffee_stamps, a.balance_bottle_stamps,
+                a.name, a.email, a.role_id, account_role.name AS role_name, account_role.permissions AS role_permissions,
+                coalesce(array_agg(account_auth_method.data ORDER BY account_auth_method.id ASC) FILTER (where account_auth_method.id IS NOT NULL), '{}') AS auth_methods,
+                a.enable_monthly_mail_report, a.enable_automatic_stamp_usage, a.archived_at,
+                (array_agg(account_status.id))[1] as status_id,
+                (array_agg(account_status.name))[1] as status_name,
+                (array_agg(account_status.color))[1] as status_color,
+                (array_agg(account_status.priority))[1] as status_priority
+            FROM account AS a
+                LEFT OUTER JOIN account_auth_method ON a.id = account_auth_method.account_id
+                LEFT OUTER JOIN account_status on a.status_id = account_status.id
+                INNER JOIN account_role ON a.role_id = account_role.id
+            WHERE a.email <> '' AND lower(a.email) = lower($1)
+            GROUP BY a.id, account_role.id
+        "#)
+        .bind(email)
+        .fetch_all(self.connection.as_mut())
+        .await;
+        let r = to_service_result(r)?;
+
+        Ok(r.into_iter().map(Account::from).collect())
+    }
+
+    pub async fn get_account_by_auth_method(
+        &mut self,
+        auth_method: AuthRequest,
+    ) -> ServiceResult<Option<models::Account>> {
+        let r = sqlx::query_as::<_, AccountRow>(
+            r#"
+            WITH
+                matching AS (SELECT account_id FROM account_auth_method WHERE login_key = $1)
+            SELECT
+                a.id, a.balance_cents, a.balance_coffee_stamps, a.balance_bottle_stamps,
+                a.name, a.email, a.role_id, account_role.name AS role_name, account_role.permissions AS role_permissions,
+                coalesce(array_agg(account_auth_method.data ORDER BY account_auth_method.id ASC) FILTER (where account_auth_method.id IS NOT NULL), '{}') AS auth_methods,
+                a.enable_monthly_mail_report, a.enable_automatic_stamp_usage, a.archived_at,
+                (array_agg(account_status.id))[1] as status_id,
+                (array_agg(account_status.name))[1] as status_name,
+                (array_agg(account_status.color))[1] as status_color,
+                (array_agg(account_status.priority))[1] as status_priority
+            FROM account AS a INNER JOIN matching ON matching.account_id = a.id
+                LEFT OUTER JOIN account_auth_method ON a.id = account_auth_method.account_id
+                LEFT OUTER JOIN account_status on a.status_id = account_status.id
+                INNER JOIN account_role ON a.role_id = account_role.id
+            GROUP BY a.id, account_role.id
+        "#)
+        .bind(auth_method.login_key())
+        .fetch_optional(self.connection.as_mut())
+        .await;
+        let r = to_service_result(r)?;
+
+        Ok(r.map(Account::from))
+    }
+
+    pub async fn create_session_token(
+        &mut self,
+        account: u64,
+        auth_method: models::AuthMethodType,
+        lifetime: models::SessionLifetime,
+        is_single_use: bool,
+        client: &models::SessionClient,
+    ) -> ServiceResult<String> {
+        let (valid_until, sliding_expiry_seconds) = match lifetime {
+            models::SessionLifetime::Fixed(valid_until) => (valid_until, None),
+            models::SessionLifetime::Sliding(duration) => (
+                Utc::now() + duration,
+                Some(
+                    i32::try_from(duration.num_seconds())
+                        .expect("session lifetime is less than 2**31 seconds"),
+                ),
+            ),
+        };
+
+        let r = sqlx::query(
+            r#"
+            INSERT INTO session (account_id, auth_method, valid_until, sliding_expiry_seconds, is_single_use, user_agent, ip_address, device_name) VALUES
+                ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING CAST(uuid AS TEXT)
+        "#,
+        )
+        .bind(i64::try_from(account).expect("account id is less than 2**63"))
+        .bind(AuthMethodTypeDto::from(auth_method))
+        .bind(valid_until)
+        .bind(sliding_expiry_seconds)
+        .bind(is_single_use)
+        .bind(&client.user_agent)
+        .bind(&client.ip_address)
+        .bind(&client.device_name)
+        .fetch_one(self.connection.as_mut())
+        .await;
+        let r = to_service_result(r)?;
+
+        Ok(r.get(0))
+    }
+
+    /// Create a single use session that can only be used for one payment up to the given limit
+    pub async fn create_payment_token(
+        &mut self,
+        account: u64,
+        auth_method: models::AuthMethodType,
+        valid_until: DateTime<Utc>,
+        payment_limit: &CoinAmount,
+        impersonated_by: Option<u64>,
+        client: &models::SessionClient,
+    ) -> ServiceResult<String> {
+        let limit = |t: CoinType| payment_limit.0.get(&t).copied().unwrap_or(0);
+
+        let r = sqlx::query(
+            r#"
+            INSERT INTO session (
+                account_id, auth_method, valid_until, is_single_use, user_agent, ip_address, device_name,
+                payment_limit_cents, payment_limit_bottle_stamps, payment_limit_coffee_stamps, impersonated_by_account_id
+            ) VALUES
+                ($1, $2, $3, TRUE, $4, $5, $6, $7, $8, $9, $10)
+            RETURNING CAST(uuid AS TEXT)
+        "#,
+        )
+        .bind(i64::try_from(account).expect("account id is less than 2**63"))
+        .bind(AuthMethodTypeDto::from(auth_method))
+        .bind(valid_until)
+        .bind(&client.user_agent)
+        .bind(&client.ip_address)
+        .bind(&client.device_name)
+        .bind(limit(CoinType::Cent))
+        .bind(limit(CoinType::BottleStamp))
+        .bind(limit(CoinType::CoffeeStamp))
+        .bind(impersonated_by.map(|id| i64::try_from(id).expect("account id is less than 2**63")))
+        .fetch_one(self.connection.as_mut())
+        .await;
+        let r = to_service_result(r)?;
+
+        Ok(r.get(0))
+    }
+
+    /// Create a session for `admin` to act on behalf of `account`
+    pub async fn create_impersonation_session(
+        &mut self,
+        account: u64,
+        admin: u64,
+        valid_until: DateTime<Utc>,
+        client: &models::SessionClient,
+    ) -> ServiceResult<String> {
+        let r = sqlx::query(
+            r#"
+            INSERT INTO session (account_id, auth_method, valid_until, is_single_use, user_agent, ip_address, device_name, impersonated_by_account_id) VALUES
+                ($1, $2, $3, FALSE, $4, $5, $6, $7)
+            RETURNING CAST(uuid AS TEXT)
+        "#,
+        )
+        .bind(i64::try_from(account).expect("account id is less than 2**63"))
+        .bind(AuthMethodTypeDto::Impersonation)
+        .bind(valid_until)
+        .bind(&client.user_agent)
+        .bind(&client.ip_address)
+        .bind(&client.device_name)
+        .bind(i64::try_from(admin).expect("account id is less than 2**63"))
+        .fetch_one(self.connection.as_mut())
+        .await;
+        let r = to_service_result(r)?;
+
+        Ok(r.get(0))
+    }
+
+    /// Delete a single use session, returns `false` if the session was already consumed by another request
+    pub async fn consume_single_use_session(&mut self, session_token: &str) -> ServiceResult<bool> {
+        let r = sqlx::query(
+            r#"
+            DELETE FROM session WHERE uuid = CAST($1 as UUID) AND is_single_use
+        "#,
+        )
+        .bind(session_token)
+        .execute(self.connection.as_mut())
+        .await;
+        let r = to_service_result(r)?;
+
+        Ok(r.rows_affected() == 1)
+    }
+
+    pub async fn delete_session_token(&mut self, session_token: String) -> ServiceResult<()> {
+        let r = sqlx::query(
+            r#"
+            DELETE FROM session WHERE uuid = CAST($1 as UUID)
+        "#,
+        )
+        .bind(session_token)
+        .execute(self.connection.as_mut())
+        .await;
+        to_service_result(r)?;
+        Ok(())
+    }
+
+    pub async fn delete_session_by_id(
+        &mut self,
+        account_id: u64,
+        session_id: &str,
+    ) -> ServiceResult<()> {
+        let r = sqlx::query(
+            r#"
+            DELETE FROM session WHERE account_id = $1 AND CAST(public_id AS TEXT) = $2
+        "#,
+        )
+        .bind(i64::try_from(account_id).expect("account id is less than 2**63"))
+        .bind(session_id)
+        .execute(self.connection.as_mut())
+        .await;
+        let r = to_service_result(r)?;
+        if r.rows_affected() != 1 {
+            return Err(ServiceError::NotFound);
+        }
+        Ok(())
+    }
+
+    /// Delete all sessions of the account except the session with the given token
+    pub async fn delete_other_sessions(
+        &mut self,
+        account_id: u64,
+        keep_session_token: &str,
+    ) -> ServiceResult<()> {
+        let r = sqlx::query(
+            r#"
+            DELETE FROM session WHERE account_id = $1 AND CAST(uuid AS TEXT) <> $2
+        "#,
+        )
+        .bind(i64::try_from(account_id).expect("account id is less than 2**63"))
+        .bind(keep_session_token)
+        .execute(self.connection.as_mut())
+        .await;
+        to_service_result(r)?;
+        Ok(())
+    }
+
+    pub async fn set_session_device_name(
+        &mut self,
+        account_id: u64,
+        session_id: &str,
+        device_name: Option<String>,
+    ) -> ServiceResult<()> {
+        let r = sqlx::query(
+            r#"
+            UPDATE session SET device_name = $3
+            WHERE account_id = $1 AND CAST(public_id AS TEXT) = $2 AND valid_until > now()
+        "#,
+        )
+        .bind(i64::try_from(account_id).expect("account id is less than 2**63"))
+        .bind(session_id)
+        .bind(device_name)
+        .execute(self.connection.as_mut())
+        .await;
+        let r = to_service_result(r)?;
+        if r.rows_affected() != 1 {
+            return Err(ServiceError::NotFound);
+        }
+        Ok(())
+    }
+
+    /// Attach a refresh token to the session, returns the refresh token
+    pub async fn create_refresh_token(
+        &mut self,
+        session_token: &str,
+        refresh_valid_until: DateTime<Utc>,
+    ) -> ServiceResult<String> {
+        let r = sqlx::query(
+            r#"
+            UPDATE session SET refresh_token = gen_random_uuid(), refresh_valid_until = $2
+            WHERE CAST(uuid AS TEXT) = $1
+            RETURNING CAST(refresh_token AS TEXT)
+        "#,
+        )
+        .bind(session_token)
+        .bind(refresh_valid_until)
+        .fetch_optional(self.connection.as_mut())
+        .await;
+
+        match to_service_result(r)? {
+            Some(row) => Ok(row.get(0)),
+            None => Err(ServiceError::NotFound),
+        }
+    }
+
+    /// Replace session token and refresh token of the session that belongs to the given refresh token.
+    ///
+    /// Returns the new session token and the new refresh token.
+    pub async fn refresh_session_token(
+        &mut self,
+        refresh_token: &str,
+        refresh_valid_until: DateTime<Utc>,
+    ) -> ServiceResult<Option<(String, String)>> {
+        let mut transaction = self.connection.begin().await?;
+
+        let r = sqlx::query(
+            r#"
+            WITH
+                previous AS (
+                    SELECT uuid FROM session
+                    WHERE CAST(refresh_token AS TEXT) = $1 AND refresh_valid_until > now()
+                    FOR UPDATE
+                )
+            UPDATE session
+            SET
+                uuid = gen_random_uuid(),
+                refresh_token = gen_random_uuid(),
+                refresh_valid_until = $2,
+                last_used_at = now(),
+                valid_until = CASE
+                    WHEN sliding_expiry_seconds IS NULL THEN valid_until
+                    ELSE now() + make_interval(secs => sliding_expiry_seconds)
+                END
+            FROM previous
+            WHERE session.uuid = previous.uuid
+            RETURNING CAST(previous.uuid AS TEXT), CAST(session.uuid AS TEXT), CAST(session.refresh_token AS TEXT)
+        "#,
+        )
+        .bind(refresh_token)
+        .bind(refresh_valid_until)
+        .fetch_optional(transaction.as_mut())
+        .await;
+
+        let Some(row) = to_service_result(r)? else {
+            return Ok(None);
+        };
+        let previous_token: String = row.get(0);
+        let session_token: String = row.get(1);
+        let refresh_token: String = row.get(2);
+
+        // The foreign key column follows the new token, the json copy has to be updated manually
+        let r = sqlx::query(
+            r#"
+            UPDATE account_auth_method
+            SET data = jsonb_set(data, '{Nfc,depends_on_session}', to_jsonb($2::TEXT))
+            WHERE data->'Nfc'->>'depends_on_session' = $1
+        "#,
+        )
+        .bind(&previous_token)
+        .bind(&session_token)
+        .execute(transaction.as_mut())
+        .await;
+        to_service_result(r)?;
+
+        to_service_result(transaction.commit().await)?;
+        Ok(Some((session_token, refresh_token)))
+    }
+
+    pub async fn cleanup_session_tokens(&mut self) -> ServiceResult<()> {
+        let r = sqlx::query(
+            r#"
+            DELETE FROM session
+            WHERE valid_until < now() AND (refresh_valid_until IS NULL OR refresh_valid_until < now())
+        "#,
+        )
+        .execute(self.connection.as_mut())
+        .await;
+        to_service_result(r)?;
+        Ok(())
+    }
+
+    /// Count a hit for `key` and check whether at most `max_hits` hits happened within `window`
+    pub async fn rate_limit_hit(
+        &mut self,
+        key: &str,
+        window: Duration,
+        max_hits: u32,
+    ) -> ServiceResult<bool> {
+        let r = sqlx::query(
+            r#"
+            WITH inserted AS (
+                INSERT INTO rate_limit_event (key, expires_at) VALUES ($1, $2)
+            )
+            SELECT count(*) FROM rate_limit_event WHERE key = $1 AND expires_at > now()
+        "#,
+        )
+        .bind(key)
+        .bind(Utc::now() + window)
+        .fetch_one(self.connection.as_mut())
+        .await;
+        let r = to_service_result(r)?;
+
+        // the inserted row is not visible within the same statement
+        Ok(r.get::<i64, _>(0) < i64::from(max_hits))
+    }
+
+    pub async fn cleanup_rate_limit_events(&mut self) -> ServiceResult<()> {
+        let r = sqlx::query(
+            r#"
+            DELETE FROM rate_limit_event WHERE expires_at < now()
+        "#,
+        )
+        .execute(self.connection.as_mut())
+        .await;
+        to_service_result(r)?;
+        Ok(())
+    }
+
+    pub async fn append_audit_log(
+        &mut self,
+        entry: models::AuditLogEntry,
+    ) -> ServiceResult<models::AuditLogEntry> {
+        let to_db_id = |id: u64| i64::try_from(id).expect("id is less than 2**63");
+        let r = sqlx::query(
+            r#"
+            INSERT INTO audit_log (actor_account_id, impersonated_account_id, action, target_type, target_id, before, after)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING id, timestamp
+        "#,
+        )
+        .bind(entry.actor_account_id.map(to_db_id))
+        .bind(entry.impersonated_account_id.map(to_db_id))
+        .bind(&entry.action)
+        .bind(&entry.target_type)
+        .bind(entry.target_id.map(to_db_id))
+        .bind(&entry.before)
+        .bind(&entry.after)
+        .fetch_one(self.connection.as_mut())
+        .await;
+        let r = to_service_result(r)?;
+
+        Ok(models::AuditLogEntry {
+            id: r
+                .get::<i64, _>(0)
+                .try_into()
+                .expect("id is always positive"),
+            timestamp: r.get(1),
+            ..entry
+        })
+    }
+
+    /// Audit log entries matching the filter, newest first
+    pub async fn get_audit_log(
+        &mut self,
+        filter: &models::AuditLogFilter,
+    ) -> ServiceResult<Vec<models::AuditLogEntry>> {
+        let to_db_id = |id: u64| i64::try_from(id).expect("id is less than 2**63");
+        let r = sqlx::query_as::<_, AuditLogRow>(
+            r#"
+            SELECT id, timestamp, actor_account_id, impersonated_account_id, action, target_type, target_id, before, after
+            FROM audit_log
+            WHERE
+                ($1::BIGINT IS NULL OR actor_account_id = $1)
+                AND ($2::TEXT IS NULL OR action = $2)
+                AND ($3::TEXT IS NULL OR target_type = $3)
+                AND ($4::BIGINT IS NULL OR target_id = $4)
+                AND ($5::TIMESTAMPTZ IS NULL OR timestamp >= $5)
+                AND ($6::TIMESTAMPTZ IS NULL OR timestamp < $6)
+            ORDER BY id DESC
+            LIMIT $7
+        "#,
+        )
+        .bind(filter.actor_account_id.map(to_db_id))
+        .bind(&filter.action)
+        .bind(&filter.target_type)
+        .bind(filter.target_id.map(to_db_id))
+        .bind(filter.from)
+        .bind(filter.to)
+        .bind(to_db_id(filter.limit))
+        .fetch_all(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?
+            .into_iter()
+            .map(AuditLogEntry::from)
+            .collect())
+    }
+
+    /// Record an action of an impersonation session, sessions of other types are ignored
+    pub async fn log_impersonation(
+        &mut self,
+        session: &models::Session,
+        action: &str,
+        client: &models::SessionClient,
+    ) -> ServiceResult<()> {
+        let Some(admin) = session.impersonated_by else {
+            return Ok(());
+        };
+
+        let r = sqlx::query(
+            r#"
+            INSERT INTO impersonation_log (admin_account_id, account_id, session_id, action, ip_address)
+            VALUES ($1, $2, CAST($3 AS UUID), $4, $5)
+        "#,
+        )
+        .bind(i64::try_from(admin).expect("account id is less than 2**63"))
+        .bind(i64::try_from(session.account.id).expect("account id is less than 2**63"))
+        .bind(&session.id)
+        .bind(action)
+        .bind(&client.ip_address)
+        .execute(self.connection.as_mut())
+        .await;
+        to_service_result(r)?;
+        Ok(())
+    }
+
+    /// Impersonation log entries, newest first. Filters by the impersonated account if given.
+    pub async fn get_impersonation_log(
+        &mut self,
+        account_id: Option<u64>,
+    ) -> ServiceResult<Vec<models::ImpersonationLogEntry>> {
+        let r = sqlx::query_as::<_, ImpersonationLogRow>(
+            r#"
+            SELECT id, admin_account_id, account_id, CAST(session_id AS TEXT) AS session_id, action, ip_address, timestamp
+            FROM impersonation_log
+            WHERE $1::BIGINT IS NULL OR account_id = $1
+            ORDER BY id DESC
+        "#,
+        )
+        .bind(account_id.map(|id| i64::try_from(id).expect("account id is less than 2**63")))
+        .fetch_all(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?
+            .into_iter()
+            .map(ImpersonationLogEntry::from)
+            .collect())
+    }
+
+    pub async fn get_session_by_session_token(
+        &mut self,
+        session_token: String,
+    ) -> ServiceResult<Option<models::Session>> {
+        let r = sqlx::query_as::<_, SessionRow>(r#"
+            WITH
+                fulL_account AS (
+                    SELECT
+                        a.id, a.balance_cents, a.balance_coffee_stamps, a.balance_bottle_stamps,
+                        a.name, a.email, a.role_id, account_role.name AS role_name, account_role.permissions AS role_permissions,
+                        coalesce(array_agg(account_auth_method.data ORDER BY account_auth_method.id ASC) FILTER (where account_auth_method.id IS NOT NULL), '{}') AS auth_methods,
+                        a.enable_monthly_mail_report, a.enable_automatic_stamp_usage, a.archived_at,
+                        (array_agg(account_status.id))[1] as status_id,
+                        (array_agg(account_status.name))[1] as status_name,
+                        (array_agg(account_status.color))[1] as status_color,
+                        (array_agg(account_status.priority))[1] as status_priority
+                    FROM account AS a
+                        LEFT OUTER JOIN account_auth_method ON a.id = account_auth_method.account_id
+                        LEFT OUTER JOIN account_status on a.status_id = account_status.id
+                        INNER JOIN account_role ON a.role_id = account_role.id
+                    GROUP BY a.id, account_role.id
+                ),
+                used_session AS (
+                    UPDATE session
+                    SET
+                        last_used_at = now(),
+                        valid_until = CASE
+                            WHEN sliding_expiry_seconds IS NULL THEN valid_until
+                            ELSE greatest(valid_until, now() + make_interval(secs => sliding_expiry_seconds))
+                        END
+                    WHERE valid_until > now() AND uuid = CAST($1 as UUID)
+                    RETURNING *
+                )
+            SELECT
+                CAST(session.uuid as TEXT) as uuid, CAST(session.public_id as TEXT) as public_id,
+                session.auth_method, session.valid_until, session.is_single_use,
+                session.created_at, session.last_used_at, session.user_agent, session.ip_address, session.device_name,
+                session.payment_limit_cents, session.payment_limit_bottle_stamps, session.payment_limit_coffee_stamps,
+                session.impersonated_by_account_id,
+                full_account.*
+            FROM full_account INNER JOIN used_session AS session on full_account.id = session.account_id
+        "#)
+        .bind(session_token)
+        .fetch_optional(self.connection.as_mut())
+        .await;
+
+        if let Ok(r) = r {
+            Ok(r.map(Session::from))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub async fn get_sessions_by_account(
+        &mut self,
+        account_id: u64,
+    ) -> ServiceResult<Vec<models::Session>> {
+        let account_id = i64::try_from(account_id).expect("ids must fit into i64");
+        let mut r = sqlx::query_as::<_, SessionRow>(r#"
+            WITH
+                fulL_account AS (
+                    SELECT
+                        a.id, a.balance_cents, a.balance_coffee_stamps, a.balance_bottle_stamps,
+                        a.name, a.email, a.role_id, account_role.name AS role_name, account_role.permissions AS role_permissions,
+                        coalesce(array_agg(account_auth_method.data ORDER BY account_auth_method.id ASC) FILTER (where account_auth_method.id IS NOT NULL), '{}') AS auth_methods,
+                        a.enable_monthly_mail_report, a.enable_automatic_stamp_usage, a.archived_at,
+                        (array_agg(account_status.id))[1] as status_id,
+                        (array_agg(account_status.name))[1] as status_name,
+                        (array_agg(account_status.color))[1] as status_color,
+                        (array_agg(account_status.priority))[1] as status_priority
+                    FROM account AS a
+                        LEFT OUTER JOIN account_auth_method ON a.id = account_auth_method.account_id
+                        LEFT OUTER JOIN account_status on a.status_id = account_status.id
+                        INNER JOIN account_role ON a.role_id = account_role.id
+                    GROUP BY a.id, account_role.id
+                )
+            SELECT
+                CAST(session.uuid as TEXT) as uuid, CAST(session.public_id as TEXT) as public_id,
+                session.auth_method, session.valid_until, session.is_single_use,
+                session.created_at, session.last_used_at, session.user_agent, session.ip_address, session.device_name,
+                session.payment_limit_cents, session.payment_limit_bottle_stamps, session.payment_limit_coffee_stamps,
+                session.impersonated_by_account_id,
+                full_account.*
+            FROM full_account INNER JOIN session on full_account.id = session.account_id
+            WHERE (session.valid_until > now() OR session.refresh_valid_until > now()) AND session.account_id = $1
+            ORDER BY session.created_at ASC
+        "#)
+        .bind(account_id)
+        .fetch(self.connection.as_mut());
+
+        let mut out = Vec::new();
+        while let Some(row) = r.next().await {
+            let row = to_service_result(row)?;
+
+            out.push(row.into());
+        }
+        Ok(out)
+    }
+
+    pub async fn store_account(
+        &mut self,
+        account: models::Account,
+    ) -> ServiceResult<models::Account> {
+        store_account(self.connection.as_mut(), account).await
+    }
+
+    /// Store the account only if no other account exists yet, returns `None` otherwise
+    pub async fn store_first_account(
+        &mut self,
+        account: models::Account,
+    ) -> ServiceResult<Option<models::Account>> {
+        let mut transaction = self.connection.begin().await?;
+
+        // Concurrent setup requests must not both see an empty table
+        let r = sqlx::query(r#"LOCK TABLE account IN SHARE ROW EXCLUSIVE MODE"#)
+            .execute(transaction.as_mut())
+            .await;
+        to_service_result(r)?;
+
+        let r = sqlx::query(r#"SELECT EXISTS (SELECT 1 FROM account) AS account_exists"#)
+            .fetch_one(transaction.as_mut())
+            .await;
+        if to_service_result(r)?.get::<bool, _>("account_exists") {
+            return Ok(None);
+        }
+
+        let account = store_account(transaction.as_mut(), account).await?;
+
+        transaction.commit().await?;
+        Ok(Some(account))
+    }
+
+    /// Update the last use of the nfc card without touching the rest of the account
+    pub async fn set_nfc_card_last_used(&mut self, card_id: &[u8]) -> ServiceResult<()> {
+        let r = sqlx::query(
+            r#"
+            UPDATE account_auth_method
+            SET data = jsonb_set(data, '{Nfc,last_used_at}', $2)
+            WHERE login_key = $1 AND data ? 'Nfc'
+            "#,
+        )
+        .bind(
+            AuthRequest::NfcBased {
+                card_id: card_id.to_vec(),
+            }
+            .login_key(),
+        )
+        .bind(serde_json::Value::String(Utc::now().to_rfc3339()))
+        .execute(self.connection.as_mut())
+        .await;
+        to_service_result(r)?;
+        Ok(())
+    }
+
+    /// Re-encrypt all nfc card and reader keys that are not encrypted with the current master key.
+    ///
+    /// Returns the number of updated keys.
+    pub async fn reencrypt_nfc_secrets(&mut self) -> ServiceResult<usize> {
+        let mut transaction = self.connection.begin().await?;
+
+        let r = sqlx::query(
+            r#"
+            SELECT id, data FROM account_auth_method WHERE data ? 'Nfc' FOR UPDATE
+        "#,
+        )
+        .fetch_all(transaction.as_mut())
+        .await;
+        let rows = to_service_result(r)?;
+
+        let mut count = 0;
+        for row in rows {
+            let id: i64 = row.get(0);
+            let data: Json<AccountAuthMethodData> = row.get(1);
+
+            if let AuthMethod::NfcBased(mut auth_nfc) = AuthMethod::from(data.0) {
+                if !auth_nfc.data.needs_reseal() {
+                    continue;
+                }
+                auth_nfc.data = auth_nfc.data.reseal()?;
+
+                let r = sqlx::query(
+                    r#"
+                    UPDATE account_auth_method SET data = $2 WHERE id = $1
+                "#,
+                )
+                .bind(id)
+                .bind(Json(AccountAuthMethodData::from(AuthMethod::NfcBased(
+                    auth_nfc,
+                ))))
+                .execute(transaction.as_mut())
+                .await;
+                to_service_result(r)?;
+                count += 1;
+            }
+        }
+
+        let r = sqlx::query_as::<_, NfcReaderRow>(
+            r#"
+            SELECT id, name, secret, secret_key_id FROM nfc_reader FOR UPDATE
+        "#,
+        )
+        .fetch_all(transaction.as_mut())
+        .await;
+        for reader in to_service_result(r)?.into_iter().map(NfcReader::from) {
+            if !reader.key.needs_reseal() {
+                continue;
+            }
+            let NfcSecret::Sealed { key_id, data } = reader.key.reseal()? else {
+                unreachable!("reseal always returns a sealed secret");
+            };
+
+            let r = sqlx::query(
+                r#"
+                UPDATE nfc_reader SET secret = $2, secret_key_id = $3 WHERE id = $1
+            "#,
+            )
+            .bind(i64::try_from(reader.id).expect("reader id is less than 2**63"))
+            .bind(data)
+            .bind(key_id)
+            .execute(transaction.as_mut())
+            .await;
+            to_service_result(r)?;
+            count += 1;
+        }
+
+        to_service_result(transaction.commit().await)?;
+        Ok(count)
+    }
+
+    pub async fn delete_account(&mut self, id: u64) -> ServiceResult<()> {
+        let r = sqlx::query(
+            r#"
+            DELETE FROM account WHERE id = $1
+        "#,
+        )
+        .bind(i64::try_from(id).expect("account id is less than 2**63"))
+        .execute(self.connection.as_mut())
+        .await;
+        to_service_result(r)?;
+        Ok(())
+    }
+
+    pub async fn get_all_account_status(&mut self) -> ServiceResult<Vec<models::AccountStatus>> {
+        let mut r = sqlx::query_as::<_, AccountStatusRow>(
+            r#"
+            SELECT
+                id, name, priority, color
+            FROM account_status
+            "#,
+        )
+        .fetch(self.connection.as_mut());
+
+        let mut out = Vec::new();
+        while let Some(row) = r.next().await {
+            let row = to_service_result(row)?;
+            out.push(row.into());
+        }
+
+        Ok(out)
+    }
+
+    pub async fn get_account_status_by_id(
+        &mut self,
+        id: u64,
+    ) -> ServiceResult<Option<models::AccountStatus>> {
+        let r = sqlx::query_as::<_, AccountStatusRow>(
+            r#"
+            SELECT
+                id, name, color, priority
+            FROM account_status
+            WHERE
+                id = $1
+            "#,
+        )
+        .bind(i64::try_from(id).expect("ids are less than 2**63"))
+        .fetch_optional(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?.map(AccountStatus::from))
+    }
+
+    pub async fn store_account_status(
+        &mut self,
+        mut account_status: models::AccountStatus,
+    ) -> ServiceResult<models::AccountStatus> {
+        let q = if account_status.id == 0 {
+            sqlx::query(
+                r#"
+            INSERT INTO account_status (
+                name,
+                color,
+                priority
+            ) VALUES (
+                $1,
+                $2,
+                $3
+            ) RETURNING id
+            "#,
+            )
+        } else {
+            sqlx::query(
+                r#"
+                UPDATE account_status
+                SET
+                    name = $2,
+                    color = $3,
+                    priority = $4
+                WHERE id = $1
+                RETURNING id
+            "#,
+            )
+            .bind(i64::try_from(account_status.id).expect("product id is less than 2**63"))
+        };
+        let r = q
+            .bind(&account_status.name)
+            .bind(&account_status.color)
+            .bind(i32::try_from(account_status.priority).expect("product id is less than 2**31"))
+            .fetch_one(self.connection.as_mut())
+            .await;
+        let r = to_service_result(r)?;
+        account_status.id = r
+            .get::<i64, _>(0)
+            .try_into()
+            .expect("id is always positive");
+
+        Ok(account_status)
+    }
+
+    pub async fn delete_account_status(&mut self, id: u64) -> ServiceResult<()> {
+        let id = i64::try_from(id).expect("id is always less than 2**63");
+        let r = sqlx::query(r#"DELETE FROM account_status WHERE id = $1"#)
+            .bind(id)
+            .execute(self.connection.as_mut())
+            .await;
+        let r = to_service_result(r)?;
+        if r.rows_affected() != 1 {
+            return Err(ServiceError::NotFound);
+        }
+        Ok(())
+    }
+
+    pub async fn get_all_roles(&mut self) -> ServiceResult<Vec<models::Role>> {
+        let mut r = sqlx::query_as::<_, RoleRow>(
+            r#"
+            SELECT
+                id, name, permissions
+            FROM account_role
+            ORDER BY id ASC
+            "#,
+        )
+        .fetch(self.connection.as_mut());
+
+        let mut out = Vec::new();
+        while let Some(row) = r.next().await {
+            let row = to_service_result(row)?;
+            out.push(row.into());
+        }
+
+        Ok(out)
+    }
+
+    pub async fn get_role_by_id(&mut self, id: u64) -> ServiceResult<Option<models::Role>> {
+        let r = sqlx::query_as::<_, RoleRow>(
+            r#"
+            SELECT
+                id, name, permissions
+            FROM account_role
+            WHERE
+                id = $1
+            "#,
+        )
+        .bind(i64::try_from(id).expect("ids are less than 2**63"))
+        .fetch_optional(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?.map(Role::from))
+    }
+
+    pub async fn get_role_by_name(&mut self, name: &str) -> ServiceResult<Option<models::Role>> {
+        let r = sqlx::query_as::<_, RoleRow>(
+            r#"
+            SELECT
+                id, name, permissions
+            FROM account_role
+            WHERE
+                name = $1
+            "#,
+        )
+        .bind(name)
+        .fetch_optional(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?.map(Role::from))
+    }
+
+    pub async fn store_role(&mut self, mut role: models::Role) -> ServiceResult<models::Role> {
+        let q = if role.id == 0 {
+            sqlx::query(
+                r#"
+            INSERT INTO account_role (
+                name,
+                permissions
+            ) VALUES (
+                $1,
+                $2
+            ) RETURNING id
+            "#,
+            )
+        } else {
+            sqlx::query(
+                r#"
+                UPDATE account_role
+                SET
+                    name = $2,
+                    permissions = $3
+                WHERE id = $1
+                RETURNING id
+            "#,
+            )
+            .bind(i64::try_from(role.id).expect("role id is less than 2**63"))
+        };
+        let r = q
+            .bind(&role.name)
+            .bind(permissions_to_keys(&role.permissions))
+            .fetch_one(self.connection.as_mut())
+            .await;
+        let r = to_service_result(r)?;
+        role.id = r
+            .get::<i64, _>(0)
+            .try_into()
+            .expect("id is always positive");
+
+        Ok(role)
+    }
+
+    pub async fn delete_role(&mut self, id: u64) -> ServiceResult<()> {
+        let id = i64::try_from(id).expect("id is always less than 2**63");
+        let r = sqlx::query(
+            r#"
+            SELECT EXISTS (SELECT 1 FROM account WHERE role_id = $1)
+                OR EXISTS (SELECT 1 FROM invitation WHERE role_id = $1)
+        "#,
+        )
+        .bind(id)
+        .fetch_one(self.connection.as_mut())
+        .await;
+        if to_service_result(r)?.get::<bool, _>(0) {
+            return Err(ServiceError::RoleInUse);
+        }
+
+        let r = sqlx::query(r#"DELETE FROM account_role WHERE id = $1"#)
+            .bind(id)
+            .execute(self.connection.as_mut())
+            .await;
+        let r = to_service_result(r)?;
+        if r.rows_affected() != 1 {
+            return Err(ServiceError::NotFound);
+        }
+        Ok(())
+    }
+
+    pub async fn get_all_invitations(&mut self) -> ServiceResult<Vec<models::Invitation>> {
+        let r = sqlx::query_as::<_, InvitationRow>(
+            r#"
+            SELECT
+                i.id, CAST(i.token AS TEXT) AS token, i.name, i.email,
+                i.role_id, account_role.name AS role_name, account_role.permissions AS role_permissions,
+                i.status_id, account_status.name AS status_name, account_status.color AS status_color, account_status.priority AS status_priority,
+                i.invited_by_account_id, i.created_at, i.valid_until
+            FROM invitation AS i
+                INNER JOIN account_role ON i.role_id = account_role.id
+                LEFT OUTER JOIN account_status ON i.status_id = account_status.id
+            ORDER BY i.id ASC
+        "#,
+        )
+        .fetch_all(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?
+            .into_iter()
+            .map(Invitation::from)
+            .collect())
+    }
+
+    pub async fn get_invitation_by_id(
+        &mut self,
+        id: u64,
+    ) -> ServiceResult<Option<models::Invitation>> {
+        let r = sqlx::query_as::<_, InvitationRow>(
+            r#"
+            SELECT
+                i.id, CAST(i.token AS TEXT) AS token, i.name, i.email,
+                i.role_id, account_role.name AS role_name, account_role.permissions AS role_permissions,
+                i.status_id, account_status.name AS status_name, account_status.color AS status_color, account_status.priority AS status_priority,
+                i.invited_by_account_id, i.created_at, i.valid_until
+            FROM invitation AS i
+                INNER JOIN account_role ON i.role_id = account_role.id
+                LEFT OUTER JOIN account_status ON i.status_id = account_status.id
+            WHERE i.id = $1
+        "#,
+        )
+        .bind(i64::try_from(id).expect("invitation id is less than 2**63"))
+        .fetch_optional(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?.map(Invitation::from))
+    }
+
+    /// Only returns invitations that have not expired yet
+    pub async fn get_invitation_by_token(
+        &mut self,
+        token: &str,
+    ) -> ServiceResult<Option<models::Invitation>> {
+        let r = sqlx::query_as::<_, InvitationRow>(
+            r#"
+            SELECT
+                i.id, CAST(i.token AS TEXT) AS token, i.name, i.email,
+                i.role_id, account_role.name AS role_name, account_role.permissions AS role_permissions,
+                i.status_id, account_status.name AS status_name, account_status.color AS status_color, account_status.priority AS status_priority,
+                i.invited_by_account_id, i.created_at, i.valid_until
+            FROM invitation AS i
+                INNER JOIN account_role ON i.role_id = account_role.id
+                LEFT OUTER JOIN account_status ON i.status_id = account_status.id
+            WHERE CAST(i.token AS TEXT) = $1 AND i.valid_until > now()
+        "#,
+        )
+        .bind(token)
+        .fetch_optional(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?.map(Invitation::from))
+    }
+
+    /// Insert or update the invitation, `renew_token` replaces the token so previous links stop working
+    pub async fn store_invitation(
+        &mut self,
+        invitation: models::Invitation,
+        renew_token: bool,
+    ) -> ServiceResult<models::Invitation> {
+        let q = if invitation.id == 0 {
+            sqlx::query(
+                r#"
+                INSERT INTO invitation (name, email, role_id, status_id, invited_by_account_id, valid_until)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                RETURNING id
+            "#,
+            )
+        } else {
+            sqlx::query(
+                r#"
+                UPDATE invitation
+                SET
+                    name = $2,
+                    email = $3,
+                    role_id = $4,
+                    status_id = $5,
+                    invited_by_account_id = $6,
+                    valid_until = $7,
+                    token = CASE WHEN $8 THEN gen_random_uuid() ELSE token END
+                WHERE id = $1
+                RETURNING id
+            "#,
+            )
+            .bind(i64::try_from(invitation.id).expect("invitation id is less than 2**63"))
+        };
+        let q = q
+            .bind(&invitation.name)
+            .bind(&invitation.email)
+            .bind(i64::try_from(invitation.role.id).expect("role id is less than 2**63"))
+            .bind(
+                invitation
+                    .status
+                    .as_ref()
+                    .map(|s| i64::try_from(s.id).expect("status id is less than 2**63")),
+            )
+            .bind(
+                invitation
+                    .invited_by_account_id
+                    .map(|id| i64::try_from(id).expect("account id is less than 2**63")),
+            )
+            .bind(invitation.valid_until);
+        let q = if invitation.id == 0 {
+            q
+        } else {
+            q.bind(renew_token)
+        };
+        let r = q.fetch_one(self.connection.as_mut()).await;
+        let r = to_service_result(r)?;
+
+        let id = r
+            .get::<i64, _>(0)
+            .try_into()
+            .expect("id is always positive");
+        self.get_invitation_by_id(id)
+            .await?
+            .ok_or(ServiceError::NotFound)
+    }
+
+    pub async fn delete_invitation(&mut self, id: u64) -> ServiceResult<()> {
+        let r = sqlx::query(r#"DELETE FROM invitation WHERE id = $1"#)
+            .bind(i64::try_from(id).expect("invitation id is less than 2**63"))
+            .execute(self.connection.as_mut())
+            .await;
+        let r = to_service_result(r)?;
+        if r.rows_affected() != 1 {
+            return Err(ServiceError::NotFound);
         }
+        Ok(())
+    }
 
-        Ok(out)
+    /// Remove the invitation and create its account, an invitation can only be accepted once
+    pub async fn accept_invitation(
+        &mut self,
+        invitation_id: u64,
+        account: models::Account,
+    ) -> ServiceResult<models::Account> {
+        let mut transaction = self.connection.begin().await?;
+
+        let r = sqlx::query(r#"DELETE FROM invitation WHERE id = $1"#)
+            .bind(i64::try_from(invitation_id).expect("invitation id is less than 2**63"))
+            .execute(transaction.as_mut())
+            .await;
+        let r = to_service_result(r)?;
+        if r.rows_affected() != 1 {
+            return Err(ServiceError::NotFound);
+        }
+
+        let account = store_account(transaction.as_mut(), account).await?;
+
+        transaction.commit().await?;
+        Ok(account)
     }
 
-    pub async fn get_account_status_by_id(
+    /// All voucher batches, newest first
+    pub async fn get_all_voucher_batches(&mut self) -> ServiceResult<Vec<models::VoucherBatch>> {
+        let r = sqlx::query_as::<_, VoucherBatchRow>(
+            r#"
+            SELECT
+                b.id, b.name, b.created_at, b.created_by_account_id, b.valid_until,
+                b.value_cents, b.value_coffee_stamps, b.value_bottle_stamps,
+                count(v.id) AS voucher_count, count(v.used_at) AS used_count
+            FROM voucher_batch AS b
+                LEFT OUTER JOIN voucher AS v ON b.id = v.batch_id
+            GROUP BY b.id
+            ORDER BY b.id DESC
+        "#,
+        )
+        .fetch_all(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?
+            .into_iter()
+            .map(models::VoucherBatch::from)
+            .collect())
+    }
+
+    pub async fn get_voucher_batch_by_id(
         &mut self,
         id: u64,
-    ) -> ServiceResult<Option<models::AccountStatus>> {
-        let r = sqlx::query_as::<_, AccountStatusRow>(
+    ) -> ServiceResult<Option<models::VoucherBatch>> {
+        let r = sqlx::query_as::<_, VoucherBatchRow>(
             r#"
             SELECT
-                id, name, color, priority
-            FROM account_status
-            WHERE
-                id = $1
+                b.id, b.name, b.created_at, b.created_by_account_id, b.valid_until,
+                b.value_cents, b.value_coffee_stamps, b.value_bottle_stamps,
+                count(v.id) AS voucher_count, count(v.used_at) AS used_count
+            FROM voucher_batch AS b
+                LEFT OUTER JOIN voucher AS v ON b.id = v.batch_id
+            WHERE b.id = $1
+            GROUP BY b.id
+        "#,
+        )
+        .bind(i64::try_from(id).expect("voucher batch id is less than 2**63"))
+        .fetch_optional(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?.map(models::VoucherBatch::from))
+    }
+
+    /// Create a new batch with one voucher per code
+    pub async fn create_voucher_batch(
+        &mut self,
+        batch: models::VoucherBatch,
+        codes: &[String],
+    ) -> ServiceResult<models::VoucherBatch> {
+        let get = |t: CoinType| batch.value.0.get(&t).copied().unwrap_or(0);
+        let r = sqlx::query(
+            r#"
+            WITH
+                inserted_batch AS (
+                    INSERT INTO voucher_batch (name, created_by_account_id, valid_until, value_cents, value_coffee_stamps, value_bottle_stamps)
+                    VALUES ($1, $2, $3, $4, $5, $6)
+                    RETURNING id
+                ),
+                inserted_vouchers AS (
+                    INSERT INTO voucher (batch_id, code)
+                    SELECT inserted_batch.id, code FROM inserted_batch, UNNEST($7::VARCHAR[]) AS code
+                )
+            SELECT id FROM inserted_batch
+        "#,
+        )
+        .bind(&batch.name)
+        .bind(
+            batch
+                .created_by_account_id
+                .map(|id| i64::try_from(id).expect("account id is less than 2**63")),
+        )
+        .bind(batch.valid_until)
+        .bind(get(CoinType::Cent))
+        .bind(get(CoinType::CoffeeStamp))
+        .bind(get(CoinType::BottleStamp))
+        .bind(codes)
+        .fetch_one(self.connection.as_mut())
+        .await;
+        let id: i64 = to_service_result(r)?.get(0);
+
+        self.get_voucher_batch_by_id(id.try_into().expect("id is always positive"))
+            .await?
+            .ok_or(ServiceError::NotFound)
+    }
+
+    pub async fn get_vouchers_by_batch(
+        &mut self,
+        batch_id: u64,
+    ) -> ServiceResult<Vec<models::Voucher>> {
+        let r = sqlx::query_as::<_, VoucherRow>(
+            r#"
+            SELECT id, batch_id, code, used_at, account_id, is_guest
+            FROM voucher
+            WHERE batch_id = $1
+            ORDER BY id ASC
+        "#,
+        )
+        .bind(i64::try_from(batch_id).expect("voucher batch id is less than 2**63"))
+        .fetch_all(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?
+            .into_iter()
+            .map(models::Voucher::from)
+            .collect())
+    }
+
+    pub async fn get_voucher_by_code(
+        &mut self,
+        code: &str,
+    ) -> ServiceResult<Option<models::Voucher>> {
+        let r = sqlx::query_as::<_, VoucherRow>(
+            r#"
+            SELECT id, batch_id, code, used_at, account_id, is_guest
+            FROM voucher
+            WHERE code = $1
+        "#,
+        )
+        .bind(code)
+        .fetch_optional(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?.map(models::Voucher::from))
+    }
+
+    /// Mark an unused voucher of a batch that has not expired yet as used by the given account
+    /// and credit the value of its batch in one transaction.
+    ///
+    /// An account without id is created first as guest account of the voucher.
+    /// Fails with `NotFound` if the voucher cannot be used (anymore).
+    pub async fn redeem_voucher(
+        &mut self,
+        code: &str,
+        account: models::Account,
+        authorization: Option<models::Session>,
+    ) -> ServiceResult<(models::Voucher, models::Transaction)> {
+        let mut transaction = self.connection.begin().await?;
+
+        let is_guest = account.id == 0;
+        let account = if is_guest {
+            store_account(transaction.as_mut(), account).await?
+        } else {
+            account
+        };
+
+        let r = sqlx::query_as::<_, VoucherRow>(
+            r#"
+            UPDATE voucher
+            SET used_at = now(), account_id = $2, is_guest = $3
+            FROM voucher_batch
+            WHERE voucher.code = $1
+                AND voucher.used_at IS NULL
+                AND voucher.batch_id = voucher_batch.id
+                AND voucher_batch.valid_until > now()
+            RETURNING voucher.id, voucher.batch_id, voucher.code, voucher.used_at, voucher.account_id, voucher.is_guest
+        "#,
+        )
+        .bind(code)
+        .bind(i64::try_from(account.id).expect("account id is less than 2**63"))
+        .bind(is_guest)
+        .fetch_optional(transaction.as_mut())
+        .await;
+        let voucher = to_service_result(r)?
+            .map(models::Voucher::from)
+            .ok_or(ServiceError::NotFound)?;
+
+        let r = sqlx::query(
+            r#"
+            SELECT value_cents, value_coffee_stamps, value_bottle_stamps
+            FROM voucher_batch
+            WHERE id = $1
+        "#,
+        )
+        .bind(i64::try_from(voucher.batch_id).expect("batch id is less than 2**63"))
+        .fetch_one(transaction.as_mut())
+        .await;
+        let r = to_service_result(r)?;
+
+        let payment = models::Payment {
+            account: account.id,
+            items: vec![PaymentItem {
+                effective_price: to_coin_amount(&[
+                    (CoinType::Cent, Some(-r.get::<i32, _>("value_cents"))),
+                    (
+                        CoinType::CoffeeStamp,
+                        Some(-r.get::<i32, _>("value_coffee_stamps")),
+                    ),
+                    (
+                        CoinType::BottleStamp,
+                        Some(-r.get::<i32, _>("value_bottle_stamps")),
+                    ),
+                ]),
+                product_id: None,
+            }],
+            authorization,
+        };
+        let tx = insert_payment(transaction.as_mut(), payment, Utc::now()).await?;
+
+        transaction.commit().await?;
+        Ok((voucher, tx))
+    }
+
+    pub async fn get_spending_policy(
+        &mut self,
+        account_id: u64,
+    ) -> ServiceResult<models::SpendingPolicy> {
+        let account_id = i64::try_from(account_id).expect("account id is less than 2**63");
+        let r = sqlx::query(
+            r#"SELECT blocked_product_tags, locked FROM account_spending_policy WHERE account_id = $1"#,
+        )
+        .bind(account_id)
+        .fetch_optional(self.connection.as_mut())
+        .await;
+        let policy = to_service_result(r)?;
+
+        let r = sqlx::query_as::<_, SpendingLimitRow>(
+            r#"
+            SELECT period, limit_cents, limit_coffee_stamps, limit_bottle_stamps
+            FROM account_spending_limit
+            WHERE account_id = $1
+            ORDER BY period ASC
+        "#,
+        )
+        .bind(account_id)
+        .fetch_all(self.connection.as_mut())
+        .await;
+
+        Ok(models::SpendingPolicy {
+            limits: to_service_result(r)?
+                .into_iter()
+                .map(models::SpendingLimit::from)
+                .collect(),
+            blocked_product_tags: policy
+                .as_ref()
+                .map(|row| row.get("blocked_product_tags"))
+                .unwrap_or_default(),
+            locked: policy.map(|row| row.get("locked")).unwrap_or(false),
+        })
+    }
+
+    pub async fn store_spending_policy(
+        &mut self,
+        account_id: u64,
+        policy: &models::SpendingPolicy,
+    ) -> ServiceResult<()> {
+        let account_id = i64::try_from(account_id).expect("account id is less than 2**63");
+        let mut transaction = self.connection.begin().await?;
+
+        let r = sqlx::query(
+            r#"
+            INSERT INTO account_spending_policy (account_id, blocked_product_tags, locked)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (account_id) DO UPDATE
+            SET blocked_product_tags = $2, locked = $3
+        "#,
+        )
+        .bind(account_id)
+        .bind(&policy.blocked_product_tags)
+        .bind(policy.locked)
+        .execute(transaction.as_mut())
+        .await;
+        to_service_result(r)?;
+
+        let r = sqlx::query(r#"DELETE FROM account_spending_limit WHERE account_id = $1"#)
+            .bind(account_id)
+            .execute(transaction.as_mut())
+            .await;
+        to_service_result(r)?;
+
+        for limit in policy.limits.iter() {
+            let get = |t: CoinType| limit.limit.0.get(&t).copied();
+            let r = sqlx::query(
+                r#"
+                INSERT INTO account_spending_limit (account_id, period, limit_cents, limit_coffee_stamps, limit_bottle_stamps)
+                VALUES ($1, $2, $3, $4, $5)
             "#,
+            )
+            .bind(account_id)
+            .bind(SpendingLimitPeriodDto::from(limit.period))
+            .bind(get(CoinType::Cent))
+            .bind(get(CoinType::CoffeeStamp))
+            .bind(get(CoinType::BottleStamp))
+            .execute(transaction.as_mut())
+            .await;
+            to_service_result(r)?;
+        }
+
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    pub async fn get_account_group_members(
+        &mut self,
+        group_account_id: u64,
+    ) -> ServiceResult<Vec<models::AccountGroupMember>> {
+        let r = sqlx::query_as::<_, AccountGroupMemberRow>(
+            r#"
+            SELECT m.group_account_id, m.member_account_id, a.name AS member_name, m.is_admin, m.spending_limit_cents
+            FROM account_group_member AS m
+                INNER JOIN account AS a ON m.member_account_id = a.id
+            WHERE m.group_account_id = $1
+            ORDER BY m.member_account_id ASC
+        "#,
         )
-        .bind(i64::try_from(id).expect("ids are less than 2**63"))
+        .bind(i64::try_from(group_account_id).expect("account id is less than 2**63"))
+        .fetch_all(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?
+            .into_iter()
+            .map(models::AccountGroupMember::from)
+            .collect())
+    }
+
+    /// Memberships of the given account in group accounts
+    pub async fn get_account_groups_by_member(
+        &mut self,
+        member_account_id: u64,
+    ) -> ServiceResult<Vec<models::AccountGroupMember>> {
+        let r = sqlx::query_as::<_, AccountGroupMemberRow>(
+            r#"
+            SELECT m.group_account_id, m.member_account_id, a.name AS member_name, m.is_admin, m.spending_limit_cents
+            FROM account_group_member AS m
+                INNER JOIN account AS a ON m.member_account_id = a.id
+            WHERE m.member_account_id = $1
+            ORDER BY m.group_account_id ASC
+        "#,
+        )
+        .bind(i64::try_from(member_account_id).expect("account id is less than 2**63"))
+        .fetch_all(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?
+            .into_iter()
+            .map(models::AccountGroupMember::from)
+            .collect())
+    }
+
+    pub async fn get_account_group_member(
+        &mut self,
+        group_account_id: u64,
+        member_account_id: u64,
+    ) -> ServiceResult<Option<models::AccountGroupMember>> {
+        let r = sqlx::query_as::<_, AccountGroupMemberRow>(
+            r#"
+            SELECT m.group_account_id, m.member_account_id, a.name AS member_name, m.is_admin, m.spending_limit_cents
+            FROM account_group_member AS m
+                INNER JOIN account AS a ON m.member_account_id = a.id
+            WHERE m.group_account_id = $1 AND m.member_account_id = $2
+        "#,
+        )
+        .bind(i64::try_from(group_account_id).expect("account id is less than 2**63"))
+        .bind(i64::try_from(member_account_id).expect("account id is less than 2**63"))
+        .fetch_optional(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?.map(models::AccountGroupMember::from))
+    }
+
+    pub async fn store_account_group_member(
+        &mut self,
+        member: models::AccountGroupMember,
+    ) -> ServiceResult<models::AccountGroupMember> {
+        let r = sqlx::query(
+            r#"
+            INSERT INTO account_group_member (group_account_id, member_account_id, is_admin, spending_limit_cents)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (group_account_id, member_account_id) DO UPDATE
+            SET is_admin = $3, spending_limit_cents = $4
+        "#,
+        )
+        .bind(i64::try_from(member.group_account_id).expect("account id is less than 2**63"))
+        .bind(i64::try_from(member.member_account_id).expect("account id is less than 2**63"))
+        .bind(member.is_admin)
+        .bind(member.spending_limit_cents)
+        .execute(self.connection.as_mut())
+        .await;
+        to_service_result(r)?;
+
+        self.get_account_group_member(member.group_account_id, member.member_account_id)
+            .await?
+            .ok_or(ServiceError::NotFound)
+    }
+
+    pub async fn delete_account_group_member(
+        &mut self,
+        group_account_id: u64,
+        member_account_id: u64,
+    ) -> ServiceResult<()> {
+        let r = sqlx::query(
+            r#"DELETE FROM account_group_member WHERE group_account_id = $1 AND member_account_id = $2"#,
+        )
+        .bind(i64::try_from(group_account_id).expect("account id is less than 2**63"))
+        .bind(i64::try_from(member_account_id).expect("account id is less than 2**63"))
+        .execute(self.connection.as_mut())
+        .await;
+        let r = to_service_result(r)?;
+        if r.rows_affected() != 1 {
+            return Err(ServiceError::NotFound);
+        }
+        Ok(())
+    }
+
+    /// Cents the member has spent from the group account since the given time
+    pub async fn get_all_nfc_readers(&mut self) -> ServiceResult<Vec<models::NfcReader>> {
+        let r = sqlx::query_as::<_, NfcReaderRow>(
+            r#"
+            SELECT id, name, secret, secret_key_id FROM nfc_reader ORDER BY id ASC
+            "#,
+        )
+        .fetch_all(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?
+            .into_iter()
+            .map(NfcReader::from)
+            .collect())
+    }
+
+    pub async fn get_nfc_reader_by_id(
+        &mut self,
+        id: u64,
+    ) -> ServiceResult<Option<models::NfcReader>> {
+        let r = sqlx::query_as::<_, NfcReaderRow>(
+            r#"
+            SELECT id, name, secret, secret_key_id FROM nfc_reader WHERE id = $1
+            "#,
+        )
+        .bind(i64::try_from(id).expect("reader id is less than 2**63"))
+        .fetch_optional(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?.map(NfcReader::from))
+    }
+
+    pub async fn store_nfc_reader(
+        &mut self,
+        mut reader: models::NfcReader,
+    ) -> ServiceResult<models::NfcReader> {
+        let NfcSecret::Sealed { key_id, data } = &reader.key else {
+            return Err(ServiceError::InternalServerError(
+                "Reader keys must be encrypted".to_string(),
+            ));
+        };
+
+        let q = if reader.id == 0 {
+            sqlx::query(
+                r#"
+            INSERT INTO nfc_reader (name, secret, secret_key_id) VALUES ($1, $2, $3) RETURNING id
+            "#,
+            )
+        } else {
+            sqlx::query(
+                r#"
+                UPDATE nfc_reader
+                SET
+                    name = $2,
+                    secret = $3,
+                    secret_key_id = $4
+                WHERE id = $1
+                RETURNING id
+            "#,
+            )
+            .bind(i64::try_from(reader.id).expect("reader id is less than 2**63"))
+        };
+        let r = q
+            .bind(&reader.name)
+            .bind(data)
+            .bind(key_id)
+            .fetch_one(self.connection.as_mut())
+            .await;
+        let r = to_service_result(r)?;
+        reader.id = r
+            .get::<i64, _>(0)
+            .try_into()
+            .expect("id is always positive");
+
+        Ok(reader)
+    }
+
+    pub async fn delete_nfc_reader(&mut self, id: u64) -> ServiceResult<()> {
+        let r = sqlx::query(r#"DELETE FROM nfc_reader WHERE id = $1"#)
+            .bind(i64::try_from(id).expect("reader id is less than 2**63"))
+            .execute(self.connection.as_mut())
+            .await;
+        let r = to_service_result(r)?;
+        if r.rows_affected() != 1 {
+            return Err(ServiceError::NotFound);
+        }
+        Ok(())
+    }
+
+    pub async fn get_nfc_provisioning(
+        &mut self,
+        account_id: u64,
+        id: &str,
+    ) -> ServiceResult<Option<models::NfcProvisioning>> {
+        let r = sqlx::query_as::<_, NfcProvisioningRow>(
+            r#"
+            SELECT CAST(id AS TEXT) AS id, account_id, name, card_id, secret, secret_key_id, replaces_card_id, key_change_issued, valid_until
+            FROM nfc_provisioning
+            WHERE CAST(id AS TEXT) = $2 AND account_id = $1 AND valid_until > now()
+            "#,
+        )
+        .bind(i64::try_from(account_id).expect("account id is less than 2**63"))
+        .bind(id)
         .fetch_optional(self.connection.as_mut())
         .await;
 
-        Ok(to_service_result(r)?.map(AccountStatus::from))
+        Ok(to_service_result(r)?.map(NfcProvisioning::from))
     }
 
-    pub async fn store_account_status(
+    pub async fn store_nfc_provisioning(
         &mut self,
-        mut account_status: models::AccountStatus,
-    ) -> ServiceResult<models::AccountStatus> {
-        let q = if account_status.id == 0 {
+        mut provisioning: models::NfcProvisioning,
+    ) -> ServiceResult<models::NfcProvisioning> {
+        let NfcSecret::Sealed { key_id, data } = &provisioning.key else {
+            return Err(ServiceError::InternalServerError(
+                "Card keys must be encrypted".to_string(),
+            ));
+        };
+
+        let q = if provisioning.id.is_empty() {
             sqlx::query(
                 r#"
-            INSERT INTO account_status (
-                name,
-                color,
-                priority
-            ) VALUES (
-                $1,
-                $2,
-                $3
-            ) RETURNING id
+                INSERT INTO nfc_provisioning (account_id, name, card_id, secret, secret_key_id, replaces_card_id, key_change_issued, valid_until)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                RETURNING CAST(id AS TEXT)
             "#,
             )
         } else {
             sqlx::query(
                 r#"
-                UPDATE account_status
+                UPDATE nfc_provisioning
                 SET
-                    name = $2,
-                    color = $3,
-                    priority = $4
-                WHERE id = $1
-                RETURNING id
+                    account_id = $2,
+                    name = $3,
+                    card_id = $4,
+                    secret = $5,
+                    secret_key_id = $6,
+                    replaces_card_id = $7,
+                    key_change_issued = $8,
+                    valid_until = $9
+                WHERE CAST(id AS TEXT) = $1
+                RETURNING CAST(id AS TEXT)
             "#,
             )
-            .bind(i64::try_from(account_status.id).expect("product id is less than 2**63"))
+            .bind(&provisioning.id)
         };
         let r = q
-            .bind(&account_status.name)
-            .bind(&account_status.color)
-            .bind(i32::try_from(account_status.priority).expect("product id is less than 2**31"))
+            .bind(i64::try_from(provisioning.account_id).expect("account id is less than 2**63"))
+            .bind(&provisioning.name)
+            .bind(&provisioning.card_id)
+            .bind(data)
+            .bind(key_id)
+            .bind(&provisioning.replaces_card_id)
+            .bind(provisioning.key_change_issued)
+            .bind(provisioning.valid_until)
             .fetch_one(self.connection.as_mut())
             .await;
         let r = to_service_result(r)?;
-        account_status.id = r
-            .get::<i64, _>(0)
-            .try_into()
-            .expect("id is always positive");
+        provisioning.id = r.get(0);
 
-        Ok(account_status)
+        Ok(provisioning)
     }
 
-    pub async fn delete_account_status(&mut self, id: u64) -> ServiceResult<()> {
-        let id = i64::try_from(id).expect("id is always less than 2**63");
-        let r = sqlx::query(r#"DELETE FROM account_status WHERE id = $1"#)
-            .bind(id)
-            .execute(self.connection.as_mut())
-            .await;
+    pub async fn delete_nfc_provisioning(
+        &mut self,
+        account_id: u64,
+        id: &str,
+    ) -> ServiceResult<()> {
+        let r = sqlx::query(
+            r#"DELETE FROM nfc_provisioning WHERE CAST(id AS TEXT) = $2 AND account_id = $1"#,
+        )
+        .bind(i64::try_from(account_id).expect("account id is less than 2**63"))
+        .bind(id)
+        .execute(self.connection.as_mut())
+        .await;
         let r = to_service_result(r)?;
         if r.rows_affected() != 1 {
             return Err(ServiceError::NotFound);
@@ -1070,6 +3450,14 @@ impl DatabaseConnection {
         Ok(())
     }
 
+    pub async fn cleanup_nfc_provisionings(&mut self) -> ServiceResult<()> {
+        let r = sqlx::query(r#"DELETE FROM nfc_provisioning WHERE valid_until < now()"#)
+            .execute(self.connection.as_mut())
+            .await;
+        to_service_result(r)?;
+        Ok(())
+    }
+
     pub async fn get_all_products(&mut self) -> ServiceResult<Vec<models::Product>> {
         let mut r = sqlx::query_as::<_, ProductRow>(
             r#"
@@ -1361,6 +3749,7 @@ impl DatabaseConnection {
                     item.effective_price_bottle_stamps as effective_price_bottle_stamps,
                     item.authorized_by_account_id as authorized_by_account_id,
                     item.authorized_with_method as authorized_with_method,
+                    item.linked_transaction_id as linked_transaction_id,
                     p.id as id,
                     p.name as name,
                     p.price_cents as price_cents,
@@ -1393,7 +3782,7 @@ impl DatabaseConnection {
                         LEFT OUTER JOIN product_status_price ON p.id = product_status_price.product_id
                         LEFT OUTER JOIN account_status on product_status_price.status_id = account_status.id
                 GROUP BY item.id, p.id
-                ORDER BY item.timestamp ASC, item.transaction_id ASC
+                ORDER BY item.timestamp ASC, item.transaction_id ASC, item.id ASC
             "#,
         )
         .fetch(self.connection.as_mut());
@@ -1427,6 +3816,7 @@ impl DatabaseConnection {
                     item.effective_price_bottle_stamps as effective_price_bottle_stamps,
                     item.authorized_by_account_id as authorized_by_account_id,
                     item.authorized_with_method as authorized_with_method,
+                    item.linked_transaction_id as linked_transaction_id,
                     p.id as id,
                     p.name as name,
                     p.price_cents as price_cents,
@@ -1461,7 +3851,7 @@ impl DatabaseConnection {
                 WHERE
                     (item.account_id = $1) OR ($1 = 0 AND item.account_id IS NULL)
                 GROUP BY item.id, p.id
-                ORDER BY item.timestamp ASC, item.transaction_id ASC
+                ORDER BY item.timestamp ASC, item.transaction_id ASC, item.id ASC
             "#,
         )
         .bind(id)
@@ -1496,6 +3886,7 @@ impl DatabaseConnection {
                 item.effective_price_bottle_stamps as effective_price_bottle_stamps,
                 item.authorized_by_account_id as authorized_by_account_id,
                 item.authorized_with_method as authorized_with_method,
+                item.linked_transaction_id as linked_transaction_id,
                 p.id as id,
                 p.name as name,
                 p.price_cents as price_cents,
@@ -1529,6 +3920,7 @@ impl DatabaseConnection {
                     LEFT OUTER JOIN account_status on product_status_price.status_id = account_status.id
             WHERE item.transaction_id = $1
             GROUP BY item.id, p.id
+            ORDER BY item.id ASC
             "#,
         )
         .bind(id)
@@ -1575,13 +3967,58 @@ impl DatabaseConnection {
         let mut transaction = self.connection.begin().await?;
 
         if check_payment_conditions {
-            let allow_credit_loading = if let Some(ref session) = payment.authorization {
-                matches!(session.account.role, Role::Admin)
-                    || matches!(session.auth_method, AuthMethodType::NfcBased)
-            } else {
-                true
+            let allow_credit_loading = match payment.authorization {
+                // Impersonation sessions and their payment tokens act with the permissions of the admin
+                Some(ref session) => match session.impersonated_by {
+                    Some(admin) => {
+                        account_has_permission(
+                            transaction.as_mut(),
+                            admin,
+                            Permission::PaymentsCredit,
+                        )
+                        .await?
+                    }
+                    None => {
+                        session
+                            .account
+                            .role
+                            .has_permission(Permission::PaymentsCredit)
+                            || matches!(session.auth_method, AuthMethodType::NfcBased)
+                    }
+                },
+                None => true,
             };
 
+            if let Some(payment_limit) = payment
+                .authorization
+                .as_ref()
+                .and_then(|session| session.payment_limit.as_ref())
+            {
+                let mut errors: Vec<String> = Vec::new();
+                let limit = |t: CoinType| payment_limit.0.get(&t).copied().unwrap_or(0);
+                // Credit items must not make room for more expensive items
+                let spent = |t: CoinType| -> i32 {
+                    get_type_amounts(t, &payment.items)
+                        .into_iter()
+                        .map(|amount| amount.max(0))
+                        .sum()
+                };
+
+                if spent(CoinType::Cent) > limit(CoinType::Cent) {
+                    errors.push(String::from("Cent"));
+                }
+                if spent(CoinType::BottleStamp) > limit(CoinType::BottleStamp) {
+                    errors.push(String::from("BottleStamp"));
+                }
+                if spent(CoinType::CoffeeStamp) > limit(CoinType::CoffeeStamp) {
+                    errors.push(String::from("CoffeeStamp"));
+                }
+
+                if !errors.is_empty() {
+                    return ServiceResult::Err(ServiceError::PaymentLimitExceeded(errors));
+                }
+            }
+
             if !allow_credit_loading {
                 for item in payment.items.iter() {
                     if item
@@ -1597,11 +4034,13 @@ impl DatabaseConnection {
                 }
             }
 
+            // Locking the account serializes concurrent payments, so limits cannot be exceeded by parallel requests
             let r = sqlx::query_as::<_, PaymentAccountRow>(
                 r#"
             SELECT a.balance_cents, a.balance_coffee_stamps, a.balance_bottle_stamps
             FROM account AS a
             WHERE a.id = $1
+            FOR UPDATE
         "#,
             )
             .bind(i64::try_from(payment.account).expect("account id is less than 2**63"))
@@ -1643,27 +4082,149 @@ impl DatabaseConnection {
                     return ServiceResult::Err(ServiceError::NotFound);
                 }
             }
+
+            let errors = check_spending_policy(
+                transaction.as_mut(),
+                i64::try_from(payment.account).expect("account id is less than 2**63"),
+                &payment.items,
+                timestamp,
+            )
+            .await?;
+            if !errors.is_empty() {
+                return ServiceResult::Err(ServiceError::PaymentError(errors));
+            }
+
+            if let Some(ref session) = payment.authorization {
+                if session.account.id != payment.account {
+                    check_group_member_payment(
+                        transaction.as_mut(),
+                        payment.account,
+                        session.account.id,
+                        &payment.items,
+                        timestamp,
+                    )
+                    .await?;
+                }
+            }
+        }
+
+        let tx = insert_payment(transaction.as_mut(), payment, timestamp).await?;
+        to_service_result(transaction.commit().await)?;
+
+        ServiceResult::Ok(tx)
+    }
+
+    /// Move the given amount from the source to the target account.
+    ///
+    /// Both accounts get a transaction that is linked to the other one. Returns the source and the target transaction.
+    pub async fn transfer(
+        &mut self,
+        source_account_id: u64,
+        target_account_id: u64,
+        amount: &CoinAmount,
+        authorization: Option<&Session>,
+        timestamp: DateTime<Utc>,
+    ) -> ServiceResult<(models::Transaction, models::Transaction)> {
+        let get = |t: CoinType| amount.0.get(&t).copied().unwrap_or(0);
+        let amount_cents = get(CoinType::Cent);
+        let amount_bottle_stamps = get(CoinType::BottleStamp);
+        let amount_coffee_stamps = get(CoinType::CoffeeStamp);
+
+        let source_id = i64::try_from(source_account_id).expect("account id is less than 2**63");
+        let target_id = i64::try_from(target_account_id).expect("account id is less than 2**63");
+        let mut transaction = self.connection.begin().await?;
+
+        // Lock both accounts in a stable order so that concurrent transfers cannot deadlock
+        let r = sqlx::query(
+            r#"
+            SELECT id, balance_cents, balance_coffee_stamps, balance_bottle_stamps, archived_at
+            FROM account
+            WHERE id = $1 OR id = $2
+            ORDER BY id
+            FOR UPDATE
+        "#,
+        )
+        .bind(source_id)
+        .bind(target_id)
+        .fetch_all(transaction.as_mut())
+        .await;
+        let rows = to_service_result(r)?;
+
+        let source = rows.iter().find(|row| row.get::<i64, _>("id") == source_id);
+        let target = rows.iter().find(|row| row.get::<i64, _>("id") == target_id);
+        let (Some(source), Some(target)) = (source, target) else {
+            return Err(ServiceError::NotFound);
+        };
+        if source
+            .get::<Option<DateTime<Utc>>, _>("archived_at")
+            .is_some()
+            || target
+                .get::<Option<DateTime<Utc>>, _>("archived_at")
+                .is_some()
+        {
+            return Err(ServiceError::AccountArchived);
+        }
+
+        let mut errors: Vec<String> = Vec::new();
+        if amount_cents > 0
+            && source.get::<i32, _>("balance_cents") - amount_cents < MINIMUM_PAYMENT_CENTS
+        {
+            errors.push(String::from("Cent"));
+        }
+        if amount_bottle_stamps > 0
+            && source.get::<i32, _>("balance_bottle_stamps") - amount_bottle_stamps
+                < MINIMUM_PAYMENT_BOTTLE_STAMPS
+        {
+            errors.push(String::from("BottleStamp"));
+        }
+        if amount_coffee_stamps > 0
+            && source.get::<i32, _>("balance_coffee_stamps") - amount_coffee_stamps
+                < MINIMUM_PAYMENT_COFFEE_STAMPS
+        {
+            errors.push(String::from("CoffeeStamp"));
+        }
+        if !errors.is_empty() {
+            return ServiceResult::Err(ServiceError::PaymentError(errors));
+        }
+
+        // Transfers count against the spending limits of the source account like payments
+        let errors = check_spending_policy(
+            transaction.as_mut(),
+            source_id,
+            &[PaymentItem {
+                effective_price: amount.clone(),
+                product_id: None,
+            }],
+            timestamp,
+        )
+        .await?;
+        if !errors.is_empty() {
+            return ServiceResult::Err(ServiceError::PaymentError(errors));
         }
 
         let r = sqlx::query(
             r#"
             WITH
-                transaction_args AS (
+                ids AS (
                     SELECT
-                        nextval('transaction_id_seq') AS transaction_id,
-                        $1 AS timestamp,
-                        $2 AS account_id,
-                        $10 AS authorized_by_account_id,
-                        $11 AS authorized_with_method
+                        nextval('transaction_id_seq') AS source_transaction_id,
+                        nextval('transaction_id_seq') AS target_transaction_id
+                ),
+                source_updated AS (
+                    UPDATE account
+                    SET
+                        balance_cents = balance_cents - $3,
+                        balance_bottle_stamps = balance_bottle_stamps - $4,
+                        balance_coffee_stamps = balance_coffee_stamps - $5
+                    WHERE id = $1
                 ),
-                updated AS (
+                target_updated AS (
                     UPDATE account
                     SET
-                        balance_cents = balance_cents - $7,
-                        balance_bottle_stamps = balance_bottle_stamps - $8,
-                        balance_coffee_stamps = balance_coffee_stamps - $9
-                    WHERE
-                        id = $2
+                        balance_cents = balance_cents + $3,
+                        balance_bottle_stamps = balance_bottle_stamps + $4,
+                        balance_coffee_stamps = balance_coffee_stamps + $5
+                    WHERE id = $2
                 ),
                 inserted AS (
                     INSERT INTO transaction_item (
@@ -1671,128 +4232,48 @@ impl DatabaseConnection {
                         effective_price_cents,
                         effective_price_bottle_stamps,
                         effective_price_coffee_stamps,
-                        product_id,
-                        timestamp,
-                        account_id,
-                        authorized_by_account_id,
-                        authorized_with_method
-                    )
-                    SELECT
-                        transaction_id,
-                        effective_price_cents,
-                        effective_price_bottle_stamps,
-                        effective_price_coffee_stamps,
-                        product_id,
-                        timestamp,
-                        account_id,
-                        authorized_by_account_id,
-                        authorized_with_method
-                    FROM
-                        transaction_args,
-                        UNNEST($3, $4, $5, $6) AS item_args(
-                            effective_price_cents,
-                            effective_price_bottle_stamps,
-                            effective_price_coffee_stamps,
-                            product_id
-                        )
-                    RETURNING
-                        id AS transaction_item_id,
-                        transaction_id,
-                        effective_price_cents,
-                        effective_price_bottle_stamps,
-                        effective_price_coffee_stamps,
-                        product_id,
                         timestamp,
                         account_id,
                         authorized_by_account_id,
-                        authorized_with_method
+                        authorized_with_method,
+                        linked_transaction_id
                     )
-            SELECT
-                inserted.*,
-                p.id as id,
-                p.name as name,
-                p.price_cents as price_cents,
-                p.price_coffee_stamps as price_coffee_stamps,
-                p.price_bottle_stamps as price_bottle_stamps,
-                p.bonus_cents as bonus_cents,
-                p.bonus_coffee_stamps as bonus_coffee_stamps,
-                p.bonus_bottle_stamps as bonus_bottle_stamps,
-                p.nickname as nickname,
-                p.purchase_tax as purchase_tax,
-                NULL as image,
-                NULL as image_mimetype,
-                p.barcode as barcode,
-                p.category as category,
-                p.print_lists as print_lists,
-                p.tags as tags,
-                coalesce(array_agg(account_status.id) FILTER (where account_status.id IS NOT NULL), '{}') as status_id,
-                coalesce(array_agg(account_status.name) FILTER (where account_status.name IS NOT NULL), '{}') as status_name,
-                coalesce(array_agg(account_status.color) FILTER (where account_status.color IS NOT NULL), '{}') as status_color,
-                coalesce(array_agg(account_status.priority) FILTER (where account_status.priority IS NOT NULL), '{}') as status_priority,
-                coalesce(array_agg(product_status_price.price_cents) FILTER (where product_status_price.price_cents IS NOT NULL), '{}') as status_price_cents,
-                coalesce(array_agg(product_status_price.price_bottle_stamps) FILTER (where product_status_price.price_bottle_stamps IS NOT NULL), '{}') as status_price_bottle_stamps,
-                coalesce(array_agg(product_status_price.price_coffee_stamps) FILTER (where product_status_price.price_coffee_stamps IS NOT NULL), '{}') as status_price_coffee_stamps,
-                coalesce(array_agg(product_status_price.bonus_cents) FILTER (where product_status_price.bonus_cents IS NOT NULL), '{}') as status_bonus_cents,
-                coalesce(array_agg(product_status_price.bonus_bottle_stamps) FILTER (where product_status_price.bonus_bottle_stamps IS NOT NULL), '{}') as status_bonus_bottle_stamps,
-                coalesce(array_agg(product_status_price.bonus_coffee_stamps) FILTER (where product_status_price.bonus_coffee_stamps IS NOT NULL), '{}') as status_bonus_coffee_stamps
-            FROM
-                inserted
-                    LEFT OUTER JOIN product p ON inserted.product_id = p.id
-                    LEFT OUTER JOIN product_status_price ON p.id = product_status_price.product_id
-                    LEFT OUTER JOIN account_status on product_status_price.status_id = account_status.id
-            GROUP BY inserted.transaction_item_id, inserted.product_id, inserted.transaction_id, inserted.timestamp, inserted.account_id,
-                    inserted.effective_price_cents, inserted.effective_price_coffee_stamps, inserted.effective_price_bottle_stamps,
-                    inserted.authorized_by_account_id, inserted.authorized_with_method,
-                    p.id, p.name,
-                    p.price_cents, p.price_coffee_stamps, p.price_bottle_stamps,
-                    p.bonus_cents, p.bonus_coffee_stamps, p.bonus_bottle_stamps,
-                    p.nickname, p.purchase_tax, p.barcode, p.category, p.print_lists, p.tags
-            "#,
+                    SELECT source_transaction_id, $3, $4, $5, $6, $1, $7, $8, target_transaction_id FROM ids
+                    UNION ALL
+                    SELECT target_transaction_id, -$3, -$4, -$5, $6, $2, $7, $8, source_transaction_id FROM ids
+                )
+            SELECT source_transaction_id, target_transaction_id FROM ids
+        "#,
         )
+        .bind(source_id)
+        .bind(target_id)
+        .bind(amount_cents)
+        .bind(amount_bottle_stamps)
+        .bind(amount_coffee_stamps)
         .bind(timestamp)
-        .bind(i64::try_from(payment.account).expect("id less than 2**63"))
-        .bind(get_type_amounts(CoinType::Cent, &payment.items))
-        .bind(get_type_amounts(CoinType::BottleStamp, &payment.items))
-        .bind(get_type_amounts(CoinType::CoffeeStamp, &payment.items))
-        .bind(
-            payment
-                .items
-                .iter()
-                .map(|i| {
-                    i.product_id
-                        .map(|v| i64::try_from(v).expect("ids are less than 2**63"))
-                })
-                .collect::<Vec<_>>(),
-        )
-        .bind(total_price_cents)
-        .bind(total_price_bottle_stamps)
-        .bind(total_price_coffee_stamps)
-        .bind(
-            payment
-                .authorization
-                .as_ref()
-                .map(|session| i64::try_from(session.account.id).expect("id less than 2**63")),
-        )
-        .bind(
-            payment
-                .authorization
-                .map(|ref session| AuthMethodTypeDto::from(session.auth_method)),
-        )
-        .fetch_all(transaction.as_mut())
+        .bind(authorization.map(|session| {
+            i64::try_from(session.impersonated_by.unwrap_or(session.account.id))
+                .expect("id less than 2**63")
+        }))
+        .bind(authorization.map(|session| AuthMethodTypeDto::from(session.auth_method)))
+        .fetch_one(transaction.as_mut())
         .await;
+        let r = to_service_result(r)?;
+        let source_transaction_id: i64 = r.get(0);
+        let target_transaction_id: i64 = r.get(1);
 
         to_service_result(transaction.commit().await)?;
 
-        let mut tx = None;
-        for row in to_service_result(r)? {
-            let new_tx = extend_transaction_with_row(tx.as_mut(), row)?;
-            if new_tx.is_some() {
-                assert!(tx.is_none(), "inserted only one tx");
-                tx = new_tx;
-            };
-        }
-
-        ServiceResult::Ok(tx.expect("inserted one TX"))
+        let to_id = |id: i64| u64::try_from(id).expect("id is always non-negative");
+        let source_transaction = self
+            .get_transaction_by_id(to_id(source_transaction_id))
+            .await?
+            .expect("transaction was just inserted");
+        let target_transaction = self
+            .get_transaction_by_id(to_id(target_transaction_id))
+            .await?
+            .expect("transaction was just inserted");
+        Ok((source_transaction, target_transaction))
     }
 
     pub async fn get_all_register_histories(
@@ -1903,6 +4384,55 @@ impl DatabaseConnection {
         Ok(())
     }
 
+    pub async fn get_all_register_payouts(&mut self) -> ServiceResult<Vec<models::RegisterPayout>> {
+        let mut r = sqlx::query_as::<_, RegisterPayoutRow>(
+            r#"
+            SELECT id, timestamp, account_id, transaction_id, amount_cents, paid_by_account_id
+            FROM register_payout
+            ORDER BY timestamp DESC, id DESC
+            "#,
+        )
+        .fetch(self.connection.as_mut());
+
+        let mut out = Vec::new();
+        while let Some(row) = r.next().await {
+            let row = to_service_result(row)?;
+            out.push(row.into());
+        }
+
+        Ok(out)
+    }
+
+    pub async fn store_register_payout(
+        &mut self,
+        payout: models::RegisterPayout,
+    ) -> ServiceResult<models::RegisterPayout> {
+        let to_db_id = |id: u64| i64::try_from(id).expect("id is less than 2**63");
+        let r = sqlx::query(
+            r#"
+            INSERT INTO register_payout (timestamp, account_id, transaction_id, amount_cents, paid_by_account_id)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id
+        "#,
+        )
+        .bind(payout.timestamp)
+        .bind(payout.account_id.map(to_db_id))
+        .bind(to_db_id(payout.transaction_id))
+        .bind(payout.amount_cents)
+        .bind(payout.paid_by_account_id.map(to_db_id))
+        .fetch_one(self.connection.as_mut())
+        .await;
+        let r = to_service_result(r)?;
+
+        Ok(models::RegisterPayout {
+            id: r
+                .get::<i64, _>(0)
+                .try_into()
+                .expect("id is always positive"),
+            ..payout
+        })
+    }
+
     pub async fn get_apple_wallet_pass(
         &mut self,
         account_id: u64,
@@ -1923,10 +4453,95 @@ impl DatabaseConnection {
         )
         .bind(i64::try_from(account_id).expect("ids are less than 2**63"))
         .bind(pass_type_id)
-        .fetch_optional(self.connection.as_mut())
+        .fetch_optional(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?.map(models::AppleWalletPass::from))
+    }
+
+    pub async fn get_apple_wallet_pass_by_qr_code(
+        &mut self,
+        qr_code: &str,
+        pass_type_id: &str,
+    ) -> ServiceResult<Option<AppleWalletPass>> {
+        let r = sqlx::query_as::<_, AppleWalletPassRow>(
+            r#"
+            SELECT
+                account_id,
+                pass_type_id,
+                CAST(authentication_token AS TEXT),
+                qr_code,
+                updated_at
+            FROM apple_wallet_pass
+            WHERE
+                (apple_wallet_pass.qr_code = $1 OR apple_wallet_pass.previous_qr_code = $1)
+                AND apple_wallet_pass.pass_type_id = $2
+            "#,
+        )
+        .bind(qr_code)
+        .bind(pass_type_id)
+        .fetch_optional(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?.map(models::AppleWalletPass::from))
+    }
+
+    /// Replace the qr code of the pass that currently shows `qr_code`, returns `None` if no pass shows this code.
+    ///
+    /// The lookup and the replacement are a single statement, so every code is rotated at most once. The replaced code
+    /// is kept as previous code until a wallet received the new one.
+    pub async fn rotate_apple_wallet_pass_qr_code(
+        &mut self,
+        qr_code: &str,
+        pass_type_id: &str,
+        new_qr_code: &str,
+        updated_at: u64,
+    ) -> ServiceResult<Option<AppleWalletPass>> {
+        let r = sqlx::query_as::<_, AppleWalletPassRow>(
+            r#"
+            UPDATE apple_wallet_pass
+            SET previous_qr_code = qr_code, qr_code = $3, updated_at = $4
+            WHERE
+                apple_wallet_pass.qr_code = $1 AND apple_wallet_pass.pass_type_id = $2
+            RETURNING
+                account_id,
+                pass_type_id,
+                CAST(authentication_token AS TEXT),
+                qr_code,
+                updated_at
+            "#,
+        )
+        .bind(qr_code)
+        .bind(pass_type_id)
+        .bind(new_qr_code)
+        .bind(i64::try_from(updated_at).expect("ids are less than 2**63"))
+        .fetch_optional(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?.map(models::AppleWalletPass::from))
+    }
+
+    /// A wallet received the pass with `qr_code`, the previous code is no longer valid
+    pub async fn confirm_apple_wallet_pass_qr_code(
+        &mut self,
+        account_id: u64,
+        pass_type_id: &str,
+        qr_code: &str,
+    ) -> ServiceResult<()> {
+        let r = sqlx::query(
+            r#"
+            UPDATE apple_wallet_pass
+            SET previous_qr_code = NULL
+            WHERE account_id = $1 AND pass_type_id = $2 AND qr_code = $3 AND previous_qr_code IS NOT NULL
+            "#,
+        )
+        .bind(i64::try_from(account_id).expect("ids are less than 2**63"))
+        .bind(pass_type_id)
+        .bind(qr_code)
+        .execute(self.connection.as_mut())
         .await;
-
-        Ok(to_service_result(r)?.map(models::AppleWalletPass::from))
+        to_service_result(r)?;
+        Ok(())
     }
 
     pub async fn list_passes_for_device(
@@ -1997,6 +4612,207 @@ impl DatabaseConnection {
         Ok(pass)
     }
 
+    /// Queue a push job for every device that registered the pass, pending jobs of a device are due again immediately
+    pub async fn enqueue_push_jobs(
+        &mut self,
+        account_id: u64,
+        pass_type_id: &str,
+    ) -> ServiceResult<u64> {
+        let r = sqlx::query(
+            r#"
+            INSERT INTO push_job (account_id, pass_type_id, device_id, push_token)
+            SELECT account_id, pass_type_id, device_id, push_token
+            FROM apple_wallet_registration
+            WHERE account_id = $1 AND pass_type_id = $2
+            ON CONFLICT (kind, account_id, pass_type_id, device_id) WHERE dead_at IS NULL
+            DO UPDATE SET push_token = EXCLUDED.push_token, next_attempt_at = now()
+            "#,
+        )
+        .bind(i64::try_from(account_id).expect("ids are less than 2**63"))
+        .bind(pass_type_id)
+        .execute(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?.rows_affected())
+    }
+
+    /// Queue an update of the google wallet object of the account, if the account has one
+    pub async fn enqueue_google_wallet_job(&mut self, account_id: u64) -> ServiceResult<u64> {
+        let r = sqlx::query(
+            r#"
+            INSERT INTO push_job (kind, account_id, pass_type_id, device_id, push_token)
+            SELECT 'google_wallet', account_id, '', object_id, ''
+            FROM google_wallet_object
+            WHERE account_id = $1
+            ON CONFLICT (kind, account_id, pass_type_id, device_id) WHERE dead_at IS NULL
+            DO UPDATE SET next_attempt_at = now()
+            "#,
+        )
+        .bind(i64::try_from(account_id).expect("ids are less than 2**63"))
+        .execute(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?.rows_affected())
+    }
+
+    /// Take up to `limit` due jobs. The jobs are hidden from other workers for `lease` until they are resolved.
+    pub async fn claim_push_jobs(
+        &mut self,
+        limit: u32,
+        lease: Duration,
+    ) -> ServiceResult<Vec<models::PushJob>> {
+        let r = sqlx::query_as::<_, PushJobRow>(
+            r#"
+            UPDATE push_job
+            SET next_attempt_at = now() + $2
+            WHERE id IN (
+                SELECT id FROM push_job
+                WHERE dead_at IS NULL AND next_attempt_at <= now()
+                ORDER BY next_attempt_at ASC
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, kind, account_id, pass_type_id, device_id, push_token, attempts, next_attempt_at, last_error, created_at, dead_at
+            "#,
+        )
+        .bind(i64::from(limit))
+        .bind(lease)
+        .fetch_all(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?
+            .into_iter()
+            .map(models::PushJob::from)
+            .collect())
+    }
+
+    pub async fn delete_push_job(&mut self, id: u64) -> ServiceResult<()> {
+        let r = sqlx::query(r#"DELETE FROM push_job WHERE id = $1"#)
+            .bind(i64::try_from(id).expect("id is always less than 2**63"))
+            .execute(self.connection.as_mut())
+            .await;
+        to_service_result(r)?;
+        Ok(())
+    }
+
+    /// Record a failed delivery, the job is retried at `next_attempt_at` or moved to the dead letters if `None`
+    pub async fn fail_push_job(
+        &mut self,
+        id: u64,
+        attempts: u32,
+        last_error: &str,
+        next_attempt_at: Option<DateTime<Utc>>,
+    ) -> ServiceResult<()> {
+        let r = sqlx::query(
+            r#"
+            UPDATE push_job
+            SET attempts = $2, last_error = $3,
+                next_attempt_at = COALESCE($4, next_attempt_at),
+                dead_at = CASE WHEN $4 IS NULL THEN now() END
+            WHERE id = $1
+            "#,
+        )
+        .bind(i64::try_from(id).expect("id is always less than 2**63"))
+        .bind(i32::try_from(attempts).expect("attempts are less than 2**31"))
+        .bind(last_error)
+        .bind(next_attempt_at)
+        .execute(self.connection.as_mut())
+        .await;
+        to_service_result(r)?;
+        Ok(())
+    }
+
+    pub async fn get_dead_push_jobs(&mut self) -> ServiceResult<Vec<models::PushJob>> {
+        let r = sqlx::query_as::<_, PushJobRow>(
+            r#"
+            SELECT id, kind, account_id, pass_type_id, device_id, push_token, attempts, next_attempt_at, last_error, created_at, dead_at
+            FROM push_job
+            WHERE dead_at IS NOT NULL
+            ORDER BY dead_at DESC
+            "#,
+        )
+        .fetch_all(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?
+            .into_iter()
+            .map(models::PushJob::from)
+            .collect())
+    }
+
+    /// Move a dead job back into the queue, it is merged with a pending job of the same device
+    pub async fn retry_dead_push_job(&mut self, id: u64) -> ServiceResult<()> {
+        let r = sqlx::query(
+            r#"
+            WITH dead AS (
+                DELETE FROM push_job
+                WHERE id = $1 AND dead_at IS NOT NULL
+                RETURNING kind, account_id, pass_type_id, device_id, push_token
+            )
+            INSERT INTO push_job (kind, account_id, pass_type_id, device_id, push_token)
+            SELECT kind, account_id, pass_type_id, device_id, push_token FROM dead
+            ON CONFLICT (kind, account_id, pass_type_id, device_id) WHERE dead_at IS NULL
+            DO UPDATE SET next_attempt_at = now()
+            "#,
+        )
+        .bind(i64::try_from(id).expect("id is always less than 2**63"))
+        .execute(self.connection.as_mut())
+        .await;
+        if to_service_result(r)?.rows_affected() != 1 {
+            return Err(ServiceError::NotFound);
+        }
+        Ok(())
+    }
+
+    /// Remove dead jobs that are older than `max_age`
+    pub async fn cleanup_dead_push_jobs(&mut self, max_age: Duration) -> ServiceResult<()> {
+        let r = sqlx::query(r#"DELETE FROM push_job WHERE dead_at < now() - $1"#)
+            .bind(max_age)
+            .execute(self.connection.as_mut())
+            .await;
+        to_service_result(r)?;
+        Ok(())
+    }
+
+    pub async fn get_google_wallet_object(
+        &mut self,
+        account_id: u64,
+    ) -> ServiceResult<Option<models::GoogleWalletObject>> {
+        let r = sqlx::query_as::<_, GoogleWalletObjectRow>(
+            r#"
+            SELECT account_id, object_id, created_at
+            FROM google_wallet_object
+            WHERE account_id = $1
+            "#,
+        )
+        .bind(i64::try_from(account_id).expect("ids are less than 2**63"))
+        .fetch_optional(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?.map(models::GoogleWalletObject::from))
+    }
+
+    pub async fn store_google_wallet_object(
+        &mut self,
+        account_id: u64,
+        object_id: &str,
+    ) -> ServiceResult<models::GoogleWalletObject> {
+        let r = sqlx::query_as::<_, GoogleWalletObjectRow>(
+            r#"
+            INSERT INTO google_wallet_object (account_id, object_id)
+            VALUES ($1, $2)
+            ON CONFLICT (account_id) DO UPDATE SET object_id = $2
+            RETURNING account_id, object_id, created_at
+            "#,
+        )
+        .bind(i64::try_from(account_id).expect("ids are less than 2**63"))
+        .bind(object_id)
+        .fetch_one(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?.into())
+    }
+
     #[allow(dead_code)]
     pub async fn delete_apple_wallet_pass(
         &mut self,
@@ -2044,6 +4860,7 @@ impl DatabaseConnection {
 
         Ok(to_service_result(r)?.map(models::AppleWalletRegistration::from))
     }
+    #[allow(dead_code)]
     pub async fn list_apple_wallet_registration(
         &mut self,
         account_id: u64,
@@ -2074,6 +4891,32 @@ impl DatabaseConnection {
         Ok(out)
     }
 
+    /// Registrations of all pass types of the given account
+    pub async fn get_apple_wallet_registrations_by_account(
+        &mut self,
+        account_id: u64,
+    ) -> ServiceResult<Vec<AppleWalletRegistration>> {
+        let r = sqlx::query_as::<_, AppleWalletRegistrationRow>(
+            r#"
+            SELECT
+                account_id,
+                pass_type_id,
+                device_id,
+                push_token
+            FROM apple_wallet_registration
+            WHERE apple_wallet_registration.account_id = $1
+            "#,
+        )
+        .bind(i64::try_from(account_id).expect("ids are less than 2**63"))
+        .fetch_all(self.connection.as_mut())
+        .await;
+
+        Ok(to_service_result(r)?
+            .into_iter()
+            .map(AppleWalletRegistration::from)
+            .collect())
+    }
+
     pub async fn store_apple_wallet_registration(
         &mut self,
         registration: AppleWalletRegistration,
@@ -2102,9 +4945,9 @@ impl DatabaseConnection {
         .bind(&registration.pass_type_id)
         .bind(&registration.device_id)
         .bind(&registration.push_token)
-        .fetch_one(self.connection.as_mut())
+        .execute(self.connection.as_mut())
         .await;
-        let r = to_service_result(r)?;
+        to_service_result(r)?;
 
         Ok(registration)
     }
@@ -2416,6 +5259,486 @@ impl DatabaseConnection {
     }
 }
 
+/// Book the payment items as a new transaction and update the balance without any checks
+async fn account_has_permission(
+    connection: &mut PgConnection,
+    account_id: u64,
+    permission: Permission,
+) -> ServiceResult<bool> {
+    let r = sqlx::query(
+        r#"
+        SELECT $2 = ANY(account_role.permissions) AS has_permission
+        FROM account
+            INNER JOIN account_role ON account.role_id = account_role.id
+        WHERE account.id = $1
+    "#,
+    )
+    .bind(i64::try_from(account_id).expect("account id is less than 2**63"))
+    .bind(permission_to_key(permission))
+    .fetch_optional(&mut *connection)
+    .await;
+
+    Ok(to_service_result(r)?.is_some_and(|row| row.get::<bool, _>("has_permission")))
+}
+
+async fn insert_payment(
+    connection: &mut PgConnection,
+    payment: models::Payment,
+    timestamp: DateTime<Utc>,
+) -> ServiceResult<models::Transaction> {
+    fn get_type_amounts(t: CoinType, items: &[PaymentItem]) -> Vec<i32> {
+        items
+            .iter()
+            .map(|item| *item.effective_price.0.get(&t).unwrap_or(&0))
+            .collect()
+    }
+
+    let total_price_cents: i32 = get_type_amounts(CoinType::Cent, &payment.items)
+        .into_iter()
+        .sum();
+    let total_price_bottle_stamps: i32 = get_type_amounts(CoinType::BottleStamp, &payment.items)
+        .into_iter()
+        .sum();
+    let total_price_coffee_stamps: i32 = get_type_amounts(CoinType::CoffeeStamp, &payment.items)
+        .into_iter()
+        .sum();
+
+    let r = sqlx::query(r#"SELECT archived_at FROM account WHERE id = $1 FOR UPDATE"#)
+        .bind(i64::try_from(payment.account).expect("account id is less than 2**63"))
+        .fetch_optional(&mut *connection)
+        .await;
+    match to_service_result(r)? {
+        None => return Err(ServiceError::NotFound),
+        Some(row) if row.get::<Option<DateTime<Utc>>, _>("archived_at").is_some() => {
+            return Err(ServiceError::AccountArchived)
+        }
+        Some(_) => {}
+    }
+
+    let r = sqlx::query(
+        r#"
+        WITH
+            transaction_args AS (
+                SELECT
+                    nextval('transaction_id_seq') AS transaction_id,
+                    $1 AS timestamp,
+                    $2 AS account_id,
+                    $10 AS authorized_by_account_id,
+                    $11 AS authorized_with_method
+            ),
+            updated AS (
+                UPDATE account
+                SET
+                    balance_cents = balance_cents - $7,
+                    balance_bottle_stamps = balance_bottle_stamps - $8,
+                    balance_coffee_stamps = balance_coffee_stamps - $9
+                WHERE
+                    id = $2
+            ),
+            inserted AS (
+                INSERT INTO transaction_item (
+                    transaction_id,
+                    effective_price_cents,
+                    effective_price_bottle_stamps,
+                    effective_price_coffee_stamps,
+                    product_id,
+                    timestamp,
+                    account_id,
+                    authorized_by_account_id,
+                    authorized_with_method
+                )
+                SELECT
+                    transaction_id,
+                    effective_price_cents,
+                    effective_price_bottle_stamps,
+                    effective_price_coffee_stamps,
+                    product_id,
+                    timestamp,
+                    account_id,
+                    authorized_by_account_id,
+                    authorized_with_method
+                FROM
+                    transaction_args,
+                    UNNEST($3, $4, $5, $6) AS item_args(
+                        effective_price_cents,
+                        effective_price_bottle_stamps,
+                        effective_price_coffee_stamps,
+                        product_id
+                    )
+                RETURNING
+                    id AS transaction_item_id,
+                    transaction_id,
+                    effective_price_cents,
+                    effective_price_bottle_stamps,
+                    effective_price_coffee_stamps,
+                    product_id,
+                    timestamp,
+                    account_id,
+                    authorized_by_account_id,
+                    authorized_with_method,
+                    linked_transaction_id
+                )
+        SELECT
+            inserted.*,
+            p.id as id,
+            p.name as name,
+            p.price_cents as price_cents,
+            p.price_coffee_stamps as price_coffee_stamps,
+            p.price_bottle_stamps as price_bottle_stamps,
+            p.bonus_cents as bonus_cents,
+            p.bonus_coffee_stamps as bonus_coffee_stamps,
+            p.bonus_bottle_stamps as bonus_bottle_stamps,
+            p.nickname as nickname,
+            p.purchase_tax as purchase_tax,
+            NULL as image,
+            NULL as image_mimetype,
+            p.barcode as barcode,
+            p.category as category,
+            p.print_lists as print_lists,
+            p.tags as tags,
+            coalesce(array_agg(account_status.id) FILTER (where account_status.id IS NOT NULL), '{}') as status_id,
+            coalesce(array_agg(account_status.name) FILTER (where account_status.name IS NOT NULL), '{}') as status_name,
+            coalesce(array_agg(account_status.color) FILTER (where account_status.color IS NOT NULL), '{}') as status_color,
+            coalesce(array_agg(account_status.priority) FILTER (where account_status.priority IS NOT NULL), '{}') as status_priority,
+            coalesce(array_agg(product_status_price.price_cents) FILTER (where product_status_price.price_cents IS NOT NULL), '{}') as status_price_cents,
+            coalesce(array_agg(product_status_price.price_bottle_stamps) FILTER (where product_status_price.price_bottle_stamps IS NOT NULL), '{}') as status_price_bottle_stamps,
+            coalesce(array_agg(product_status_price.price_coffee_stamps) FILTER (where product_status_price.price_coffee_stamps IS NOT NULL), '{}') as status_price_coffee_stamps,
+            coalesce(array_agg(product_status_price.bonus_cents) FILTER (where product_status_price.bonus_cents IS NOT NULL), '{}') as status_bonus_cents,
+            coalesce(array_agg(product_status_price.bonus_bottle_stamps) FILTER (where product_status_price.bonus_bottle_stamps IS NOT NULL), '{}') as status_bonus_bottle_stamps,
+            coalesce(array_agg(product_status_price.bonus_coffee_stamps) FILTER (where product_status_price.bonus_coffee_stamps IS NOT NULL), '{}') as status_bonus_coffee_stamps
+        FROM
+            inserted
+                LEFT OUTER JOIN product p ON inserted.product_id = p.id
+                LEFT OUTER JOIN product_status_price ON p.id = product_status_price.product_id
+                LEFT OUTER JOIN account_status on product_status_price.status_id = account_status.id
+        GROUP BY inserted.transaction_item_id, inserted.product_id, inserted.transaction_id, inserted.timestamp, inserted.account_id,
+                inserted.effective_price_cents, inserted.effective_price_coffee_stamps, inserted.effective_price_bottle_stamps,
+                inserted.authorized_by_account_id, inserted.authorized_with_method, inserted.linked_transaction_id,
+                p.id, p.name,
+                p.price_cents, p.price_coffee_stamps, p.price_bottle_stamps,
+                p.bonus_cents, p.bonus_coffee_stamps, p.bonus_bottle_stamps,
+                p.nickname, p.purchase_tax, p.barcode, p.category, p.print_lists, p.tags
+        ORDER BY inserted.transaction_item_id ASC
+        "#,
+    )
+    .bind(timestamp)
+    .bind(i64::try_from(payment.account).expect("id less than 2**63"))
+    .bind(get_type_amounts(CoinType::Cent, &payment.items))
+    .bind(get_type_amounts(CoinType::BottleStamp, &payment.items))
+    .bind(get_type_amounts(CoinType::CoffeeStamp, &payment.items))
+    .bind(
+        payment
+            .items
+            .iter()
+            .map(|i| {
+                i.product_id
+                    .map(|v| i64::try_from(v).expect("ids are less than 2**63"))
+            })
+            .collect::<Vec<_>>(),
+    )
+    .bind(total_price_cents)
+    .bind(total_price_bottle_stamps)
+    .bind(total_price_coffee_stamps)
+    .bind(
+        payment
+            .authorization
+            .as_ref()
+            .map(|session| {
+                i64::try_from(session.impersonated_by.unwrap_or(session.account.id))
+                    .expect("id less than 2**63")
+            }),
+    )
+    .bind(
+        payment
+            .authorization
+            .map(|ref session| AuthMethodTypeDto::from(session.auth_method)),
+    )
+    .fetch_all(&mut *connection)
+    .await;
+
+    let mut tx = None;
+    for row in to_service_result(r)? {
+        let new_tx = extend_transaction_with_row(tx.as_mut(), row)?;
+        if new_tx.is_some() {
+            assert!(tx.is_none(), "inserted only one tx");
+            tx = new_tx;
+        };
+    }
+
+    ServiceResult::Ok(tx.expect("inserted one TX"))
+}
+
+/// Insert or update the account together with its auth methods
+async fn store_account(
+    connection: &mut PgConnection,
+    mut account: models::Account,
+) -> ServiceResult<models::Account> {
+    let q = if account.id == 0 {
+        sqlx::query(
+            r#"
+            INSERT INTO account (balance_cents, balance_coffee_stamps, balance_bottle_stamps, name, email, role_id, enable_monthly_mail_report, enable_automatic_stamp_usage, status_id)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            RETURNING id
+        "#,
+        )
+    } else {
+        sqlx::query(r#"
+            WITH
+                delete AS (DELETE FROM account_auth_method WHERE account_id = $1)
+            UPDATE account
+            SET balance_cents = $2, balance_coffee_stamps = $3, balance_bottle_stamps = $4, name = $5, email = $6, role_id = $7, enable_monthly_mail_report = $8, enable_automatic_stamp_usage = $9, status_id = $10
+            WHERE id = $1
+            RETURNING id
+
+        "#).bind(i64::try_from(account.id).expect("account id is less than 2**63"))
+    };
+    let r = q
+        .bind(account.balance.0.get(&CoinType::Cent).unwrap_or(&0))
+        .bind(account.balance.0.get(&CoinType::CoffeeStamp).unwrap_or(&0))
+        .bind(account.balance.0.get(&CoinType::BottleStamp).unwrap_or(&0))
+        .bind(&account.name)
+        .bind(&account.email)
+        .bind(i64::try_from(account.role.id).expect("role id is less than 2**63"))
+        .bind(account.enable_monthly_mail_report)
+        .bind(account.enable_automatic_stamp_usage)
+        .bind(
+            account
+                .status
+                .as_ref()
+                .map(|s| i64::try_from(s.id).expect("status id is less than 2**63")),
+        )
+        .fetch_one(&mut *connection)
+        .await;
+    let r = to_service_result(r)?;
+    let account_id = r.get::<i64, _>(0);
+    account.id = account_id.try_into().expect("id is always positive");
+
+    let r = sqlx::query(
+        r#"
+        INSERT INTO account_auth_method (account_id, login_key, data, depends_on_session)
+        SELECT $1, login_key, data, CAST(depends_on_session AS UUID) as depends_on_session
+        FROM UNNEST($2, $3, $4) AS input (login_key, data, depends_on_session)
+    "#,
+    )
+    .bind(account_id)
+    .bind(
+        account
+            .auth_methods
+            .iter()
+            .map(|m| m.to_request(account.id).login_key())
+            .collect::<Vec<_>>(),
+    )
+    .bind(
+        account
+            .auth_methods
+            .iter()
+            .map(|m| {
+                serde_json::to_value(AccountAuthMethodData::from(m.clone()))
+                    .expect("to json cannot fail")
+            })
+            .collect::<Vec<_>>(),
+    )
+    .bind(
+        account
+            .auth_methods
+            .iter()
+            .map(|m| {
+                if let AuthMethod::NfcBased(ref nfc_based) = m {
+                    nfc_based.depends_on_session.clone()
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>(),
+    )
+    .execute(&mut *connection)
+    .await;
+    to_service_result(r)?;
+
+    Ok(account)
+}
+
+/// Violations of the spending policy of the account by the given payment items, eg. `SpendingLimit:day:Cent` or `BlockedProductTag:alcohol`
+/// Payments of a member from a group account must stay within the spending limit of the member
+/// and the spending policy of the member's own account. Other accounts are not checked.
+async fn check_group_member_payment(
+    connection: &mut PgConnection,
+    group_account_id: u64,
+    member_account_id: u64,
+    items: &[PaymentItem],
+    timestamp: DateTime<Utc>,
+) -> ServiceResult<()> {
+    let r = sqlx::query(
+        r#"
+        SELECT spending_limit_cents
+        FROM account_group_member
+        WHERE group_account_id = $1 AND member_account_id = $2
+    "#,
+    )
+    .bind(i64::try_from(group_account_id).expect("account id is less than 2**63"))
+    .bind(i64::try_from(member_account_id).expect("account id is less than 2**63"))
+    .fetch_optional(&mut *connection)
+    .await;
+    let Some(member) = to_service_result(r)? else {
+        return Ok(());
+    };
+
+    if let Some(limit) = member.get::<Option<i32>, _>("spending_limit_cents") {
+        let price: i32 = items
+            .iter()
+            .map(|item| {
+                item.effective_price
+                    .0
+                    .get(&CoinType::Cent)
+                    .copied()
+                    .unwrap_or(0)
+                    .max(0)
+            })
+            .sum();
+        let spent = group_member_spending(
+            &mut *connection,
+            group_account_id,
+            member_account_id,
+            timestamp - Duration::days(MEMBER_SPENDING_LIMIT_DAYS),
+        )
+        .await?;
+        if price > 0 && spent + price > limit {
+            return Err(ServiceError::PaymentLimitExceeded(vec![String::from(
+                "Cent",
+            )]));
+        }
+    }
+
+    let errors = check_spending_policy(
+        &mut *connection,
+        i64::try_from(member_account_id).expect("account id is less than 2**63"),
+        items,
+        timestamp,
+    )
+    .await?;
+    if !errors.is_empty() {
+        return Err(ServiceError::PaymentError(errors));
+    }
+
+    Ok(())
+}
+
+/// Cents the member spent from the group account since the given time, credit and refunds are not counted
+async fn group_member_spending(
+    connection: &mut PgConnection,
+    group_account_id: u64,
+    member_account_id: u64,
+    since: DateTime<Utc>,
+) -> ServiceResult<i32> {
+    let r = sqlx::query(
+        r#"
+        SELECT CAST(coalesce(sum(greatest(effective_price_cents, 0)), 0) AS INT)
+        FROM transaction_item
+        WHERE account_id = $1 AND authorized_by_account_id = $2 AND timestamp > $3
+    "#,
+    )
+    .bind(i64::try_from(group_account_id).expect("account id is less than 2**63"))
+    .bind(i64::try_from(member_account_id).expect("account id is less than 2**63"))
+    .bind(since)
+    .fetch_one(&mut *connection)
+    .await;
+
+    Ok(to_service_result(r)?.get(0))
+}
+
+async fn check_spending_policy(
+    connection: &mut PgConnection,
+    account_id: i64,
+    items: &[PaymentItem],
+    timestamp: DateTime<Utc>,
+) -> ServiceResult<Vec<String>> {
+    let mut errors: Vec<String> = Vec::new();
+
+    let product_ids: Vec<i64> = items
+        .iter()
+        .filter_map(|item| item.product_id)
+        .map(|id| i64::try_from(id).expect("ids are less than 2**63"))
+        .collect();
+    if !product_ids.is_empty() {
+        let r = sqlx::query(
+            r#"
+            SELECT DISTINCT tag
+            FROM product, unnest(product.tags) AS tag
+            WHERE product.id = ANY($2) AND tag = ANY(
+                SELECT unnest(blocked_product_tags) FROM account_spending_policy WHERE account_id = $1
+            )
+            ORDER BY tag ASC
+        "#,
+        )
+        .bind(account_id)
+        .bind(&product_ids)
+        .fetch_all(&mut *connection)
+        .await;
+        for row in to_service_result(r)? {
+            errors.push(format!("BlockedProductTag:{}", row.get::<String, _>(0)));
+        }
+    }
+
+    let r = sqlx::query_as::<_, SpendingLimitRow>(
+        r#"
+        SELECT period, limit_cents, limit_coffee_stamps, limit_bottle_stamps
+        FROM account_spending_limit
+        WHERE account_id = $1
+        ORDER BY period ASC
+    "#,
+    )
+    .bind(account_id)
+    .fetch_all(&mut *connection)
+    .await;
+
+    for limit in to_service_result(r)? {
+        // Credit loading and refunds do not count against the limit
+        let r = sqlx::query(
+            r#"
+            SELECT
+                CAST(coalesce(sum(greatest(effective_price_cents, 0)), 0) AS INT),
+                CAST(coalesce(sum(greatest(effective_price_coffee_stamps, 0)), 0) AS INT),
+                CAST(coalesce(sum(greatest(effective_price_bottle_stamps, 0)), 0) AS INT)
+            FROM transaction_item
+            WHERE account_id = $1 AND timestamp > $2
+        "#,
+        )
+        .bind(account_id)
+        .bind(timestamp - limit.period.duration())
+        .fetch_one(&mut *connection)
+        .await;
+        let spent = to_service_result(r)?;
+
+        for (coin_type, max) in limit.limits() {
+            let spent: i32 = spent.get(match coin_type {
+                CoinType::Cent => 0,
+                CoinType::CoffeeStamp => 1,
+                CoinType::BottleStamp => 2,
+            });
+            let price: i32 = items
+                .iter()
+                .map(|item| {
+                    item.effective_price
+                        .0
+                        .get(&coin_type)
+                        .copied()
+                        .unwrap_or(0)
+                        .max(0)
+                })
+                .sum();
+            if price > 0 && spent + price > max {
+                errors.push(format!(
+                    "SpendingLimit:{}:{:?}",
+                    limit.period.name(),
+                    coin_type
+                ));
+            }
+        }
+    }
+
+    Ok(errors)
+}
+
 fn extend_transaction_with_row(
     prev: Option<&mut Transaction>,
     row: PgRow,
@@ -2434,6 +5757,9 @@ fn extend_transaction_with_row(
                     .authorized_by_account_id
                     .map(|id| u64::try_from(id).expect("id is always non-negative")),
                 authorized_with_method: txrow.authorized_with_method.map(|m| m.into()),
+                linked_transaction_id: txrow
+                    .linked_transaction_id
+                    .map(|id| u64::try_from(id).expect("id is always non-negative")),
                 items: Vec::new(),
             });
             new_tx.as_mut().expect("just constructed as Some")
@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use base64::engine::general_purpose;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use sqlx::{PgPool, Row};
+use tokio::sync::Mutex;
+
+use crate::error::ServiceResult;
+
+use super::to_service_result;
+
+/// Intermediate state of a running nfc challenge/response authentication
+#[derive(Debug, PartialEq, Clone)]
+pub struct AppStateNfcChallenge {
+    pub card_id: Vec<u8>,
+    /// The nfc reader that requested the challenge, if it is registered
+    pub reader_id: Option<u64>,
+    pub valid_until: DateTime<Utc>,
+    pub rnd_a: Vec<u8>,
+    pub rnd_b: Vec<u8>,
+}
+
+/// Storage for pending nfc challenges, keyed by a random challenge id.
+///
+/// The in-memory storage only works for a single server instance,
+/// use the postgres storage if multiple instances share the same database.
+#[derive(Clone)]
+pub enum NfcChallengeStorage {
+    Memory(Arc<Mutex<HashMap<String, AppStateNfcChallenge>>>),
+    Postgres(PgPool),
+}
+
+fn generate_challenge_id() -> String {
+    let mut data = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut data);
+    general_purpose::STANDARD.encode(data)
+}
+
+impl NfcChallengeStorage {
+    pub fn memory() -> Self {
+        NfcChallengeStorage::Memory(Arc::new(Mutex::new(HashMap::new())))
+    }
+
+    pub fn postgres(pool: PgPool) -> Self {
+        NfcChallengeStorage::Postgres(pool)
+    }
+
+    /// Store the challenge state, returns the id the client has to send with its response
+    pub async fn insert(&self, challenge: AppStateNfcChallenge) -> ServiceResult<String> {
+        let challenge_id = generate_challenge_id();
+
+        match self {
+            NfcChallengeStorage::Memory(map) => {
+                let mut map = map.lock().await;
+                map.insert(challenge_id.clone(), challenge);
+            }
+            NfcChallengeStorage::Postgres(pool) => {
+                let r = sqlx::query(
+                    r#"
+                    INSERT INTO nfc_challenge (challenge_id, card_id, reader_id, valid_until, rnd_a, rnd_b) VALUES
+                        ($1, $2, $3, $4, $5, $6)
+                "#,
+                )
+                .bind(&challenge_id)
+                .bind(&challenge.card_id)
+                .bind(
+                    challenge
+                        .reader_id
+                        .map(|id| i64::try_from(id).expect("reader id is less than 2**63")),
+                )
+                .bind(challenge.valid_until)
+                .bind(&challenge.rnd_a)
+                .bind(&challenge.rnd_b)
+                .execute(pool)
+                .await;
+                to_service_result(r)?;
+            }
+        }
+
+        Ok(challenge_id)
+    }
+
+    /// Remove and return the challenge state, every challenge can only be taken once
+    pub async fn take(&self, challenge_id: &str) -> ServiceResult<Option<AppStateNfcChallenge>> {
+        match self {
+            NfcChallengeStorage::Memory(map) => {
+                let mut map = map.lock().await;
+                Ok(map.remove(challenge_id))
+            }
+            NfcChallengeStorage::Postgres(pool) => {
+                let r = sqlx::query(
+                    r#"
+                    DELETE FROM nfc_challenge WHERE challenge_id = $1
+                    RETURNING card_id, reader_id, valid_until, rnd_a, rnd_b
+                "#,
+                )
+                .bind(challenge_id)
+                .fetch_optional(pool)
+                .await;
+
+                Ok(to_service_result(r)?.map(|row| AppStateNfcChallenge {
+                    card_id: row.get(0),
+                    reader_id: row
+                        .get::<Option<i64>, _>(1)
+                        .map(|id| id.try_into().expect("id is always positive")),
+                    valid_until: row.get(2),
+                    rnd_a: row.get(3),
+                    rnd_b: row.get(4),
+                }))
+            }
+        }
+    }
+
+    /// Remove all expired challenges
+    pub async fn sweep_expired(&self) -> ServiceResult<()> {
+        match self {
+            NfcChallengeStorage::Memory(map) => {
+                let now = Utc::now();
+                let mut map = map.lock().await;
+                map.retain(|_, challenge| challenge.valid_until >= now);
+            }
+            NfcChallengeStorage::Postgres(pool) => {
+                let r = sqlx::query(
+                    r#"
+                    DELETE FROM nfc_challenge WHERE valid_until < now()
+                "#,
+                )
+                .execute(pool)
+                .await;
+                to_service_result(r)?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -279,3 +279,376 @@ ALTER TABLE product ADD COLUMN purchase_tax INT NOT NULL DEFAULT 19;
 
 --##25 Add print lists
 ALTER TABLE product ADD COLUMN print_lists TEXT[] NOT NULL DEFAULT ARRAY[]::TEXT[];
+
+--##26 Replace fixed account roles with permission based roles
+CREATE TABLE account_role (
+    id BIGINT
+        GENERATED ALWAYS AS IDENTITY (START WITH 1)
+        PRIMARY KEY
+        CHECK (id > 0),
+    name VARCHAR NOT NULL UNIQUE,
+    permissions TEXT[] NOT NULL DEFAULT ARRAY[]::TEXT[]
+);
+
+INSERT INTO account_role (name, permissions) VALUES
+    ('Basic', ARRAY[]::TEXT[]),
+    ('Member', ARRAY[]::TEXT[]),
+    ('Purchaser', ARRAY['products.write', 'purchases.write']),
+    ('Admin', ARRAY[
+        'accounts.read', 'accounts.write', 'roles.write',
+        'payments.write', 'payments.credit',
+        'products.write', 'purchases.write', 'register.write', 'reports.send'
+    ]);
+
+ALTER TABLE account ADD COLUMN role_id BIGINT;
+UPDATE account SET role_id = account_role.id
+    FROM account_role
+    WHERE lower(account_role.name) = CAST(account.role AS TEXT);
+ALTER TABLE account ALTER COLUMN role_id SET NOT NULL;
+ALTER TABLE account
+    ADD CONSTRAINT fk_account_role
+    FOREIGN KEY(role_id)
+        REFERENCES account_role(id)
+        ON DELETE RESTRICT;
+ALTER TABLE account DROP COLUMN role;
+DROP TYPE tp_account_role;
+
+--##27 Add session metadata
+ALTER TABLE session ADD COLUMN public_id UUID NOT NULL UNIQUE DEFAULT gen_random_uuid();
+ALTER TABLE session ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+ALTER TABLE session ADD COLUMN last_used_at TIMESTAMPTZ NOT NULL DEFAULT now();
+ALTER TABLE session ADD COLUMN user_agent TEXT;
+ALTER TABLE session ADD COLUMN ip_address TEXT;
+ALTER TABLE session ADD COLUMN device_name TEXT;
+
+--##28 Add sliding session expiry and refresh tokens
+ALTER TABLE session ADD COLUMN sliding_expiry_seconds INT;
+ALTER TABLE session ADD COLUMN refresh_token UUID UNIQUE;
+ALTER TABLE session ADD COLUMN refresh_valid_until TIMESTAMPTZ;
+ALTER TABLE account_auth_method DROP CONSTRAINT fk_depends_on_session;
+ALTER TABLE account_auth_method
+    ADD CONSTRAINT fk_depends_on_session
+    FOREIGN KEY(depends_on_session)
+        REFERENCES session(uuid)
+        ON DELETE CASCADE
+        ON UPDATE CASCADE;
+
+--##29 Add payment limits for single use payment tokens
+ALTER TABLE session ADD COLUMN payment_limit_cents INT;
+ALTER TABLE session ADD COLUMN payment_limit_bottle_stamps INT;
+ALTER TABLE session ADD COLUMN payment_limit_coffee_stamps INT;
+
+--##30 Add nfc challenge storage
+CREATE TABLE IF NOT EXISTS nfc_challenge (
+    challenge_id TEXT NOT NULL PRIMARY KEY,
+    card_id BYTEA NOT NULL,
+    valid_until TIMESTAMPTZ NOT NULL,
+    rnd_a BYTEA NOT NULL,
+    rnd_b BYTEA NOT NULL
+);
+
+--##31 Add nfc readers with individual keys
+CREATE TABLE IF NOT EXISTS nfc_reader (
+    id BIGINT
+        GENERATED ALWAYS AS IDENTITY (START WITH 1)
+        PRIMARY KEY
+        CHECK (id > 0),
+    name VARCHAR NOT NULL UNIQUE,
+    secret BYTEA NOT NULL,
+    secret_key_id TEXT NOT NULL
+);
+ALTER TABLE nfc_challenge ADD COLUMN reader_id BIGINT;
+UPDATE account_role SET permissions = array_append(permissions, 'nfc_readers.write')
+    WHERE 'roles.write' = ANY(permissions);
+
+--##32 Add server side nfc card provisioning
+CREATE TABLE IF NOT EXISTS nfc_provisioning (
+    id UUID NOT NULL PRIMARY KEY DEFAULT gen_random_uuid(),
+    account_id BIGINT NOT NULL,
+    name VARCHAR NOT NULL,
+    card_id BYTEA NOT NULL,
+    secret BYTEA NOT NULL,
+    secret_key_id TEXT NOT NULL,
+    replaces_card_id BYTEA,
+    key_change_issued BOOLEAN NOT NULL DEFAULT FALSE,
+    valid_until TIMESTAMPTZ NOT NULL,
+    CONSTRAINT fk_account_id
+        FOREIGN KEY(account_id)
+            REFERENCES account(id)
+            ON DELETE CASCADE
+);
+
+--##33 Add impersonation sessions
+ALTER TYPE tp_auth_method_kind ADD VALUE 'impersonation';
+ALTER TABLE session ADD COLUMN impersonated_by_account_id BIGINT;
+ALTER TABLE session
+    ADD CONSTRAINT fk_impersonated_by_account_id
+    FOREIGN KEY(impersonated_by_account_id)
+        REFERENCES account(id)
+        ON DELETE CASCADE;
+CREATE TABLE IF NOT EXISTS impersonation_log (
+    id BIGINT
+        GENERATED ALWAYS AS IDENTITY (START WITH 1)
+        PRIMARY KEY
+        CHECK (id > 0),
+    admin_account_id BIGINT NOT NULL,
+    account_id BIGINT NOT NULL,
+    session_id UUID NOT NULL,
+    action TEXT NOT NULL,
+    ip_address TEXT,
+    timestamp TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX idx_impersonation_log_account_id ON impersonation_log(account_id);
+
+--##34 Add audit log
+CREATE TABLE IF NOT EXISTS audit_log (
+    id BIGINT
+        GENERATED ALWAYS AS IDENTITY (START WITH 1)
+        PRIMARY KEY
+        CHECK (id > 0),
+    timestamp TIMESTAMPTZ NOT NULL DEFAULT now(),
+    actor_account_id BIGINT,
+    impersonated_account_id BIGINT,
+    action TEXT NOT NULL,
+    target_type TEXT NOT NULL,
+    target_id BIGINT,
+    before JSONB,
+    after JSONB
+);
+CREATE INDEX idx_audit_log_target ON audit_log(target_type, target_id);
+CREATE INDEX idx_audit_log_actor_account_id ON audit_log(actor_account_id);
+CREATE OR REPLACE FUNCTION fn_audit_log_append_only() RETURNS TRIGGER AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_log is append-only';
+END;
+$$ LANGUAGE plpgsql;
+CREATE TRIGGER trg_audit_log_append_only
+    BEFORE UPDATE OR DELETE ON audit_log
+    FOR EACH ROW EXECUTE FUNCTION fn_audit_log_append_only();
+UPDATE account_role SET permissions = array_append(permissions, 'audit_log.read')
+    WHERE 'roles.write' = ANY(permissions);
+
+--##35 Add rate limit events
+CREATE TABLE IF NOT EXISTS rate_limit_event (
+    key TEXT NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL
+);
+CREATE INDEX idx_rate_limit_event_key ON rate_limit_event(key, expires_at);
+
+--##36 Add account invitations
+CREATE TABLE IF NOT EXISTS invitation (
+    id BIGINT
+        GENERATED ALWAYS AS IDENTITY (START WITH 1)
+        PRIMARY KEY
+        CHECK (id > 0),
+    token UUID NOT NULL UNIQUE DEFAULT gen_random_uuid(),
+    name VARCHAR NOT NULL,
+    email VARCHAR NOT NULL,
+    role_id BIGINT NOT NULL,
+    status_id BIGINT,
+    invited_by_account_id BIGINT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    valid_until TIMESTAMPTZ NOT NULL,
+    CONSTRAINT fk_role_id
+        FOREIGN KEY(role_id)
+            REFERENCES account_role(id),
+    CONSTRAINT fk_status_id
+        FOREIGN KEY(status_id)
+            REFERENCES account_status(id)
+            ON DELETE SET NULL,
+    CONSTRAINT fk_invited_by_account_id
+        FOREIGN KEY(invited_by_account_id)
+            REFERENCES account(id)
+            ON DELETE SET NULL
+);
+
+--##37 Add account merges
+CREATE TABLE IF NOT EXISTS account_merge (
+    source_account_id BIGINT NOT NULL PRIMARY KEY,
+    target_account_id BIGINT NOT NULL,
+    merged_by_account_id BIGINT,
+    timestamp TIMESTAMPTZ NOT NULL DEFAULT now(),
+    CONSTRAINT fk_target_account_id
+        FOREIGN KEY(target_account_id)
+            REFERENCES account(id)
+            ON DELETE CASCADE
+);
+
+--##38 Add account closure with register payouts
+ALTER TABLE account ADD COLUMN IF NOT EXISTS archived_at TIMESTAMPTZ;
+CREATE TABLE IF NOT EXISTS register_payout (
+    id BIGINT NOT NULL GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    timestamp TIMESTAMPTZ NOT NULL DEFAULT now(),
+    account_id BIGINT,
+    transaction_id BIGINT NOT NULL,
+    amount_cents INT NOT NULL,
+    paid_by_account_id BIGINT,
+    CONSTRAINT fk_account_id
+        FOREIGN KEY(account_id)
+            REFERENCES account(id)
+            ON DELETE SET NULL,
+    CONSTRAINT fk_paid_by_account_id
+        FOREIGN KEY(paid_by_account_id)
+            REFERENCES account(id)
+            ON DELETE SET NULL
+);
+
+--##39 Add linked transactions for transfers between accounts
+ALTER TABLE transaction_item ADD COLUMN IF NOT EXISTS linked_transaction_id BIGINT;
+
+--##40 Add group accounts with members
+CREATE TABLE IF NOT EXISTS account_group_member (
+    group_account_id BIGINT NOT NULL,
+    member_account_id BIGINT NOT NULL,
+    is_admin BOOLEAN NOT NULL DEFAULT FALSE,
+    spending_limit_cents INT,
+    PRIMARY KEY (group_account_id, member_account_id),
+    CONSTRAINT fk_group_account_id
+        FOREIGN KEY(group_account_id)
+            REFERENCES account(id)
+            ON DELETE CASCADE,
+    CONSTRAINT fk_member_account_id
+        FOREIGN KEY(member_account_id)
+            REFERENCES account(id)
+            ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS account_group_member_member_account_id_idx ON account_group_member (member_account_id);
+
+--##41 Add spending limits and blocked product tags per account
+CREATE TYPE tp_spending_limit_period AS ENUM ('day', 'week', 'month');
+CREATE TABLE IF NOT EXISTS account_spending_policy (
+    account_id BIGINT NOT NULL PRIMARY KEY,
+    blocked_product_tags TEXT[] NOT NULL DEFAULT '{}',
+    locked BOOLEAN NOT NULL DEFAULT FALSE,
+    CONSTRAINT fk_account_id
+        FOREIGN KEY(account_id)
+            REFERENCES account(id)
+            ON DELETE CASCADE
+);
+CREATE TABLE IF NOT EXISTS account_spending_limit (
+    account_id BIGINT NOT NULL,
+    period tp_spending_limit_period NOT NULL,
+    limit_cents INT,
+    limit_coffee_stamps INT,
+    limit_bottle_stamps INT,
+    PRIMARY KEY (account_id, period),
+    CONSTRAINT fk_account_id
+        FOREIGN KEY(account_id)
+            REFERENCES account(id)
+            ON DELETE CASCADE
+);
+
+--##42 Add voucher batches and guest logins
+ALTER TYPE tp_auth_method_kind ADD VALUE 'qr_code';
+CREATE TABLE IF NOT EXISTS voucher_batch (
+    id BIGINT NOT NULL GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    name VARCHAR NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    created_by_account_id BIGINT,
+    valid_until TIMESTAMPTZ NOT NULL,
+    value_cents INT NOT NULL,
+    value_coffee_stamps INT NOT NULL,
+    value_bottle_stamps INT NOT NULL,
+    CONSTRAINT fk_created_by_account_id
+        FOREIGN KEY(created_by_account_id)
+            REFERENCES account(id)
+            ON DELETE SET NULL
+);
+CREATE TABLE IF NOT EXISTS voucher (
+    id BIGINT NOT NULL GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    batch_id BIGINT NOT NULL,
+    code VARCHAR NOT NULL UNIQUE,
+    used_at TIMESTAMPTZ,
+    account_id BIGINT,
+    is_guest BOOLEAN NOT NULL DEFAULT FALSE,
+    CONSTRAINT fk_batch_id
+        FOREIGN KEY(batch_id)
+            REFERENCES voucher_batch(id)
+            ON DELETE CASCADE,
+    CONSTRAINT fk_account_id
+        FOREIGN KEY(account_id)
+            REFERENCES account(id)
+            ON DELETE SET NULL
+);
+CREATE INDEX IF NOT EXISTS voucher_batch_id_idx ON voucher (batch_id);
+UPDATE account_role SET permissions = array_append(permissions, 'vouchers.write')
+    WHERE 'roles.write' = ANY(permissions);
+
+--##43 Add qr code login for wallet passes
+CREATE INDEX IF NOT EXISTS apple_wallet_pass_qr_code_idx ON apple_wallet_pass (qr_code);
+
+--##44 Add google wallet objects
+CREATE TABLE IF NOT EXISTS google_wallet_object (
+    account_id BIGINT NOT NULL PRIMARY KEY,
+    object_id VARCHAR NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    CONSTRAINT fk_account_id
+        FOREIGN KEY(account_id)
+            REFERENCES account(id)
+            ON DELETE CASCADE
+);
+
+--##45 Add push job queue
+CREATE TABLE IF NOT EXISTS push_job (
+    id BIGINT NOT NULL GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    account_id BIGINT NOT NULL,
+    pass_type_id VARCHAR NOT NULL,
+    device_id VARCHAR NOT NULL,
+    push_token VARCHAR NOT NULL,
+    attempts INT NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_error VARCHAR,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    dead_at TIMESTAMPTZ,
+    CONSTRAINT fk_account_id
+        FOREIGN KEY(account_id)
+            REFERENCES account(id)
+            ON DELETE CASCADE
+);
+CREATE UNIQUE INDEX IF NOT EXISTS push_job_pending_idx ON push_job (account_id, pass_type_id, device_id)
+    WHERE dead_at IS NULL;
+CREATE INDEX IF NOT EXISTS push_job_next_attempt_at_idx ON push_job (next_attempt_at)
+    WHERE dead_at IS NULL;
+
+--##46 Add separate permission for impersonation
+UPDATE account_role SET permissions = array_append(permissions, 'accounts.impersonate')
+    WHERE 'roles.write' = ANY(permissions);
+
+--##47 Allow redaction of personal data in the audit log
+CREATE OR REPLACE FUNCTION fn_audit_log_append_only() RETURNS TRIGGER AS $$
+BEGIN
+    -- Anonymization may rewrite the recorded data, everything else stays untouched
+    IF TG_OP = 'UPDATE'
+        AND current_setting('ascii_pay.audit_log_redaction', true) = 'on'
+        AND NEW.id = OLD.id
+        AND NEW.timestamp = OLD.timestamp
+        AND NEW.actor_account_id IS NOT DISTINCT FROM OLD.actor_account_id
+        AND NEW.impersonated_account_id IS NOT DISTINCT FROM OLD.impersonated_account_id
+        AND NEW.action = OLD.action
+        AND NEW.target_type = OLD.target_type
+        AND NEW.target_id IS NOT DISTINCT FROM OLD.target_id
+    THEN
+        RETURN NEW;
+    END IF;
+    RAISE EXCEPTION 'audit_log is append-only';
+END;
+$$ LANGUAGE plpgsql;
+CREATE OR REPLACE FUNCTION fn_audit_log_redact(data JSONB, pseudonym TEXT) RETURNS JSONB AS $$
+    SELECT CASE WHEN jsonb_typeof(data) = 'object' THEN
+        data
+            || CASE WHEN data ? 'name' THEN jsonb_build_object('name', pseudonym) ELSE '{}'::JSONB END
+            || CASE WHEN data ? 'email' THEN jsonb_build_object('email', '') ELSE '{}'::JSONB END
+            || CASE WHEN data ? 'auth_methods' THEN jsonb_build_object('auth_methods', '[]'::JSONB) ELSE '{}'::JSONB END
+    ELSE data END
+$$ LANGUAGE sql IMMUTABLE;
+
+--##48 Add google wallet updates to the push job queue
+CREATE TYPE tp_push_job_kind AS ENUM ('apple_wallet', 'google_wallet');
+ALTER TABLE push_job ADD COLUMN IF NOT EXISTS kind tp_push_job_kind NOT NULL DEFAULT 'apple_wallet';
+DROP INDEX IF EXISTS push_job_pending_idx;
+CREATE UNIQUE INDEX IF NOT EXISTS push_job_pending_idx ON push_job (kind, account_id, pass_type_id, device_id)
+    WHERE dead_at IS NULL;
+
+--##49 Add previous qr code of wallet passes until the new code is delivered
+ALTER TABLE apple_wallet_pass ADD COLUMN IF NOT EXISTS previous_qr_code VARCHAR;
+CREATE INDEX IF NOT EXISTS apple_wallet_pass_previous_qr_code_idx ON apple_wallet_pass (previous_qr_code);
@@ -1,9 +1,9 @@
 INSERT INTO account 
-    (balance_cents, balance_coffee_stamps, balance_bottle_stamps, name, email, role)
+    (balance_cents, balance_coffee_stamps, balance_bottle_stamps, name, email, role_id)
   VALUES
-    (473, 11, 5, 'John Doe', 'john.doe@example.com', 'member'),
-    (0, 0, 0, 'Poor Guy', 'poor.guy@example.org', 'basic'),
-    (81, 1, 4, 'Mr Root', 'root@localhost', 'admin');
+    (473, 11, 5, 'John Doe', 'john.doe@example.com', (SELECT id FROM account_role WHERE name = 'Member')),
+    (0, 0, 0, 'Poor Guy', 'poor.guy@example.org', (SELECT id FROM account_role WHERE name = 'Basic')),
+    (81, 1, 4, 'Mr Root', 'root@localhost', (SELECT id FROM account_role WHERE name = 'Admin'));
 
 INSERT INTO account_auth_method
     (account_id, login_key, data)
@@ -4,14 +4,28 @@ use chrono::{Duration, Utc};
 use sqlx::PgPool;
 
 use crate::{
-    error::ServiceError,
+    error::{ServiceError, ServiceResult},
     models::{
-        Account, AuthMethod, AuthMethodType, AuthNfc, AuthPassword, CardType, CoinAmount, CoinType,
-        Image, Payment, PaymentItem, Product, Role, TransactionItem,
+        Account, AccountGroupMember, AppleWalletPass, AppleWalletRegistration, AuditLogEntry,
+        AuditLogFilter, AuthMethod, AuthMethodType, AuthNfc, AuthPassword, AuthRequest, CardType,
+        CoinAmount, CoinType, Image, Invitation, NfcCardStatus, NfcProvisioning, NfcReader,
+        NfcSecret, Payment, PaymentItem, Permission, Product, RegisterPayout, Role, SessionClient,
+        SessionLifetime, SpendingLimit, SpendingLimitPeriod, SpendingPolicy, TransactionItem,
+        VoucherBatch,
     },
 };
 
-use super::{AppState, DatabaseConnection};
+use crate::google_wallet::GoogleWalletApi;
+use crate::push_queue::{process_push_jobs, PushService};
+
+use super::{AppState, AppStateNfcChallenge, DatabaseConnection, NfcChallengeStorage};
+
+async fn get_role(db: &mut DatabaseConnection, name: &str) -> Role {
+    db.get_role_by_name(name)
+        .await
+        .unwrap()
+        .expect("default roles are created by the migrations")
+}
 
 #[sqlx::test]
 async fn test_session_crud(pool: PgPool) {
@@ -29,11 +43,12 @@ async fn test_session_crud(pool: PgPool) {
         email: "john.doe@example.org".to_string(),
         id: 0,
         balance: CoinAmount(HashMap::new()),
-        role: Role::Basic,
+        role: get_role(&mut db, "Basic").await,
         auth_methods: vec![john_pw.clone()],
         enable_monthly_mail_report: false,
         enable_automatic_stamp_usage: true,
         status: None,
+        archived_at: None,
     };
     let acc1 = db.store_account(acc1).await.unwrap();
 
@@ -41,8 +56,9 @@ async fn test_session_crud(pool: PgPool) {
         .create_session_token(
             acc1.id,
             AuthMethodType::PasswordBased,
-            Utc::now().add(Duration::minutes(30)),
+            SessionLifetime::Fixed(Utc::now().add(Duration::minutes(30))),
             false,
+            &SessionClient::default(),
         )
         .await
         .unwrap();
@@ -70,8 +86,9 @@ async fn test_session_crud(pool: PgPool) {
         .create_session_token(
             acc1.id,
             AuthMethodType::PasswordBased,
-            Utc::now().add(Duration::minutes(30)),
+            SessionLifetime::Fixed(Utc::now().add(Duration::minutes(30))),
             false,
+            &SessionClient::default(),
         )
         .await
         .unwrap();
@@ -79,6 +96,442 @@ async fn test_session_crud(pool: PgPool) {
     assert_eq!(db.get_session_by_session_token(token).await.unwrap(), None);
 }
 
+#[sqlx::test]
+async fn test_session_revocation(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let acc1 = Account {
+        name: "John Doe".to_string(),
+        email: "john.doe@example.org".to_string(),
+        id: 0,
+        balance: CoinAmount(HashMap::new()),
+        role: get_role(&mut db, "Basic").await,
+        auth_methods: vec![],
+        enable_monthly_mail_report: false,
+        enable_automatic_stamp_usage: true,
+        status: None,
+        archived_at: None,
+    };
+    let acc1 = db.store_account(acc1).await.unwrap();
+
+    let client = SessionClient {
+        user_agent: Some("curl/8.0".to_string()),
+        ip_address: Some("192.0.2.1".to_string()),
+        device_name: Some("Laptop".to_string()),
+    };
+    let mut tokens = Vec::new();
+    for _ in 0..3 {
+        let token = db
+            .create_session_token(
+                acc1.id,
+                AuthMethodType::PasswordBased,
+                SessionLifetime::Fixed(Utc::now().add(Duration::minutes(30))),
+                false,
+                &client,
+            )
+            .await
+            .unwrap();
+        tokens.push(token);
+    }
+
+    let session = db
+        .get_session_by_session_token(tokens[0].clone())
+        .await
+        .unwrap()
+        .expect("there is a session for the token");
+    assert_eq!(session.client, client);
+    assert_ne!(session.id, session.token);
+    assert!(session.last_used_at >= session.created_at);
+
+    db.set_session_device_name(acc1.id, &session.id, Some("Kiosk".to_string()))
+        .await
+        .unwrap();
+    let session = db
+        .get_session_by_session_token(tokens[0].clone())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(session.client.device_name, Some("Kiosk".to_string()));
+
+    // sessions can only be revoked through the owning account
+    let sessions = db.get_sessions_by_account(acc1.id).await.unwrap();
+    assert_eq!(sessions.len(), 3);
+    assert_eq!(
+        db.delete_session_by_id(acc1.id + 1, &sessions[1].id).await,
+        Err(ServiceError::NotFound)
+    );
+    db.delete_session_by_id(acc1.id, &sessions[1].id)
+        .await
+        .unwrap();
+    assert_eq!(db.get_sessions_by_account(acc1.id).await.unwrap().len(), 2);
+
+    db.delete_other_sessions(acc1.id, &tokens[0]).await.unwrap();
+    let sessions = db.get_sessions_by_account(acc1.id).await.unwrap();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].token, tokens[0]);
+}
+
+#[sqlx::test]
+async fn test_session_expiry_and_refresh(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let acc1 = Account {
+        name: "John Doe".to_string(),
+        email: "john.doe@example.org".to_string(),
+        id: 0,
+        balance: CoinAmount(HashMap::new()),
+        role: get_role(&mut db, "Basic").await,
+        auth_methods: vec![],
+        enable_monthly_mail_report: false,
+        enable_automatic_stamp_usage: true,
+        status: None,
+        archived_at: None,
+    };
+    let acc1 = db.store_account(acc1).await.unwrap();
+
+    // sliding sessions are extended on every use
+    let token = db
+        .create_session_token(
+            acc1.id,
+            AuthMethodType::PasswordBased,
+            SessionLifetime::Sliding(Duration::minutes(30)),
+            false,
+            &SessionClient::default(),
+        )
+        .await
+        .unwrap();
+    let first = db
+        .get_session_by_session_token(token.clone())
+        .await
+        .unwrap()
+        .unwrap();
+    let second = db
+        .get_session_by_session_token(token.clone())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(second.valid_until >= first.valid_until);
+    assert!(second.valid_until > Utc::now().add(Duration::minutes(29)));
+
+    // refreshing replaces both tokens
+    let refresh_token = db
+        .create_refresh_token(&token, Utc::now().add(Duration::days(1)))
+        .await
+        .unwrap();
+    let (new_token, new_refresh_token) = db
+        .refresh_session_token(&refresh_token, Utc::now().add(Duration::days(1)))
+        .await
+        .unwrap()
+        .expect("refresh token is valid");
+    assert_ne!(new_token, token);
+    assert_ne!(new_refresh_token, refresh_token);
+    assert_eq!(db.get_session_by_session_token(token).await.unwrap(), None);
+    let session = db
+        .get_session_by_session_token(new_token.clone())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(session.id, first.id);
+    assert_eq!(
+        db.refresh_session_token(&refresh_token, Utc::now().add(Duration::days(1)))
+            .await
+            .unwrap(),
+        None
+    );
+
+    // expired sessions are only removed once they cannot be refreshed anymore
+    let expired_token = db
+        .create_session_token(
+            acc1.id,
+            AuthMethodType::PasswordBased,
+            SessionLifetime::Fixed(Utc::now() - Duration::minutes(1)),
+            false,
+            &SessionClient::default(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        db.get_session_by_session_token(expired_token)
+            .await
+            .unwrap(),
+        None
+    );
+    assert_eq!(db.get_sessions_by_account(acc1.id).await.unwrap().len(), 1);
+    db.cleanup_session_tokens().await.unwrap();
+    let sessions = db.get_sessions_by_account(acc1.id).await.unwrap();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].token, new_token);
+}
+
+#[sqlx::test]
+async fn test_payment_token(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let acc1 = Account {
+        name: "John Doe".to_string(),
+        email: "john.doe@example.org".to_string(),
+        id: 0,
+        balance: CoinAmount(HashMap::from([(CoinType::Cent, 1000)])),
+        role: get_role(&mut db, "Basic").await,
+        auth_methods: vec![],
+        enable_monthly_mail_report: false,
+        enable_automatic_stamp_usage: true,
+        status: None,
+        archived_at: None,
+    };
+    let acc1 = db.store_account(acc1).await.unwrap();
+
+    let limit = CoinAmount(HashMap::from([(CoinType::Cent, 200)]));
+    let token = db
+        .create_payment_token(
+            acc1.id,
+            AuthMethodType::NfcBased,
+            Utc::now().add(Duration::minutes(1)),
+            &limit,
+            None,
+            &SessionClient::default(),
+        )
+        .await
+        .unwrap();
+    let session = db
+        .get_session_by_session_token(token.clone())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(session.is_single_use);
+    assert!(session.is_payment_only());
+    assert_eq!(session.payment_limit, Some(limit.clone()));
+
+    let payment = |cents: i32| Payment {
+        account: acc1.id,
+        items: vec![PaymentItem {
+            effective_price: CoinAmount(HashMap::from([(CoinType::Cent, cents)])),
+            product_id: None,
+        }],
+        authorization: Some(session.clone()),
+    };
+    assert_eq!(
+        db.payment(payment(300), Utc::now(), true).await,
+        Err(ServiceError::PaymentLimitExceeded(vec!["Cent".to_string()]))
+    );
+
+    // credit items do not raise the limit
+    let mut mixed = payment(300);
+    mixed.items.push(PaymentItem {
+        effective_price: CoinAmount(HashMap::from([(CoinType::Cent, -200)])),
+        product_id: None,
+    });
+    assert_eq!(
+        db.payment(mixed, Utc::now(), true).await,
+        Err(ServiceError::PaymentLimitExceeded(vec!["Cent".to_string()]))
+    );
+    db.payment(payment(150), Utc::now(), true).await.unwrap();
+
+    // only the first request may consume the token
+    assert!(db.consume_single_use_session(&token).await.unwrap());
+    assert!(!db.consume_single_use_session(&token).await.unwrap());
+    assert_eq!(db.get_session_by_session_token(token).await.unwrap(), None);
+
+    // tokens created by an impersonation session keep the admin
+    let role = get_role(&mut db, "Admin").await;
+    let admin = db
+        .store_account(Account {
+            id: 0,
+            name: "Admin".to_string(),
+            email: "admin@example.org".to_string(),
+            role,
+            ..acc1.clone()
+        })
+        .await
+        .unwrap();
+    let token = db
+        .create_payment_token(
+            acc1.id,
+            AuthMethodType::Impersonation,
+            Utc::now().add(Duration::minutes(1)),
+            &limit,
+            Some(admin.id),
+            &SessionClient::default(),
+        )
+        .await
+        .unwrap();
+    let session = db
+        .get_session_by_session_token(token)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(session.account.id, acc1.id);
+    assert_eq!(session.impersonated_by, Some(admin.id));
+}
+
+#[sqlx::test]
+async fn test_impersonation_session(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let account = |name: &str, role: Role| Account {
+        name: name.to_string(),
+        email: format!("{name}@example.org"),
+        id: 0,
+        balance: CoinAmount(HashMap::from([(CoinType::Cent, 1000)])),
+        role,
+        auth_methods: vec![],
+        enable_monthly_mail_report: false,
+        enable_automatic_stamp_usage: true,
+        status: None,
+        archived_at: None,
+    };
+    let role = get_role(&mut db, "Admin").await;
+    let admin = db.store_account(account("admin", role)).await.unwrap();
+    let role = get_role(&mut db, "Basic").await;
+    let john = db.store_account(account("john", role)).await.unwrap();
+
+    let token = db
+        .create_impersonation_session(
+            john.id,
+            admin.id,
+            Utc::now().add(Duration::minutes(30)),
+            &SessionClient::default(),
+        )
+        .await
+        .unwrap();
+    let session = db
+        .get_session_by_session_token(token)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(session.account.id, john.id);
+    assert_eq!(session.auth_method, AuthMethodType::Impersonation);
+    assert_eq!(session.impersonated_by, Some(admin.id));
+
+    let transaction = db
+        .payment(
+            Payment {
+                account: john.id,
+                items: vec![PaymentItem {
+                    effective_price: CoinAmount(HashMap::from([(CoinType::Cent, 100)])),
+                    product_id: None,
+                }],
+                authorization: Some(session.clone()),
+            },
+            Utc::now(),
+            true,
+        )
+        .await
+        .unwrap();
+    assert_eq!(transaction.account, john.id);
+    assert_eq!(transaction.authorized_by_account_id, Some(admin.id));
+    assert_eq!(
+        transaction.authorized_with_method,
+        Some(AuthMethodType::Impersonation)
+    );
+
+    // Credit may only be loaded if the admin may load credit
+    let credit = |session: &crate::models::Session| Payment {
+        account: john.id,
+        items: vec![PaymentItem {
+            effective_price: CoinAmount(HashMap::from([(CoinType::Cent, -100)])),
+            product_id: None,
+        }],
+        authorization: Some(session.clone()),
+    };
+    db.payment(credit(&session), Utc::now(), true)
+        .await
+        .unwrap();
+
+    let support = db
+        .store_role(Role {
+            id: 0,
+            name: "Support".to_string(),
+            permissions: vec![Permission::AccountsImpersonate, Permission::PaymentsWrite],
+        })
+        .await
+        .unwrap();
+    let supporter = db
+        .store_account(account("supporter", support))
+        .await
+        .unwrap();
+    let token = db
+        .create_impersonation_session(
+            john.id,
+            supporter.id,
+            Utc::now().add(Duration::minutes(30)),
+            &SessionClient::default(),
+        )
+        .await
+        .unwrap();
+    let support_session = db
+        .get_session_by_session_token(token)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        db.payment(credit(&support_session), Utc::now(), true).await,
+        Err(ServiceError::Forbidden)
+    );
+
+    db.log_impersonation(
+        &session,
+        "POST /api/v1/account/1/payment",
+        &SessionClient::default(),
+    )
+    .await
+    .unwrap();
+    db.log_impersonation(
+        &session,
+        "GET /api/v1/auth/account",
+        &SessionClient::default(),
+    )
+    .await
+    .unwrap();
+
+    let log = db.get_impersonation_log(Some(john.id)).await.unwrap();
+    assert_eq!(log.len(), 2);
+    assert_eq!(log[0].action, "GET /api/v1/auth/account");
+    assert_eq!(log[0].admin_account_id, admin.id);
+    assert_eq!(log[0].session_id, session.id);
+    assert_eq!(
+        db.get_impersonation_log(Some(admin.id)).await.unwrap(),
+        vec![]
+    );
+
+    // other sessions are not logged
+    let admin_token = db
+        .create_session_token(
+            admin.id,
+            AuthMethodType::PasswordBased,
+            SessionLifetime::Fixed(Utc::now().add(Duration::minutes(30))),
+            false,
+            &SessionClient::default(),
+        )
+        .await
+        .unwrap();
+    let admin_session = db
+        .get_session_by_session_token(admin_token)
+        .await
+        .unwrap()
+        .unwrap();
+    db.log_impersonation(
+        &admin_session,
+        "GET /api/v1/accounts",
+        &SessionClient::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(db.get_impersonation_log(None).await.unwrap().len(), 2);
+}
+
 #[sqlx::test]
 async fn test_account_crud(pool: PgPool) {
     let app_state = AppState::from_pool(pool).await;
@@ -95,11 +548,12 @@ async fn test_account_crud(pool: PgPool) {
         email: "john.doe@example.org".to_string(),
         id: 0,
         balance: CoinAmount(HashMap::new()),
-        role: Role::Basic,
+        role: get_role(&mut db, "Basic").await,
         auth_methods: vec![john_pw.clone()],
         enable_monthly_mail_report: false,
         enable_automatic_stamp_usage: true,
         status: None,
+        archived_at: None,
     };
     let mut acc1_clone = acc1.clone();
     let mut acc1 = db.store_account(acc1).await.unwrap();
@@ -113,11 +567,12 @@ async fn test_account_crud(pool: PgPool) {
         email: "rich,don@example.com".to_string(),
         id: 0,
         balance: CoinAmount(HashMap::new()),
-        role: Role::Member,
+        role: get_role(&mut db, "Member").await,
         auth_methods: vec![],
         enable_monthly_mail_report: false,
         enable_automatic_stamp_usage: true,
         status: None,
+        archived_at: None,
     };
     let acc2 = db.store_account(acc2).await.unwrap();
 
@@ -127,10 +582,13 @@ async fn test_account_crud(pool: PgPool) {
 
     let john_nfc = AuthMethod::NfcBased(AuthNfc {
         card_id: vec![1; 32],
-        data: vec![],
+        data: NfcSecret::seal(&[2; 16]).unwrap(),
         card_type: CardType::GenericNfc,
         name: "My NFC Card".to_string(),
         depends_on_session: None,
+        status: NfcCardStatus::Active,
+        last_used_at: None,
+        valid_until: None,
     });
     acc1.auth_methods.push(john_nfc.clone());
     let acc1_clone = acc1.clone();
@@ -305,17 +763,20 @@ pub fn test_transaction(pool: PgPool) {
     };
 
     // create some test data
+    let basic_role = get_role(&mut db, "Basic").await;
+    let admin_role = get_role(&mut db, "Admin").await;
     let acc1 = db
         .store_account(Account {
             name: "John Doe".to_string(),
             email: "john.doe@example.org".to_string(),
             id: 0,
             balance: CoinAmount([(CoinType::Cent, 368)].into_iter().collect()),
-            role: Role::Basic,
+            role: basic_role,
             auth_methods: vec![],
             enable_monthly_mail_report: false,
             enable_automatic_stamp_usage: true,
             status: None,
+            archived_at: None,
         })
         .await
         .unwrap();
@@ -330,11 +791,12 @@ pub fn test_transaction(pool: PgPool) {
                     .into_iter()
                     .collect(),
             ),
-            role: Role::Admin,
+            role: admin_role,
             auth_methods: vec![],
             enable_monthly_mail_report: false,
             enable_automatic_stamp_usage: true,
             status: None,
+            archived_at: None,
         })
         .await
         .unwrap();
@@ -504,3 +966,2561 @@ pub fn test_transaction(pool: PgPool) {
         vec![tx1_anon, tx3_anon]
     )
 }
+
+#[sqlx::test]
+async fn test_store_first_account(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let admin_role = get_role(&mut db, "Admin").await;
+    let account = Account {
+        name: "admin".to_string(),
+        email: String::new(),
+        id: 0,
+        balance: CoinAmount::zero(),
+        role: admin_role.clone(),
+        auth_methods: vec![],
+        enable_monthly_mail_report: false,
+        enable_automatic_stamp_usage: true,
+        status: None,
+        archived_at: None,
+    };
+    let admin = db
+        .store_first_account(account.clone())
+        .await
+        .unwrap()
+        .unwrap();
+
+    // The setup stays closed after the admin role lost a permission
+    db.store_role(Role {
+        permissions: admin_role
+            .permissions
+            .iter()
+            .copied()
+            .filter(|p| *p != Permission::PaymentsCredit)
+            .collect(),
+        ..admin_role.clone()
+    })
+    .await
+    .unwrap();
+    db.store_role(Role {
+        id: 0,
+        name: "Owner".to_string(),
+        permissions: admin_role.permissions.clone(),
+    })
+    .await
+    .unwrap();
+    assert!(!db
+        .get_account_by_id(admin.id)
+        .await
+        .unwrap()
+        .unwrap()
+        .role
+        .has_all_permissions());
+    assert_eq!(db.store_first_account(account).await.unwrap(), None);
+    assert_eq!(db.get_all_accounts().await.unwrap().len(), 1);
+}
+
+#[sqlx::test]
+async fn test_role_crud(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let admin = get_role(&mut db, "Admin").await;
+    assert!(admin.has_all_permissions());
+    let purchaser = get_role(&mut db, "Purchaser").await;
+    assert!(purchaser.has_permission(Permission::ProductsWrite));
+    assert!(purchaser.has_permission(Permission::PurchasesWrite));
+    assert!(!purchaser.has_permission(Permission::AccountsRead));
+    assert!(!purchaser.has_permission(Permission::AccountsImpersonate));
+    assert!(purchaser.is_subset_of(&admin));
+    assert!(!admin.is_subset_of(&purchaser));
+    assert!(get_role(&mut db, "Member").await.permissions.is_empty());
+    assert!(get_role(&mut db, "Basic").await.permissions.is_empty());
+
+    let treasurer = db
+        .store_role(Role {
+            id: 0,
+            name: "Treasurer".to_string(),
+            permissions: vec![Permission::AccountsRead, Permission::RegisterWrite],
+        })
+        .await
+        .unwrap();
+    assert!(treasurer.id != 0);
+    assert_eq!(
+        db.get_role_by_id(treasurer.id).await.unwrap(),
+        Some(treasurer.clone())
+    );
+
+    let acc1 = db
+        .store_account(Account {
+            name: "John Doe".to_string(),
+            email: "john.doe@example.org".to_string(),
+            id: 0,
+            balance: CoinAmount(HashMap::new()),
+            role: treasurer.clone(),
+            auth_methods: vec![],
+            enable_monthly_mail_report: false,
+            enable_automatic_stamp_usage: true,
+            status: None,
+            archived_at: None,
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        db.delete_role(treasurer.id).await,
+        Err(ServiceError::RoleInUse)
+    );
+
+    let mut treasurer = treasurer;
+    treasurer.permissions.push(Permission::ReportsSend);
+    let treasurer = db.store_role(treasurer).await.unwrap();
+    let acc1 = db.get_account_by_id(acc1.id).await.unwrap().unwrap();
+    assert_eq!(acc1.role, treasurer);
+
+    db.delete_account(acc1.id).await.unwrap();
+    db.delete_role(treasurer.id).await.unwrap();
+    assert_eq!(db.get_role_by_id(treasurer.id).await.unwrap(), None);
+    assert_eq!(
+        db.delete_role(treasurer.id).await,
+        Err(ServiceError::NotFound)
+    );
+}
+
+async fn check_nfc_challenge_storage(storage: NfcChallengeStorage) {
+    let challenge = AppStateNfcChallenge {
+        card_id: vec![1, 2, 3, 4],
+        reader_id: Some(7),
+        valid_until: Utc::now().add(Duration::seconds(10)),
+        rnd_a: vec![5; 8],
+        rnd_b: vec![6; 8],
+    };
+    let expired = AppStateNfcChallenge {
+        valid_until: Utc::now() - Duration::seconds(10),
+        ..challenge.clone()
+    };
+
+    // challenges for the same card do not overwrite each other
+    let id1 = storage.insert(challenge.clone()).await.unwrap();
+    let id2 = storage.insert(challenge.clone()).await.unwrap();
+    let id3 = storage.insert(expired).await.unwrap();
+    assert_ne!(id1, id2);
+
+    storage.sweep_expired().await.unwrap();
+    assert_eq!(storage.take(&id3).await.unwrap(), None);
+
+    let taken = storage.take(&id1).await.unwrap().unwrap();
+    assert_eq!(taken.card_id, challenge.card_id);
+    assert_eq!(taken.reader_id, challenge.reader_id);
+    assert_eq!(taken.rnd_a, challenge.rnd_a);
+    assert_eq!(taken.rnd_b, challenge.rnd_b);
+    assert_eq!(storage.take(&id1).await.unwrap(), None);
+    assert!(storage.take(&id2).await.unwrap().is_some());
+}
+
+#[sqlx::test]
+async fn test_nfc_challenge_storage(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+
+    check_nfc_challenge_storage(NfcChallengeStorage::memory()).await;
+    check_nfc_challenge_storage(NfcChallengeStorage::postgres(app_state.pool.clone())).await;
+}
+
+#[sqlx::test]
+async fn test_reencrypt_nfc_secrets(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let acc1 = Account {
+        name: "John Doe".to_string(),
+        email: "john.doe@example.org".to_string(),
+        id: 0,
+        balance: CoinAmount(HashMap::new()),
+        role: get_role(&mut db, "Basic").await,
+        auth_methods: vec![AuthMethod::NfcBased(AuthNfc {
+            card_id: vec![1; 8],
+            data: NfcSecret::Plain(vec![2; 16]),
+            card_type: CardType::AsciiMifare,
+            name: "Legacy card".to_string(),
+            depends_on_session: None,
+            status: NfcCardStatus::Active,
+            last_used_at: None,
+            valid_until: None,
+        })],
+        enable_monthly_mail_report: false,
+        enable_automatic_stamp_usage: true,
+        status: None,
+        archived_at: None,
+    };
+    let acc1 = db.store_account(acc1).await.unwrap();
+
+    assert_eq!(db.reencrypt_nfc_secrets().await.unwrap(), 1);
+    assert_eq!(db.reencrypt_nfc_secrets().await.unwrap(), 0);
+
+    let acc1 = db.get_account_by_id(acc1.id).await.unwrap().unwrap();
+    let AuthMethod::NfcBased(ref auth_nfc) = acc1.auth_methods[0] else {
+        panic!("expected nfc auth method");
+    };
+    assert!(matches!(auth_nfc.data, NfcSecret::Sealed { .. }));
+    assert_eq!(auth_nfc.data.open().unwrap(), vec![2; 16]);
+}
+
+#[sqlx::test]
+async fn test_nfc_reader_crud(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    assert!(get_role(&mut db, "Admin")
+        .await
+        .has_permission(Permission::NfcReadersWrite));
+
+    let reader = NfcReader {
+        id: 0,
+        name: "Terminal 1".to_string(),
+        key: NfcSecret::seal(&[3; 32]).unwrap(),
+    };
+    let mut reader = db.store_nfc_reader(reader).await.unwrap();
+    assert!(reader.id != 0);
+    assert_eq!(
+        db.get_nfc_reader_by_id(reader.id).await.unwrap(),
+        Some(reader.clone())
+    );
+
+    reader.name = "Terminal 2".to_string();
+    reader.key = NfcSecret::seal(&[4; 32]).unwrap();
+    let reader = db.store_nfc_reader(reader).await.unwrap();
+    let stored = db.get_nfc_reader_by_id(reader.id).await.unwrap().unwrap();
+    assert_eq!(stored.name, "Terminal 2");
+    assert_eq!(stored.key.open().unwrap(), vec![4; 32]);
+    assert_eq!(
+        db.get_all_nfc_readers().await.unwrap(),
+        vec![reader.clone()]
+    );
+
+    // reader keys are never stored unencrypted
+    let plain = NfcReader {
+        key: NfcSecret::Plain(vec![5; 32]),
+        ..reader.clone()
+    };
+    assert!(db.store_nfc_reader(plain).await.is_err());
+
+    db.delete_nfc_reader(reader.id).await.unwrap();
+    assert_eq!(db.get_nfc_reader_by_id(reader.id).await.unwrap(), None);
+    assert_eq!(
+        db.delete_nfc_reader(reader.id).await,
+        Err(ServiceError::NotFound)
+    );
+}
+
+#[sqlx::test]
+async fn test_nfc_provisioning(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let acc1 = Account {
+        name: "John Doe".to_string(),
+        email: "john.doe@example.org".to_string(),
+        id: 0,
+        balance: CoinAmount(HashMap::new()),
+        role: get_role(&mut db, "Basic").await,
+        auth_methods: Vec::new(),
+        enable_monthly_mail_report: false,
+        enable_automatic_stamp_usage: true,
+        status: None,
+        archived_at: None,
+    };
+    let acc1 = db.store_account(acc1).await.unwrap();
+
+    let provisioning = NfcProvisioning {
+        id: String::new(),
+        account_id: acc1.id,
+        name: "Card".to_string(),
+        card_id: vec![1, 2, 3, 4],
+        key: NfcSecret::seal(&[6; 16]).unwrap(),
+        replaces_card_id: None,
+        key_change_issued: false,
+        valid_until: Utc::now().add(Duration::minutes(10)),
+    };
+    let mut provisioning = db.store_nfc_provisioning(provisioning).await.unwrap();
+    assert!(!provisioning.id.is_empty());
+
+    // provisionings are only visible for their own account
+    assert_eq!(
+        db.get_nfc_provisioning(acc1.id + 1, &provisioning.id)
+            .await
+            .unwrap(),
+        None
+    );
+
+    provisioning.key_change_issued = true;
+    let provisioning = db.store_nfc_provisioning(provisioning).await.unwrap();
+    let stored = db
+        .get_nfc_provisioning(acc1.id, &provisioning.id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(stored.key_change_issued);
+    assert_eq!(stored.key.open().unwrap(), vec![6; 16]);
+
+    let expired = NfcProvisioning {
+        id: String::new(),
+        valid_until: Utc::now().add(Duration::minutes(-1)),
+        ..provisioning.clone()
+    };
+    let expired = db.store_nfc_provisioning(expired).await.unwrap();
+    assert_eq!(
+        db.get_nfc_provisioning(acc1.id, &expired.id).await.unwrap(),
+        None
+    );
+    db.cleanup_nfc_provisionings().await.unwrap();
+    assert_eq!(
+        db.delete_nfc_provisioning(acc1.id, &expired.id).await,
+        Err(ServiceError::NotFound)
+    );
+
+    db.delete_nfc_provisioning(acc1.id, &provisioning.id)
+        .await
+        .unwrap();
+    assert_eq!(
+        db.get_nfc_provisioning(acc1.id, &provisioning.id)
+            .await
+            .unwrap(),
+        None
+    );
+}
+
+#[sqlx::test]
+async fn test_nfc_card_status(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let card = AuthNfc {
+        card_id: vec![7; 8],
+        data: NfcSecret::seal(&[2; 16]).unwrap(),
+        card_type: CardType::AsciiMifareAes,
+        name: "Lost card".to_string(),
+        depends_on_session: None,
+        status: NfcCardStatus::Lost,
+        last_used_at: None,
+        valid_until: Some(Utc::now().add(Duration::days(30))),
+    };
+    let acc1 = Account {
+        name: "John Doe".to_string(),
+        email: "john.doe@example.org".to_string(),
+        id: 0,
+        balance: CoinAmount(HashMap::new()),
+        role: get_role(&mut db, "Basic").await,
+        auth_methods: vec![AuthMethod::NfcBased(card.clone())],
+        enable_monthly_mail_report: false,
+        enable_automatic_stamp_usage: true,
+        status: None,
+        archived_at: None,
+    };
+    let acc1 = db.store_account(acc1).await.unwrap();
+    assert_eq!(
+        db.get_account_by_id(acc1.id).await.unwrap(),
+        Some(acc1.clone())
+    );
+
+    db.set_nfc_card_last_used(&card.card_id).await.unwrap();
+    let stored = db.get_account_by_id(acc1.id).await.unwrap().unwrap();
+    let AuthMethod::NfcBased(ref stored_card) = stored.auth_methods[0] else {
+        panic!("nfc card should be stored");
+    };
+    assert!(stored_card.last_used_at.is_some());
+    assert_eq!(stored_card.status, NfcCardStatus::Lost);
+    assert_eq!(stored_card.current_status(), NfcCardStatus::Lost);
+
+    let expired = AuthNfc {
+        status: NfcCardStatus::Active,
+        valid_until: Some(Utc::now().add(Duration::days(-1))),
+        ..card
+    };
+    assert_eq!(expired.current_status(), NfcCardStatus::Expired);
+
+    // cards stored before the status was introduced are active
+    sqlx::query(
+        r#"UPDATE account_auth_method SET data = data #- '{Nfc,status}' #- '{Nfc,valid_until}'"#,
+    )
+    .execute(db.connection.as_mut())
+    .await
+    .unwrap();
+    let stored = db.get_account_by_id(acc1.id).await.unwrap().unwrap();
+    let AuthMethod::NfcBased(ref stored_card) = stored.auth_methods[0] else {
+        panic!("nfc card should be stored");
+    };
+    assert_eq!(stored_card.status, NfcCardStatus::Active);
+    assert_eq!(stored_card.valid_until, None);
+}
+
+#[sqlx::test]
+async fn test_audit_log(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let admin = get_role(&mut db, "Admin").await;
+    assert!(admin.permissions.contains(&Permission::AuditLogRead));
+
+    let entry = |actor: u64, action: &str, target_id: u64| AuditLogEntry {
+        id: 0,
+        timestamp: Utc::now(),
+        actor_account_id: Some(actor),
+        impersonated_account_id: None,
+        action: action.to_string(),
+        target_type: "product".to_string(),
+        target_id: Some(target_id),
+        before: Some(serde_json::json!({"price": 150})),
+        after: Some(serde_json::json!({"price": 170})),
+    };
+
+    let first = db
+        .append_audit_log(entry(1, "product.update", 7))
+        .await
+        .unwrap();
+    assert_ne!(first.id, 0);
+    db.append_audit_log(entry(2, "product.delete", 7))
+        .await
+        .unwrap();
+    db.append_audit_log(entry(1, "product.create", 8))
+        .await
+        .unwrap();
+
+    let filter = |actor: Option<u64>, target_id: Option<u64>| AuditLogFilter {
+        actor_account_id: actor,
+        action: None,
+        target_type: Some("product".to_string()),
+        target_id,
+        from: None,
+        to: None,
+        limit: 100,
+    };
+
+    let log = db.get_audit_log(&filter(None, Some(7))).await.unwrap();
+    assert_eq!(log.len(), 2);
+    assert_eq!(log[0].action, "product.delete");
+    assert_eq!(log[1], first);
+
+    let log = db.get_audit_log(&filter(Some(1), None)).await.unwrap();
+    assert_eq!(log.len(), 2);
+    assert!(log.iter().all(|e| e.actor_account_id == Some(1)));
+
+    // entries can neither be changed nor removed
+    let update = sqlx::query("UPDATE audit_log SET action = 'tampered'")
+        .execute(db.connection.as_mut())
+        .await;
+    assert!(update.is_err());
+    let delete = sqlx::query("DELETE FROM audit_log")
+        .execute(db.connection.as_mut())
+        .await;
+    assert!(delete.is_err());
+    assert_eq!(
+        db.get_audit_log(&filter(None, None)).await.unwrap().len(),
+        3
+    );
+}
+
+#[sqlx::test]
+async fn test_password_reset_lookup_and_rate_limit(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let role = get_role(&mut db, "Basic").await;
+    let john = db
+        .store_account(Account {
+            name: "John".to_string(),
+            email: "John@example.org".to_string(),
+            id: 0,
+            balance: CoinAmount(HashMap::new()),
+            role,
+            auth_methods: vec![],
+            enable_monthly_mail_report: false,
+            enable_automatic_stamp_usage: true,
+            status: None,
+            archived_at: None,
+        })
+        .await
+        .unwrap();
+
+    let accounts = db.get_accounts_by_email("john@EXAMPLE.org").await.unwrap();
+    assert_eq!(accounts.len(), 1);
+    assert_eq!(accounts[0].id, john.id);
+    assert!(db.get_accounts_by_email("").await.unwrap().is_empty());
+
+    for _ in 0..3 {
+        assert!(db
+            .rate_limit_hit("test:a", Duration::minutes(5), 3)
+            .await
+            .unwrap());
+    }
+    assert!(!db
+        .rate_limit_hit("test:a", Duration::minutes(5), 3)
+        .await
+        .unwrap());
+    assert!(db
+        .rate_limit_hit("test:b", Duration::minutes(5), 3)
+        .await
+        .unwrap());
+
+    // expired hits are not counted
+    for _ in 0..3 {
+        assert!(db
+            .rate_limit_hit("test:c", Duration::minutes(-1), 1)
+            .await
+            .unwrap());
+    }
+    db.cleanup_rate_limit_events().await.unwrap();
+}
+
+#[sqlx::test]
+async fn test_invitation_crud(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let role = get_role(&mut db, "Basic").await;
+    let invitation = db
+        .store_invitation(
+            Invitation {
+                id: 0,
+                token: String::new(),
+                name: "Jane".to_string(),
+                email: "jane@example.org".to_string(),
+                role: role.clone(),
+                status: None,
+                invited_by_account_id: None,
+                created_at: Utc::now(),
+                valid_until: Utc::now().add(Duration::days(7)),
+            },
+            false,
+        )
+        .await
+        .unwrap();
+    assert_ne!(invitation.id, 0);
+    assert!(!invitation.token.is_empty());
+    assert_eq!(invitation.role, role);
+
+    // pending invitations are not accounts
+    assert!(db
+        .get_all_accounts()
+        .await
+        .unwrap()
+        .iter()
+        .all(|a| a.email != "jane@example.org"));
+    assert_eq!(
+        db.get_all_invitations().await.unwrap(),
+        vec![invitation.clone()]
+    );
+    assert_eq!(
+        db.get_invitation_by_token(&invitation.token).await.unwrap(),
+        Some(invitation.clone())
+    );
+
+    // resending replaces the token
+    let resent = db.store_invitation(invitation.clone(), true).await.unwrap();
+    assert_eq!(resent.id, invitation.id);
+    assert_ne!(resent.token, invitation.token);
+    assert_eq!(
+        db.get_invitation_by_token(&invitation.token).await.unwrap(),
+        None
+    );
+
+    // expired invitations cannot be used
+    let expired = db
+        .store_invitation(
+            Invitation {
+                valid_until: Utc::now().add(Duration::minutes(-1)),
+                ..resent.clone()
+            },
+            false,
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        db.get_invitation_by_token(&expired.token).await.unwrap(),
+        None
+    );
+
+    // roles of pending invitations cannot be deleted
+    assert_eq!(db.delete_role(role.id).await, Err(ServiceError::RoleInUse));
+
+    db.delete_invitation(invitation.id).await.unwrap();
+    assert_eq!(
+        db.delete_invitation(invitation.id).await,
+        Err(ServiceError::NotFound)
+    );
+    assert_eq!(db.get_all_invitations().await.unwrap(), vec![]);
+}
+
+#[sqlx::test]
+async fn test_accept_invitation(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let role = get_role(&mut db, "Basic").await;
+    let invitation = db
+        .store_invitation(
+            Invitation {
+                id: 0,
+                token: String::new(),
+                name: "Jane".to_string(),
+                email: "jane@example.org".to_string(),
+                role: role.clone(),
+                status: None,
+                invited_by_account_id: None,
+                created_at: Utc::now(),
+                valid_until: Utc::now().add(Duration::days(7)),
+            },
+            false,
+        )
+        .await
+        .unwrap();
+
+    let account = |username: &str| Account {
+        id: 0,
+        balance: CoinAmount(HashMap::new()),
+        name: "Jane".to_string(),
+        email: "jane@example.org".to_string(),
+        role: role.clone(),
+        auth_methods: vec![AuthMethod::PasswordBased(AuthPassword {
+            username: username.to_string(),
+            password_hash: vec![],
+        })],
+        enable_monthly_mail_report: false,
+        enable_automatic_stamp_usage: true,
+        status: None,
+        archived_at: None,
+    };
+    db.store_account(Account {
+        email: "john@example.org".to_string(),
+        ..account("taken")
+    })
+    .await
+    .unwrap();
+
+    // a failed account creation keeps the invitation
+    assert!(db
+        .accept_invitation(invitation.id, account("taken"))
+        .await
+        .is_err());
+    assert_eq!(
+        db.get_invitation_by_token(&invitation.token).await.unwrap(),
+        Some(invitation.clone())
+    );
+
+    let jane = db
+        .accept_invitation(invitation.id, account("jane"))
+        .await
+        .unwrap();
+    assert_ne!(jane.id, 0);
+    assert_eq!(
+        db.get_invitation_by_token(&invitation.token).await.unwrap(),
+        None
+    );
+
+    // an invitation can only be accepted once
+    assert_eq!(
+        db.accept_invitation(invitation.id, account("jane2")).await,
+        Err(ServiceError::NotFound)
+    );
+    assert_eq!(
+        db.get_all_accounts()
+            .await
+            .unwrap()
+            .iter()
+            .filter(|a| a.email == "jane@example.org")
+            .count(),
+        1
+    );
+}
+
+#[sqlx::test]
+async fn test_merge_accounts(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let password = |username: &str| {
+        AuthMethod::PasswordBased(AuthPassword {
+            username: username.to_string(),
+            password_hash: vec![],
+        })
+    };
+    let nfc = |card_id: u8| {
+        AuthMethod::NfcBased(AuthNfc {
+            name: "card".to_string(),
+            card_id: vec![card_id],
+            card_type: CardType::GenericNfc,
+            data: NfcSecret::Plain(vec![]),
+            depends_on_session: None,
+            status: NfcCardStatus::Active,
+            last_used_at: None,
+            valid_until: None,
+        })
+    };
+    let account = |name: &str, role: Role, cents: i32, auth_methods: Vec<AuthMethod>| Account {
+        name: name.to_string(),
+        email: format!("{name}@example.org"),
+        id: 0,
+        balance: CoinAmount(HashMap::from([(CoinType::Cent, cents)])),
+        role,
+        auth_methods,
+        enable_monthly_mail_report: false,
+        enable_automatic_stamp_usage: true,
+        status: None,
+        archived_at: None,
+    };
+
+    let role = get_role(&mut db, "Basic").await;
+    let target = db
+        .store_account(account("a", role.clone(), 500, vec![password("a"), nfc(1)]))
+        .await
+        .unwrap();
+    let source = db
+        .store_account(account(
+            "b",
+            role,
+            300,
+            vec![password("b"), nfc(2), AuthMethod::PublicTab],
+        ))
+        .await
+        .unwrap();
+
+    db.payment(
+        Payment {
+            account: source.id,
+            items: vec![PaymentItem {
+                effective_price: CoinAmount(HashMap::from([(CoinType::Cent, 100)])),
+                product_id: None,
+            }],
+            authorization: None,
+        },
+        Utc::now(),
+        false,
+    )
+    .await
+    .unwrap();
+
+    db.store_apple_wallet_pass(AppleWalletPass {
+        account_id: source.id,
+        pass_type_id: "pass.test".to_string(),
+        authentication_token: String::new(),
+        qr_code: "qr".to_string(),
+        updated_at: 0,
+    })
+    .await
+    .unwrap();
+    db.store_apple_wallet_registration(AppleWalletRegistration {
+        account_id: source.id,
+        pass_type_id: "pass.test".to_string(),
+        device_id: "device".to_string(),
+        push_token: "push".to_string(),
+    })
+    .await
+    .unwrap();
+
+    db.merge_accounts(target.id, source.id, None).await.unwrap();
+
+    let merged = db.get_account_by_id(target.id).await.unwrap().unwrap();
+    assert_eq!(merged.balance.0.get(&CoinType::Cent), Some(&700));
+    assert_eq!(
+        merged
+            .auth_methods
+            .iter()
+            .map(|m| m.to_request(target.id))
+            .collect::<Vec<_>>(),
+        [password("a"), nfc(1), nfc(2), AuthMethod::PublicTab]
+            .iter()
+            .map(|m| m.to_request(target.id))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        db.get_account_by_auth_method(AuthRequest::PublicTab {
+            account_id: target.id
+        })
+        .await
+        .unwrap()
+        .map(|a| a.id),
+        Some(target.id)
+    );
+    assert_eq!(
+        db.get_account_by_auth_method(AuthRequest::PasswordBased {
+            username: "b".to_string()
+        })
+        .await
+        .unwrap(),
+        None
+    );
+
+    let transactions = db.get_transactions_by_account(target.id).await.unwrap();
+    assert_eq!(transactions.len(), 1);
+
+    let pass = db
+        .get_apple_wallet_pass(target.id, "pass.test")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(pass.qr_code, "qr");
+    assert_eq!(
+        db.list_apple_wallet_registration(target.id, "pass.test")
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+
+    assert_eq!(
+        db.get_account_by_id(source.id).await,
+        Err(ServiceError::AccountMerged(target.id))
+    );
+    assert_eq!(
+        db.merge_accounts(target.id, source.id, None).await,
+        Err(ServiceError::NotFound)
+    );
+}
+
+#[sqlx::test]
+async fn test_merge_accounts_group_members(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let role = get_role(&mut db, "Basic").await;
+    let mut accounts = Vec::new();
+    for name in ["workshop", "kitchen", "alice", "alice-old", "bob"] {
+        let account = db
+            .store_account(Account {
+                name: name.to_string(),
+                email: String::new(),
+                id: 0,
+                balance: CoinAmount::zero(),
+                role: role.clone(),
+                auth_methods: vec![],
+                enable_monthly_mail_report: false,
+                enable_automatic_stamp_usage: true,
+                status: None,
+                archived_at: None,
+            })
+            .await
+            .unwrap();
+        accounts.push(account);
+    }
+    let [workshop, kitchen, target, source, bob] = accounts.try_into().unwrap();
+
+    let member = |group: u64, member: u64, is_admin: bool| AccountGroupMember {
+        group_account_id: group,
+        member_account_id: member,
+        member_name: String::new(),
+        is_admin,
+        spending_limit_cents: None,
+    };
+    db.store_account_group_member(member(workshop.id, target.id, false))
+        .await
+        .unwrap();
+    db.store_account_group_member(member(workshop.id, source.id, true))
+        .await
+        .unwrap();
+    db.store_account_group_member(member(kitchen.id, source.id, true))
+        .await
+        .unwrap();
+    db.store_account_group_member(member(source.id, bob.id, false))
+        .await
+        .unwrap();
+    db.store_account_group_member(member(source.id, target.id, false))
+        .await
+        .unwrap();
+
+    db.merge_accounts(target.id, source.id, None).await.unwrap();
+
+    let groups = db.get_account_groups_by_member(target.id).await.unwrap();
+    assert_eq!(
+        groups
+            .iter()
+            .map(|m| (m.group_account_id, m.is_admin))
+            .collect::<Vec<_>>(),
+        vec![(workshop.id, false), (kitchen.id, true)]
+    );
+    let members = db.get_account_group_members(target.id).await.unwrap();
+    assert_eq!(
+        members
+            .iter()
+            .map(|m| m.member_account_id)
+            .collect::<Vec<_>>(),
+        vec![bob.id]
+    );
+}
+
+#[sqlx::test]
+async fn test_merge_accounts_spending_policy(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let role = get_role(&mut db, "Basic").await;
+    let mut accounts = Vec::new();
+    for name in ["a", "b"] {
+        let account = db
+            .store_account(Account {
+                name: name.to_string(),
+                email: String::new(),
+                id: 0,
+                balance: CoinAmount::zero(),
+                role: role.clone(),
+                auth_methods: vec![],
+                enable_monthly_mail_report: false,
+                enable_automatic_stamp_usage: true,
+                status: None,
+                archived_at: None,
+            })
+            .await
+            .unwrap();
+        accounts.push(account);
+    }
+    let [target, source] = accounts.try_into().unwrap();
+
+    db.store_spending_policy(
+        target.id,
+        &SpendingPolicy {
+            limits: vec![SpendingLimit {
+                period: SpendingLimitPeriod::Day,
+                limit: CoinAmount(HashMap::from([(CoinType::Cent, 500)])),
+            }],
+            blocked_product_tags: vec!["alcohol".to_string()],
+            locked: false,
+        },
+    )
+    .await
+    .unwrap();
+    db.store_spending_policy(
+        source.id,
+        &SpendingPolicy {
+            limits: vec![
+                SpendingLimit {
+                    period: SpendingLimitPeriod::Day,
+                    limit: CoinAmount(HashMap::from([
+                        (CoinType::Cent, 300),
+                        (CoinType::CoffeeStamp, 2),
+                    ])),
+                },
+                SpendingLimit {
+                    period: SpendingLimitPeriod::Month,
+                    limit: CoinAmount(HashMap::from([(CoinType::Cent, 5000)])),
+                },
+            ],
+            blocked_product_tags: vec!["alcohol".to_string(), "energy".to_string()],
+            locked: true,
+        },
+    )
+    .await
+    .unwrap();
+
+    db.merge_accounts(target.id, source.id, None).await.unwrap();
+
+    let mut policy = db.get_spending_policy(target.id).await.unwrap();
+    policy.blocked_product_tags.sort();
+    assert_eq!(
+        policy,
+        SpendingPolicy {
+            limits: vec![
+                SpendingLimit {
+                    period: SpendingLimitPeriod::Day,
+                    limit: CoinAmount(HashMap::from([
+                        (CoinType::Cent, 300),
+                        (CoinType::CoffeeStamp, 2),
+                    ])),
+                },
+                SpendingLimit {
+                    period: SpendingLimitPeriod::Month,
+                    limit: CoinAmount(HashMap::from([(CoinType::Cent, 5000)])),
+                },
+            ],
+            blocked_product_tags: vec!["alcohol".to_string(), "energy".to_string()],
+            locked: true,
+        }
+    );
+}
+
+#[sqlx::test]
+async fn test_merge_accounts_vouchers(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let role = get_role(&mut db, "Basic").await;
+    let target = db
+        .store_account(Account {
+            name: "a".to_string(),
+            email: String::new(),
+            id: 0,
+            balance: CoinAmount::zero(),
+            role,
+            auth_methods: vec![],
+            enable_monthly_mail_report: false,
+            enable_automatic_stamp_usage: true,
+            status: None,
+            archived_at: None,
+        })
+        .await
+        .unwrap();
+
+    db.create_voucher_batch(
+        VoucherBatch {
+            id: 0,
+            name: "Open day".to_string(),
+            created_at: Utc::now(),
+            created_by_account_id: None,
+            valid_until: Utc::now().add(Duration::days(1)),
+            value: CoinAmount(HashMap::from([(CoinType::Cent, 500)])),
+            voucher_count: 0,
+            used_count: 0,
+        },
+        &["voucher-a".to_string()],
+    )
+    .await
+    .unwrap();
+    let (voucher, _) = db
+        .redeem_voucher(
+            "voucher-a",
+            Account {
+                id: 0,
+                ..target.clone()
+            },
+            None,
+        )
+        .await
+        .unwrap();
+    let guest_id = voucher.account_id.unwrap();
+
+    db.merge_accounts(target.id, guest_id, None).await.unwrap();
+
+    let voucher = db.get_voucher_by_code("voucher-a").await.unwrap().unwrap();
+    assert_eq!(voucher.account_id, Some(target.id));
+    assert!(voucher.is_guest);
+}
+
+#[sqlx::test]
+async fn test_merge_accounts_google_wallet_object(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let role = get_role(&mut db, "Basic").await;
+    let mut accounts = Vec::new();
+    for name in ["a", "b", "c"] {
+        let account = db
+            .store_account(Account {
+                name: name.to_string(),
+                email: String::new(),
+                id: 0,
+                balance: CoinAmount::zero(),
+                role: role.clone(),
+                auth_methods: vec![],
+                enable_monthly_mail_report: false,
+                enable_automatic_stamp_usage: true,
+                status: None,
+                archived_at: None,
+            })
+            .await
+            .unwrap();
+        accounts.push(account);
+    }
+    let [target, source, other] = accounts.try_into().unwrap();
+
+    db.store_google_wallet_object(source.id, "issuer.source")
+        .await
+        .unwrap();
+    db.merge_accounts(target.id, source.id, None).await.unwrap();
+    assert_eq!(
+        db.get_google_wallet_object(target.id)
+            .await
+            .unwrap()
+            .map(|o| o.object_id),
+        Some("issuer.source".to_string())
+    );
+
+    // The object of the target is kept
+    db.store_google_wallet_object(other.id, "issuer.other")
+        .await
+        .unwrap();
+    db.merge_accounts(target.id, other.id, None).await.unwrap();
+    assert_eq!(
+        db.get_google_wallet_object(target.id)
+            .await
+            .unwrap()
+            .map(|o| o.object_id),
+        Some("issuer.source".to_string())
+    );
+}
+
+#[sqlx::test]
+async fn test_anonymize_account(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let role = get_role(&mut db, "Basic").await;
+    let account = db
+        .store_account(Account {
+            name: "John".to_string(),
+            email: "john@example.org".to_string(),
+            id: 0,
+            balance: CoinAmount(HashMap::new()),
+            role,
+            auth_methods: vec![
+                AuthMethod::PasswordBased(AuthPassword {
+                    username: "john".to_string(),
+                    password_hash: vec![],
+                }),
+                AuthMethod::PublicTab,
+            ],
+            enable_monthly_mail_report: true,
+            enable_automatic_stamp_usage: true,
+            status: None,
+            archived_at: None,
+        })
+        .await
+        .unwrap();
+
+    db.payment(
+        Payment {
+            account: account.id,
+            items: vec![PaymentItem {
+                effective_price: CoinAmount(HashMap::from([(CoinType::Cent, -100)])),
+                product_id: None,
+            }],
+            authorization: None,
+        },
+        Utc::now(),
+        false,
+    )
+    .await
+    .unwrap();
+    db.create_session_token(
+        account.id,
+        AuthMethodType::PasswordBased,
+        SessionLifetime::Fixed(Utc::now().add(Duration::minutes(30))),
+        false,
+        &SessionClient::default(),
+    )
+    .await
+    .unwrap();
+    db.store_apple_wallet_pass(AppleWalletPass {
+        account_id: account.id,
+        pass_type_id: "pass.test".to_string(),
+        authentication_token: String::new(),
+        qr_code: "qr".to_string(),
+        updated_at: 0,
+    })
+    .await
+    .unwrap();
+    db.store_apple_wallet_registration(AppleWalletRegistration {
+        account_id: account.id,
+        pass_type_id: "pass.test".to_string(),
+        device_id: "device".to_string(),
+        push_token: "push".to_string(),
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+        db.get_apple_wallet_registrations_by_account(account.id)
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+
+    let role = get_role(&mut db, "Admin").await;
+    let admin = db
+        .store_account(Account {
+            id: 0,
+            name: "Admin".to_string(),
+            email: "admin@example.org".to_string(),
+            auth_methods: vec![],
+            role,
+            ..account.clone()
+        })
+        .await
+        .unwrap();
+    let client = SessionClient {
+        ip_address: Some("192.0.2.1".to_string()),
+        ..SessionClient::default()
+    };
+    let token = db
+        .create_impersonation_session(
+            account.id,
+            admin.id,
+            Utc::now().add(Duration::minutes(30)),
+            &client,
+        )
+        .await
+        .unwrap();
+    let session = db
+        .get_session_by_session_token(token)
+        .await
+        .unwrap()
+        .unwrap();
+    db.log_impersonation(&session, "GET /api/v1/account/1", &client)
+        .await
+        .unwrap();
+
+    let audit_entry = |target_type: &str, target_id: u64, data: serde_json::Value| AuditLogEntry {
+        id: 0,
+        timestamp: Utc::now(),
+        actor_account_id: Some(admin.id),
+        impersonated_account_id: None,
+        action: format!("{target_type}.update"),
+        target_type: target_type.to_string(),
+        target_id: Some(target_id),
+        before: None,
+        after: Some(data),
+    };
+    db.append_audit_log(audit_entry(
+        "account",
+        account.id,
+        serde_json::json!({"name": "John", "email": "john@example.org", "auth_methods": [{"username": "john"}], "role": "Basic"}),
+    ))
+    .await
+    .unwrap();
+    db.append_audit_log(audit_entry(
+        "invitation",
+        1,
+        serde_json::json!({"name": "John", "email": "john@example.org"}),
+    ))
+    .await
+    .unwrap();
+    let unrelated = db
+        .append_audit_log(audit_entry(
+            "account",
+            admin.id,
+            serde_json::json!({"name": "Admin", "email": "admin@example.org"}),
+        ))
+        .await
+        .unwrap();
+    let invitation = db
+        .store_invitation(
+            Invitation {
+                id: 0,
+                token: String::new(),
+                name: "John".to_string(),
+                email: "john@example.org".to_string(),
+                role: account.role.clone(),
+                status: None,
+                invited_by_account_id: None,
+                created_at: Utc::now(),
+                valid_until: Utc::now().add(Duration::days(7)),
+            },
+            false,
+        )
+        .await
+        .unwrap();
+
+    db.anonymize_account(account.id, "anonymous-1")
+        .await
+        .unwrap();
+
+    let anonymized = db.get_account_by_id(account.id).await.unwrap().unwrap();
+    assert_eq!(anonymized.name, "anonymous-1");
+    assert_eq!(anonymized.email, "");
+    assert_eq!(anonymized.auth_methods, vec![]);
+    assert!(!anonymized.enable_monthly_mail_report);
+    assert_eq!(anonymized.balance.0.get(&CoinType::Cent), Some(&100));
+    assert_eq!(
+        db.get_sessions_by_account(account.id).await.unwrap(),
+        vec![]
+    );
+    assert_eq!(
+        db.get_apple_wallet_registrations_by_account(account.id)
+            .await
+            .unwrap(),
+        vec![]
+    );
+    assert_eq!(
+        db.get_transactions_by_account(account.id)
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+
+    // personal data is removed from the logs as well
+    let log = db
+        .get_audit_log(&AuditLogFilter {
+            actor_account_id: None,
+            action: None,
+            target_type: None,
+            target_id: None,
+            from: None,
+            to: None,
+            limit: 100,
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        log[2].after,
+        Some(
+            serde_json::json!({"name": "anonymous-1", "email": "", "auth_methods": [], "role": "Basic"})
+        )
+    );
+    assert_eq!(
+        log[1].after,
+        Some(serde_json::json!({"name": "anonymous-1", "email": ""}))
+    );
+    assert_eq!(log[0], unrelated);
+    assert!(db
+        .get_impersonation_log(Some(account.id))
+        .await
+        .unwrap()
+        .iter()
+        .all(|e| e.ip_address.is_none()));
+    assert_eq!(
+        db.get_invitation_by_token(&invitation.token).await.unwrap(),
+        None
+    );
+
+    assert_eq!(
+        db.anonymize_account(0, "anonymous-2").await,
+        Err(ServiceError::NotFound)
+    );
+}
+
+#[sqlx::test]
+async fn test_archive_account_with_register_payout(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let role = get_role(&mut db, "Basic").await;
+    let account = db
+        .store_account(Account {
+            name: "John".to_string(),
+            email: "john@example.org".to_string(),
+            id: 0,
+            balance: CoinAmount(HashMap::from([
+                (CoinType::Cent, 500),
+                (CoinType::CoffeeStamp, 3),
+            ])),
+            role,
+            auth_methods: vec![AuthMethod::PasswordBased(AuthPassword {
+                username: "john".to_string(),
+                password_hash: vec![],
+            })],
+            enable_monthly_mail_report: true,
+            enable_automatic_stamp_usage: true,
+            status: None,
+            archived_at: None,
+        })
+        .await
+        .unwrap();
+    db.create_session_token(
+        account.id,
+        AuthMethodType::PasswordBased,
+        SessionLifetime::Fixed(Utc::now().add(Duration::minutes(30))),
+        false,
+        &SessionClient::default(),
+    )
+    .await
+    .unwrap();
+
+    let transaction = db
+        .payment(
+            Payment {
+                account: account.id,
+                items: vec![
+                    PaymentItem {
+                        effective_price: CoinAmount(HashMap::from([(CoinType::CoffeeStamp, 3)])),
+                        product_id: None,
+                    },
+                    PaymentItem {
+                        effective_price: CoinAmount(HashMap::from([(CoinType::Cent, 500)])),
+                        product_id: None,
+                    },
+                ],
+                authorization: None,
+            },
+            Utc::now(),
+            false,
+        )
+        .await
+        .unwrap();
+    let payout = db
+        .store_register_payout(RegisterPayout {
+            id: 0,
+            timestamp: transaction.timestamp,
+            account_id: Some(account.id),
+            transaction_id: transaction.id,
+            amount_cents: 500,
+            paid_by_account_id: None,
+        })
+        .await
+        .unwrap();
+    assert_ne!(payout.id, 0);
+
+    db.archive_account(account.id).await.unwrap();
+
+    let archived = db.get_account_by_id(account.id).await.unwrap().unwrap();
+    assert!(archived.archived_at.is_some());
+    assert_eq!(archived.name, "John");
+    assert_eq!(archived.auth_methods, vec![]);
+    assert!(archived.balance.0.values().all(|amount| *amount == 0));
+    assert_eq!(
+        db.get_sessions_by_account(account.id).await.unwrap(),
+        vec![]
+    );
+    assert_eq!(db.get_all_register_payouts().await.unwrap(), vec![payout]);
+
+    // Archiving twice is rejected
+    assert_eq!(
+        db.archive_account(account.id).await,
+        Err(ServiceError::NotFound)
+    );
+}
+
+#[sqlx::test]
+async fn test_dormant_accounts(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let role = get_role(&mut db, "Basic").await;
+    let mut accounts = Vec::new();
+    for name in ["never used", "old", "recent"] {
+        let account = db
+            .store_account(Account {
+                name: name.to_string(),
+                email: String::new(),
+                id: 0,
+                balance: CoinAmount(HashMap::new()),
+                role: role.clone(),
+                auth_methods: vec![],
+                enable_monthly_mail_report: false,
+                enable_automatic_stamp_usage: true,
+                status: None,
+                archived_at: None,
+            })
+            .await
+            .unwrap();
+        accounts.push(account);
+    }
+    let [never_used, old, recent] = accounts.try_into().unwrap();
+
+    for (account, timestamp) in [
+        (&old, Utc::now() - Duration::days(800)),
+        (&recent, Utc::now() - Duration::days(10)),
+    ] {
+        db.payment(
+            Payment {
+                account: account.id,
+                items: vec![PaymentItem {
+                    effective_price: CoinAmount(HashMap::from([(CoinType::Cent, 100)])),
+                    product_id: None,
+                }],
+                authorization: None,
+            },
+            timestamp,
+            false,
+        )
+        .await
+        .unwrap();
+    }
+
+    let dormant = db
+        .get_dormant_accounts(Utc::now() - Duration::days(365))
+        .await
+        .unwrap();
+    let ids: Vec<u64> = dormant.iter().map(|d| d.account.id).collect();
+    assert!(ids.contains(&never_used.id));
+    assert!(ids.contains(&old.id));
+    assert!(!ids.contains(&recent.id));
+    assert_eq!(dormant[0].last_activity, None);
+    let old_entry = dormant.iter().find(|d| d.account.id == old.id).unwrap();
+    assert!(old_entry.last_activity.is_some());
+    assert_eq!(
+        old_entry.account.balance.0.get(&CoinType::Cent),
+        Some(&-100)
+    );
+
+    // A recent session counts as activity
+    db.create_session_token(
+        never_used.id,
+        AuthMethodType::PasswordBased,
+        SessionLifetime::Fixed(Utc::now().add(Duration::minutes(30))),
+        false,
+        &SessionClient::default(),
+    )
+    .await
+    .unwrap();
+    db.archive_account(old.id).await.unwrap();
+
+    let dormant = db
+        .get_dormant_accounts(Utc::now() - Duration::days(365))
+        .await
+        .unwrap();
+    let ids: Vec<u64> = dormant.iter().map(|d| d.account.id).collect();
+    assert!(!ids.contains(&never_used.id));
+    assert!(!ids.contains(&old.id));
+}
+
+#[sqlx::test]
+async fn test_transfer(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let role = get_role(&mut db, "Basic").await;
+    let mut accounts = Vec::new();
+    for (name, balance) in [("sender", 1000), ("receiver", -200)] {
+        let account = db
+            .store_account(Account {
+                name: name.to_string(),
+                email: String::new(),
+                id: 0,
+                balance: CoinAmount(HashMap::from([
+                    (CoinType::Cent, balance),
+                    (CoinType::CoffeeStamp, 2),
+                ])),
+                role: role.clone(),
+                auth_methods: vec![],
+                enable_monthly_mail_report: false,
+                enable_automatic_stamp_usage: true,
+                status: None,
+                archived_at: None,
+            })
+            .await
+            .unwrap();
+        accounts.push(account);
+    }
+    let [sender, receiver] = accounts.try_into().unwrap();
+
+    let amount = CoinAmount(HashMap::from([
+        (CoinType::Cent, 300),
+        (CoinType::CoffeeStamp, 2),
+    ]));
+    let (source_transaction, target_transaction) = db
+        .transfer(sender.id, receiver.id, &amount, None, Utc::now())
+        .await
+        .unwrap();
+    assert_eq!(source_transaction.account, sender.id);
+    assert_eq!(target_transaction.account, receiver.id);
+    assert_eq!(
+        source_transaction.linked_transaction_id,
+        Some(target_transaction.id)
+    );
+    assert_eq!(
+        target_transaction.linked_transaction_id,
+        Some(source_transaction.id)
+    );
+    assert_eq!(source_transaction.items[0].effective_price, amount);
+
+    let sender = db.get_account_by_id(sender.id).await.unwrap().unwrap();
+    let receiver = db.get_account_by_id(receiver.id).await.unwrap().unwrap();
+    assert_eq!(sender.balance.0.get(&CoinType::Cent), Some(&700));
+    assert_eq!(sender.balance.0.get(&CoinType::CoffeeStamp), None);
+    assert_eq!(receiver.balance.0.get(&CoinType::Cent), Some(&100));
+    assert_eq!(receiver.balance.0.get(&CoinType::CoffeeStamp), Some(&4));
+    assert_eq!(
+        db.get_transactions_by_account(receiver.id)
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+
+    // The sender must not go below the minimum balance
+    assert_eq!(
+        db.transfer(
+            sender.id,
+            receiver.id,
+            &CoinAmount(HashMap::from([(CoinType::Cent, 800)])),
+            None,
+            Utc::now()
+        )
+        .await,
+        Err(ServiceError::PaymentError(vec![String::from("Cent")]))
+    );
+    // Transfers work in both directions
+    db.transfer(
+        receiver.id,
+        sender.id,
+        &CoinAmount(HashMap::from([(CoinType::CoffeeStamp, 1)])),
+        None,
+        Utc::now(),
+    )
+    .await
+    .unwrap();
+
+    db.archive_account(receiver.id).await.unwrap();
+    assert_eq!(
+        db.transfer(sender.id, receiver.id, &amount, None, Utc::now())
+            .await,
+        Err(ServiceError::AccountArchived)
+    );
+    assert_eq!(
+        db.transfer(receiver.id, sender.id, &amount, None, Utc::now())
+            .await,
+        Err(ServiceError::AccountArchived)
+    );
+    for check_payment_conditions in [true, false] {
+        assert_eq!(
+            db.payment(
+                Payment {
+                    account: receiver.id,
+                    items: vec![PaymentItem {
+                        effective_price: CoinAmount(HashMap::from([(CoinType::Cent, -100)])),
+                        product_id: None,
+                    }],
+                    authorization: None,
+                },
+                Utc::now(),
+                check_payment_conditions,
+            )
+            .await,
+            Err(ServiceError::AccountArchived)
+        );
+    }
+    assert_eq!(
+        db.transfer(sender.id, 0, &amount, None, Utc::now()).await,
+        Err(ServiceError::NotFound)
+    );
+}
+
+#[sqlx::test]
+async fn test_account_group_members(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let role = get_role(&mut db, "Basic").await;
+    let mut accounts = Vec::new();
+    for name in ["workshop", "alice", "bob"] {
+        let account = db
+            .store_account(Account {
+                name: name.to_string(),
+                email: String::new(),
+                id: 0,
+                balance: CoinAmount(HashMap::from([(CoinType::Cent, 1000)])),
+                role: role.clone(),
+                auth_methods: vec![],
+                enable_monthly_mail_report: false,
+                enable_automatic_stamp_usage: true,
+                status: None,
+                archived_at: None,
+            })
+            .await
+            .unwrap();
+        accounts.push(account);
+    }
+    let [group, alice, bob] = accounts.try_into().unwrap();
+
+    let member = db
+        .store_account_group_member(AccountGroupMember {
+            group_account_id: group.id,
+            member_account_id: alice.id,
+            member_name: String::new(),
+            is_admin: true,
+            spending_limit_cents: None,
+        })
+        .await
+        .unwrap();
+    assert_eq!(member.member_name, "alice");
+    db.store_account_group_member(AccountGroupMember {
+        group_account_id: group.id,
+        member_account_id: bob.id,
+        member_name: String::new(),
+        is_admin: false,
+        spending_limit_cents: Some(500),
+    })
+    .await
+    .unwrap();
+
+    // Storing again updates the membership
+    let member = db
+        .store_account_group_member(AccountGroupMember {
+            is_admin: false,
+            ..member
+        })
+        .await
+        .unwrap();
+    assert!(!member.is_admin);
+
+    let members = db.get_account_group_members(group.id).await.unwrap();
+    assert_eq!(members.len(), 2);
+    assert_eq!(members[0], member);
+    assert_eq!(members[1].spending_limit_cents, Some(500));
+    assert_eq!(
+        db.get_account_groups_by_member(bob.id).await.unwrap(),
+        vec![members[1].clone()]
+    );
+
+    let token = db
+        .create_session_token(
+            bob.id,
+            AuthMethodType::PasswordBased,
+            SessionLifetime::Fixed(Utc::now().add(Duration::minutes(30))),
+            false,
+            &SessionClient::default(),
+        )
+        .await
+        .unwrap();
+    let session = db.get_session_by_session_token(token).await.unwrap();
+    let transaction = db
+        .payment(
+            Payment {
+                account: group.id,
+                items: vec![PaymentItem {
+                    effective_price: CoinAmount(HashMap::from([(CoinType::Cent, 200)])),
+                    product_id: None,
+                }],
+                authorization: session.clone(),
+            },
+            Utc::now(),
+            true,
+        )
+        .await
+        .unwrap();
+    assert_eq!(transaction.authorized_by_account_id, Some(bob.id));
+
+    let group_payment = |cents: i32, product_id: Option<u64>| Payment {
+        account: group.id,
+        items: vec![PaymentItem {
+            effective_price: CoinAmount(HashMap::from([(CoinType::Cent, cents)])),
+            product_id,
+        }],
+        authorization: session.clone(),
+    };
+
+    // Refunds do not make room for more spending
+    db.payment(group_payment(-300, None), Utc::now(), false)
+        .await
+        .unwrap();
+    assert_eq!(
+        db.payment(group_payment(400, None), Utc::now(), true).await,
+        Err(ServiceError::PaymentLimitExceeded(vec!["Cent".to_string()]))
+    );
+    db.payment(group_payment(300, None), Utc::now(), true)
+        .await
+        .unwrap();
+
+    // The spending policy of the member applies to group payments as well
+    let product = db
+        .store_product(Product {
+            id: 0,
+            price: CoinAmount(HashMap::from([(CoinType::Cent, 100)])),
+            bonus: CoinAmount(HashMap::new()),
+            barcode: None,
+            category: "drinks".to_string(),
+            name: "Beer".to_string(),
+            nickname: None,
+            purchase_tax: 19,
+            image: None,
+            print_lists: vec![],
+            tags: vec!["alcohol".to_string()],
+            status_prices: Vec::new(),
+        })
+        .await
+        .unwrap();
+    db.store_spending_policy(
+        alice.id,
+        &SpendingPolicy {
+            limits: vec![],
+            blocked_product_tags: vec!["alcohol".to_string()],
+            locked: false,
+        },
+    )
+    .await
+    .unwrap();
+    let token = db
+        .create_session_token(
+            alice.id,
+            AuthMethodType::PasswordBased,
+            SessionLifetime::Fixed(Utc::now().add(Duration::minutes(30))),
+            false,
+            &SessionClient::default(),
+        )
+        .await
+        .unwrap();
+    let alice_session = db.get_session_by_session_token(token).await.unwrap();
+    assert_eq!(
+        db.payment(
+            Payment {
+                authorization: alice_session,
+                ..group_payment(100, Some(product.id))
+            },
+            Utc::now(),
+            true,
+        )
+        .await,
+        Err(ServiceError::PaymentError(vec![
+            "BlockedProductTag:alcohol".to_string()
+        ]))
+    );
+
+    db.delete_account_group_member(group.id, bob.id)
+        .await
+        .unwrap();
+    assert_eq!(
+        db.delete_account_group_member(group.id, bob.id).await,
+        Err(ServiceError::NotFound)
+    );
+    assert_eq!(
+        db.get_account_group_members(group.id).await.unwrap().len(),
+        1
+    );
+}
+
+#[sqlx::test]
+async fn test_spending_policy(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let role = get_role(&mut db, "Basic").await;
+    let account = db
+        .store_account(Account {
+            name: "guest".to_string(),
+            email: String::new(),
+            id: 0,
+            balance: CoinAmount(HashMap::from([
+                (CoinType::Cent, 2000),
+                (CoinType::CoffeeStamp, 10),
+            ])),
+            role,
+            auth_methods: vec![],
+            enable_monthly_mail_report: false,
+            enable_automatic_stamp_usage: true,
+            status: None,
+            archived_at: None,
+        })
+        .await
+        .unwrap();
+    let beer = db
+        .store_product(Product {
+            id: 0,
+            price: CoinAmount(HashMap::from([(CoinType::Cent, 200)])),
+            bonus: CoinAmount(HashMap::new()),
+            barcode: None,
+            category: "drinks".to_string(),
+            name: "Beer".to_string(),
+            nickname: None,
+            purchase_tax: 19,
+            image: None,
+            print_lists: vec![],
+            tags: vec!["alcohol".to_string()],
+            status_prices: Vec::new(),
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(
+        db.get_spending_policy(account.id).await.unwrap(),
+        SpendingPolicy::default()
+    );
+    let policy = SpendingPolicy {
+        limits: vec![
+            SpendingLimit {
+                period: SpendingLimitPeriod::Day,
+                limit: CoinAmount(HashMap::from([(CoinType::Cent, 500)])),
+            },
+            SpendingLimit {
+                period: SpendingLimitPeriod::Month,
+                limit: CoinAmount(HashMap::from([(CoinType::CoffeeStamp, 0)])),
+            },
+        ],
+        blocked_product_tags: vec!["alcohol".to_string()],
+        locked: true,
+    };
+    db.store_spending_policy(account.id, &policy).await.unwrap();
+    assert_eq!(db.get_spending_policy(account.id).await.unwrap(), policy);
+
+    let pay = |cents: i32, coffee_stamps: i32, product_id: Option<u64>| Payment {
+        account: account.id,
+        items: vec![PaymentItem {
+            effective_price: CoinAmount(HashMap::from([
+                (CoinType::Cent, cents),
+                (CoinType::CoffeeStamp, coffee_stamps),
+            ])),
+            product_id,
+        }],
+        authorization: None,
+    };
+
+    // Transactions older than the period do not count
+    db.payment(pay(1000, 0, None), Utc::now() - Duration::days(2), false)
+        .await
+        .unwrap();
+    db.payment(pay(300, 0, None), Utc::now(), true)
+        .await
+        .unwrap();
+    assert_eq!(
+        db.payment(pay(300, 0, None), Utc::now(), true).await,
+        Err(ServiceError::PaymentError(vec![String::from(
+            "SpendingLimit:day:Cent"
+        )]))
+    );
+    // Credit loading is not limited
+    db.payment(pay(-300, 0, None), Utc::now(), true)
+        .await
+        .unwrap();
+    assert_eq!(
+        db.payment(pay(200, 1, Some(beer.id)), Utc::now(), true)
+            .await,
+        Err(ServiceError::PaymentError(vec![
+            String::from("BlockedProductTag:alcohol"),
+            String::from("SpendingLimit:month:CoffeeStamp"),
+        ]))
+    );
+    // Payments without checks, eg. account closures, ignore the policy
+    db.payment(pay(200, 1, Some(beer.id)), Utc::now(), false)
+        .await
+        .unwrap();
+
+    db.store_spending_policy(account.id, &SpendingPolicy::default())
+        .await
+        .unwrap();
+    db.payment(pay(200, 1, Some(beer.id)), Utc::now(), true)
+        .await
+        .unwrap();
+}
+
+#[sqlx::test]
+async fn test_transfer_spending_policy(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let role = get_role(&mut db, "Basic").await;
+    let account = |name: &str| Account {
+        name: name.to_string(),
+        email: String::new(),
+        id: 0,
+        balance: CoinAmount(HashMap::from([(CoinType::Cent, 2000)])),
+        role: role.clone(),
+        auth_methods: vec![],
+        enable_monthly_mail_report: false,
+        enable_automatic_stamp_usage: true,
+        status: None,
+        archived_at: None,
+    };
+    let source = db.store_account(account("source")).await.unwrap();
+    let target = db.store_account(account("target")).await.unwrap();
+
+    db.store_spending_policy(
+        source.id,
+        &SpendingPolicy {
+            limits: vec![SpendingLimit {
+                period: SpendingLimitPeriod::Day,
+                limit: CoinAmount(HashMap::from([(CoinType::Cent, 500)])),
+            }],
+            blocked_product_tags: vec![],
+            locked: false,
+        },
+    )
+    .await
+    .unwrap();
+
+    let amount = CoinAmount(HashMap::from([(CoinType::Cent, 300)]));
+    db.transfer(source.id, target.id, &amount, None, Utc::now())
+        .await
+        .unwrap();
+    assert_eq!(
+        db.transfer(source.id, target.id, &amount, None, Utc::now())
+            .await,
+        Err(ServiceError::PaymentError(vec![String::from(
+            "SpendingLimit:day:Cent"
+        )]))
+    );
+
+    // Received transfers do not count against the limit of the target account
+    db.store_spending_policy(
+        target.id,
+        &SpendingPolicy {
+            limits: vec![SpendingLimit {
+                period: SpendingLimitPeriod::Day,
+                limit: CoinAmount(HashMap::from([(CoinType::Cent, 0)])),
+            }],
+            blocked_product_tags: vec![],
+            locked: false,
+        },
+    )
+    .await
+    .unwrap();
+    db.transfer(
+        source.id,
+        target.id,
+        &CoinAmount(HashMap::from([(CoinType::Cent, 100)])),
+        None,
+        Utc::now(),
+    )
+    .await
+    .unwrap();
+}
+
+#[sqlx::test]
+async fn test_vouchers(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let role = get_role(&mut db, "Basic").await;
+    let account = db
+        .store_account(Account {
+            name: "guest".to_string(),
+            email: String::new(),
+            id: 0,
+            balance: CoinAmount::zero(),
+            role,
+            auth_methods: vec![],
+            enable_monthly_mail_report: false,
+            enable_automatic_stamp_usage: true,
+            status: None,
+            archived_at: None,
+        })
+        .await
+        .unwrap();
+
+    let batch = |name: &str, valid_until| VoucherBatch {
+        id: 0,
+        name: name.to_string(),
+        created_at: Utc::now(),
+        created_by_account_id: Some(account.id),
+        valid_until,
+        value: CoinAmount(HashMap::from([(CoinType::Cent, 500)])),
+        voucher_count: 0,
+        used_count: 0,
+    };
+    let codes = vec!["voucher-a".to_string(), "voucher-b".to_string()];
+    let open = db
+        .create_voucher_batch(batch("Open day", Utc::now().add(Duration::days(1))), &codes)
+        .await
+        .unwrap();
+    assert_eq!(open.voucher_count, 2);
+    assert_eq!(open.used_count, 0);
+    assert_eq!(open.value.0.get(&CoinType::Cent), Some(&500));
+
+    let expired = db
+        .create_voucher_batch(
+            batch("Last year", Utc::now().add(Duration::days(-1))),
+            &["voucher-c".to_string()],
+        )
+        .await
+        .unwrap();
+
+    // Accounts without id are created as guest account
+    let guest = Account {
+        id: 0,
+        ..account.clone()
+    };
+    let (voucher, transaction) = db
+        .redeem_voucher("voucher-a", guest.clone(), None)
+        .await
+        .unwrap();
+    assert_eq!(voucher.batch_id, open.id);
+    assert!(voucher.is_guest);
+    assert!(voucher.used_at.is_some());
+    let guest_id = voucher.account_id.unwrap();
+    assert_ne!(guest_id, account.id);
+    assert_eq!(transaction.account, guest_id);
+    let guest_account = db.get_account_by_id(guest_id).await.unwrap().unwrap();
+    assert_eq!(guest_account.balance.0.get(&CoinType::Cent), Some(&500));
+
+    let account_count = db.get_all_accounts().await.unwrap().len();
+
+    // Every code can only be used once, failed redemptions create no account and no credit
+    for code in ["voucher-a", "voucher-c", "unknown"] {
+        assert_eq!(
+            db.redeem_voucher(code, guest.clone(), None).await,
+            Err(ServiceError::NotFound)
+        );
+        assert_eq!(
+            db.redeem_voucher(code, account.clone(), None).await,
+            Err(ServiceError::NotFound)
+        );
+    }
+    assert_eq!(db.get_all_accounts().await.unwrap().len(), account_count);
+    assert_eq!(
+        db.get_account_by_id(account.id)
+            .await
+            .unwrap()
+            .unwrap()
+            .balance
+            .0
+            .get(&CoinType::Cent),
+        None
+    );
+
+    let unused = db.get_voucher_by_code("voucher-b").await.unwrap().unwrap();
+    assert_eq!(unused.used_at, None);
+    assert_eq!(unused.account_id, None);
+
+    let (voucher, _) = db
+        .redeem_voucher("voucher-b", account.clone(), None)
+        .await
+        .unwrap();
+    assert_eq!(voucher.account_id, Some(account.id));
+    assert!(!voucher.is_guest);
+    assert_eq!(
+        db.get_account_by_id(account.id)
+            .await
+            .unwrap()
+            .unwrap()
+            .balance
+            .0
+            .get(&CoinType::Cent),
+        Some(&500)
+    );
+
+    let batches = db.get_all_voucher_batches().await.unwrap();
+    assert_eq!(
+        batches.iter().map(|b| b.id).collect::<Vec<_>>(),
+        vec![expired.id, open.id]
+    );
+    assert_eq!(batches[1].used_count, 2);
+    assert_eq!(db.get_vouchers_by_batch(open.id).await.unwrap().len(), 2);
+}
+
+#[sqlx::test]
+async fn test_rotate_wallet_pass_qr_code(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let role = get_role(&mut db, "Basic").await;
+    let account = db
+        .store_account(Account {
+            name: "phone".to_string(),
+            email: String::new(),
+            id: 0,
+            balance: CoinAmount::zero(),
+            role,
+            auth_methods: vec![],
+            enable_monthly_mail_report: false,
+            enable_automatic_stamp_usage: true,
+            status: None,
+            archived_at: None,
+        })
+        .await
+        .unwrap();
+    db.store_apple_wallet_pass(AppleWalletPass {
+        account_id: account.id,
+        pass_type_id: "pass.test".to_string(),
+        authentication_token: String::new(),
+        qr_code: "qr-1".to_string(),
+        updated_at: 0,
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(
+        db.get_apple_wallet_pass_by_qr_code("qr-1", "pass.other")
+            .await
+            .unwrap(),
+        None
+    );
+    assert_eq!(
+        db.get_apple_wallet_pass_by_qr_code("qr-1", "pass.test")
+            .await
+            .unwrap()
+            .map(|pass| pass.account_id),
+        Some(account.id)
+    );
+    assert_eq!(
+        db.rotate_apple_wallet_pass_qr_code("qr-1", "pass.other", "qr-2", 1)
+            .await
+            .unwrap(),
+        None
+    );
+    let pass = db
+        .rotate_apple_wallet_pass_qr_code("qr-1", "pass.test", "qr-2", 1)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(pass.account_id, account.id);
+    assert_eq!(pass.qr_code, "qr-2");
+    assert_eq!(pass.updated_at, 1);
+
+    // A scanned code is not rotated a second time, but stays valid until the new code is delivered
+    assert_eq!(
+        db.rotate_apple_wallet_pass_qr_code("qr-1", "pass.test", "qr-3", 2)
+            .await
+            .unwrap(),
+        None
+    );
+    let pass = db
+        .get_apple_wallet_pass(account.id, "pass.test")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(pass.qr_code, "qr-2");
+    assert_eq!(
+        db.get_apple_wallet_pass_by_qr_code("qr-1", "pass.test")
+            .await
+            .unwrap()
+            .map(|pass| pass.qr_code),
+        Some("qr-2".to_string())
+    );
+
+    // Delivering an outdated pass keeps the previous code
+    db.confirm_apple_wallet_pass_qr_code(account.id, "pass.test", "qr-1")
+        .await
+        .unwrap();
+    assert!(db
+        .get_apple_wallet_pass_by_qr_code("qr-1", "pass.test")
+        .await
+        .unwrap()
+        .is_some());
+    db.confirm_apple_wallet_pass_qr_code(account.id, "pass.test", "qr-2")
+        .await
+        .unwrap();
+    assert_eq!(
+        db.get_apple_wallet_pass_by_qr_code("qr-1", "pass.test")
+            .await
+            .unwrap(),
+        None
+    );
+
+    let limit = CoinAmount(HashMap::from([(CoinType::Cent, 2000)]));
+    let token = db
+        .create_payment_token(
+            account.id,
+            AuthMethodType::QrCode,
+            Utc::now().add(Duration::seconds(60)),
+            &limit,
+            None,
+            &SessionClient::default(),
+        )
+        .await
+        .unwrap();
+    let session = db
+        .get_session_by_session_token(token)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(session.auth_method, AuthMethodType::QrCode);
+    assert!(session.is_payment_only());
+    assert!(session.is_single_use);
+}
+
+#[sqlx::test]
+async fn test_google_wallet_object(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let role = get_role(&mut db, "Basic").await;
+    let account = db
+        .store_account(Account {
+            name: "android".to_string(),
+            email: String::new(),
+            id: 0,
+            balance: CoinAmount::zero(),
+            role,
+            auth_methods: vec![],
+            enable_monthly_mail_report: false,
+            enable_automatic_stamp_usage: true,
+            status: None,
+            archived_at: None,
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(db.get_google_wallet_object(account.id).await.unwrap(), None);
+
+    let object = db
+        .store_google_wallet_object(account.id, "issuer.1")
+        .await
+        .unwrap();
+    assert_eq!(object.account_id, account.id);
+    let object = db
+        .store_google_wallet_object(account.id, "issuer.2")
+        .await
+        .unwrap();
+    assert_eq!(
+        db.get_google_wallet_object(account.id).await.unwrap(),
+        Some(object)
+    );
+
+    // Archived accounts no longer receive updates
+    db.archive_account(account.id).await.unwrap();
+    assert_eq!(db.get_google_wallet_object(account.id).await.unwrap(), None);
+}
+
+struct StubPushService {
+    status_codes: HashMap<String, u16>,
+}
+
+impl PushService for StubPushService {
+    async fn send(&self, push_token: &str) -> ServiceResult<u16> {
+        Ok(self.status_codes.get(push_token).copied().unwrap_or(500))
+    }
+}
+
+struct StubGoogleWalletApi {
+    available: bool,
+    objects: std::sync::Mutex<Vec<serde_json::Value>>,
+}
+
+impl GoogleWalletApi for StubGoogleWalletApi {
+    async fn update_loyalty_object(&self, object: &serde_json::Value) -> ServiceResult<bool> {
+        if !self.available {
+            return Err(ServiceError::InternalServerError(
+                "Google wallet api is not available".to_string(),
+            ));
+        }
+        self.objects.lock().unwrap().push(object.clone());
+        Ok(true)
+    }
+}
+
+#[sqlx::test]
+async fn test_push_job_queue(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let role = get_role(&mut db, "Basic").await;
+    let account = db
+        .store_account(Account {
+            name: "phone".to_string(),
+            email: String::new(),
+            id: 0,
+            balance: CoinAmount::zero(),
+            role,
+            auth_methods: vec![],
+            enable_monthly_mail_report: false,
+            enable_automatic_stamp_usage: true,
+            status: None,
+            archived_at: None,
+        })
+        .await
+        .unwrap();
+    db.store_apple_wallet_pass(AppleWalletPass {
+        account_id: account.id,
+        pass_type_id: "pass.test".to_string(),
+        authentication_token: String::new(),
+        qr_code: "qr".to_string(),
+        updated_at: 0,
+    })
+    .await
+    .unwrap();
+    for device in ["ok", "gone", "broken"] {
+        db.store_apple_wallet_registration(AppleWalletRegistration {
+            account_id: account.id,
+            pass_type_id: "pass.test".to_string(),
+            device_id: device.to_string(),
+            push_token: format!("token-{}", device),
+        })
+        .await
+        .unwrap();
+    }
+
+    // Repeated updates are merged into one job per device
+    assert_eq!(
+        db.enqueue_push_jobs(account.id, "pass.test").await.unwrap(),
+        3
+    );
+    assert_eq!(
+        db.enqueue_push_jobs(account.id, "pass.test").await.unwrap(),
+        3
+    );
+
+    let service = StubPushService {
+        status_codes: HashMap::from([
+            ("token-ok".to_string(), 200),
+            ("token-gone".to_string(), 410),
+        ]),
+    };
+    let google = StubGoogleWalletApi {
+        available: true,
+        objects: std::sync::Mutex::new(Vec::new()),
+    };
+    assert_eq!(
+        process_push_jobs(&mut db, &service, &google).await.unwrap(),
+        3
+    );
+    assert_eq!(
+        db.list_apple_wallet_registration(account.id, "pass.test")
+            .await
+            .unwrap()
+            .len(),
+        2
+    );
+
+    // The failed job waits for its retry
+    assert_eq!(
+        process_push_jobs(&mut db, &service, &google).await.unwrap(),
+        0
+    );
+    let jobs = db.claim_push_jobs(10, Duration::minutes(5)).await.unwrap();
+    assert!(jobs.is_empty());
+
+    let r = sqlx::query_as::<_, super::PushJobRow>(
+        r#"SELECT id, kind, account_id, pass_type_id, device_id, push_token, attempts, next_attempt_at, last_error, created_at, dead_at FROM push_job"#,
+    )
+    .fetch_all(db.connection.as_mut())
+    .await
+    .unwrap();
+    assert_eq!(r.len(), 1);
+    let job = crate::models::PushJob::from(r.into_iter().next().unwrap());
+    assert_eq!(job.device_id, "broken");
+    assert_eq!(job.attempts, 1);
+    assert!(job.next_attempt_at > Utc::now());
+
+    // The last retry moves the job to the dead letters
+    db.fail_push_job(
+        job.id,
+        7,
+        "failed",
+        Some(Utc::now().add(Duration::seconds(-1))),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        process_push_jobs(&mut db, &service, &google).await.unwrap(),
+        1
+    );
+    let dead = db.get_dead_push_jobs().await.unwrap();
+    assert_eq!(dead.len(), 1);
+    assert_eq!(dead[0].attempts, 8);
+    assert!(dead[0].dead_at.is_some());
+    assert_eq!(
+        dead[0].last_error.as_deref(),
+        Some("APNS returned illegal status code: 500")
+    );
+
+    db.retry_dead_push_job(dead[0].id).await.unwrap();
+    assert!(db.get_dead_push_jobs().await.unwrap().is_empty());
+    assert_eq!(
+        db.retry_dead_push_job(dead[0].id).await,
+        Err(ServiceError::NotFound)
+    );
+    let jobs = db.claim_push_jobs(10, Duration::minutes(5)).await.unwrap();
+    assert_eq!(jobs.len(), 1);
+    assert_eq!(jobs[0].attempts, 0);
+}
+
+#[sqlx::test]
+async fn test_google_wallet_push_job(pool: PgPool) {
+    let app_state = AppState::from_pool(pool).await;
+    let mut db = DatabaseConnection {
+        connection: app_state.pool.acquire().await.unwrap(),
+    };
+
+    let role = get_role(&mut db, "Basic").await;
+    let account = db
+        .store_account(Account {
+            name: "phone".to_string(),
+            email: String::new(),
+            id: 0,
+            balance: CoinAmount::zero(),
+            role,
+            auth_methods: vec![],
+            enable_monthly_mail_report: false,
+            enable_automatic_stamp_usage: true,
+            status: None,
+            archived_at: None,
+        })
+        .await
+        .unwrap();
+    db.store_apple_wallet_pass(AppleWalletPass {
+        account_id: account.id,
+        pass_type_id: crate::env::APPLE_WALLET_PASS_TYPE_IDENTIFIER.to_string(),
+        authentication_token: String::new(),
+        qr_code: "qr".to_string(),
+        updated_at: 0,
+    })
+    .await
+    .unwrap();
+
+    // Accounts without a saved object have nothing to update
+    assert_eq!(db.enqueue_google_wallet_job(account.id).await.unwrap(), 0);
+
+    db.store_google_wallet_object(account.id, "issuer.1")
+        .await
+        .unwrap();
+    assert_eq!(db.enqueue_google_wallet_job(account.id).await.unwrap(), 1);
+    assert_eq!(db.enqueue_google_wallet_job(account.id).await.unwrap(), 1);
+
+    let service = StubPushService {
+        status_codes: HashMap::new(),
+    };
+
+    // A failed update is retried later
+    let google = StubGoogleWalletApi {
+        available: false,
+        objects: std::sync::Mutex::new(Vec::new()),
+    };
+    assert_eq!(
+        process_push_jobs(&mut db, &service, &google).await.unwrap(),
+        1
+    );
+    let r = sqlx::query_as::<_, super::PushJobRow>(
+        r#"SELECT id, kind, account_id, pass_type_id, device_id, push_token, attempts, next_attempt_at, last_error, created_at, dead_at FROM push_job"#,
+    )
+    .fetch_all(db.connection.as_mut())
+    .await
+    .unwrap();
+    assert_eq!(r.len(), 1);
+    let job = crate::models::PushJob::from(r.into_iter().next().unwrap());
+    assert_eq!(job.kind, crate::models::PushJobKind::GoogleWallet);
+    assert_eq!(job.device_id, "issuer.1");
+    assert_eq!(job.attempts, 1);
+    assert!(job.next_attempt_at > Utc::now());
+
+    db.fail_push_job(
+        job.id,
+        1,
+        "failed",
+        Some(Utc::now().add(Duration::seconds(-1))),
+    )
+    .await
+    .unwrap();
+    let google = StubGoogleWalletApi {
+        available: true,
+        objects: std::sync::Mutex::new(Vec::new()),
+    };
+    assert_eq!(
+        process_push_jobs(&mut db, &service, &google).await.unwrap(),
+        1
+    );
+    let objects = google.objects.lock().unwrap().clone();
+    assert_eq!(objects.len(), 1);
+    assert_eq!(objects[0]["accountId"], account.id.to_string());
+    assert!(db
+        .claim_push_jobs(10, Duration::minutes(0))
+        .await
+        .unwrap()
+        .is_empty());
+}
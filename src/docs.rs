@@ -22,6 +22,41 @@ pub fn api_docs(api: TransformOpenApi) -> TransformOpenApi {
             description: Some("Account authentication methods".into()),
             ..Default::default()
         })
+        .tag(Tag {
+            name: "invitations".into(),
+            description: Some("Account invitations".into()),
+            ..Default::default()
+        })
+        .tag(Tag {
+            name: "account_groups".into(),
+            description: Some("Group accounts and their members".into()),
+            ..Default::default()
+        })
+        .tag(Tag {
+            name: "vouchers".into(),
+            description: Some("Prepaid and guest voucher codes".into()),
+            ..Default::default()
+        })
+        .tag(Tag {
+            name: "roles".into(),
+            description: Some("Role and permission management".into()),
+            ..Default::default()
+        })
+        .tag(Tag {
+            name: "nfc_readers".into(),
+            description: Some("Nfc reader management".into()),
+            ..Default::default()
+        })
+        .tag(Tag {
+            name: "push_jobs".into(),
+            description: Some("Wallet update notification queue".into()),
+            ..Default::default()
+        })
+        .tag(Tag {
+            name: "audit_log".into(),
+            description: Some("Audit log of administrative changes".into()),
+            ..Default::default()
+        })
         .tag(Tag {
             name: "account_status".into(),
             description: Some("Account status management".into()),
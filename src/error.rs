@@ -9,11 +9,19 @@ use serde_json::json;
 #[derive(Debug, Clone, JsonSchema, PartialEq)]
 pub enum ServiceError {
     InternalServerError(String),
+    BadRequest(&'static str),
     NotFound,
     Unauthorized(&'static str),
     Forbidden,
     PaymentError(Vec<String>),
+    PaymentLimitExceeded(Vec<String>),
     BalanceNotZero,
+    RoleInUse,
+    CardInUse,
+    UsernameInUse,
+    AccountMerged(u64),
+    AccountArchived,
+    TooManyRequests,
 }
 
 impl std::fmt::Display for ServiceError {
@@ -99,6 +107,12 @@ impl IntoResponse for ServiceError {
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(json!({ "cause": cause })),
             ),
+            ServiceError::BadRequest(cause) => (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": cause,
+                })),
+            ),
             ServiceError::NotFound => (
                 StatusCode::NOT_FOUND,
                 Json(json!({
@@ -124,12 +138,56 @@ impl IntoResponse for ServiceError {
                     "cause": cause,
                 })),
             ),
+            ServiceError::PaymentLimitExceeded(cause) => (
+                StatusCode::CONFLICT,
+                Json(json!({
+                    "error": "PaymentLimitExceeded",
+                    "cause": cause,
+                })),
+            ),
             ServiceError::BalanceNotZero => (
                 StatusCode::CONFLICT,
                 Json(json!({
                     "error": "BalanceNotZero",
                 })),
             ),
+            ServiceError::RoleInUse => (
+                StatusCode::CONFLICT,
+                Json(json!({
+                    "error": "RoleInUse",
+                })),
+            ),
+            ServiceError::CardInUse => (
+                StatusCode::CONFLICT,
+                Json(json!({
+                    "error": "CardInUse",
+                })),
+            ),
+            ServiceError::UsernameInUse => (
+                StatusCode::CONFLICT,
+                Json(json!({
+                    "error": "UsernameInUse",
+                })),
+            ),
+            ServiceError::AccountMerged(target) => (
+                StatusCode::GONE,
+                Json(json!({
+                    "error": "AccountMerged",
+                    "merged_into": target,
+                })),
+            ),
+            ServiceError::AccountArchived => (
+                StatusCode::CONFLICT,
+                Json(json!({
+                    "error": "AccountArchived",
+                })),
+            ),
+            ServiceError::TooManyRequests => (
+                StatusCode::TOO_MANY_REQUESTS,
+                Json(json!({
+                    "error": "TooManyRequests",
+                })),
+            ),
         }
         .into_response()
     }
@@ -0,0 +1,452 @@
+use std::fs;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use log::info;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use reqwest::{header, Client, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::{Mutex, OnceCell};
+
+use crate::database::DatabaseConnection;
+use crate::env;
+use crate::error::{ServiceError, ServiceResult};
+use crate::models::{Account, AppleWalletPass, CoinType};
+use crate::wallet::get_current_time;
+
+const SAVE_URL: &str = "https://pay.google.com/gp/v/save/";
+const TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+const TOKEN_SCOPE: &str = "https://www.googleapis.com/auth/wallet_object.issuer";
+/// Cached access tokens are renewed this many seconds before they expire
+const ACCESS_TOKEN_MARGIN_SECONDS: u64 = 60;
+
+/// Google wallet passes are only offered if an issuer id is configured
+pub fn is_enabled() -> bool {
+    !env::GOOGLE_WALLET_ISSUER_ID.is_empty()
+}
+
+pub fn get_object_id(account_id: u64) -> String {
+    format!("{}.{}", env::GOOGLE_WALLET_ISSUER_ID.as_str(), account_id)
+}
+
+fn get_class_id() -> String {
+    format!(
+        "{}.{}",
+        env::GOOGLE_WALLET_ISSUER_ID.as_str(),
+        env::GOOGLE_WALLET_CLASS_SUFFIX.as_str()
+    )
+}
+
+/// Build the loyalty object for the account. The barcode shows the same qr code as the apple wallet pass.
+pub fn create_loyalty_object(account: &Account, wallet_pass: &AppleWalletPass) -> Value {
+    let get = |t: CoinType| account.balance.0.get(&t).copied().unwrap_or(0);
+
+    json!({
+        "id": get_object_id(account.id),
+        "classId": get_class_id(),
+        "state": "ACTIVE",
+        "accountId": account.id.to_string(),
+        "accountName": account.name,
+        "loyaltyPoints": {
+            "label": "Balance",
+            "balance": {
+                "money": {
+                    "micros": i64::from(get(CoinType::Cent)) * 10_000,
+                    "currencyCode": "EUR",
+                },
+            },
+        },
+        "secondaryLoyaltyPoints": {
+            "label": "Coffee stamps",
+            "balance": {
+                "int": get(CoinType::CoffeeStamp),
+            },
+        },
+        "textModulesData": [
+            {
+                "id": "account_bottle_stamps",
+                "header": "Bottle stamps",
+                "body": get(CoinType::BottleStamp).to_string(),
+            },
+        ],
+        "barcode": {
+            "type": "QR_CODE",
+            "value": wallet_pass.qr_code,
+        },
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct ServiceAccountKeyFile {
+    client_email: String,
+    private_key: String,
+}
+
+/// Key of the google cloud service account that is allowed to issue wallet objects
+pub struct ServiceAccountKey {
+    client_email: String,
+    private_key: PKey<Private>,
+}
+
+impl ServiceAccountKey {
+    /// Load the json key file as downloaded from the google cloud console
+    pub fn load() -> ServiceResult<Self> {
+        let content = fs::read_to_string(env::GOOGLE_WALLET_SERVICE_ACCOUNT_KEY.as_str())?;
+        Self::from_json(&content)
+    }
+
+    pub fn from_json(content: &str) -> ServiceResult<Self> {
+        let key: ServiceAccountKeyFile = serde_json::from_str(content)
+            .map_err(|e| ServiceError::InternalServerError(e.to_string()))?;
+
+        Ok(Self {
+            client_email: key.client_email,
+            private_key: PKey::private_key_from_pem(key.private_key.as_bytes())?,
+        })
+    }
+
+    /// Encode and sign the claims as RS256 json web token
+    pub fn sign_jwt(&self, claims: &Value) -> ServiceResult<String> {
+        let encode = |value: &Value| URL_SAFE_NO_PAD.encode(value.to_string());
+
+        let mut jwt = format!(
+            "{}.{}",
+            encode(&json!({ "alg": "RS256", "typ": "JWT" })),
+            encode(claims)
+        );
+
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.private_key)?;
+        let signature = signer.sign_oneshot_to_vec(jwt.as_bytes())?;
+
+        jwt.push('.');
+        jwt.push_str(&URL_SAFE_NO_PAD.encode(signature));
+        Ok(jwt)
+    }
+}
+
+/// Create a "save to google wallet" link that contains the complete loyalty object
+pub fn create_save_link(key: &ServiceAccountKey, object: Value) -> ServiceResult<String> {
+    let claims = json!({
+        "iss": key.client_email,
+        "aud": "google",
+        "typ": "savetowallet",
+        "iat": get_current_time(),
+        "origins": [env::DOMAIN_NAME.as_str()],
+        "payload": {
+            "loyaltyObjects": [object],
+        },
+    });
+
+    Ok(format!("{}{}", SAVE_URL, key.sign_jwt(&claims)?))
+}
+
+/// Access to the google wallet api, can be replaced by a stub for local testing
+pub trait GoogleWalletApi {
+    /// Replace the loyalty object, returns `false` if the user never saved the object to a wallet
+    async fn update_loyalty_object(&self, object: &Value) -> ServiceResult<bool>;
+}
+
+#[derive(Debug, Deserialize)]
+struct AccessTokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+struct AccessToken {
+    token: String,
+    expires_at: u64,
+}
+
+lazy_static::lazy_static! {
+    static ref GOOGLE_WALLET: OnceCell<GoogleWalletClient> = OnceCell::new();
+}
+
+/// The client is created once, the service account key is only read on first use
+pub async fn get_client() -> ServiceResult<&'static GoogleWalletClient> {
+    GOOGLE_WALLET
+        .get_or_try_init(|| async { GoogleWalletClient::new() })
+        .await
+}
+
+pub struct GoogleWalletClient {
+    client: Client,
+    key: ServiceAccountKey,
+    access_token: Mutex<Option<AccessToken>>,
+}
+
+impl GoogleWalletClient {
+    pub fn new() -> ServiceResult<Self> {
+        Ok(Self {
+            client: Client::new(),
+            key: ServiceAccountKey::load()?,
+            access_token: Mutex::new(None),
+        })
+    }
+
+    /// Oauth access token of the service account, a new one is requested shortly before the cached one expires
+    async fn get_access_token(&self) -> ServiceResult<String> {
+        let mut access_token = self.access_token.lock().await;
+        let now = get_current_time();
+        if let Some(ref cached) = *access_token {
+            if cached.expires_at > now + ACCESS_TOKEN_MARGIN_SECONDS {
+                return Ok(cached.token.clone());
+            }
+        }
+
+        let token = self.request_access_token().await?;
+        *access_token = Some(AccessToken {
+            token: token.access_token.clone(),
+            expires_at: now + token.expires_in,
+        });
+        Ok(token.access_token)
+    }
+
+    /// Exchange a signed assertion of the service account for an oauth access token
+    async fn request_access_token(&self) -> ServiceResult<AccessTokenResponse> {
+        let now = get_current_time();
+        let assertion = self.key.sign_jwt(&json!({
+            "iss": self.key.client_email,
+            "scope": TOKEN_SCOPE,
+            "aud": TOKEN_URL,
+            "iat": now,
+            "exp": now + 3600,
+        }))?;
+
+        let response = self
+            .client
+            .post(TOKEN_URL)
+            .form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", assertion.as_str()),
+            ])
+            .send()
+            .await?
+            .error_for_status()?;
+
+        serde_json::from_str(&response.text().await?)
+            .map_err(|e| ServiceError::InternalServerError(e.to_string()))
+    }
+}
+
+impl GoogleWalletApi for GoogleWalletClient {
+    async fn update_loyalty_object(&self, object: &Value) -> ServiceResult<bool> {
+        let object_id = object["id"]
+            .as_str()
+            .filter(|id| !id.is_empty())
+            .ok_or_else(|| {
+                ServiceError::InternalServerError(String::from("Loyalty object without id"))
+            })?;
+        let path = format!(
+            "{}loyaltyObject/{}",
+            env::GOOGLE_WALLET_API_URL.as_str(),
+            object_id
+        );
+
+        let response = self
+            .client
+            .put(&path)
+            .bearer_auth(self.get_access_token().await?)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(object.to_string())
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(false),
+            status if status.is_success() => Ok(true),
+            status => Err(ServiceError::InternalServerError(format!(
+                "Google wallet api returned illegal status code: {}",
+                status
+            ))),
+        }
+    }
+}
+
+/// Push the current state of the account to its loyalty object. Objects of merged accounts keep their original id.
+///
+/// Returns `false` if the object was not saved to a wallet yet.
+pub async fn update_account_object(
+    api: &impl GoogleWalletApi,
+    object_id: &str,
+    account: &Account,
+    wallet_pass: &AppleWalletPass,
+) -> ServiceResult<bool> {
+    let mut object = create_loyalty_object(account, wallet_pass);
+    object["id"] = json!(object_id);
+    let saved = api.update_loyalty_object(&object).await?;
+    if !saved {
+        info!(
+            "Google wallet object of account {:?} was not saved yet",
+            account.id
+        );
+    }
+    Ok(saved)
+}
+
+/// Shared client that is created on first use, used by the push queue worker
+pub struct SharedGoogleWalletClient;
+
+impl GoogleWalletApi for SharedGoogleWalletClient {
+    async fn update_loyalty_object(&self, object: &Value) -> ServiceResult<bool> {
+        get_client().await?.update_loyalty_object(object).await
+    }
+}
+
+pub async fn send_update(
+    db: &mut DatabaseConnection,
+    api: &impl GoogleWalletApi,
+    account_id: u64,
+) -> ServiceResult<()> {
+    let Some(wallet_object) = db.get_google_wallet_object(account_id).await? else {
+        return Ok(());
+    };
+
+    let account = db.get_account_by_id(account_id).await?;
+    let wallet_pass = db
+        .get_apple_wallet_pass(account_id, &env::APPLE_WALLET_PASS_TYPE_IDENTIFIER)
+        .await?;
+
+    if let (Some(account), Some(wallet_pass)) = (account, wallet_pass) {
+        info!("Send google wallet update for account: {:?}", account_id);
+        if update_account_object(api, &wallet_object.object_id, &account, &wallet_pass).await? {
+            db.confirm_apple_wallet_pass_qr_code(
+                account_id,
+                &wallet_pass.pass_type_id,
+                &wallet_pass.qr_code,
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use openssl::rsa::Rsa;
+    use openssl::sign::Verifier;
+
+    use crate::models::{CoinAmount, Role};
+
+    use super::*;
+
+    struct StubGoogleWalletApi {
+        objects: std::sync::Mutex<Vec<Value>>,
+    }
+
+    impl GoogleWalletApi for StubGoogleWalletApi {
+        async fn update_loyalty_object(&self, object: &Value) -> ServiceResult<bool> {
+            self.objects.lock().unwrap().push(object.clone());
+            Ok(true)
+        }
+    }
+
+    fn test_account() -> (Account, AppleWalletPass) {
+        let account = Account {
+            id: 42,
+            balance: CoinAmount(HashMap::from([
+                (CoinType::Cent, 1250),
+                (CoinType::CoffeeStamp, 3),
+            ])),
+            name: "Max".to_string(),
+            email: String::new(),
+            role: Role {
+                id: 1,
+                name: "Basic".to_string(),
+                permissions: Vec::new(),
+            },
+            auth_methods: Vec::new(),
+            enable_monthly_mail_report: false,
+            enable_automatic_stamp_usage: true,
+            status: None,
+            archived_at: None,
+        };
+        let wallet_pass = AppleWalletPass {
+            account_id: 42,
+            pass_type_id: "pass.test".to_string(),
+            authentication_token: String::new(),
+            qr_code: "qr".to_string(),
+            updated_at: 0,
+        };
+        (account, wallet_pass)
+    }
+
+    fn test_key(rsa: &Rsa<Private>) -> ServiceAccountKey {
+        let pem = String::from_utf8(rsa.private_key_to_pem().unwrap()).unwrap();
+        ServiceAccountKey::from_json(
+            &json!({ "client_email": "wallet@example.com", "private_key": pem }).to_string(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_sign_jwt() {
+        let rsa = Rsa::generate(2048).unwrap();
+        let key = test_key(&rsa);
+
+        let jwt = key.sign_jwt(&json!({ "aud": "google" })).unwrap();
+        let parts: Vec<&str> = jwt.split('.').collect();
+        assert_eq!(parts.len(), 3);
+
+        let claims: Value =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(parts[1]).unwrap()).unwrap();
+        assert_eq!(claims, json!({ "aud": "google" }));
+
+        let public_key = PKey::public_key_from_pem(&rsa.public_key_to_pem().unwrap()).unwrap();
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &public_key).unwrap();
+        let signature = URL_SAFE_NO_PAD.decode(parts[2]).unwrap();
+        let message = format!("{}.{}", parts[0], parts[1]);
+        assert!(verifier
+            .verify_oneshot(&signature, message.as_bytes())
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_update_account_object() {
+        let (account, wallet_pass) = test_account();
+        let api = StubGoogleWalletApi {
+            objects: std::sync::Mutex::new(Vec::new()),
+        };
+
+        update_account_object(&api, "issuer.7", &account, &wallet_pass)
+            .await
+            .unwrap();
+
+        let objects = api.objects.lock().unwrap();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0]["id"], "issuer.7");
+        assert_eq!(objects[0]["accountId"], "42");
+        assert_eq!(
+            objects[0]["loyaltyPoints"]["balance"]["money"]["micros"],
+            12_500_000
+        );
+        assert_eq!(objects[0]["secondaryLoyaltyPoints"]["balance"]["int"], 3);
+        assert_eq!(objects[0]["textModulesData"][0]["body"], "0");
+        assert_eq!(objects[0]["barcode"]["value"], "qr");
+    }
+
+    #[tokio::test]
+    async fn test_update_loyalty_object_without_id() {
+        let client = GoogleWalletClient {
+            client: Client::new(),
+            key: test_key(&Rsa::generate(2048).unwrap()),
+            access_token: Mutex::new(Some(AccessToken {
+                token: "token".to_string(),
+                expires_at: u64::MAX,
+            })),
+        };
+
+        for object in [json!({}), json!({ "id": "" })] {
+            assert!(matches!(
+                client.update_loyalty_object(&object).await,
+                Err(ServiceError::InternalServerError(_))
+            ));
+        }
+        assert_eq!(client.get_access_token().await.unwrap(), "token");
+    }
+}
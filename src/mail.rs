@@ -11,7 +11,7 @@ use log::info;
 use crate::{
     env,
     error::{ServiceError, ServiceResult},
-    models::{Account, CoinType, Transaction},
+    models::{Account, CoinType, Invitation, Transaction},
 };
 
 pub async fn send_standard_mail(
@@ -19,7 +19,11 @@ pub async fn send_standard_mail(
     subj: &str,
     message: String,
 ) -> ServiceResult<()> {
-    if account.email.is_empty() {
+    send_mail(&account.name, &account.email, subj, message).await
+}
+
+async fn send_mail(name: &str, address: &str, subj: &str, message: String) -> ServiceResult<()> {
+    if address.is_empty() {
         return Err(ServiceError::InternalServerError(String::from(
             "A mail sending context was called, but no mail address was provided.",
         )));
@@ -28,8 +32,8 @@ pub async fn send_standard_mail(
     let email = Message::builder()
         // Addresses can be specified by the tuple (email, alias)
         .to(Mailbox::new(
-            Some(account.name.clone()),
-            Address::from_str(&account.email).unwrap(),
+            Some(name.to_owned()),
+            Address::from_str(address).unwrap(),
         ))
         .from(Mailbox::new(
             Some(env::MAIL_SENDER_NAME.clone()),
@@ -88,6 +92,60 @@ This mail has been automatically generated. Please do not reply.",
     .await
 }
 
+pub async fn send_account_invitation(invitation: &Invitation) -> ServiceResult<()> {
+    let timezone = FixedOffset::east_opt(60 * 60).unwrap();
+
+    let mail_text = format!("Hello {user},
+
+you have been invited to create an account in the ascii-pay system. You can use the following link to choose your username and password.
+Please note that your invitation will expire at {date}.
+
+{domain}/accept-invitation?token={token}
+
+The ascii-pay System
+
+----
+This mail has been automatically generated. Please do not reply.",
+        user = invitation.name,
+        date = invitation.valid_until.with_timezone(&timezone).format("%d.%m.%Y %H:%M"),
+        domain = env::DOMAIN_NAME.as_str(),
+        token = invitation.token);
+
+    send_mail(
+        &invitation.name,
+        &invitation.email,
+        "[ascii-pay] You have been invited to the ascii-pay service",
+        mail_text,
+    )
+    .await
+}
+
+pub async fn send_password_reset_link(
+    account: &Account,
+    token: &str,
+    valid_until: &DateTime<Utc>,
+) -> ServiceResult<()> {
+    let timezone = FixedOffset::east_opt(60 * 60).unwrap();
+
+    let mail_text = format!("Hello {user},
+
+a password reset was requested for your ascii-pay account. You can use the following link to set a new username and password.
+Please note that this link will expire at {date}. If you did not request a password reset, you can ignore this mail.
+
+{domain}/reset-password?token={token}
+
+The ascii-pay System
+
+----
+This mail has been automatically generated. Please do not reply.",
+        user = account.name,
+        date = valid_until.with_timezone(&timezone).format("%d.%m.%Y %H:%M"),
+        domain = env::DOMAIN_NAME.as_str(),
+        token = token);
+
+    send_standard_mail(account, "[ascii-pay] Password reset", mail_text).await
+}
+
 pub async fn send_monthly_report(
     account: &Account,
     transactions: &[Transaction],
@@ -131,3 +189,38 @@ This mail has been automatically generated. Please do not reply.",
 
     send_standard_mail(account, "[ascii-pay] Monthly report", mail_text).await
 }
+
+pub async fn send_dormant_account_notice(
+    account: &Account,
+    last_activity: Option<DateTime<Utc>>,
+) -> ServiceResult<()> {
+    let timezone = FixedOffset::east_opt(60 * 60).unwrap();
+
+    let last_activity = match last_activity {
+        Some(last_activity) => format!(
+            "since {}",
+            last_activity.with_timezone(&timezone).format("%d.%m.%Y")
+        ),
+        None => String::from("for a long time"),
+    };
+    let balance_cents = account.balance.0.get(&CoinType::Cent).copied().unwrap_or(0);
+
+    let mail_text = format!(
+        "Hello {user},
+
+your ascii-pay account has not been used {last_activity}.
+Your current balance is {balance: >7.2} €.
+
+If you no longer need the account, please contact the ascii team to have your balance paid out and the account closed.
+Otherwise it may be archived during the next cleanup.
+
+The ascii-pay System
+
+----
+This mail has been automatically generated. Please do not reply.",
+        user = account.name,
+        balance = f64::from(balance_cents) / 100.0,
+    );
+
+    send_standard_mail(account, "[ascii-pay] Inactive account", mail_text).await
+}
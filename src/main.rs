@@ -16,9 +16,13 @@ mod docs;
 pub mod env;
 mod error;
 mod models;
+mod nfc_secret;
 mod request_state;
+mod tasks;
 
 mod apns;
+mod google_wallet;
+mod push_queue;
 mod wallet;
 
 #[cfg(feature = "mail")]
@@ -34,7 +38,12 @@ async fn main() {
     });
     aide::gen::extract_schemas(true);
 
+    nfc_secret::check_master_keys();
+    request_state::check_trusted_proxies();
+    api::nfc_id::check_reader_key();
+
     let app_state = AppState::connect(env::DATABASE_URL.as_str()).await;
+    tasks::spawn_background_tasks(app_state.clone());
     let mut api = OpenApi::default();
 
     let app = ApiRouter::new()
@@ -57,10 +66,13 @@ async fn main() {
     info!("listening on {}", addr);
 
     let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-    axum::serve(listener, app.into_make_service())
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .unwrap();
 }
 
 async fn shutdown_signal() {
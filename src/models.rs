@@ -10,18 +10,71 @@ pub enum CoinType {
     BottleStamp,
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
-pub enum Role {
-    Basic,
-    Member,
-    Purchaser,
-    Admin,
+#[derive(Debug, PartialEq, Hash, Eq, Clone, Copy)]
+pub enum Permission {
+    AccountsRead,
+    AccountsWrite,
+    RolesWrite,
+    PaymentsWrite,
+    PaymentsCredit,
+    ProductsWrite,
+    PurchasesWrite,
+    RegisterWrite,
+    ReportsSend,
+    NfcReadersWrite,
+    AuditLogRead,
+    VouchersWrite,
+    AccountsImpersonate,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 13] = [
+        Permission::AccountsRead,
+        Permission::AccountsWrite,
+        Permission::RolesWrite,
+        Permission::PaymentsWrite,
+        Permission::PaymentsCredit,
+        Permission::ProductsWrite,
+        Permission::PurchasesWrite,
+        Permission::RegisterWrite,
+        Permission::ReportsSend,
+        Permission::NfcReadersWrite,
+        Permission::AuditLogRead,
+        Permission::VouchersWrite,
+        Permission::AccountsImpersonate,
+    ];
+}
+
+/// A named set of permissions, every account is assigned exactly one role
+#[derive(Debug, PartialEq, Clone)]
+pub struct Role {
+    pub id: u64,
+    pub name: String,
+    pub permissions: Vec<Permission>,
+}
+
+impl Role {
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+    }
+
+    pub fn has_all_permissions(&self) -> bool {
+        Permission::ALL.iter().all(|p| self.has_permission(*p))
+    }
+
+    /// Every permission of this role is also granted by `other`
+    pub fn is_subset_of(&self, other: &Role) -> bool {
+        self.permissions.iter().all(|p| other.has_permission(*p))
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum CardType {
     GenericNfc,
+    /// Mifare DESFire with legacy 2K3DES authentication
     AsciiMifare,
+    /// Mifare DESFire EV2 with AES-128 `AuthenticateEV2First`
+    AsciiMifareAes,
     HostCardEmulation,
 }
 
@@ -31,13 +84,82 @@ pub struct AuthPassword {
     pub password_hash: Vec<u8>,
 }
 
+/// Secret key material of a nfc card, see `nfc_secret` for encryption
+#[derive(Debug, PartialEq, Clone)]
+pub enum NfcSecret {
+    /// Unencrypted key that was stored before encryption was introduced
+    Plain(Vec<u8>),
+    /// Key encrypted with the master key identified by `key_id`
+    Sealed { key_id: String, data: Vec<u8> },
+}
+
+/// A registered nfc terminal with its own key for generic nfc cards
+#[derive(Debug, PartialEq, Clone)]
+pub struct NfcReader {
+    pub id: u64,
+    pub name: String,
+    pub key: NfcSecret,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum NfcCardStatus {
+    Active,
+    Blocked,
+    Lost,
+    Expired,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct AuthNfc {
     pub name: String,
     pub card_id: Vec<u8>,
     pub card_type: CardType,
-    pub data: Vec<u8>,
+    pub data: NfcSecret,
     pub depends_on_session: Option<String>,
+    pub status: NfcCardStatus,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub valid_until: Option<DateTime<Utc>>,
+}
+
+impl AuthNfc {
+    /// The stored status, active cards are reported as expired after `valid_until`
+    pub fn current_status(&self) -> NfcCardStatus {
+        match (self.status, self.valid_until) {
+            (NfcCardStatus::Active, Some(valid_until)) if valid_until < Utc::now() => {
+                NfcCardStatus::Expired
+            }
+            (status, _) => status,
+        }
+    }
+}
+
+/// A card key change that has been started but is not confirmed by the terminal yet
+#[derive(Debug, PartialEq, Clone)]
+pub struct NfcProvisioning {
+    pub id: String,
+    pub account_id: u64,
+    pub name: String,
+    pub card_id: Vec<u8>,
+    /// The new key generated by the server
+    pub key: NfcSecret,
+    /// The card that is replaced on confirmation, equals `card_id` when re-keying a card
+    pub replaces_card_id: Option<Vec<u8>>,
+    pub key_change_issued: bool,
+    pub valid_until: DateTime<Utc>,
+}
+
+/// A pending account, the account is only created once the invitation is accepted
+#[derive(Debug, PartialEq, Clone)]
+pub struct Invitation {
+    pub id: u64,
+    pub token: String,
+    pub name: String,
+    pub email: String,
+    pub role: Role,
+    pub status: Option<AccountStatus>,
+    pub invited_by_account_id: Option<u64>,
+    pub created_at: DateTime<Utc>,
+    pub valid_until: DateTime<Utc>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -107,6 +229,52 @@ pub struct Account {
     pub enable_monthly_mail_report: bool,
     pub enable_automatic_stamp_usage: bool,
     pub status: Option<AccountStatus>,
+    /// Set once the account was closed, archived accounts keep their transactions but can no longer log in
+    pub archived_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SpendingLimitPeriod {
+    Day,
+    Week,
+    Month,
+}
+
+/// Maximum amount an account may spend within a rolling period, coin types without a value are not limited
+#[derive(Debug, PartialEq, Clone)]
+pub struct SpendingLimit {
+    pub period: SpendingLimitPeriod,
+    pub limit: CoinAmount,
+}
+
+/// Restrictions an account applies to its own payments
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SpendingPolicy {
+    pub limits: Vec<SpendingLimit>,
+    /// Products with one of these tags cannot be bought
+    pub blocked_product_tags: Vec<String>,
+    /// Locked policies can only be changed by admins, eg. for guest accounts
+    pub locked: bool,
+}
+
+/// Membership of an account in a group account, members may pay from the group account
+#[derive(Debug, PartialEq, Clone)]
+pub struct AccountGroupMember {
+    pub group_account_id: u64,
+    pub member_account_id: u64,
+    pub member_name: String,
+    /// Group admins can manage the members of the group
+    pub is_admin: bool,
+    /// Maximum amount of cents the member may spend from the group account within 30 days
+    pub spending_limit_cents: Option<i32>,
+}
+
+/// Account without transactions or sessions for a longer period
+#[derive(Debug, PartialEq, Clone)]
+pub struct DormantAccount {
+    pub account: Account,
+    /// Latest transaction or session use, `None` if the account was never used
+    pub last_activity: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -187,6 +355,8 @@ pub struct Transaction {
     pub account: u64,
     pub authorized_by_account_id: Option<u64>,
     pub authorized_with_method: Option<AuthMethodType>,
+    /// Counterpart of a transfer between two accounts
+    pub linked_transaction_id: Option<u64>,
     pub items: Vec<TransactionItem>,
 }
 
@@ -209,15 +379,94 @@ pub enum AuthMethodType {
     NfcBased,
     PublicTab,
     PasswordResetToken,
+    /// Session of an admin acting on behalf of the account
+    Impersonation,
+    /// Login by scanning a qr code, eg. of a guest voucher
+    QrCode,
+}
+
+/// Defines when a session expires
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SessionLifetime {
+    /// The session expires at the given point in time
+    Fixed(DateTime<Utc>),
+    /// The session expires if it is not used for the given duration
+    Sliding(chrono::Duration),
+}
+
+/// Client information that is recorded when a session is created
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SessionClient {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub device_name: Option<String>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Session {
+    /// Public identifier of the session, can be shown to other clients (unlike the `token`)
+    pub id: String,
     pub account: Account,
     pub token: String,
     pub auth_method: AuthMethodType,
     pub valid_until: DateTime<Utc>,
     pub is_single_use: bool,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+    pub client: SessionClient,
+    /// Set for payment tokens, the session may only be used for a single payment up to this amount
+    pub payment_limit: Option<CoinAmount>,
+    /// The admin account that acts on behalf of `account`
+    pub impersonated_by: Option<u64>,
+}
+
+impl Session {
+    pub fn is_payment_only(&self) -> bool {
+        self.payment_limit.is_some()
+    }
+}
+
+/// Append-only record of an administrative or security relevant change
+#[derive(Debug, PartialEq, Clone)]
+pub struct AuditLogEntry {
+    pub id: u64,
+    pub timestamp: DateTime<Utc>,
+    /// `None` for changes without login, e.g. a password reset
+    pub actor_account_id: Option<u64>,
+    /// Set if the actor used an impersonation session
+    pub impersonated_account_id: Option<u64>,
+    pub action: String,
+    pub target_type: String,
+    pub target_id: Option<u64>,
+    /// Only the changed fields of the target before the change
+    pub before: Option<serde_json::Value>,
+    /// Only the changed fields of the target after the change
+    pub after: Option<serde_json::Value>,
+}
+
+/// Filter for the audit log query, unset fields match every entry
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct AuditLogFilter {
+    pub actor_account_id: Option<u64>,
+    pub action: Option<String>,
+    pub target_type: Option<String>,
+    pub target_id: Option<u64>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub limit: u64,
+}
+
+/// Every request of an impersonation session is recorded in the impersonation log
+#[derive(Debug, PartialEq, Clone)]
+pub struct ImpersonationLogEntry {
+    pub id: u64,
+    pub admin_account_id: u64,
+    pub account_id: u64,
+    /// Public id of the impersonation session
+    pub session_id: String,
+    pub action: String,
+    pub ip_address: Option<String>,
+    pub timestamp: DateTime<Utc>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -246,6 +495,44 @@ pub struct RegisterHistoryState {
     pub note5: i32,
 }
 
+/// Cash handed out of the register when an account is closed
+#[derive(Debug, PartialEq, Clone)]
+pub struct RegisterPayout {
+    pub id: u64,
+    pub timestamp: DateTime<Utc>,
+    pub account_id: Option<u64>,
+    pub transaction_id: u64,
+    /// Negative if the account owner paid a debt into the register
+    pub amount_cents: i32,
+    pub paid_by_account_id: Option<u64>,
+}
+
+/// A set of voucher codes with the same value and expiry
+#[derive(Debug, PartialEq, Clone)]
+pub struct VoucherBatch {
+    pub id: u64,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub created_by_account_id: Option<u64>,
+    pub valid_until: DateTime<Utc>,
+    /// Value of every single voucher in this batch
+    pub value: CoinAmount,
+    pub voucher_count: u64,
+    pub used_count: u64,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Voucher {
+    pub id: u64,
+    pub batch_id: u64,
+    pub code: String,
+    pub used_at: Option<DateTime<Utc>>,
+    /// The account the voucher was redeemed into or the guest account that was created for it
+    pub account_id: Option<u64>,
+    /// The code is the login of a guest account
+    pub is_guest: bool,
+}
+
 /// Represent a wallet pass
 #[derive(Debug, PartialEq, Clone)]
 pub struct AppleWalletPass {
@@ -265,6 +552,40 @@ pub struct AppleWalletRegistration {
     pub push_token: String,
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PushJobKind {
+    /// Empty APNS notification to a device that registered the apple wallet pass
+    AppleWallet,
+    /// Update of the google wallet loyalty object, `device_id` is the object id
+    GoogleWallet,
+}
+
+/// Pending wallet update notification for a single device
+#[derive(Debug, PartialEq, Clone)]
+pub struct PushJob {
+    pub id: u64,
+    pub kind: PushJobKind,
+    pub account_id: u64,
+    pub pass_type_id: String,
+    pub device_id: String,
+    pub push_token: String,
+    /// Number of failed deliveries
+    pub attempts: u32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// Set once the job exceeded its retries, dead jobs are kept for inspection but not retried
+    pub dead_at: Option<DateTime<Utc>>,
+}
+
+/// Google wallet loyalty object that was offered to the account by a save link
+#[derive(Debug, PartialEq, Clone)]
+pub struct GoogleWalletObject {
+    pub account_id: u64,
+    pub object_id: String,
+    pub created_at: DateTime<Utc>,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Purchase {
     pub id: u64,
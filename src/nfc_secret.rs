@@ -0,0 +1,232 @@
+//! Encryption of nfc card keys at rest.
+//!
+//! Card keys are sealed with AES-256-GCM under the master key from `NFC_MASTER_KEY`.
+//! Keys from `NFC_PREVIOUS_MASTER_KEYS` are only used to open secrets that were sealed
+//! before a key rotation, the re-encryption job reseals them with the current key.
+//! In dev mode without `NFC_MASTER_KEY` the key is derived from the database url.
+use log::warn;
+use openssl::sha::sha256;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use rand::RngCore;
+
+use crate::env;
+use crate::error::{ServiceError, ServiceResult};
+use crate::models::NfcSecret;
+
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+
+struct MasterKey {
+    id: String,
+    key: Vec<u8>,
+}
+
+impl MasterKey {
+    /// Panics with the name of the setting if the key is not valid, the key itself is never printed
+    fn parse(setting: &str, hex: &str) -> Self {
+        let key = match hex_to_bytes(hex.trim()) {
+            Some(key) if key.len() == 32 => key,
+            _ => panic!("{setting} must be a 32 byte key given as 64 hex chars!"),
+        };
+        Self::new(key)
+    }
+
+    /// Stable key for local development, so that sealed secrets survive restarts of the dev server
+    fn derive_dev_key() -> Self {
+        let seed = format!(
+            "ascii-pay dev nfc master key {}",
+            env::DATABASE_URL.as_str()
+        );
+        Self::new(sha256(seed.as_bytes()).to_vec())
+    }
+
+    fn new(key: Vec<u8>) -> Self {
+        // The id only identifies the key, it must not leak the key itself
+        let id = sha256(&key)[0..8]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+
+        MasterKey { id, key }
+    }
+}
+
+struct MasterKeyRing {
+    current: MasterKey,
+    previous: Vec<MasterKey>,
+}
+
+lazy_static::lazy_static! {
+    static ref MASTER_KEYS: MasterKeyRing = MasterKeyRing {
+        current: match env::NFC_MASTER_KEY.as_deref() {
+            Some(key) => MasterKey::parse("NFC_MASTER_KEY", key),
+            None => MasterKey::derive_dev_key(),
+        },
+        previous: env::NFC_PREVIOUS_MASTER_KEYS
+            .split(',')
+            .filter(|key| !key.trim().is_empty())
+            .map(|key| MasterKey::parse("NFC_PREVIOUS_MASTER_KEYS", key))
+            .collect(),
+    };
+}
+
+/// Returns `None` unless the string consists of pairs of hex digits
+fn hex_to_bytes(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let digits = std::str::from_utf8(pair).ok()?;
+            u8::from_str_radix(digits, 16).ok()
+        })
+        .collect()
+}
+
+/// Validate the configured master keys, should be called once at startup
+pub fn check_master_keys() {
+    if env::NFC_MASTER_KEY.is_none() {
+        if !*env::DEV_MODE {
+            panic!("NFC_MASTER_KEY must be set outside of dev mode!");
+        }
+        warn!("NFC_MASTER_KEY is not set, nfc card keys are encrypted with a key derived from DATABASE_URL!");
+    }
+    lazy_static::initialize(&MASTER_KEYS);
+}
+
+fn seal_with(key: &MasterKey, plain: &[u8]) -> ServiceResult<NfcSecret> {
+    let mut nonce = [0u8; NONCE_LENGTH];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let mut tag = [0u8; TAG_LENGTH];
+    let ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        &key.key,
+        Some(&nonce),
+        key.id.as_bytes(),
+        plain,
+        &mut tag,
+    )?;
+
+    let mut data = Vec::with_capacity(NONCE_LENGTH + ciphertext.len() + TAG_LENGTH);
+    data.extend(nonce);
+    data.extend(ciphertext);
+    data.extend(tag);
+
+    Ok(NfcSecret::Sealed {
+        key_id: key.id.clone(),
+        data,
+    })
+}
+
+impl NfcSecret {
+    /// Encrypt the given card key with the current master key
+    pub fn seal(plain: &[u8]) -> ServiceResult<Self> {
+        seal_with(&MASTER_KEYS.current, plain)
+    }
+
+    /// Decrypt the card key. Only the nfc authentication modules should need this.
+    pub fn open(&self) -> ServiceResult<Vec<u8>> {
+        match self {
+            NfcSecret::Plain(plain) => Ok(plain.clone()),
+            NfcSecret::Sealed { key_id, data } => {
+                let key = std::iter::once(&MASTER_KEYS.current)
+                    .chain(MASTER_KEYS.previous.iter())
+                    .find(|key| &key.id == key_id)
+                    .ok_or_else(|| {
+                        ServiceError::InternalServerError(format!(
+                            "Unknown nfc master key '{key_id}'"
+                        ))
+                    })?;
+
+                if data.len() < NONCE_LENGTH + TAG_LENGTH {
+                    return Err(ServiceError::InternalServerError(
+                        "Invalid sealed nfc secret".to_string(),
+                    ));
+                }
+                let (nonce, rest) = data.split_at(NONCE_LENGTH);
+                let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LENGTH);
+
+                Ok(decrypt_aead(
+                    Cipher::aes_256_gcm(),
+                    &key.key,
+                    Some(nonce),
+                    key.id.as_bytes(),
+                    ciphertext,
+                    tag,
+                )?)
+            }
+        }
+    }
+
+    /// `true` if the secret is not encrypted with the current master key
+    pub fn needs_reseal(&self) -> bool {
+        match self {
+            NfcSecret::Plain(_) => true,
+            NfcSecret::Sealed { key_id, .. } => key_id != &MASTER_KEYS.current.id,
+        }
+    }
+
+    /// Re-encrypt the secret with the current master key
+    pub fn reseal(&self) -> ServiceResult<Self> {
+        let plain = match self {
+            NfcSecret::Plain(plain) => plain.clone(),
+            NfcSecret::Sealed { .. } => self.open()?,
+        };
+        Self::seal(&plain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_and_open() {
+        let secret = NfcSecret::seal(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        assert!(!secret.needs_reseal());
+        assert_eq!(secret.open().unwrap(), vec![1, 2, 3, 4, 5, 6, 7, 8]);
+
+        let NfcSecret::Sealed { key_id, mut data } = secret else {
+            panic!("secret should be sealed");
+        };
+        assert!(!data.windows(8).any(|w| w == [1, 2, 3, 4, 5, 6, 7, 8]));
+
+        // tampered data is rejected
+        data[NONCE_LENGTH] ^= 1;
+        assert!(NfcSecret::Sealed { key_id, data }.open().is_err());
+    }
+
+    #[test]
+    fn test_reseal_rotated_key() {
+        let old_key = MasterKey::parse("NFC_PREVIOUS_MASTER_KEYS", &"11".repeat(32));
+        let secret = seal_with(&old_key, &[42; 16]).unwrap();
+        assert!(secret.needs_reseal());
+        // the old key is not configured as previous key
+        assert!(secret.open().is_err());
+
+        let plain = NfcSecret::Plain(vec![42; 16]);
+        assert!(plain.needs_reseal());
+        let resealed = plain.reseal().unwrap();
+        assert!(!resealed.needs_reseal());
+        assert_eq!(resealed.open().unwrap(), vec![42; 16]);
+    }
+
+    #[test]
+    fn test_hex_to_bytes() {
+        assert_eq!(hex_to_bytes("00ff7A"), Some(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(hex_to_bytes(""), Some(vec![]));
+        assert_eq!(hex_to_bytes("abc"), None);
+        assert_eq!(hex_to_bytes("zz"), None);
+        assert_eq!(hex_to_bytes("+1"), None);
+        assert_eq!(hex_to_bytes("äa"), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "NFC_MASTER_KEY must be a 32 byte key given as 64 hex chars!")]
+    fn test_parse_invalid_master_key() {
+        MasterKey::parse("NFC_MASTER_KEY", &"1".repeat(63));
+    }
+}
@@ -0,0 +1,137 @@
+//! Persistent queue for wallet update notifications.
+//!
+//! `wallet::send_update_notification` only stores a job per registered device and google wallet object, the
+//! background worker delivers them with shared clients and retries failed deliveries with exponential backoff.
+use chrono::{Duration, Utc};
+use log::{error, info, warn};
+use tokio::sync::{Notify, OnceCell};
+
+use crate::apns::ApplePushNotificationService;
+use crate::database::DatabaseConnection;
+use crate::error::ServiceResult;
+use crate::google_wallet::{self, GoogleWalletApi};
+use crate::models::{PushJob, PushJobKind};
+
+/// Jobs that are delivered per worker run
+pub const PUSH_BATCH_SIZE: u32 = 50;
+/// Failed deliveries after which a job is moved to the dead letters
+const PUSH_MAX_ATTEMPTS: u32 = 8;
+const PUSH_RETRY_BASE_SECONDS: i64 = 30;
+const PUSH_RETRY_MAX_SECONDS: i64 = 60 * 60;
+/// Claimed jobs are picked up again if the worker did not resolve them within this time
+const PUSH_LEASE_SECONDS: i64 = 5 * 60;
+
+/// Delivery of a single push notification, can be replaced by a stub for local testing
+pub trait PushService {
+    /// Send an empty notification to the device, returns the http status code of the response
+    async fn send(&self, push_token: &str) -> ServiceResult<u16>;
+}
+
+lazy_static::lazy_static! {
+    static ref JOBS_QUEUED: Notify = Notify::new();
+    static ref APNS: OnceCell<ApplePushNotificationService> = OnceCell::new();
+}
+
+/// Wake the worker to deliver new jobs without waiting for the next interval
+pub fn notify_worker() {
+    JOBS_QUEUED.notify_one();
+}
+
+pub async fn wait_for_jobs() {
+    JOBS_QUEUED.notified().await;
+}
+
+/// The APNS client is created once, certificates are only read on first use
+pub async fn get_apns() -> ServiceResult<&'static ApplePushNotificationService> {
+    APNS.get_or_try_init(|| async { ApplePushNotificationService::new() })
+        .await
+}
+
+fn retry_delay(attempts: u32) -> Duration {
+    let seconds = PUSH_RETRY_BASE_SECONDS.saturating_mul(1 << attempts.saturating_sub(1).min(16));
+    Duration::seconds(seconds.min(PUSH_RETRY_MAX_SECONDS))
+}
+
+async fn deliver_apple_wallet_job(
+    db: &mut DatabaseConnection,
+    apns: &impl PushService,
+    job: &PushJob,
+) -> Option<String> {
+    match apns.send(&job.push_token).await {
+        Ok(200) => None,
+        Ok(410) => {
+            info!(
+                "Unregister device {} of account {:?} as APNS requested",
+                job.device_id, job.account_id
+            );
+            if let Err(e) = db
+                .delete_apple_wallet_registration(job.account_id, &job.pass_type_id, &job.device_id)
+                .await
+            {
+                warn!("Could not unregister device {}: {:?}", job.device_id, e);
+            }
+            None
+        }
+        Ok(status) => Some(format!("APNS returned illegal status code: {}", status)),
+        Err(e) => Some(format!("Error while communicating with APNS: {:?}", e)),
+    }
+}
+
+/// Deliver due jobs, returns the number of jobs that were processed
+pub async fn process_push_jobs(
+    db: &mut DatabaseConnection,
+    apns: &impl PushService,
+    google: &impl GoogleWalletApi,
+) -> ServiceResult<usize> {
+    let jobs = db
+        .claim_push_jobs(PUSH_BATCH_SIZE, Duration::seconds(PUSH_LEASE_SECONDS))
+        .await?;
+
+    for job in jobs.iter() {
+        let error = match job.kind {
+            PushJobKind::AppleWallet => deliver_apple_wallet_job(db, apns, job).await,
+            PushJobKind::GoogleWallet => google_wallet::send_update(db, google, job.account_id)
+                .await
+                .err()
+                .map(|e| format!("Error while updating google wallet object: {:?}", e)),
+        };
+
+        let Some(error) = error else {
+            db.delete_push_job(job.id).await?;
+            continue;
+        };
+
+        let attempts = job.attempts + 1;
+        if attempts >= PUSH_MAX_ATTEMPTS {
+            error!(
+                "Giving up push job {} for account {:?} after {} attempts: {}",
+                job.id, job.account_id, attempts, error
+            );
+            db.fail_push_job(job.id, attempts, &error, None).await?;
+        } else {
+            warn!(
+                "Push job {} for account {:?} failed, retrying: {}",
+                job.id, job.account_id, error
+            );
+            let next_attempt_at = Utc::now() + retry_delay(attempts);
+            db.fail_push_job(job.id, attempts, &error, Some(next_attempt_at))
+                .await?;
+        }
+    }
+
+    Ok(jobs.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1), Duration::seconds(30));
+        assert_eq!(retry_delay(2), Duration::seconds(60));
+        assert_eq!(retry_delay(4), Duration::seconds(240));
+        assert_eq!(retry_delay(8), Duration::seconds(60 * 60));
+        assert_eq!(retry_delay(100), Duration::seconds(60 * 60));
+    }
+}
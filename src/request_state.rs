@@ -1,17 +1,17 @@
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::net::{IpAddr, SocketAddr};
 
 use aide::OperationInput;
-use axum::extract::{FromRef, FromRequestParts, Query};
+use axum::extract::{ConnectInfo, FromRef, FromRequestParts, Query};
+use axum::http::header;
 use axum::http::request::Parts;
 use axum::{async_trait, RequestPartsExt};
 use axum_extra::TypedHeader;
 use headers::authorization::Bearer;
 use headers::Authorization;
 use serde::Deserialize;
-use tokio::sync::Mutex;
 
-use crate::database::{AppState, AppStateNfcChallenge, DatabaseConnection};
+use crate::database::{AppState, DatabaseConnection, NfcChallengeStorage};
+use crate::env;
 use crate::error::{ServiceError, ServiceResult};
 use crate::models::{self, Session};
 
@@ -20,7 +20,8 @@ use crate::models::{self, Session};
 pub struct RequestState {
     pub db: DatabaseConnection,
     pub session: Option<Session>,
-    pub challenge_storage: Arc<Mutex<HashMap<u64, AppStateNfcChallenge>>>,
+    pub client: models::SessionClient,
+    pub challenge_storage: NfcChallengeStorage,
 }
 
 #[derive(Debug, Deserialize)]
@@ -41,6 +42,77 @@ async fn get_session_token(parts: &mut Parts) -> Option<String> {
     None
 }
 
+fn get_header(parts: &Parts, name: &str) -> Option<String> {
+    parts
+        .headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_owned())
+}
+
+lazy_static::lazy_static! {
+    static ref TRUSTED_PROXIES: Vec<IpAddr> = env::TRUSTED_PROXIES
+        .split(',')
+        .map(|ip| ip.trim())
+        .filter(|ip| !ip.is_empty())
+        .map(|ip| {
+            ip.parse()
+                .unwrap_or_else(|_| panic!("TRUSTED_PROXIES contains an invalid ip address: '{ip}'"))
+        })
+        .collect();
+}
+
+/// Validate the configured proxy addresses, should be called once at startup
+pub fn check_trusted_proxies() {
+    lazy_static::initialize(&TRUSTED_PROXIES);
+}
+
+/// Forwarding headers are only honored if the request comes from a trusted proxy,
+/// otherwise every client could choose its own address.
+fn resolve_client_ip(
+    peer: Option<IpAddr>,
+    forwarded_for: Option<&str>,
+    real_ip: Option<&str>,
+    trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+    let peer = peer?;
+    if !trusted_proxies.contains(&peer) {
+        return Some(peer);
+    }
+
+    // Each proxy appends the address it received the request from, the first untrusted one from the right is the client
+    if let Some(forwarded_for) = forwarded_for {
+        let mut chain = forwarded_for
+            .split(',')
+            .map(|ip| ip.trim().parse::<IpAddr>())
+            .rev();
+        let client = chain.find(|ip| !matches!(ip, Ok(ip) if trusted_proxies.contains(ip)));
+        return Some(client.and_then(|ip| ip.ok()).unwrap_or(peer));
+    }
+
+    real_ip.and_then(|ip| ip.trim().parse().ok()).or(Some(peer))
+}
+
+fn get_session_client(parts: &Parts) -> models::SessionClient {
+    let peer = parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let ip_address = resolve_client_ip(
+        peer,
+        get_header(parts, "x-forwarded-for").as_deref(),
+        get_header(parts, "x-real-ip").as_deref(),
+        &TRUSTED_PROXIES,
+    )
+    .map(|ip| ip.to_string());
+
+    models::SessionClient {
+        user_agent: get_header(parts, header::USER_AGENT.as_str()),
+        ip_address,
+        device_name: None,
+    }
+}
+
 #[async_trait]
 impl<S> FromRequestParts<S> for RequestState
 where
@@ -59,16 +131,46 @@ where
             .map_err(|err| ServiceError::InternalServerError(err.to_string()))?;
         let mut db = DatabaseConnection { connection };
 
-        let session = if let Some(session_token) = get_session_token(parts).await {
+        let mut session = if let Some(session_token) = get_session_token(parts).await {
             db.get_session_by_session_token(session_token).await?
         } else {
             None
         };
 
+        // Single use sessions are consumed by the first request, concurrent requests lose the race
+        if let Some(ref s) = session {
+            if s.is_single_use && !db.consume_single_use_session(&s.token).await? {
+                session = None;
+            }
+        }
+
+        // Impersonation sessions only keep the permissions that the admin has as well
+        if let Some(ref mut s) = session {
+            if let Some(admin_id) = s.impersonated_by {
+                let admin_role = db
+                    .get_account_by_id(admin_id)
+                    .await?
+                    .map(|admin| admin.role);
+                s.account.role.permissions.retain(|permission| {
+                    admin_role
+                        .as_ref()
+                        .is_some_and(|role| role.has_permission(*permission))
+                });
+            }
+        }
+
+        let client = get_session_client(parts);
+
+        if let Some(ref s) = session {
+            let action = format!("{} {}", parts.method, parts.uri.path());
+            db.log_impersonation(s, &action, &client).await?;
+        }
+
         Ok(Self {
             db,
             session,
-            challenge_storage: state.ascii_mifare_challenge.clone(),
+            client,
+            challenge_storage: state.nfc_challenge_storage.clone(),
         })
     }
 }
@@ -80,19 +182,9 @@ impl RequestState {
         self.session.is_some()
     }
 
-    pub fn session_is_purchaser(&self) -> bool {
+    pub fn session_has_permission(&self, permission: models::Permission) -> bool {
         if let Some(ref session) = self.session {
-            if matches!(session.account.role, models::Role::Purchaser) {
-                return true;
-            }
-        }
-
-        false
-    }
-
-    pub fn session_is_admin(&self) -> bool {
-        if let Some(ref session) = self.session {
-            if matches!(session.account.role, models::Role::Admin) {
+            if !session.is_payment_only() && session.account.role.has_permission(permission) {
                 return true;
             }
         }
@@ -102,7 +194,7 @@ impl RequestState {
 
     pub fn session_is_self(&self, account_id: u64) -> bool {
         if let Some(ref session) = self.session {
-            if session.account.id == account_id {
+            if !session.is_payment_only() && session.account.id == account_id {
                 return true;
             }
         }
@@ -110,20 +202,27 @@ impl RequestState {
         false
     }
 
-    pub fn session_require_admin(&self) -> ServiceResult<models::Account> {
+    pub fn session_require_permission(
+        &self,
+        permission: models::Permission,
+    ) -> ServiceResult<models::Account> {
         let account = self.session_require_login()?;
 
-        if self.session_is_admin() {
+        if self.session_has_permission(permission) {
             return Ok(account);
         }
 
         Err(ServiceError::Forbidden)
     }
 
-    pub fn session_require_admin_or_self(&self, account_id: u64) -> ServiceResult<models::Account> {
+    pub fn session_require_permission_or_self(
+        &self,
+        permission: models::Permission,
+        account_id: u64,
+    ) -> ServiceResult<models::Account> {
         let account = self.session_require_login()?;
 
-        if self.session_is_admin() {
+        if self.session_has_permission(permission) {
             return Ok(account);
         }
 
@@ -134,28 +233,37 @@ impl RequestState {
         Err(ServiceError::Forbidden)
     }
 
-    pub fn session_require_purchaser_or_admin(&self) -> ServiceResult<models::Account> {
-        let account = self.session_require_login()?;
-
-        if self.session_is_purchaser() || self.session_is_admin() {
-            return Ok(account);
-        }
-
-        Err(ServiceError::Forbidden)
-    }
-
+    /// Payment tokens are only accepted by `session_require_payment`
     pub fn session_require_login(&self) -> ServiceResult<models::Account> {
         if !self.session_is_present() {
             return Err(ServiceError::Unauthorized("Missing login!"));
         }
 
         if let Some(ref session) = self.session {
-            return Ok(session.account.clone());
+            if !session.is_payment_only() {
+                return Ok(session.account.clone());
+            }
         }
 
         Err(ServiceError::Forbidden)
     }
 
+    /// Require a session that may execute a payment for the given account, this includes payment tokens
+    pub fn session_require_payment(&self, account_id: u64) -> ServiceResult<Session> {
+        if let Some(ref session) = self.session {
+            if session.is_payment_only() {
+                if session.account.id == account_id {
+                    return Ok(session.clone());
+                }
+
+                return Err(ServiceError::Forbidden);
+            }
+        }
+
+        self.session_require_permission_or_self(models::Permission::PaymentsWrite, account_id)?;
+        self.session_require()
+    }
+
     pub fn session_require_password_reset_token(&self) -> ServiceResult<models::Account> {
         if !self.session_is_present() {
             return Err(ServiceError::Unauthorized("Missing login!"));
@@ -179,9 +287,52 @@ impl RequestState {
         }
 
         if let Some(ref session) = self.session {
-            return Ok(session.clone());
+            if !session.is_payment_only() {
+                return Ok(session.clone());
+            }
         }
 
         Err(ServiceError::Forbidden)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_client_ip() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let client: IpAddr = "192.0.2.7".parse().unwrap();
+        let trusted = [proxy];
+
+        // Headers of untrusted peers are ignored
+        assert_eq!(
+            resolve_client_ip(
+                Some(client),
+                Some("198.51.100.1"),
+                Some("198.51.100.2"),
+                &trusted
+            ),
+            Some(client)
+        );
+
+        // A spoofed entry in front of the chain is skipped
+        assert_eq!(
+            resolve_client_ip(Some(proxy), Some("198.51.100.1, 192.0.2.7"), None, &trusted),
+            Some(client)
+        );
+        assert_eq!(
+            resolve_client_ip(Some(proxy), None, Some("192.0.2.7"), &trusted),
+            Some(client)
+        );
+        assert_eq!(
+            resolve_client_ip(Some(proxy), Some("garbage"), None, &trusted),
+            Some(proxy)
+        );
+        assert_eq!(
+            resolve_client_ip(None, Some("192.0.2.7"), None, &trusted),
+            None
+        );
+    }
+}
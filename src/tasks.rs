@@ -0,0 +1,96 @@
+//! Periodic maintenance jobs that run in the background of the server process.
+use std::time::Duration;
+
+use log::{error, info};
+
+use crate::database::{AppState, DatabaseConnection};
+use crate::error::{ServiceError, ServiceResult};
+use crate::{google_wallet, push_queue};
+
+const SESSION_CLEANUP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const NFC_CHALLENGE_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const PUSH_QUEUE_INTERVAL: Duration = Duration::from_secs(30);
+/// Dead push jobs are kept this long for inspection
+const PUSH_DEAD_LETTER_DAYS: i64 = 30;
+
+pub fn spawn_background_tasks(app_state: AppState) {
+    let reencrypt_state = app_state.clone();
+    tokio::task::spawn(async move {
+        match reencrypt_nfc_secrets(&reencrypt_state).await {
+            Ok(0) => {}
+            Ok(count) => info!(
+                "Re-encrypted {} nfc card keys with the current master key",
+                count
+            ),
+            Err(e) => error!("Could not re-encrypt nfc card keys! {:?}", e),
+        }
+    });
+
+    let challenge_storage = app_state.nfc_challenge_storage.clone();
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(NFC_CHALLENGE_SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = challenge_storage.sweep_expired().await {
+                error!("Could not sweep expired nfc challenges! {:?}", e)
+            }
+        }
+    });
+
+    let push_state = app_state.clone();
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(PUSH_QUEUE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = push_queue::wait_for_jobs() => {}
+            }
+            if let Err(e) = deliver_push_jobs(&push_state).await {
+                error!("Could not deliver push jobs! {:?}", e)
+            }
+        }
+    });
+
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(SESSION_CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = cleanup_sessions(&app_state).await {
+                error!("Could not cleanup expired sessions! {:?}", e)
+            }
+        }
+    });
+}
+
+async fn connect(app_state: &AppState) -> ServiceResult<DatabaseConnection> {
+    let connection = app_state
+        .pool
+        .acquire()
+        .await
+        .map_err(|err| ServiceError::InternalServerError(err.to_string()))?;
+    Ok(DatabaseConnection { connection })
+}
+
+async fn cleanup_sessions(app_state: &AppState) -> ServiceResult<()> {
+    let mut db = connect(app_state).await?;
+    db.cleanup_session_tokens().await?;
+    db.cleanup_nfc_provisionings().await?;
+    db.cleanup_rate_limit_events().await?;
+    db.cleanup_dead_push_jobs(chrono::Duration::days(PUSH_DEAD_LETTER_DAYS))
+        .await
+}
+
+async fn deliver_push_jobs(app_state: &AppState) -> ServiceResult<()> {
+    let mut db = connect(app_state).await?;
+    let apns = push_queue::get_apns().await?;
+    while push_queue::process_push_jobs(&mut db, apns, &google_wallet::SharedGoogleWalletClient)
+        .await?
+        == push_queue::PUSH_BATCH_SIZE as usize
+    {}
+    Ok(())
+}
+
+async fn reencrypt_nfc_secrets(app_state: &AppState) -> ServiceResult<usize> {
+    let mut db = connect(app_state).await?;
+    db.reencrypt_nfc_secrets().await
+}
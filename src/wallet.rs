@@ -1,15 +1,14 @@
-use log::{error, info};
+use log::info;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
 use std::io::Cursor;
 use std::time::{SystemTime, UNIX_EPOCH};
 use wallet_pass::{template, Pass};
 
-use crate::apns::ApplePushNotificationService;
 use crate::database::DatabaseConnection;
-use crate::env;
-use crate::error::{ServiceError, ServiceResult};
+use crate::error::ServiceResult;
 use crate::models::{Account, AppleWalletPass, CoinType};
+use crate::{env, google_wallet, push_queue};
 
 pub fn get_current_time() -> u64 {
     let start = SystemTime::now();
@@ -99,10 +98,38 @@ pub fn create_pass_binary(
     Ok(cursor.into_inner())
 }
 
+/// Get the wallet pass of the account, a new pass with a random qr code is created if there is none
+pub async fn get_or_create_pass(
+    db: &mut DatabaseConnection,
+    account_id: u64,
+) -> ServiceResult<AppleWalletPass> {
+    let pass = db
+        .get_apple_wallet_pass(account_id, &env::APPLE_WALLET_PASS_TYPE_IDENTIFIER)
+        .await?;
+
+    if let Some(pass) = pass {
+        return Ok(pass);
+    }
+
+    db.store_apple_wallet_pass(AppleWalletPass {
+        account_id,
+        pass_type_id: env::APPLE_WALLET_PASS_TYPE_IDENTIFIER.to_string(),
+        authentication_token: String::new(),
+        qr_code: generate_random_string(64),
+        updated_at: get_current_time(),
+    })
+    .await
+}
+
 pub async fn send_update_notification(
     db: &mut DatabaseConnection,
     account_id: u64,
 ) -> ServiceResult<()> {
+    let mut queued = 0;
+    if google_wallet::is_enabled() {
+        queued += db.enqueue_google_wallet_job(account_id).await?;
+    }
+
     let pass = db
         .get_apple_wallet_pass(account_id, &env::APPLE_WALLET_PASS_TYPE_IDENTIFIER)
         .await?;
@@ -114,54 +141,12 @@ pub async fn send_update_notification(
         return Ok(());
     }
 
-    let registrations = db
-        .list_apple_wallet_registration(account_id, &env::APPLE_WALLET_PASS_TYPE_IDENTIFIER)
+    queued += db
+        .enqueue_push_jobs(account_id, &env::APPLE_WALLET_PASS_TYPE_IDENTIFIER)
         .await?;
-
-    info!("Send APNS message for account: {:?}", account_id);
-
-    let mut unregister_vec = Vec::<String>::new();
-
-    let apns = ApplePushNotificationService::new()?;
-    for registration in registrations {
-        let response_code = match apns.send(&registration.push_token).await {
-            Ok(response_code) => response_code,
-            Err(e) => {
-                error!("Error while communicating with APNS: {:?}", e);
-                continue;
-            }
-        };
-
-        let unregister = match response_code {
-            200 => false,
-            410 => true,
-            _ => {
-                return Err(ServiceError::InternalServerError(format!(
-                    "APNS returned illegal status code: {}",
-                    response_code
-                )))
-            }
-        };
-
-        if unregister {
-            unregister_vec.push(registration.device_id.clone());
-        }
-    }
-
-    for device_id in unregister_vec {
-        if let Err(e) = db
-            .delete_apple_wallet_registration(
-                account_id,
-                &env::APPLE_WALLET_PASS_TYPE_IDENTIFIER,
-                &device_id,
-            )
-            .await
-        {
-            error!(
-                "Cannot unregister device {} as APNS requested: {:?}",
-                &device_id, e
-            );
-        }
+    if queued > 0 {
+        info!("Queued wallet update for account: {:?}", account_id);
+        push_queue::notify_worker();
     }
 
     Ok(())